@@ -1,68 +1,359 @@
 use crate::{
     config::Config,
+    db,
     dd::{DdBuilder, run_once},
     error::AppError,
-    metadata::{BackupMeta, JsonWriter},
+    hooks::{Hooks, Phase},
+    inventory::Inventory,
+    metadata::{
+        SnapshotRecord,
+        record::{Details, TarSection},
+    },
+    partial, preflight, replicate,
+    shell::Cmd,
     ssh::Ssh,
-    tar::{Compression, TarBuilder, verify::sha256_file},
+    storage::{self, Storage, Stored},
+    tar::{
+        Compression, Compressor, TarBuilder, TarOptions,
+        snapshot::{SnapshotKind, SnapshotRoot},
+    },
+    throttle::{self, Limits},
+    volume::{self, HashWriter, VolumeWriter},
 };
 
-use chrono::{SecondsFormat, Utc};
-use std::path::PathBuf;
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::path::{Path, PathBuf};
 pub fn run(cfg: &Config) -> Result<(), AppError> {
-    log::info!("Connecting to {}", cfg.remote.host);
-    let ssh = Ssh::connect(
-        cfg.remote.host,
-        cfg.remote.port,
-        &cfg.remote.user,
-        &cfg.remote.password,
-        cfg.remote.private_key.as_deref(),
-    )?;
+    let mut hooks = Hooks::new(cfg)?;
+    let res = run_tar(cfg, &mut hooks);
+    if let Err(e) = &res {
+        hooks.run_on_failure(e);
+    }
+    res
+}
 
-    let filename = resolve_filename(&cfg.backup.filename);
+fn run_tar(cfg: &Config, hooks: &mut Hooks) -> Result<(), AppError> {
+    let compressor = Compressor::new(
+        Compression::parse(&cfg.backup.compression)?,
+        cfg.backup.compression_level,
+        cfg.backup.compression_threads,
+    )?;
+    let tar_options = TarOptions {
+        acls: cfg.backup.acls,
+        xattrs: cfg.backup.xattrs,
+        selinux: cfg.backup.selinux,
+        numeric_owner: cfg.backup.numeric_owner,
+        one_file_system: cfg.backup.one_file_system,
+        sparse: cfg.backup.sparse,
+    };
+    let filename = with_extension(
+        &resolve_filename(&cfg.backup.filename, Utc::now()),
+        compressor.codec,
+    );
     let remote_path = format!("{}/{}", cfg.backup.dir.trim_end_matches('/'), filename);
+    hooks.set("REMOTE_PATH", remote_path.as_str());
+
+    let kind = SnapshotKind::parse(&cfg.backup.snapshot, &cfg.backup.snapshot_size)?;
+    let details = Details::Tar(TarSection {
+        remote_path: remote_path.clone(),
+        filesystems: cfg.filesystems.iter().map(ToString::to_string).collect(),
+        options: tar_options,
+        snapshot: kind.name().into(),
+    });
+    let record = SnapshotRecord::new(
+        details,
+        &Path::new(&cfg.options.local_download_dir).join(&filename),
+        &cfg.remote.host.to_string(),
+        &compressor.codec.to_string(),
+        Utc::now(),
+    );
+
+    let res = tar_and_store(
+        cfg,
+        hooks,
+        compressor,
+        tar_options,
+        &kind,
+        &remote_path,
+        record.clone(),
+    );
+    if let Err(e) = &res {
+        let mut record = record;
+        if !cfg.options.download_to_local {
+            record.local_path.clear();
+        }
+        record.record_aborted(&e.to_string());
+    }
+    res
+}
 
-    let tar_cmd = TarBuilder::new(&remote_path)
-        .paths(cfg.filesystems.iter().map(|fs| fs.to_string()))
-        .exclude_default_runtime()
-        .compression(Compression::Gzip)
-        .build()
-        .unwrap();
+fn tar_and_store(
+    cfg: &Config,
+    hooks: &mut Hooks,
+    compressor: Compressor,
+    tar_options: TarOptions,
+    kind: &SnapshotKind,
+    remote_path: &str,
+    record: SnapshotRecord,
+) -> Result<(), AppError> {
+    let store = storage::open(cfg)?;
+    let previous = storage::snapshots(store.as_ref())?;
+    let limits = Limits::from_config(&cfg.throttle)?;
+    log_limit(&limits);
+    hooks.run(Phase::PreConnect, None)?;
+
+    log::info!("Connecting to {}", cfg.remote.host);
+    let ssh = Ssh::connect_remote(&cfg.remote)?;
+    preflight::enforce(preflight::tar_checks(cfg, &ssh)?)?;
+    let inventory =
+        (cfg.options.inventory && cfg.options.download_to_local).then(|| Inventory::collect(&ssh));
+    hooks.run(Phase::PreSnapshot, Some(&ssh))?;
+    let mut records = db::dump_all(cfg, &ssh, &limits)?;
+
+    let paths: Vec<String> = cfg.filesystems.iter().map(|fs| fs.to_string()).collect();
+    // Held until tar is done; dropping it unmounts and removes the snapshots.
+    let snapshot = match kind {
+        SnapshotKind::None => None,
+        _ => Some(SnapshotRoot::prepare(
+            &ssh,
+            kind,
+            &paths,
+            tar_options.one_file_system,
+        )?),
+    };
+
+    // Written under a temporary name, removed again if the run stops early.
+    let staged = RemotePartial::new(&ssh, remote_path);
+    let mut builder = TarBuilder::new(staged.partial_path())
+        .paths(paths)
+        .excludes(tar_excludes(cfg, &ssh)?)
+        .exclude_caches(cfg.backup.exclude_caches)
+        .compressor(compressor)
+        .options(tar_options);
+    if cfg.backup.default_excludes {
+        builder = builder.exclude_default_runtime();
+    }
+    for tag in &cfg.backup.exclude_if_present {
+        builder = builder.exclude_if_present(tag);
+    }
+    if let Some(snap) = &snapshot {
+        builder = builder.directory(snap.root());
+    }
+    let tar_cmd = builder.build().unwrap();
 
     log::info!("Creating snapshot on remote: {}", tar_cmd);
-    ssh.exec_verbose(&tar_cmd)?;
+    ssh.exec_verbose(&limits.niced(&tar_cmd))?;
+    staged.commit()?;
+    drop(snapshot);
 
     log::info!("Snapshot created at {}", remote_path);
+    hooks.run(Phase::PostSnapshot, Some(&ssh))?;
 
     if cfg.options.download_to_local {
         let mut local_path = PathBuf::from(&cfg.options.local_download_dir);
         std::fs::create_dir_all(&local_path)?;
-        local_path.push(&filename);
-
-        ssh.download(&remote_path, &local_path)?;
-        log::info!("Snapshot saved to {:?}", local_path);
+        local_path.push(&record.snapshot_name);
 
-        let metadata = BackupMeta {
-            snapshot_name: filename.clone(),
-            remote_path: remote_path.clone(),
-            local_path: local_path.display().to_string(),
-            size_bytes: std::fs::metadata(&local_path)?.len(),
-            sha256: sha256_file(&local_path)?,
-            timestamp: Utc::now(),
-            filesystems: cfg.filesystems.iter().map(ToString::to_string).collect(),
+        // Hashed on the way in; the final names appear only once it is all on disk.
+        let mut pacer = limits.pacer();
+        let (volumes, sha256, size_bytes) = match cfg.options.volume_size {
+            Some(size) => {
+                let mut out = HashWriter::new(VolumeWriter::new(&local_path, size));
+                ssh.download_into(remote_path, &mut out, &mut pacer)?;
+                let (out, sha256, len) = out.finish();
+                let volumes = out.finish()?;
+                volume::commit(&local_path, &volumes)?;
+                (volumes, sha256, len)
+            }
+            None => {
+                let file = std::fs::File::create(partial::path(&local_path))?;
+                let mut out = HashWriter::new(file);
+                ssh.download_into(remote_path, &mut out, &mut pacer)?;
+                let (file, sha256, len) = out.finish();
+                file.sync_all()?;
+                partial::commit(&local_path)?;
+                (Vec::new(), sha256, len)
+            }
         };
+        log::info!("Snapshot saved to {:?}", local_path);
 
-        JsonWriter::write(&metadata, &cfg.options.local_download_dir)?;
+        hooks.set("LOCAL_PATH", local_path.display().to_string());
+        hooks.set("SHA256", sha256.as_str());
+        hooks.run(Phase::PostDownload, Some(&ssh))?;
 
-        log::info!("Metadata saved to {:?}", cfg.options.local_download_dir);
+        let mut record = record.completed(size_bytes, sha256, volumes);
+        record.hooks = hooks.records().to_vec();
+        if let Some(inv) = &inventory {
+            inv.write(&local_path)?;
+        }
+        records.push(record);
     }
 
+    store_all(cfg, store.as_ref(), &previous, records)
+}
+
+/// Completes the records of finished snapshots (tags, parent) and writes
+/// them, then moves the snapshots to the storage backend, records them in
+/// the catalog and copies them to the replicas.
+fn store_all(
+    cfg: &Config,
+    store: &dyn Storage,
+    previous: &[Stored],
+    mut records: Vec<SnapshotRecord>,
+) -> Result<(), AppError> {
+    let mut paths = Vec::new();
+    for record in &mut records {
+        let series = record.series();
+        record.parent = previous
+            .iter()
+            .rev()
+            .find(|s| s.series() == series)
+            .map(|s| s.name.clone());
+        record.tags = cfg.options.tags.clone();
+        record.write()?;
+        paths.push(PathBuf::from(&record.local_path));
+    }
+    for path in &paths {
+        storage::publish(cfg, store, path)?;
+    }
+    let names: Vec<String> = paths.iter().map(|p| storage::file_name(p)).collect();
+    let new: Vec<_> = storage::snapshots(store)?
+        .into_iter()
+        .filter(|s| names.contains(&s.name))
+        .collect();
+    // The snapshots are stored and catalogued: a replica that is down is
+    // recorded in the catalog and left for `data-backup replicate`.
+    let failed = replicate::replicate(cfg, store, &new, None)?;
+    if !failed.is_empty() {
+        log::warn!(
+            "replication failed ({}); the backup is stored in {}, retry with `data-backup replicate`",
+            failed.join(", "),
+            store.describe()
+        );
+    }
     Ok(())
 }
 
-fn resolve_filename(template: &str) -> String {
+/// The remote archive while tar writes it: `<path>.partial`, renamed by
+/// [`RemotePartial::commit`] and deleted if dropped before that.
+struct RemotePartial<'a> {
+    ssh: &'a Ssh,
+    path: String,
+    partial: String,
+    done: bool,
+}
+
+impl<'a> RemotePartial<'a> {
+    fn new(ssh: &'a Ssh, path: &str) -> Self {
+        Self {
+            ssh,
+            path: path.into(),
+            partial: format!("{path}{}", partial::SUFFIX),
+            done: false,
+        }
+    }
+
+    /// Where tar should write.
+    fn partial_path(&self) -> &str {
+        &self.partial
+    }
+
+    fn commit(mut self) -> Result<(), AppError> {
+        let mv = Cmd::new("mv")
+            .sudo(true)
+            .arg("-f")
+            .arg(&self.partial)
+            .arg(&self.path);
+        self.ssh
+            .exec_capture(&mv.to_string(), &mut std::io::sink())?;
+        self.done = true;
+        Ok(())
+    }
+}
+
+impl Drop for RemotePartial<'_> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        log::info!("Removing unfinished remote archive {}", self.partial);
+        let rm = Cmd::new("rm").sudo(true).arg("-f").arg(&self.partial);
+        if let Err(e) = self.ssh.exec_capture(&rm.to_string(), &mut std::io::sink()) {
+            log::error!("cannot remove {}: {e}", self.partial);
+        }
+    }
+}
+
+fn log_limit(limits: &Limits) {
+    let rate = limits.rate_now();
+    if rate > 0 {
+        log::info!("Bandwidth limit: {}", throttle::describe(rate));
+    }
+}
+
+/// Inline, file-based and per-filesystem exclude patterns from `[backup]`.
+fn tar_excludes(cfg: &Config, ssh: &Ssh) -> Result<Vec<String>, AppError> {
+    let mut rules = local_excludes(cfg)?;
+    for path in &cfg.backup.exclude_from {
+        let mut out = Vec::new();
+        ssh.exec_capture(&read_remote(path).to_string(), &mut out)?;
+        rules.extend(pattern_lines(&String::from_utf8_lossy(&out)));
+    }
+    Ok(rules)
+}
+
+fn read_remote(path: &str) -> Cmd {
+    Cmd::new("cat").args(["--", path])
+}
+
+/// The patterns that need no remote: inline, per-filesystem and local files.
+fn local_excludes(cfg: &Config) -> Result<Vec<String>, AppError> {
+    let mut rules = cfg.backup.excludes.clone();
+
+    for (fs, ov) in &cfg.backup.filesystem {
+        if !cfg.filesystems.iter().any(|f| f.to_string() == *fs) {
+            log::warn!("[backup.filesystem.\"{fs}\"] does not match any `filesystems` entry");
+        }
+        let base = fs.trim_end_matches('/');
+        rules.extend(ov.excludes.iter().map(|r| {
+            if r.starts_with('/') {
+                r.clone()
+            } else {
+                format!("{base}/{r}")
+            }
+        }));
+    }
+
+    for path in &cfg.backup.exclude_from_local {
+        rules.extend(pattern_lines(&std::fs::read_to_string(path)?));
+    }
+    Ok(rules)
+}
+
+/// Non-empty, non-comment lines of an exclude file.
+fn pattern_lines(text: &str) -> Vec<String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(String::from)
+        .collect()
+}
+
+/// Swaps a tar extension in `name` (or appends one) to match `codec`.
+fn with_extension(name: &str, codec: Compression) -> String {
+    let lower = name.to_ascii_lowercase();
+    let stem_len = [
+        ".tar.gz", ".tar.xz", ".tar.zst", ".tgz", ".txz", ".tzst", ".tar",
+    ]
+    .iter()
+    .find(|ext| lower.ends_with(*ext))
+    .map_or(name.len(), |ext| name.len() - ext.len());
+    format!("{}{}", &name[..stem_len], codec.ext())
+}
+
+fn resolve_filename(template: &str, now: DateTime<Utc>) -> String {
     if template.contains("{{timestamp}}") {
-        let ts = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+        let ts = now.to_rfc3339_opts(SecondsFormat::Secs, true);
         template.replace("{{timestamp}}", &ts)
     } else {
         template.into()
@@ -72,9 +363,137 @@ fn resolve_filename(template: &str) -> String {
 pub fn run_dd(cfg: &Config) -> Result<(), AppError> {
     log::info!("Running dd snapshot from config");
 
-    let dd_cfg = DdBuilder::new(cfg).build()?;
-    let meta = run_once(dd_cfg)?;
+    let mut hooks = Hooks::new(cfg)?;
+    let res = image_dd(cfg, &mut hooks);
+    if let Err(e) = &res {
+        hooks.run_on_failure(e);
+    }
+    res
+}
 
-    log::info!("Snapshot saved to {}", meta.local_path);
-    Ok(())
+/// Images are streamed straight to local disk, so post-snapshot and
+/// post-download run back to back once every image is written.
+fn image_dd(cfg: &Config, hooks: &mut Hooks) -> Result<(), AppError> {
+    let store = storage::open(cfg)?;
+    let previous = storage::snapshots(store.as_ref())?;
+    hooks.run(Phase::PreConnect, None)?;
+    let ssh = Ssh::connect_remote(&cfg.remote)?;
+    preflight::enforce(preflight::dd_checks(cfg, &ssh)?)?;
+    let dd_cfg = DdBuilder::new(cfg).build_on(ssh)?;
+    preflight::enforce(preflight::dd_target_checks(cfg, &dd_cfg)?)?;
+    let inventory = cfg
+        .options
+        .inventory
+        .then(|| Inventory::collect(&dd_cfg.ssh));
+    log_limit(&dd_cfg.limits);
+    hooks.run(Phase::PreSnapshot, Some(&dd_cfg.ssh))?;
+    let mut records = db::dump_all(cfg, &dd_cfg.ssh, &dd_cfg.limits)?;
+
+    let mut images = run_once(&dd_cfg)?;
+
+    // One line per image.
+    let lines =
+        |f: fn(&SnapshotRecord) -> &str| images.iter().map(f).collect::<Vec<_>>().join("\n");
+    fn device(m: &SnapshotRecord) -> &str {
+        m.dd.as_ref().map_or("", |d| d.device.as_str())
+    }
+    hooks.set("REMOTE_PATH", lines(device));
+    hooks.set("LOCAL_PATH", lines(|m| &m.local_path));
+    hooks.set("SHA256", lines(|m| &m.hashes.sha256));
+    hooks.run(Phase::PostSnapshot, Some(&dd_cfg.ssh))?;
+    hooks.run(Phase::PostDownload, Some(&dd_cfg.ssh))?;
+
+    for image in &mut images {
+        image.hooks = hooks.records().to_vec();
+        if let Some(inv) = &inventory {
+            inv.write(Path::new(&image.local_path))?;
+        }
+        log::info!(
+            "Snapshot of {} saved to {}",
+            device(image),
+            image.local_path
+        );
+    }
+    records.extend(images);
+    store_all(cfg, store.as_ref(), &previous, records)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::testutil::{TempDir, config};
+
+    #[test]
+    fn per_filesystem_excludes_are_joined_to_their_filesystem() {
+        let dir = TempDir::new("excludes");
+        let list = dir.join("excludes.txt");
+        std::fs::write(&list, "# caches\n\n  *.tmp  \n/srv/scratch\n").unwrap();
+        let mut cfg = config(
+            dir.path(),
+            r#"
+            [backup.filesystem."/var/"]
+            excludes = ["cache", "/var/lib/docker", "log/*.gz"]
+            [backup.filesystem."/"]
+            excludes = ["swapfile"]
+            "#,
+        );
+        cfg.backup.excludes = vec!["*.log".into()];
+        cfg.backup.exclude_from_local = vec![list.display().to_string()];
+        assert_eq!(
+            local_excludes(&cfg).unwrap(),
+            [
+                "*.log",
+                "/swapfile",
+                "/var/cache",
+                "/var/lib/docker",
+                "/var/log/*.gz",
+                "*.tmp",
+                "/srv/scratch",
+            ]
+        );
+    }
+
+    #[test]
+    fn remote_exclude_file_is_read_with_a_quoted_cat() {
+        assert_eq!(
+            read_remote("/etc/backup excludes; rm -rf ~").to_string(),
+            "cat -- '/etc/backup excludes; rm -rf ~'"
+        );
+        assert_eq!(read_remote("-n").to_string(), "cat -- -n");
+    }
+
+    #[test]
+    fn timestamp_filename_gets_the_codec_extension() {
+        let now = Utc.with_ymd_and_hms(2025, 1, 2, 3, 4, 5).unwrap();
+        let codecs = [
+            (Compression::None, ".tar"),
+            (Compression::Gzip, ".tar.gz"),
+            (Compression::Xz, ".tar.xz"),
+            (Compression::Zstd, ".tar.zst"),
+        ];
+        // template, resolved name before the extension
+        let templates = [
+            ("backup-{{timestamp}}", "backup-2025-01-02T03:04:05Z"),
+            ("backup-{{timestamp}}.tar.gz", "backup-2025-01-02T03:04:05Z"),
+            (
+                "backup-{{timestamp}}.TAR.ZST",
+                "backup-2025-01-02T03:04:05Z",
+            ),
+            ("{{timestamp}}.tgz", "2025-01-02T03:04:05Z"),
+            ("host.tar.xz", "host"),
+            ("host.tar", "host"),
+            ("host", "host"),
+        ];
+        for (codec, ext) in codecs {
+            for (template, stem) in templates {
+                assert_eq!(
+                    with_extension(&resolve_filename(template, now), codec),
+                    format!("{stem}{ext}"),
+                    "{template} {codec}"
+                );
+            }
+        }
+    }
 }
@@ -0,0 +1,88 @@
+//! Local index of finished snapshots: where each one is stored and how its
+//! copies on the replica targets stand.
+
+use std::{collections::BTreeMap, fs, io, path::Path};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{partial, storage::Stored};
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Catalog {
+    /// Keyed by snapshot name (the object key of its data).
+    #[serde(default)]
+    pub snapshots: BTreeMap<String, CatalogEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CatalogEntry {
+    /// `tar`, `dd` or `db`.
+    pub kind: String,
+    pub sha256: String,
+    pub timestamp: DateTime<Utc>,
+    pub size_bytes: u64,
+    /// Primary backend, as described in logs.
+    pub stored_in: String,
+    /// Per replica target name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub replicas: BTreeMap<String, ReplicaStatus>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReplicaState {
+    /// Copied and its hash checked.
+    Verified,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicaStatus {
+    pub state: ReplicaState,
+    /// Where the copy is, as described in logs.
+    pub target: String,
+    pub checked_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Catalog {
+    /// An empty catalog when the file does not exist yet.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(raw) => serde_json::from_str(&raw).map_err(io::Error::other),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Written atomically, so a crash never leaves half a catalog.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        partial::write(path, serde_json::to_string_pretty(self)?.as_bytes())
+    }
+
+    /// The entry for `stored`, added (or refreshed) from its metadata.
+    pub fn entry(&mut self, stored: &Stored, stored_in: &str) -> &mut CatalogEntry {
+        let e = self
+            .snapshots
+            .entry(stored.name.clone())
+            .or_insert_with(|| CatalogEntry {
+                kind: String::new(),
+                sha256: String::new(),
+                timestamp: stored.timestamp(),
+                size_bytes: 0,
+                stored_in: String::new(),
+                replicas: BTreeMap::new(),
+            });
+        e.kind = stored.kind.name().into();
+        e.sha256 = stored.meta.hashes.sha256.clone();
+        e.timestamp = stored.timestamp();
+        e.size_bytes = stored.size;
+        e.stored_in = stored_in.into();
+        e
+    }
+}
@@ -1,12 +1,126 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
 
 #[derive(Debug, Parser)]
 #[command(author, version, about)]
 pub struct Args {
     /// Path to `config.toml`.
-    #[arg(short = 'c', long, default_value = "config.toml")]
+    #[arg(short = 'c', long, default_value = "config.toml", global = true)]
     pub config: String,
 
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     pub verbose: bool,
+
+    /// If another run of the same backup job is running, wait for it to finish.
+    #[arg(long, global = true, conflicts_with = "no_wait")]
+    pub wait: bool,
+
+    /// If another run of the same backup job is running, fail at once
+    /// (overrides `lock.wait`).
+    #[arg(long, global = true)]
+    pub no_wait: bool,
+
+    /// Remove the job's remote lock file even if another run seems to hold it.
+    #[arg(long, global = true)]
+    pub break_lock: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Take a snapshot as described by the config (default).
+    Backup,
+
+    /// Compare two tar snapshots or two dd images of the same host.
+    Diff {
+        /// Older snapshot.
+        a: PathBuf,
+        /// Newer snapshot.
+        b: PathBuf,
+
+        /// Print the report as JSON instead of text.
+        #[arg(long)]
+        json: bool,
+
+        /// Block size in bytes used to compare dd images.
+        #[arg(long, default_value_t = 65536)]
+        block_size: u64,
+
+        /// Compare even if the records name different hosts or series.
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Expose a local tar archive or dd image as a read-only FUSE filesystem.
+    Mount {
+        /// Snapshot file (`*.tar*` or `*.img`).
+        snapshot: PathBuf,
+        /// Existing, empty directory to mount on.
+        mountpoint: PathBuf,
+    },
+
+    /// Write a local snapshot back onto the remote host from the config.
+    Restore {
+        /// Snapshot file, with its `.json` metadata next to it. For a split
+        /// snapshot, the name without the volume number (or its `.001`).
+        /// Fetched from the configured storage when it is not found locally.
+        snapshot: PathBuf,
+        /// Where to restore: a block device for dd images, a remote directory
+        /// for tar archives, a database name (file path for SQLite/Redis) for dumps.
+        #[arg(long)]
+        target: String,
+        /// Confirm overwriting the target.
+        #[arg(long)]
+        yes: bool,
+    },
+
+    /// Check a snapshot, volume by volume if it was split, against its metadata.
+    Verify {
+        /// Snapshot file, or the first volume of a split one. Looked up in
+        /// the configured storage when it is not found locally.
+        snapshot: PathBuf,
+    },
+
+    /// List the snapshots in the configured storage, oldest first.
+    List,
+
+    /// Copy snapshots missing from the `[[replicas]]` targets and check each copy.
+    Replicate {
+        /// Only this replica (by `name`).
+        #[arg(long)]
+        target: Option<String>,
+    },
+
+    /// Delete old snapshots from the configured storage.
+    Prune {
+        /// Keep this many of the newest snapshots of each series.
+        #[arg(long)]
+        keep_last: Option<usize>,
+        /// Only delete snapshots older than this many days.
+        #[arg(long)]
+        older_than_days: Option<u64>,
+        /// Show what would be deleted without deleting it.
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Check the remote and local side for what a backup needs (tools,
+    /// sudo, free space) without taking a snapshot.
+    Doctor,
+
+    /// Run backups on the `[schedule]` of each host config until stopped.
+    /// SIGHUP re-reads the configs.
+    Daemon {
+        /// Host configs, or directories of `*.toml` host configs.
+        /// Default: `--config`.
+        configs: Vec<PathBuf>,
+        /// Backups running at the same time.
+        #[arg(long, default_value_t = 1)]
+        max_jobs: usize,
+        /// JSON file with each job's state, last result and next run.
+        #[arg(long, default_value = "metadata/daemon-status.json")]
+        status: PathBuf,
+    },
 }
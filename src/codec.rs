@@ -0,0 +1,177 @@
+//! Local decompression of downloaded snapshots.
+
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::Path,
+    process::{Child, ChildStdout, Command, Stdio},
+};
+
+use crate::error::AppError;
+
+/// Compression of a local snapshot file, detected from its extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    None,
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+impl Codec {
+    pub fn detect<P: AsRef<Path>>(path: P) -> Self {
+        let name = path
+            .as_ref()
+            .file_name()
+            .map(|n| n.to_string_lossy().to_ascii_lowercase())
+            .unwrap_or_default();
+        if name.ends_with(".gz") || name.ends_with(".tgz") {
+            Self::Gzip
+        } else if name.ends_with(".xz") || name.ends_with(".txz") {
+            Self::Xz
+        } else if name.ends_with(".zst") || name.ends_with(".tzst") {
+            Self::Zstd
+        } else {
+            Self::None
+        }
+    }
+
+    /// Parses a compression setting: `none`, `gzip`, `zstd` or `xz`.
+    pub fn parse(txt: &str) -> Result<Self, AppError> {
+        match txt.to_ascii_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "gzip" => Ok(Self::Gzip),
+            "zstd" => Ok(Self::Zstd),
+            "xz" => Ok(Self::Xz),
+            other => Err(AppError::Validation(format!(
+                "unknown compression `{other}` (expected none, gzip, zstd or xz)"
+            ))),
+        }
+    }
+
+    /// As in the config and in snapshot records; [`Self::parse`] reads it back.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Gzip => "gzip",
+            Self::Xz => "xz",
+            Self::Zstd => "zstd",
+        }
+    }
+
+    /// File name suffix, empty for uncompressed files.
+    pub fn suffix(self) -> &'static str {
+        match self {
+            Self::None => "",
+            Self::Gzip => ".gz",
+            Self::Xz => ".xz",
+            Self::Zstd => ".zst",
+        }
+    }
+
+    /// Remote filter compressing stdin to stdout.
+    pub fn compress_cmd(self) -> &'static str {
+        match self {
+            Self::None => "cat",
+            Self::Gzip => "gzip -c",
+            Self::Xz => "xz -c",
+            Self::Zstd => "zstd -q -c",
+        }
+    }
+
+    /// Remote filter undoing [`Self::compress_cmd`].
+    pub fn decompress_cmd(self) -> Option<&'static str> {
+        match self {
+            Self::None => None,
+            Self::Gzip => Some("gzip -dc"),
+            Self::Xz => Some("xz -dc"),
+            // Skippable frames (a seek table) are ignored by `zstd -d`.
+            Self::Zstd => Some("zstd -q -dc"),
+        }
+    }
+
+    fn program(self) -> Option<&'static str> {
+        match self {
+            Self::None => None,
+            Self::Gzip => Some("gzip"),
+            Self::Xz => Some("xz"),
+            Self::Zstd => Some("zstd"),
+        }
+    }
+
+    /// Opens `path` and returns a reader over the decompressed bytes.
+    pub fn open<P: AsRef<Path>>(self, path: P) -> io::Result<Box<dyn Read>> {
+        match self.program() {
+            None => Ok(Box::new(File::open(path)?)),
+            Some(prog) => {
+                let mut child = Command::new(prog)
+                    .arg("-dc")
+                    .arg(path.as_ref())
+                    .stdout(Stdio::piped())
+                    .spawn()?;
+                let stdout = child.stdout.take().expect("piped stdout");
+                Ok(Box::new(ChildReader { child, stdout }))
+            }
+        }
+    }
+}
+
+/// Opens `path` with the codec matching its extension.
+pub fn open_decompressed<P: AsRef<Path>>(path: P) -> io::Result<Box<dyn Read>> {
+    Codec::detect(&path).open(path)
+}
+
+/// Reads until `buf` is full or EOF; returns the number of bytes read.
+pub fn read_full<R: Read + ?Sized>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match r.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Stdout of a decompressor; EOF is only reported once the child exited cleanly.
+struct ChildReader {
+    child: Child,
+    stdout: ChildStdout,
+}
+
+impl Read for ChildReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stdout.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            let status = self.child.wait()?;
+            if !status.success() {
+                return Err(io::Error::other(format!(
+                    "decompressor exited with {status}"
+                )));
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl Drop for ChildReader {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_parse_back_and_match_suffixes() {
+        for codec in [Codec::None, Codec::Gzip, Codec::Xz, Codec::Zstd] {
+            assert_eq!(Codec::parse(codec.name()).unwrap(), codec);
+            assert_eq!(Codec::detect(format!("x.tar{}", codec.suffix())), codec);
+        }
+    }
+}
@@ -1,7 +1,7 @@
 use crate::error::ConfigError;
 use core::fmt;
 use serde::{Deserialize, Deserializer};
-use std::{fs, net::IpAddr, path::Path};
+use std::{collections::BTreeMap, fs, net::IpAddr, path::Path};
 #[derive(Debug, Clone)]
 pub enum Filesystem {
     Root,     // "/"
@@ -103,6 +103,227 @@ pub struct Config {
 
     #[serde(default)]
     pub dd: Option<DdConfig>,
+
+    /// Commands run around the backup, in config order within each phase.
+    #[serde(default)]
+    pub hooks: Vec<HookConfig>,
+
+    /// Logical dumps taken on the remote before the snapshot.
+    #[serde(default)]
+    pub databases: Vec<DatabaseConfig>,
+
+    /// Where finished snapshots are kept; `local_download_dir` when absent.
+    #[serde(default)]
+    pub storage: Option<StorageConfig>,
+
+    /// Secondary targets every snapshot is copied to.
+    #[serde(default)]
+    pub replicas: Vec<ReplicaConfig>,
+
+    /// Keeps two runs of the same job (host, mode, sources, targets) apart.
+    #[serde(default)]
+    pub lock: LockConfig,
+
+    /// When the `daemon` command runs this backup.
+    #[serde(default)]
+    pub schedule: Option<ScheduleConfig>,
+
+    /// Bandwidth limits and remote CPU/I/O priority.
+    #[serde(default)]
+    pub throttle: ThrottleConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ThrottleConfig {
+    /// Bytes per second read from the remote; 0 = unlimited.
+    #[serde(default)]
+    pub bandwidth_limit: u64,
+    /// Also cap the remote stream with `pv -L` (dd images, database dumps).
+    #[serde(default)]
+    pub remote: bool,
+    /// Niceness of the remote dd/tar, 0-19.
+    #[serde(default)]
+    pub nice: Option<i32>,
+    /// I/O class of the remote dd/tar: "idle", "best-effort" or "best-effort:<0-7>".
+    #[serde(default)]
+    pub ionice: Option<String>,
+    /// Other limits by local time of day; the first matching window wins.
+    #[serde(default)]
+    pub windows: Vec<ThrottleWindow>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ThrottleWindow {
+    /// "HH:MM"; a window with `from` after `to` runs past midnight.
+    pub from: String,
+    pub to: String,
+    /// Bytes per second; 0 = unlimited.
+    #[serde(default)]
+    pub bandwidth_limit: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ScheduleConfig {
+    /// Five-field cron expression in local time, or `@daily`, `@hourly`, ...
+    pub cron: String,
+    /// Runs missed while the daemon was down: "once" (run one now) | "skip".
+    /// Default: "once"
+    #[serde(default = "ScheduleConfig::default_catch_up")]
+    pub catch_up: String,
+    /// Seconds before a running backup is interrupted; 0 = no limit.
+    #[serde(default)]
+    pub timeout: u64,
+}
+impl ScheduleConfig {
+    fn default_catch_up() -> String {
+        "once".into()
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct LockConfig {
+    /// Also take a lock file in `backup.dir` on the remote, for runs started
+    /// from other machines.
+    #[serde(default)]
+    pub remote: bool,
+    /// Wait for a running backup instead of failing; `--wait`/`--no-wait`
+    /// override it.
+    #[serde(default)]
+    pub wait: bool,
+    /// Seconds after which a remote lock file is taken over whoever holds
+    /// it, for holders on other machines whose PID cannot be checked;
+    /// 0 = never. The local `flock` goes away with its process anyway.
+    #[serde(default)]
+    pub stale_after: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReplicaConfig {
+    /// Identifies the target in the catalog and in `replicate --target`.
+    pub name: String,
+    /// Same keys as `[storage]`; `path` is required for a local target.
+    #[serde(flatten)]
+    pub target: StorageConfig,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StorageConfig {
+    /// "local" | "sftp" | "s3"
+    #[serde(default = "StorageConfig::default_backend")]
+    pub backend: String,
+    /// Directory (local, sftp) or key prefix (s3) the snapshots go under.
+    #[serde(default)]
+    pub path: String,
+    /// Keep the copy in `local_download_dir` after it is uploaded.
+    #[serde(default)]
+    pub keep_local: bool,
+    /// Second SSH server for `backend = "sftp"`.
+    #[serde(default)]
+    pub sftp: Option<Remote>,
+    #[serde(default)]
+    pub s3: Option<S3Config>,
+}
+impl StorageConfig {
+    fn default_backend() -> String {
+        "local".into()
+    }
+}
+
+/// An S3-compatible object store (AWS, MinIO, Ceph RGW, ...).
+#[derive(Debug, Deserialize)]
+pub struct S3Config {
+    /// e.g. `https://s3.eu-central-1.amazonaws.com` or `http://127.0.0.1:9000`
+    pub endpoint: String,
+    pub bucket: String,
+    #[serde(default = "S3Config::default_region")]
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// `endpoint/bucket/key` (MinIO default) instead of `bucket.endpoint/key`.
+    #[serde(default = "S3Config::default_path_style")]
+    pub path_style: bool,
+    /// Multipart upload part size in bytes; at least 5 MiB. Raised for objects
+    /// that would need more than 10,000 parts. Default: 16 MiB
+    #[serde(default = "S3Config::default_part_size")]
+    pub part_size: u64,
+}
+impl S3Config {
+    fn default_region() -> String {
+        "us-east-1".into()
+    }
+    fn default_path_style() -> bool {
+        true
+    }
+    fn default_part_size() -> u64 {
+        16 << 20
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DatabaseConfig {
+    /// "postgres" | "mysql" (also MariaDB) | "sqlite" | "redis"
+    pub engine: String,
+    /// Database name; the database file path for SQLite. Unused for Redis.
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub host: Option<String>,
+    #[serde(default)]
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub user: Option<String>,
+    /// Sent over the command's stdin and read into PGPASSWORD / MYSQL_PWD /
+    /// REDISCLI_AUTH by the remote shell, never put on a command line.
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Run the client tools as this remote user (`sudo -u`), e.g. "postgres".
+    #[serde(default)]
+    pub run_as: Option<String>,
+    /// PostgreSQL only: "custom" (`pg_dump -Fc`) or "plain" SQL. Default: "custom"
+    #[serde(default = "DatabaseConfig::default_format")]
+    pub format: String,
+    /// "none" | "gzip" | "zstd" | "xz". Default: "zstd"
+    #[serde(default = "DatabaseConfig::default_compression")]
+    pub compression: String,
+}
+impl DatabaseConfig {
+    fn default_format() -> String {
+        "custom".into()
+    }
+    fn default_compression() -> String {
+        "zstd".into()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HookConfig {
+    /// "pre-connect" | "pre-snapshot" | "post-snapshot" | "post-download" | "on-failure"
+    pub phase: String,
+    /// Shell command, run with `sh -c`.
+    pub command: String,
+    /// "remote" (over SSH) | "local". Default: "remote"
+    #[serde(default = "HookConfig::default_run_on")]
+    pub run_on: String,
+    /// Seconds before the hook is killed. Default: 300
+    #[serde(default = "HookConfig::default_timeout")]
+    pub timeout: u64,
+    /// Fail the backup when the hook fails. Default: true
+    #[serde(default = "HookConfig::default_abort_on_failure")]
+    pub abort_on_failure: bool,
+    /// Extra environment variables for the command.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+}
+impl HookConfig {
+    fn default_run_on() -> String {
+        "remote".into()
+    }
+    fn default_timeout() -> u64 {
+        300
+    }
+    fn default_abort_on_failure() -> bool {
+        true
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -135,6 +356,87 @@ pub struct Backup {
     pub dir: String,
     /// Archive filename
     pub filename: String,
+    /// "none" | "gzip" | "xz" | "zstd". Default: "gzip"
+    #[serde(default = "Backup::default_compression")]
+    pub compression: String,
+    /// Codec level (gzip 1-9, xz 0-9, zstd 1-22); the codec's default when unset.
+    #[serde(default)]
+    pub compression_level: Option<u32>,
+    /// Compressor threads, 0 = one per core (gzip goes through `pigz`). Default: single-threaded
+    #[serde(default)]
+    pub compression_threads: Option<u32>,
+    /// Keep POSIX ACLs; needs GNU tar 1.27 or later. Default: false
+    #[serde(default)]
+    pub acls: bool,
+    /// Keep extended attributes (all namespaces); needs GNU tar 1.27 or later. Default: false
+    #[serde(default)]
+    pub xattrs: bool,
+    /// Keep SELinux contexts; needs a tar built with SELinux support. Default: false
+    #[serde(default)]
+    pub selinux: bool,
+    /// Store uid/gid instead of user and group names. Default: false
+    #[serde(default)]
+    pub numeric_owner: bool,
+    /// Do not descend into other filesystems mounted below a path. Default: false
+    #[serde(default)]
+    pub one_file_system: bool,
+    /// Store holes of sparse files efficiently. Default: false
+    #[serde(default)]
+    pub sparse: bool,
+    /// Archive from a point-in-time snapshot: "none", "lvm" or "btrfs".
+    /// Devices mounted below a listed path must be listed too, unless
+    /// `one_file_system` is set.
+    #[serde(default = "Backup::default_snapshot")]
+    pub snapshot: String,
+    /// LVM snapshot size: `-L` size ("2G") or `-l` extents ("10%ORIGIN").
+    #[serde(default = "Backup::default_snapshot_size")]
+    pub snapshot_size: String,
+
+    /// Extra tar `--exclude` patterns (globs allowed). A leading `/` makes a
+    /// path rule; anything else matches at any depth.
+    #[serde(default)]
+    pub excludes: Vec<String>,
+    /// Remote files with one pattern per line (`#` comments allowed).
+    #[serde(default)]
+    pub exclude_from: Vec<String>,
+    /// Local files with one pattern per line.
+    #[serde(default)]
+    pub exclude_from_local: Vec<String>,
+    /// Skip directories tagged with `CACHEDIR.TAG`. Default: false
+    #[serde(default)]
+    pub exclude_caches: bool,
+    /// Skip directories containing one of these marker files, e.g. ".nobackup".
+    #[serde(default)]
+    pub exclude_if_present: Vec<String>,
+    /// Exclude `/proc`, `/sys`, `/dev`, `/run`, `/tmp`, … Default: true
+    #[serde(default = "Backup::default_default_excludes")]
+    pub default_excludes: bool,
+    /// Per-filesystem settings, keyed by the `filesystems` entry (`[backup.filesystem."/var"]`).
+    #[serde(default)]
+    pub filesystem: BTreeMap<String, FilesystemOverride>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct FilesystemOverride {
+    /// Patterns relative to the filesystem (`cache` under `/var` is `/var/cache`);
+    /// absolute ones are used as-is.
+    #[serde(default)]
+    pub excludes: Vec<String>,
+}
+
+impl Backup {
+    fn default_compression() -> String {
+        "gzip".into()
+    }
+    fn default_default_excludes() -> bool {
+        true
+    }
+    fn default_snapshot() -> String {
+        "none".into()
+    }
+    fn default_snapshot_size() -> String {
+        "10%ORIGIN".into()
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -144,11 +446,29 @@ pub struct Options {
     pub download_to_local: bool,
     #[serde(default = "Options::default_download_dir")]
     pub local_download_dir: String,
+    /// Split local snapshots into numbered volumes of this many bytes.
+    #[serde(default)]
+    pub volume_size: Option<u64>,
+    /// Snapshot catalog: what is stored where, and replication status.
+    #[serde(default = "Options::default_catalog")]
+    pub catalog: String,
+    /// Record the remote's host facts in a `.host.json` next to each snapshot.
+    #[serde(default = "Options::default_inventory")]
+    pub inventory: bool,
+    /// Labels copied into every snapshot record.
+    #[serde(default)]
+    pub tags: Vec<String>,
 }
 impl Options {
     fn default_download_dir() -> String {
         ".".to_string()
     }
+    fn default_catalog() -> String {
+        "metadata/catalog.json".to_string()
+    }
+    fn default_inventory() -> bool {
+        true
+    }
 }
 
 impl Default for Options {
@@ -156,6 +476,10 @@ impl Default for Options {
         Self {
             download_to_local: false,
             local_download_dir: Self::default_download_dir(),
+            volume_size: None,
+            catalog: Self::default_catalog(),
+            inventory: Self::default_inventory(),
+            tags: Vec::new(),
         }
     }
 }
@@ -178,17 +502,21 @@ pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, ConfigError> {
 }
 #[derive(Debug, Deserialize)]
 pub struct DdConfig {
-    /// Can be: `/dev/vda`, `UUID=...`, or `SERIAL=...`
+    /// Can be: `/dev/vda`, `UUID=...`, `SERIAL=...`, `LABEL=...`, `PARTUUID=...` or `PARTLABEL=...`
     pub device: String,
 
     /// Block size in bytes. Default: 65536
     #[serde(default = "DdConfig::default_block_size")]
     pub block_size: u64,
 
-    /// "none" | "gzip" | "zstd" | "xz"
+    /// "none" | "gzip" | "zstd" | "zstd-seekable" | "xz"
     #[serde(default = "DdConfig::default_compression")]
     pub compression: String,
 
+    /// Uncompressed bytes per frame for "zstd-seekable". Default: 4 MiB
+    #[serde(default = "DdConfig::default_frame_size")]
+    pub frame_size: u64,
+
     /// "fresh" | "continue"
     #[serde(default = "DdConfig::default_resume")]
     pub resume: String,
@@ -196,6 +524,26 @@ pub struct DdConfig {
     /// If true, runs `sudo dd` remotely
     #[serde(default = "DdConfig::default_sudo")]
     pub sudo: bool,
+
+    /// Leave all-zero blocks as holes when writing uncompressed images. Default: true
+    #[serde(default = "DdConfig::default_sparse")]
+    pub sparse: bool,
+
+    /// Image each partition of a disk into its own file. Default: false
+    #[serde(default)]
+    pub per_partition: bool,
+
+    /// "dd" | "auto" | "e2image" | "partclone". "auto" picks by the lsblk FSTYPE.
+    #[serde(default = "DdConfig::default_imager")]
+    pub imager: String,
+
+    /// "none" | "lvm" | "zfs" | "fsfreeze"
+    #[serde(default = "DdConfig::default_consistency")]
+    pub consistency: String,
+
+    /// Size of an LVM snapshot: `-L` size ("5G") or `-l` extents ("10%ORIGIN").
+    #[serde(default = "DdConfig::default_snapshot_size")]
+    pub snapshot_size: String,
 }
 
 impl DdConfig {
@@ -205,10 +553,25 @@ impl DdConfig {
     fn default_compression() -> String {
         "none".into()
     }
+    fn default_frame_size() -> u64 {
+        4 << 20
+    }
     fn default_resume() -> String {
         "fresh".into()
     }
     fn default_sudo() -> bool {
         true
     }
+    fn default_sparse() -> bool {
+        true
+    }
+    fn default_imager() -> String {
+        "dd".into()
+    }
+    fn default_consistency() -> String {
+        "none".into()
+    }
+    fn default_snapshot_size() -> String {
+        "10%ORIGIN".into()
+    }
 }
@@ -0,0 +1,706 @@
+//! `daemon` command: runs each host config's `[schedule]` as a `backup`
+//! child process, at most `max_jobs` at a time, and keeps a JSON status file
+//! with every job's last result and next run. SIGHUP re-reads the configs;
+//! SIGINT/SIGTERM interrupt running backups and exit once they are done.
+
+mod cron;
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs,
+    io::{BufRead, BufReader},
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Local, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::ScheduleConfig,
+    error::AppError,
+    partial,
+    signal::{self, kill_group},
+};
+use cron::Cron;
+
+const TICK: Duration = Duration::from_secs(1);
+/// After a job was interrupted, how long it gets to clean up before SIGKILL.
+const GRACE: Duration = Duration::from_secs(60);
+
+/// The part of a host config the daemon reads itself; the child does the rest.
+#[derive(Deserialize)]
+struct Scheduled {
+    schedule: Option<ScheduleConfig>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Status {
+    pub daemon_pid: u32,
+    pub updated_at: Option<DateTime<Utc>>,
+    /// Keyed by config path.
+    #[serde(default)]
+    pub jobs: BTreeMap<String, JobStatus>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum JobState {
+    Idle,
+    /// Due, waiting for a free slot under `max_jobs`.
+    Queued,
+    Running,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobStatus {
+    pub schedule: String,
+    pub state: JobState,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pid: Option<u32>,
+    /// Start of the current run.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<DateTime<Utc>>,
+    pub next_run: Option<DateTime<Utc>>,
+    /// Scheduled times up to here were run or skipped; later ones still
+    /// count as missed after a restart.
+    pub scheduled_until: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_run: Option<RunRecord>,
+    #[serde(default)]
+    pub skipped: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_skip: Option<Skip>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RunResult {
+    Succeeded,
+    Failed,
+    /// Interrupted after `schedule.timeout`.
+    TimedOut,
+    /// Interrupted because the daemon stopped.
+    Interrupted,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunRecord {
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub result: RunResult,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    /// Last line the backup logged, when it did not succeed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Skip {
+    pub at: DateTime<Utc>,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CatchUp {
+    Once,
+    Skip,
+}
+
+impl CatchUp {
+    fn parse(txt: &str) -> Result<Self, AppError> {
+        match txt.to_ascii_lowercase().as_str() {
+            "once" => Ok(Self::Once),
+            "skip" => Ok(Self::Skip),
+            other => Err(AppError::Validation(format!(
+                "unknown catch_up `{other}` (expected once or skip)"
+            ))),
+        }
+    }
+}
+
+struct Job {
+    schedule: ScheduleConfig,
+    cron: Cron,
+    status: JobStatus,
+    run: Option<Running>,
+    /// Gone from the configs; dropped once idle.
+    removed: bool,
+}
+
+struct Running {
+    child: Child,
+    started: Instant,
+    last_line: Arc<Mutex<String>>,
+    reader: JoinHandle<()>,
+    interrupted_at: Option<Instant>,
+    timed_out: bool,
+}
+
+struct Daemon {
+    paths: Vec<PathBuf>,
+    max_jobs: usize,
+    status_path: PathBuf,
+    jobs: BTreeMap<PathBuf, Job>,
+    queue: VecDeque<PathBuf>,
+    /// Status from the previous daemon run, consumed as jobs are added.
+    previous: BTreeMap<String, JobStatus>,
+    dirty: bool,
+}
+
+pub fn run(paths: &[PathBuf], max_jobs: usize, status_path: &Path) -> Result<(), AppError> {
+    if max_jobs == 0 {
+        return Err(AppError::Validation("--max-jobs must be at least 1".into()));
+    }
+    signal::install_daemon();
+    let previous = match fs::read_to_string(status_path) {
+        Ok(raw) => serde_json::from_str::<Status>(&raw)
+            .map(|s| s.jobs)
+            .unwrap_or_else(|e| {
+                log::warn!("ignoring unreadable {}: {e}", status_path.display());
+                BTreeMap::new()
+            }),
+        Err(_) => BTreeMap::new(),
+    };
+    let mut d = Daemon {
+        paths: paths.to_vec(),
+        max_jobs,
+        status_path: status_path.to_path_buf(),
+        jobs: BTreeMap::new(),
+        queue: VecDeque::new(),
+        previous,
+        dirty: true,
+    };
+    d.reload();
+    if d.jobs.is_empty() {
+        return Err(AppError::Validation(
+            "no config with a [schedule] section to run".into(),
+        ));
+    }
+    log::info!(
+        "Daemon started: {} jobs, at most {max_jobs} at a time, status in {}",
+        d.jobs.len(),
+        status_path.display()
+    );
+
+    while !signal::interrupted() {
+        if signal::take_reload() {
+            log::info!("SIGHUP: reloading configs");
+            d.reload();
+        }
+        d.reap(false);
+        d.schedule(Local::now());
+        d.start_queued();
+        d.save_status();
+        thread::sleep(TICK);
+    }
+
+    log::info!("Stopping: interrupting running backups");
+    d.queue.clear();
+    for job in d.jobs.values_mut() {
+        if job.status.state == JobState::Queued {
+            job.status.state = JobState::Idle;
+        }
+    }
+    while d.jobs.values().any(|j| j.run.is_some()) {
+        d.reap(true);
+        d.save_status();
+        thread::sleep(TICK);
+    }
+    d.dirty = true;
+    d.save_status();
+    Ok(())
+}
+
+impl Daemon {
+    /// Re-reads the job list. A config that no longer parses keeps its old
+    /// schedule; one that disappeared is dropped once it is not running.
+    fn reload(&mut self) {
+        let now = Local::now();
+        let found = discover(&self.paths);
+        for path in &found {
+            let sched = match read_schedule(path) {
+                Ok(Some(s)) => s,
+                Ok(None) => {
+                    log::warn!("{}: no [schedule] section, not scheduled", path.display());
+                    self.retire(path);
+                    continue;
+                }
+                Err(e) => {
+                    log::error!("{}: {e}", path.display());
+                    continue;
+                }
+            };
+            let parsed = Cron::parse(&sched.cron).and_then(|c| {
+                CatchUp::parse(&sched.catch_up)?;
+                Ok(c)
+            });
+            let cron = match parsed {
+                Ok(c) => c,
+                Err(e) => {
+                    log::error!("{}: {e}", path.display());
+                    continue;
+                }
+            };
+            match self.jobs.get_mut(path) {
+                Some(job) => {
+                    job.removed = false;
+                    if job.schedule != sched {
+                        log::info!("{}: schedule is now `{}`", path.display(), sched.cron);
+                        job.status.schedule = sched.cron.clone();
+                        job.status.next_run = cron.next_after(now).map(|t| t.to_utc());
+                        job.schedule = sched;
+                        job.cron = cron;
+                        self.dirty = true;
+                    }
+                }
+                None => self.add(path, sched, cron, now),
+            }
+        }
+        let gone: Vec<PathBuf> = self
+            .jobs
+            .keys()
+            .filter(|p| !found.contains(p))
+            .cloned()
+            .collect();
+        for path in gone {
+            self.retire(&path);
+        }
+    }
+
+    fn add(&mut self, path: &Path, schedule: ScheduleConfig, cron: Cron, now: DateTime<Local>) {
+        let key = path.display().to_string();
+        let mut status = JobStatus {
+            schedule: schedule.cron.clone(),
+            state: JobState::Idle,
+            pid: None,
+            started_at: None,
+            next_run: cron.next_after(now).map(|t| t.to_utc()),
+            scheduled_until: now.to_utc(),
+            last_run: None,
+            skipped: 0,
+            last_skip: None,
+        };
+        if let Some(prev) = self.previous.remove(&key) {
+            status.last_run = prev.last_run;
+            status.skipped = prev.skipped;
+            status.last_skip = prev.last_skip;
+            if prev.state == JobState::Running {
+                log::warn!("{key}: the daemon stopped while this backup was running");
+                status.last_run = Some(RunRecord {
+                    started_at: prev.started_at.unwrap_or(prev.scheduled_until),
+                    finished_at: now.to_utc(),
+                    result: RunResult::Interrupted,
+                    exit_code: None,
+                    message: Some("daemon stopped during the run".into()),
+                });
+            }
+
+            let missed = cron
+                .next_after(prev.scheduled_until.with_timezone(&Local))
+                .filter(|t| *t <= now);
+            if let Some(t) = missed {
+                // Validated by the caller.
+                if CatchUp::parse(&schedule.catch_up).ok() == Some(CatchUp::Skip) {
+                    log::warn!("{key}: skipping runs missed since {t}");
+                    status.skipped += 1;
+                    status.last_skip = Some(Skip {
+                        at: now.to_utc(),
+                        reason: format!("missed since {} while the daemon was down", t.to_utc()),
+                    });
+                } else {
+                    log::info!("{key}: catching up on the run missed at {t}");
+                    status.state = JobState::Queued;
+                    self.queue.push_back(path.to_path_buf());
+                }
+            }
+        }
+        log::info!(
+            "{key}: `{}`, next run {}",
+            schedule.cron,
+            describe_next(status.next_run)
+        );
+        self.jobs.insert(
+            path.to_path_buf(),
+            Job {
+                schedule,
+                cron,
+                status,
+                run: None,
+                removed: false,
+            },
+        );
+        self.dirty = true;
+    }
+
+    fn retire(&mut self, path: &Path) {
+        let Some(job) = self.jobs.get_mut(path) else {
+            return;
+        };
+        if job.run.is_some() {
+            job.removed = true;
+        } else {
+            log::info!("{}: no longer scheduled", path.display());
+            self.jobs.remove(path);
+            self.queue.retain(|p| p != path);
+            self.dirty = true;
+        }
+    }
+
+    /// Queues the jobs whose time has come. A job still queued or running
+    /// from its last turn skips this one.
+    fn schedule(&mut self, now: DateTime<Local>) {
+        for (path, job) in &mut self.jobs {
+            let due = job.status.next_run.is_some_and(|t| t <= now.to_utc());
+            if !due || job.removed {
+                continue;
+            }
+            job.status.scheduled_until = now.to_utc();
+            job.status.next_run = job.cron.next_after(now).map(|t| t.to_utc());
+            if job.status.state == JobState::Idle {
+                job.status.state = JobState::Queued;
+                self.queue.push_back(path.clone());
+            } else {
+                let reason = match job.status.state {
+                    JobState::Running => "previous run still running",
+                    _ => "previous run still waiting for a slot",
+                };
+                log::warn!("{}: skipped, {reason}", path.display());
+                job.status.skipped += 1;
+                job.status.last_skip = Some(Skip {
+                    at: now.to_utc(),
+                    reason: reason.into(),
+                });
+            }
+            self.dirty = true;
+        }
+    }
+
+    fn start_queued(&mut self) {
+        while self.jobs.values().filter(|j| j.run.is_some()).count() < self.max_jobs {
+            let Some(path) = self.queue.pop_front() else {
+                return;
+            };
+            let Some(job) = self.jobs.get_mut(&path) else {
+                continue;
+            };
+            match spawn(&path) {
+                Ok(run) => {
+                    log::info!(
+                        "{}: backup started (pid {})",
+                        path.display(),
+                        run.child.id()
+                    );
+                    job.status.state = JobState::Running;
+                    job.status.pid = Some(run.child.id());
+                    job.status.started_at = Some(Utc::now());
+                    job.run = Some(run);
+                }
+                Err(e) => {
+                    log::error!("{}: cannot start backup: {e}", path.display());
+                    job.status.state = JobState::Idle;
+                    job.status.last_run = Some(RunRecord {
+                        started_at: Utc::now(),
+                        finished_at: Utc::now(),
+                        result: RunResult::Failed,
+                        exit_code: None,
+                        message: Some(e.to_string()),
+                    });
+                }
+            }
+            self.dirty = true;
+        }
+    }
+
+    /// Collects finished backups and enforces timeouts; with `stopping`,
+    /// interrupts every running one.
+    fn reap(&mut self, stopping: bool) {
+        let mut finished = Vec::new();
+        for (path, job) in &mut self.jobs {
+            let Some(run) = &mut job.run else {
+                continue;
+            };
+            let timeout = job.schedule.timeout;
+            if run.interrupted_at.is_none() {
+                if timeout > 0 && run.started.elapsed() > Duration::from_secs(timeout) {
+                    log::warn!(
+                        "{}: running for over {timeout}s, interrupting",
+                        path.display()
+                    );
+                    run.timed_out = true;
+                    interrupt(run);
+                } else if stopping {
+                    interrupt(run);
+                }
+            } else if run.interrupted_at.is_some_and(|t| t.elapsed() > GRACE) {
+                log::warn!("{}: still running, killing it", path.display());
+                kill_group(&run.child, libc::SIGKILL);
+            }
+
+            let exit = match run.child.try_wait() {
+                Ok(Some(exit)) => exit,
+                Ok(None) => continue,
+                Err(e) => {
+                    log::error!("{}: {e}", path.display());
+                    continue;
+                }
+            };
+            let run = job.run.take().expect("checked above");
+            let _ = run.reader.join();
+            let result = if exit.success() {
+                RunResult::Succeeded
+            } else if run.timed_out {
+                RunResult::TimedOut
+            } else if run.interrupted_at.is_some() {
+                RunResult::Interrupted
+            } else {
+                RunResult::Failed
+            };
+            let last_line = run.last_line.lock().map(|l| l.clone()).unwrap_or_default();
+            match result {
+                RunResult::Succeeded => log::info!("{}: backup succeeded", path.display()),
+                _ => log::error!("{}: backup {result:?}: {last_line}", path.display()),
+            }
+            job.status.last_run = Some(RunRecord {
+                started_at: job.status.started_at.take().unwrap_or_else(Utc::now),
+                finished_at: Utc::now(),
+                result,
+                exit_code: exit.code(),
+                message: (result != RunResult::Succeeded && !last_line.is_empty())
+                    .then_some(last_line),
+            });
+            job.status.state = JobState::Idle;
+            job.status.pid = None;
+            if job.removed {
+                finished.push(path.clone());
+            }
+            self.dirty = true;
+        }
+        for path in finished {
+            self.retire(&path);
+        }
+    }
+
+    fn save_status(&mut self) {
+        if !self.dirty {
+            return;
+        }
+        let status = Status {
+            daemon_pid: std::process::id(),
+            updated_at: Some(Utc::now()),
+            jobs: self
+                .jobs
+                .iter()
+                .map(|(p, j)| (p.display().to_string(), j.status.clone()))
+                .collect(),
+        };
+        let res = (|| {
+            if let Some(dir) = self.status_path.parent()
+                && !dir.as_os_str().is_empty()
+            {
+                fs::create_dir_all(dir)?;
+            }
+            let raw = serde_json::to_string_pretty(&status).map_err(std::io::Error::other)?;
+            partial::write(&self.status_path, raw.as_bytes())
+        })();
+        match res {
+            Ok(()) => self.dirty = false,
+            Err(e) => log::error!("cannot write {}: {e}", self.status_path.display()),
+        }
+    }
+}
+
+/// Config files given directly, and the `*.toml` files in given directories.
+fn discover(paths: &[PathBuf]) -> Vec<PathBuf> {
+    let mut out = Vec::new();
+    for path in paths {
+        if !path.is_dir() {
+            out.push(path.clone());
+            continue;
+        }
+        match fs::read_dir(path) {
+            Ok(entries) => {
+                let mut found: Vec<PathBuf> = entries
+                    .filter_map(|e| e.ok().map(|e| e.path()))
+                    .filter(|p| p.extension().is_some_and(|x| x == "toml"))
+                    .collect();
+                found.sort();
+                out.extend(found);
+            }
+            Err(e) => log::error!("{}: {e}", path.display()),
+        }
+    }
+    out
+}
+
+fn read_schedule(path: &Path) -> Result<Option<ScheduleConfig>, AppError> {
+    let raw = fs::read_to_string(path)?;
+    let parsed: Scheduled =
+        toml::from_str(&raw).map_err(|e| AppError::Config(crate::error::ConfigError::Toml(e)))?;
+    Ok(parsed.schedule)
+}
+
+/// `data-backup -c <config> --no-wait backup` in its own process group, so
+/// a Ctrl-C meant for the daemon does not reach it directly. Its log lines
+/// are passed through with the config name in front.
+fn spawn(config: &Path) -> Result<Running, AppError> {
+    let mut child = Command::new(std::env::current_exe()?)
+        .arg("-c")
+        .arg(config)
+        .args(["--no-wait", "backup"])
+        .stdin(Stdio::null())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()?;
+    let stderr = child.stderr.take().expect("piped");
+    let name = config.file_stem().map_or_else(
+        || config.display().to_string(),
+        |s| s.to_string_lossy().into_owned(),
+    );
+    let last_line = Arc::new(Mutex::new(String::new()));
+    let keep = Arc::clone(&last_line);
+    let reader = thread::spawn(move || {
+        for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+            eprintln!("[{name}] {line}");
+            if !line.trim().is_empty()
+                && let Ok(mut l) = keep.lock()
+            {
+                *l = line;
+            }
+        }
+    });
+    Ok(Running {
+        child,
+        started: Instant::now(),
+        last_line,
+        reader,
+        interrupted_at: None,
+        timed_out: false,
+    })
+}
+
+fn interrupt(run: &mut Running) {
+    kill_group(&run.child, libc::SIGINT);
+    run.interrupted_at = Some(Instant::now());
+}
+
+fn describe_next(next: Option<DateTime<Utc>>) -> String {
+    next.map_or_else(
+        || "never".into(),
+        |t| t.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    const PATH: &str = "/etc/data-backup/web.toml";
+
+    fn daemon(previous: Option<JobStatus>) -> Daemon {
+        Daemon {
+            paths: Vec::new(),
+            max_jobs: 1,
+            status_path: PathBuf::new(),
+            jobs: BTreeMap::new(),
+            queue: VecDeque::new(),
+            previous: previous.into_iter().map(|s| (PATH.into(), s)).collect(),
+            dirty: false,
+        }
+    }
+
+    fn previous(state: JobState, scheduled_until: DateTime<Local>) -> JobStatus {
+        JobStatus {
+            schedule: "0 2 * * *".into(),
+            state,
+            pid: None,
+            started_at: None,
+            next_run: None,
+            scheduled_until: scheduled_until.to_utc(),
+            last_run: None,
+            skipped: 0,
+            last_skip: None,
+        }
+    }
+
+    /// Adds the nightly job at noon on June 15 and returns its status.
+    fn add(d: &mut Daemon, catch_up: &str) -> JobStatus {
+        let schedule = ScheduleConfig {
+            cron: "0 2 * * *".into(),
+            catch_up: catch_up.into(),
+            timeout: 0,
+        };
+        let cron = Cron::parse(&schedule.cron).unwrap();
+        d.add(Path::new(PATH), schedule, cron, noon(15));
+        d.jobs[Path::new(PATH)].status.clone()
+    }
+
+    fn noon(day: u32) -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 6, day, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn first_start_waits_for_the_schedule() {
+        let mut d = daemon(None);
+        let s = add(&mut d, "once");
+        assert_eq!(s.state, JobState::Idle);
+        assert!(d.queue.is_empty());
+        let next = Local.with_ymd_and_hms(2024, 6, 16, 2, 0, 0).unwrap();
+        assert_eq!(s.next_run, Some(next.to_utc()));
+    }
+
+    #[test]
+    fn runs_missed_while_down_are_caught_up_once() {
+        let mut d = daemon(Some(previous(JobState::Idle, noon(12))));
+        let s = add(&mut d, "once");
+        assert_eq!(s.state, JobState::Queued);
+        // Three nights were missed; one run makes up for them.
+        assert_eq!(Vec::from(d.queue.clone()), [PathBuf::from(PATH)]);
+        assert_eq!(s.skipped, 0);
+    }
+
+    #[test]
+    fn runs_missed_while_down_can_be_skipped() {
+        let mut d = daemon(Some(previous(JobState::Idle, noon(12))));
+        let s = add(&mut d, "skip");
+        assert_eq!(s.state, JobState::Idle);
+        assert!(d.queue.is_empty());
+        assert_eq!(s.skipped, 1);
+        assert!(
+            s.last_skip
+                .unwrap()
+                .reason
+                .contains("while the daemon was down")
+        );
+    }
+
+    #[test]
+    fn nothing_missed_since_the_last_turn() {
+        let mut d = daemon(Some(previous(
+            JobState::Idle,
+            noon(15) - Duration::from_secs(3600),
+        )));
+        let s = add(&mut d, "once");
+        assert_eq!(s.state, JobState::Idle);
+        assert!(d.queue.is_empty());
+    }
+
+    #[test]
+    fn run_cut_off_by_a_stop_is_recorded_as_interrupted() {
+        let mut prev = previous(JobState::Running, noon(15) - Duration::from_secs(3600));
+        prev.started_at = Some(noon(15).to_utc() - Duration::from_secs(600));
+        let mut d = daemon(Some(prev));
+        let s = add(&mut d, "once");
+        let last = s.last_run.unwrap();
+        assert_eq!(last.result, RunResult::Interrupted);
+        assert_eq!(last.finished_at, noon(15).to_utc());
+        assert_eq!(s.state, JobState::Idle);
+    }
+}
@@ -0,0 +1,362 @@
+//! Five-field cron expressions (`minute hour day-of-month month day-of-week`)
+//! evaluated in local time.
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, TimeZone, Timelike};
+
+use crate::error::AppError;
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const DAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// Gives up looking for a match this far ahead (e.g. `0 0 30 2 *`).
+const HORIZON_DAYS: i64 = 366 * 5;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Both day fields restricted: either may match, as in Vixie cron.
+    day_or: bool,
+}
+
+impl Cron {
+    pub fn parse(expr: &str) -> Result<Self, AppError> {
+        let expanded = match expr.trim() {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            other => other,
+        };
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+        let [min, hour, dom, mon, dow] = fields[..] else {
+            return Err(AppError::Validation(format!(
+                "cron `{expr}`: expected 5 fields (minute hour day month weekday)"
+            )));
+        };
+        let bad = |e: String| AppError::Validation(format!("cron `{expr}`: {e}"));
+        let mut weekdays = field(dow, 0, 7, &DAYS).map_err(bad)?;
+        // 7 is another name for Sunday.
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+        Ok(Self {
+            minutes: field(min, 0, 59, &[]).map_err(bad)?,
+            hours: field(hour, 0, 23, &[]).map_err(bad)?,
+            days: field(dom, 1, 31, &[]).map_err(bad)?,
+            months: field(mon, 1, 12, &MONTHS).map_err(bad)?,
+            weekdays,
+            day_or: !dom.starts_with('*') && !dow.starts_with('*'),
+        })
+    }
+
+    /// The first matching minute strictly after `after`, in its time zone.
+    pub fn next_after<Tz: TimeZone>(&self, after: DateTime<Tz>) -> Option<DateTime<Tz>> {
+        let start = after.naive_local().with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        let limit = start + Duration::days(HORIZON_DAYS);
+        let mut t = start;
+        while t < limit {
+            if !bit(self.months, t.month()) {
+                let (y, m) = if t.month() == 12 {
+                    (t.year() + 1, 1)
+                } else {
+                    (t.year(), t.month() + 1)
+                };
+                t = NaiveDate::from_ymd_opt(y, m, 1)?.and_hms_opt(0, 0, 0)?;
+                continue;
+            }
+            if !self.day_matches(t) {
+                t = next_day(t)?;
+                continue;
+            }
+            if !bit(self.hours, t.hour()) {
+                t = t.with_minute(0)? + Duration::hours(1);
+                continue;
+            }
+            if !bit(self.minutes, t.minute()) {
+                t += Duration::minutes(1);
+                continue;
+            }
+            // A time skipped by a DST change does not exist: move on.
+            match after.timezone().from_local_datetime(&t).earliest() {
+                Some(local) if local > after => return Some(local),
+                _ => t += Duration::minutes(1),
+            }
+        }
+        None
+    }
+
+    fn day_matches(&self, t: NaiveDateTime) -> bool {
+        let dom = bit(self.days, t.day());
+        let dow = bit(self.weekdays, t.weekday().num_days_from_sunday());
+        if self.day_or { dom || dow } else { dom && dow }
+    }
+}
+
+fn next_day(t: NaiveDateTime) -> Option<NaiveDateTime> {
+    t.date().succ_opt()?.and_hms_opt(0, 0, 0)
+}
+
+fn bit(mask: u64, n: u32) -> bool {
+    mask & (1 << n) != 0
+}
+
+/// One field as a bit mask: `*`, `n`, `a-b`, any of those with `/step`, and
+/// comma-separated lists of them. `names` map to `lo`, `lo + 1`, ...
+fn field(txt: &str, lo: u32, hi: u32, names: &[&str]) -> Result<u64, String> {
+    let mut mask = 0u64;
+    for part in txt.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((r, s)) => (
+                r,
+                s.parse::<u32>()
+                    .ok()
+                    .filter(|&s| s > 0)
+                    .ok_or_else(|| format!("bad step in `{part}`"))?,
+            ),
+            None => (part, 1),
+        };
+        let (from, to) = if range == "*" {
+            (lo, hi)
+        } else if let Some((a, b)) = range.split_once('-') {
+            (value(a, lo, hi, names)?, value(b, lo, hi, names)?)
+        } else {
+            let v = value(range, lo, hi, names)?;
+            // `n/step` runs from n to the end of the range.
+            (v, if step > 1 { hi } else { v })
+        };
+        if from > to {
+            return Err(format!("empty range `{part}`"));
+        }
+        for n in (from..=to).step_by(step as usize) {
+            mask |= 1 << n;
+        }
+    }
+    Ok(mask)
+}
+
+fn value(txt: &str, lo: u32, hi: u32, names: &[&str]) -> Result<u32, String> {
+    let lower = txt.to_ascii_lowercase();
+    if let Some(i) = names.iter().position(|n| *n == lower) {
+        return Ok(lo + i as u32);
+    }
+    match txt.parse::<u32>() {
+        Ok(n) if (lo..=hi).contains(&n) => Ok(n),
+        _ => Err(format!("`{txt}` is not in {lo}-{hi}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{FixedOffset, MappedLocalTime, Utc};
+
+    use super::*;
+
+    fn at(y: i32, mo: u32, d: u32, h: u32, mi: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, mo, d, h, mi, 0).unwrap()
+    }
+
+    fn next(expr: &str, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        Cron::parse(expr).unwrap().next_after(after)
+    }
+
+    fn naive(y: i32, mo: u32, d: u32, h: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(y, mo, d)
+            .unwrap()
+            .and_hms_opt(h, 0, 0)
+            .unwrap()
+    }
+
+    /// Central European time in 2024: 02:00 jumps to 03:00 on March 31, and
+    /// 03:00 falls back to 02:00 on October 27.
+    #[derive(Debug, Clone, Copy)]
+    struct Cet;
+
+    impl Cet {
+        const WINTER: i32 = 3600;
+        const SUMMER: i32 = 7200;
+    }
+
+    impl TimeZone for Cet {
+        type Offset = FixedOffset;
+
+        fn from_offset(_: &FixedOffset) -> Self {
+            Cet
+        }
+
+        fn offset_from_local_date(&self, local: &NaiveDate) -> MappedLocalTime<FixedOffset> {
+            self.offset_from_local_datetime(&local.and_hms_opt(12, 0, 0).unwrap())
+        }
+
+        fn offset_from_local_datetime(
+            &self,
+            local: &NaiveDateTime,
+        ) -> MappedLocalTime<FixedOffset> {
+            let winter = FixedOffset::east_opt(Self::WINTER).unwrap();
+            let summer = FixedOffset::east_opt(Self::SUMMER).unwrap();
+            let (spring, fall) = (naive(2024, 3, 31, 2), naive(2024, 10, 27, 2));
+            let hour = Duration::hours(1);
+            if *local >= spring && *local < spring + hour {
+                MappedLocalTime::None
+            } else if *local >= fall && *local < fall + hour {
+                MappedLocalTime::Ambiguous(summer, winter)
+            } else if *local >= spring + hour && *local < fall {
+                MappedLocalTime::Single(summer)
+            } else {
+                MappedLocalTime::Single(winter)
+            }
+        }
+
+        fn offset_from_utc_date(&self, utc: &NaiveDate) -> FixedOffset {
+            self.offset_from_utc_datetime(&utc.and_hms_opt(12, 0, 0).unwrap())
+        }
+
+        fn offset_from_utc_datetime(&self, utc: &NaiveDateTime) -> FixedOffset {
+            let summer = *utc >= naive(2024, 3, 31, 1) && *utc < naive(2024, 10, 27, 1);
+            FixedOffset::east_opt(if summer { Self::SUMMER } else { Self::WINTER }).unwrap()
+        }
+    }
+
+    #[test]
+    fn steps_ranges_and_lists() {
+        assert_eq!(
+            next("*/15 * * * *", at(2024, 5, 1, 10, 7)),
+            Some(at(2024, 5, 1, 10, 15))
+        );
+        let c = Cron::parse("5/20 * * * *").unwrap();
+        assert_eq!(c.minutes, (1 << 5) | (1 << 25) | (1 << 45));
+        let c = Cron::parse("0 9-17/4,22 * * *").unwrap();
+        assert_eq!(c.hours, (1 << 9) | (1 << 13) | (1 << 17) | (1 << 22));
+        // Weekdays only: Friday 17:00 is followed by Monday 09:00.
+        assert_eq!(
+            next("0 9-17/4 * * 1-5", at(2024, 5, 3, 17, 0)),
+            Some(at(2024, 5, 6, 9, 0))
+        );
+    }
+
+    #[test]
+    fn names_and_aliases() {
+        assert_eq!(
+            Cron::parse("0 0 * JAN,jul Sun").unwrap(),
+            Cron::parse("0 0 * 1,7 0").unwrap()
+        );
+        assert_eq!(
+            Cron::parse("0 0 * * mon-fri").unwrap(),
+            Cron::parse("0 0 * * 1-5").unwrap()
+        );
+        assert_eq!(
+            Cron::parse("@weekly").unwrap(),
+            Cron::parse("0 0 * * 0").unwrap()
+        );
+    }
+
+    #[test]
+    fn seven_is_sunday() {
+        assert_eq!(
+            Cron::parse("0 0 * * 7").unwrap(),
+            Cron::parse("0 0 * * 0").unwrap()
+        );
+        let c = Cron::parse("0 0 * * 5-7").unwrap();
+        assert_eq!(c.weekdays, 1 | (1 << 5) | (1 << 6));
+        // 2024-05-04 is a Saturday.
+        assert_eq!(
+            next("0 3 * * 7", at(2024, 5, 4, 12, 0)),
+            Some(at(2024, 5, 5, 3, 0))
+        );
+    }
+
+    #[test]
+    fn both_day_fields_restricted_means_either() {
+        // 2024-09-01 is a Sunday; the 6th and the 13th are Fridays.
+        let after = at(2024, 9, 1, 0, 0);
+        assert_eq!(next("0 0 13 * fri", after), Some(at(2024, 9, 6, 0, 0)));
+        assert_eq!(next("0 0 13 * *", after), Some(at(2024, 9, 13, 0, 0)));
+        assert_eq!(next("0 0 * * fri", after), Some(at(2024, 9, 6, 0, 0)));
+        // A stepped `*` still counts as unrestricted: both must match.
+        assert_eq!(next("0 0 */2 * fri", after), Some(at(2024, 9, 13, 0, 0)));
+    }
+
+    #[test]
+    fn strictly_after_and_across_the_year() {
+        assert_eq!(
+            next("30 2 * * *", at(2024, 5, 1, 2, 30)),
+            Some(at(2024, 5, 2, 2, 30))
+        );
+        let mid_minute = at(2024, 5, 1, 2, 29) + Duration::seconds(59);
+        assert_eq!(next("30 2 * * *", mid_minute), Some(at(2024, 5, 1, 2, 30)));
+        assert_eq!(
+            next("@yearly", at(2024, 12, 15, 0, 0)),
+            Some(at(2025, 1, 1, 0, 0))
+        );
+    }
+
+    #[test]
+    fn impossible_and_rare_dates() {
+        assert_eq!(next("0 0 30 2 *", at(2024, 1, 1, 0, 0)), None);
+        assert_eq!(next("0 0 31 4,6,9,11 *", at(2024, 1, 1, 0, 0)), None);
+        assert_eq!(
+            next("0 0 29 2 *", at(2025, 1, 1, 0, 0)),
+            Some(at(2028, 2, 29, 0, 0))
+        );
+    }
+
+    #[test]
+    fn bad_expressions_are_rejected() {
+        for expr in [
+            "* * * *",
+            "* * * * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "* * * foo *",
+            "",
+        ] {
+            assert!(Cron::parse(expr).is_err(), "{expr:?} parsed");
+        }
+    }
+
+    #[test]
+    fn times_skipped_by_dst_are_not_run() {
+        let c = Cron::parse("30 2 * * *").unwrap();
+        let after = Cet.with_ymd_and_hms(2024, 3, 30, 12, 0, 0).unwrap();
+        // 02:30 does not exist on March 31.
+        let t = c.next_after(after).unwrap();
+        assert_eq!(t, Cet.with_ymd_and_hms(2024, 4, 1, 2, 30, 0).unwrap());
+        assert_eq!(t.offset().local_minus_utc(), Cet::SUMMER);
+        // The hour after the jump is there as usual.
+        let c = Cron::parse("30 3 * * *").unwrap();
+        assert_eq!(
+            c.next_after(after).unwrap().naive_local(),
+            naive(2024, 3, 31, 3) + Duration::minutes(30)
+        );
+    }
+
+    #[test]
+    fn times_repeated_by_dst_run_once() {
+        let c = Cron::parse("30 2 * * *").unwrap();
+        let after = Cet.with_ymd_and_hms(2024, 10, 26, 12, 0, 0).unwrap();
+        let first = c.next_after(after).unwrap();
+        assert_eq!(
+            first.naive_local(),
+            naive(2024, 10, 27, 2) + Duration::minutes(30)
+        );
+        assert_eq!(first.offset().local_minus_utc(), Cet::SUMMER);
+        // Not again at the second 02:30, an hour later.
+        let second = c.next_after(first).unwrap();
+        assert_eq!(
+            second.naive_local(),
+            naive(2024, 10, 28, 2) + Duration::minutes(30)
+        );
+        assert_eq!(second.offset().local_minus_utc(), Cet::WINTER);
+    }
+}
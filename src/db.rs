@@ -0,0 +1,43 @@
+//! Logical database dumps (`[[databases]]`), taken on the remote and
+//! streamed back compressed.
+
+mod dump; // remote dump --> compressor --> local file + hash
+pub mod engine; // per-engine dump / version / restore command lines
+
+use std::path::Path;
+
+use crate::{
+    codec::Codec, config::Config, error::AppError, metadata::SnapshotRecord, ssh::Ssh,
+    throttle::Limits,
+};
+
+use engine::Database;
+
+/// Dumps every configured database into the local download dir, in config order.
+pub fn dump_all(cfg: &Config, ssh: &Ssh, limits: &Limits) -> Result<Vec<SnapshotRecord>, AppError> {
+    // Validate every entry before the first dump starts.
+    let jobs = cfg
+        .databases
+        .iter()
+        .map(|c| Ok((Database::from_config(c)?, Codec::parse(&c.compression)?)))
+        .collect::<Result<Vec<_>, AppError>>()?;
+
+    let dir = Path::new(&cfg.options.local_download_dir);
+    let mut metas = Vec::with_capacity(jobs.len());
+    for (db, codec) in &jobs {
+        let host = cfg.remote.host.to_string();
+        let meta = dump::dump_one(ssh, &host, db, *codec, dir, limits)?;
+        log::info!(
+            "Dump of {} {} ({}) saved to {}",
+            db.engine.name(),
+            db.name,
+            meta.database
+                .as_ref()
+                .and_then(|d| d.engine_version.as_deref())
+                .unwrap_or("unknown version"),
+            meta.local_path
+        );
+        metas.push(meta);
+    }
+    Ok(metas)
+}
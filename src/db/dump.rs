@@ -0,0 +1,125 @@
+//! Streams a remote dump through the compressor into a local file.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Read, Write},
+    path::Path,
+};
+
+use chrono::Utc;
+use indicatif::{ProgressBar, ProgressStyle};
+use sha2::{Digest, Sha256};
+
+use super::engine::Database;
+use crate::{
+    codec::Codec,
+    error::AppError,
+    metadata::{
+        SnapshotRecord,
+        record::{DatabaseSection, Details},
+    },
+    partial,
+    shell::quote,
+    ssh::Ssh,
+    throttle::Limits,
+};
+
+pub fn dump_one(
+    ssh: &Ssh,
+    host: &str,
+    db: &Database,
+    codec: Codec,
+    dir: &Path,
+    limits: &Limits,
+) -> Result<SnapshotRecord, AppError> {
+    let started_at = Utc::now();
+    let engine_version = version(ssh, db);
+
+    std::fs::create_dir_all(dir)?;
+    let local_path = dir.join(format!(
+        "{}-{}-{}{}{}",
+        started_at.format("%Y%m%d%H%M%S"),
+        db.engine.name(),
+        db.label(),
+        db.format.ext(),
+        codec.suffix()
+    ));
+
+    // pipefail: a failing dump must not hide behind a successful compressor.
+    let cmd = format!(
+        "bash -o pipefail -c {}",
+        quote(&limits.remote_pipe(db.dump_cmd().pipe(codec.compress_cmd())))
+    );
+    log::info!(
+        "Dumping {} database {} -> {}",
+        db.engine.name(),
+        db.name,
+        local_path.display()
+    );
+    let mut job = ssh.spawn(&cmd)?;
+    let secret = db.stdin_prefix();
+    if !secret.is_empty() {
+        job.send_input(&secret)?;
+    }
+
+    let pb = ProgressBar::new_spinner();
+    pb.set_style(
+        ProgressStyle::with_template("[{elapsed_precise}] {spinner} {bytes} ({bytes_per_sec})")
+            .unwrap(),
+    );
+
+    let mut out = BufWriter::new(File::create(partial::path(&local_path))?);
+    let mut hasher = Sha256::new();
+    let mut pacer = limits.pacer();
+    let mut written = 0u64;
+    let mut buf = [0u8; 1 << 16];
+    loop {
+        let n = job.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        out.write_all(&buf[..n])?;
+        written += n as u64;
+        pb.inc(n as u64);
+        pacer.pace(n)?;
+    }
+    pb.finish();
+
+    let mut stderr = String::new();
+    job.stderr().read_to_string(&mut stderr)?;
+    let code = job.finish()?;
+    if code != 0 {
+        return Err(AppError::Remote(format!(
+            "{} dump of {} exited with {code}: {}",
+            db.engine.name(),
+            db.name,
+            stderr.trim()
+        )));
+    }
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    partial::commit(&local_path)?;
+
+    let details = Details::Database(DatabaseSection {
+        engine: db.engine.name().into(),
+        database: db.name.clone(),
+        engine_version,
+        format: db.format,
+    });
+    let record = SnapshotRecord::new(details, &local_path, host, codec.name(), started_at)
+        .completed(written, hex::encode(hasher.finalize()), Vec::new());
+    record.write()?;
+    Ok(record)
+}
+
+/// Best effort: a missing version only costs a metadata field.
+fn version(ssh: &Ssh, db: &Database) -> Option<String> {
+    let mut out = Vec::new();
+    match ssh.exec_capture_input(&db.version_cmd().to_string(), &db.stdin_prefix(), &mut out) {
+        Ok(()) => db.parse_version(&String::from_utf8_lossy(&out)),
+        Err(e) => {
+            log::warn!("cannot query {} version: {e}", db.engine.name());
+            None
+        }
+    }
+}
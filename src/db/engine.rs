@@ -0,0 +1,328 @@
+//! Client command lines per database engine: dump, version probe, restore.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{config::DatabaseConfig, error::AppError, shell::Cmd};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    Postgres,
+    /// MySQL and MariaDB.
+    Mysql,
+    Sqlite,
+    Redis,
+}
+
+impl Engine {
+    pub fn parse(txt: &str) -> Result<Self, AppError> {
+        match txt.to_ascii_lowercase().as_str() {
+            "postgres" | "postgresql" => Ok(Self::Postgres),
+            "mysql" | "mariadb" => Ok(Self::Mysql),
+            "sqlite" | "sqlite3" => Ok(Self::Sqlite),
+            "redis" => Ok(Self::Redis),
+            other => Err(AppError::Validation(format!(
+                "unknown database engine `{other}` (expected postgres, mysql, sqlite or redis)"
+            ))),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Postgres => "postgres",
+            Self::Mysql => "mysql",
+            Self::Sqlite => "sqlite",
+            Self::Redis => "redis",
+        }
+    }
+}
+
+/// What the dump stream contains, and so how it is fed back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DumpFormat {
+    /// `pg_dump -Fc` archive, restored with `pg_restore`.
+    PgCustom,
+    /// SQL script, restored with the engine's shell.
+    Sql,
+    /// Redis RDB snapshot, copied into place while the server is stopped.
+    Rdb,
+}
+
+impl DumpFormat {
+    pub fn ext(self) -> &'static str {
+        match self {
+            Self::PgCustom => ".dump",
+            Self::Sql => ".sql",
+            Self::Rdb => ".rdb",
+        }
+    }
+}
+
+/// One `[[databases]]` entry with its connection settings resolved.
+#[derive(Debug, Clone)]
+pub struct Database {
+    pub engine: Engine,
+    pub name: String,
+    pub format: DumpFormat,
+    host: Option<String>,
+    port: Option<u16>,
+    user: Option<String>,
+    password: Option<String>,
+    run_as: Option<String>,
+}
+
+impl Database {
+    pub fn from_config(c: &DatabaseConfig) -> Result<Self, AppError> {
+        let engine = Engine::parse(&c.engine)?;
+        let format = match engine {
+            Engine::Postgres => match c.format.to_ascii_lowercase().as_str() {
+                "custom" => DumpFormat::PgCustom,
+                "plain" | "sql" => DumpFormat::Sql,
+                other => {
+                    return Err(AppError::Validation(format!(
+                        "unknown postgres dump format `{other}` (expected custom or plain)"
+                    )));
+                }
+            },
+            Engine::Mysql | Engine::Sqlite => DumpFormat::Sql,
+            Engine::Redis => DumpFormat::Rdb,
+        };
+        if c.password
+            .as_deref()
+            .is_some_and(|p| p.contains(['\n', '\r']))
+        {
+            return Err(AppError::Validation(format!(
+                "{} password for `{}` must not contain a line break",
+                engine.name(),
+                c.name
+            )));
+        }
+        if c.name.is_empty() && engine != Engine::Redis {
+            return Err(AppError::Validation(format!(
+                "{} database entry needs a `name`",
+                engine.name()
+            )));
+        }
+        Ok(Self {
+            engine,
+            name: c.name.clone(),
+            format,
+            host: c.host.clone(),
+            port: c.port,
+            user: c.user.clone(),
+            password: c.password.clone(),
+            run_as: c.run_as.clone(),
+        })
+    }
+
+    /// A database known only from its dump metadata: local socket, no credentials.
+    pub fn bare(engine: Engine, name: &str, format: DumpFormat) -> Self {
+        Self {
+            engine,
+            name: name.into(),
+            format,
+            host: None,
+            port: None,
+            user: None,
+            password: None,
+            run_as: None,
+        }
+    }
+
+    /// File-name-safe label: the database name, or the file stem for SQLite.
+    pub fn label(&self) -> String {
+        let raw = match self.engine {
+            Engine::Sqlite => self.name.rsplit('/').next().unwrap_or_default(),
+            Engine::Redis if self.name.is_empty() => "redis",
+            _ => &self.name,
+        };
+        raw.chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || "._-".contains(c) {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect()
+    }
+
+    /// Remote command writing the dump to stdout.
+    pub fn dump_cmd(&self) -> Cmd {
+        let name = self.name.as_str();
+        match (self.engine, self.format) {
+            (Engine::Postgres, DumpFormat::PgCustom) => self
+                .client("pg_dump")
+                .arg("-Fc")
+                .args(self.conn())
+                .arg(name),
+            (Engine::Postgres, _) => self
+                .client("pg_dump")
+                .arg("-Fp")
+                .args(self.conn())
+                .arg(name),
+            (Engine::Mysql, _) => self
+                .client("mysqldump")
+                .args([
+                    "--single-transaction",
+                    "--routines",
+                    "--triggers",
+                    "--events",
+                ])
+                .args(self.conn())
+                .arg(name),
+            (Engine::Sqlite, _) => self.client("sqlite3").args(["-readonly", name, ".dump"]),
+            // `--rdb -` (redis-cli 7+) streams the RDB to stdout.
+            (Engine::Redis, _) => self
+                .client("redis-cli")
+                .args(self.conn())
+                .args(["--rdb", "-"]),
+        }
+    }
+
+    /// Remote command printing the server (or library) version.
+    pub fn version_cmd(&self) -> Cmd {
+        match self.engine {
+            Engine::Postgres => self
+                .client("psql")
+                .args(["-X", "-tA"])
+                .args(self.conn())
+                .args(["-d", &self.name, "-c", "SHOW server_version"]),
+            Engine::Mysql => self
+                .client("mysql")
+                .args(["-N", "-B"])
+                .args(self.conn())
+                .args(["-e", "SELECT VERSION()"]),
+            Engine::Sqlite => self.client("sqlite3").arg("--version"),
+            Engine::Redis => self
+                .client("redis-cli")
+                .args(self.conn())
+                .args(["INFO", "server"]),
+        }
+    }
+
+    /// Picks the version out of [`Self::version_cmd`] output.
+    pub fn parse_version(&self, out: &str) -> Option<String> {
+        let v = match self.engine {
+            Engine::Redis => out
+                .lines()
+                .find_map(|l| l.trim().strip_prefix("redis_version:"))?,
+            _ => out.split_whitespace().next()?,
+        };
+        Some(v.trim().to_string())
+    }
+
+    /// Remote command reading a dump on stdin into `target`: a database name,
+    /// or a file path for SQLite and Redis.
+    pub fn restore_cmd(&self, target: &str) -> Cmd {
+        match (self.engine, self.format) {
+            (Engine::Postgres, DumpFormat::PgCustom) => self
+                .client("pg_restore")
+                .args(["--clean", "--if-exists", "--no-owner"])
+                .args(self.conn())
+                .args(["-d", target]),
+            (Engine::Postgres, _) => self
+                .client("psql")
+                .args(["-X", "-q", "-v", "ON_ERROR_STOP=1"])
+                .args(self.conn())
+                .args(["-d", target]),
+            (Engine::Mysql, _) => self.client("mysql").args(self.conn()).arg(target),
+            (Engine::Sqlite, _) => self.client("sqlite3").args(["-bail", target]),
+            (Engine::Redis, _) => self.client("dd").opt("of", target).arg("status=none"),
+        }
+    }
+
+    /// What the commands above must be sent on stdin before anything else:
+    /// the password line, if there is a password.
+    pub fn stdin_prefix(&self) -> Vec<u8> {
+        match (&self.password, self.password_var()) {
+            (Some(pw), Some(_)) => format!("{pw}\n").into_bytes(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// `program` run as `run_as`, reading the password variable the client
+    /// knows from stdin (see [`Self::stdin_prefix`]).
+    fn client(&self, program: &str) -> Cmd {
+        let cmd = Cmd::new(program).sudo_user(self.run_as.as_deref());
+        match (&self.password, self.password_var()) {
+            (Some(_), Some(var)) => cmd.secret_env(var),
+            _ => cmd,
+        }
+    }
+
+    fn password_var(&self) -> Option<&'static str> {
+        match self.engine {
+            Engine::Postgres => Some("PGPASSWORD"),
+            Engine::Mysql => Some("MYSQL_PWD"),
+            Engine::Redis => Some("REDISCLI_AUTH"),
+            Engine::Sqlite => None,
+        }
+    }
+
+    /// Host, port and user flags.
+    fn conn(&self) -> Vec<String> {
+        let (h, p, u) = match self.engine {
+            Engine::Postgres => ("-h", "-p", "-U"),
+            Engine::Mysql => ("-h", "-P", "-u"),
+            Engine::Redis => ("-h", "-p", "--user"),
+            Engine::Sqlite => return Vec::new(),
+        };
+        let mut out = Vec::new();
+        if let Some(host) = &self.host {
+            out.extend([h.to_string(), host.clone()]);
+        }
+        if let Some(port) = self.port {
+            out.extend([p.to_string(), port.to_string()]);
+        }
+        if let Some(user) = &self.user {
+            out.extend([u.to_string(), user.clone()]);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(engine: &str, password: &str) -> DatabaseConfig {
+        DatabaseConfig {
+            engine: engine.into(),
+            name: "app".into(),
+            host: None,
+            port: None,
+            user: Some("backup".into()),
+            password: Some(password.into()),
+            run_as: Some("postgres".into()),
+            format: "custom".into(),
+            compression: "zstd".into(),
+        }
+    }
+
+    #[test]
+    fn password_is_never_on_a_command_line() {
+        let secret = "hunter2-'$(x)'";
+        for engine in ["postgres", "mysql", "redis"] {
+            let db = Database::from_config(&config(engine, secret)).unwrap();
+            for cmd in [db.dump_cmd(), db.version_cmd(), db.restore_cmd("app")] {
+                let line = cmd.to_string();
+                assert!(!line.contains("hunter2"), "{engine}: {line}");
+                assert!(line.contains("IFS= read -r "), "{engine}: {line}");
+            }
+            assert_eq!(db.stdin_prefix(), format!("{secret}\n").into_bytes());
+        }
+    }
+
+    #[test]
+    fn no_password_no_stdin_prefix() {
+        let db = Database::bare(Engine::Postgres, "app", DumpFormat::PgCustom);
+        assert!(db.stdin_prefix().is_empty());
+        assert_eq!(db.dump_cmd().to_string(), "pg_dump -Fc app");
+    }
+
+    #[test]
+    fn password_with_line_break_is_rejected() {
+        assert!(Database::from_config(&config("postgres", "a\nb")).is_err());
+    }
+}
@@ -1,10 +1,14 @@
 //! Raw-disk snapshot subsystem (remote `dd`).
 
 mod builder; // config --> validated config
-mod meta;
+mod consistency; // LVM/ZFS snapshot or fsfreeze around the read
+pub mod imager; // dd / e2image / partclone command lines
 mod pipeline; // streaming copy + hash + json
-mod probe; // lsblk device discovery // serialisable metadata
+mod probe; // lsblk device discovery
+pub mod seekable; // frame index for random access into zstd images
+mod sparse; // zero blocks --> holes in uncompressed images
 
-pub use builder::{DdBuilder, ResumeMode};
+pub use builder::{Compression, DdBuilder, DdSnapshotConfig, ResumeMode};
+pub use consistency::Strategy;
 
 pub use pipeline::run_once;
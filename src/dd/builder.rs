@@ -1,12 +1,23 @@
 //! Turn `Config` + `[dd]` toml table into `DdSnapshotConfig`.
 
-use std::{path::PathBuf, time::Duration};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-use crate::{config::Config, error::AppError, ssh::Ssh};
+use crate::{config::Config, error::AppError, partial, ssh::Ssh, throttle::Limits};
 
-use chrono::Utc;
+use chrono::{NaiveDateTime, Utc};
 
-use super::probe::{BlockDevice, remote_lsblk};
+use super::{
+    consistency::Strategy,
+    imager::{ImageFormat, Imager},
+    probe::{BlockDevice, flatten, remote_lsblk, remote_partition_table},
+    seekable,
+};
+
+/// Image names start with the run's UTC time in this format.
+const STAMP: &str = "%Y%m%dT%H%M%SZ";
 
 /// When an existing local file is present.
 #[derive(Debug, Clone, Copy)]
@@ -21,6 +32,8 @@ pub enum Compression {
     None,
     Gzip,
     Zstd,
+    /// zstd frames of `frame_size` uncompressed bytes plus a seek table.
+    SeekableZstd,
     Xz,
 }
 impl Compression {
@@ -28,38 +41,78 @@ impl Compression {
         match txt.to_ascii_lowercase().as_str() {
             "gzip" => Self::Gzip,
             "zstd" => Self::Zstd,
+            "zstd-seekable" => Self::SeekableZstd,
             "xz" => Self::Xz,
             _ => Self::None,
         }
     }
+    /// As written in the config.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+            Self::SeekableZstd => "zstd-seekable",
+            Self::Xz => "xz",
+        }
+    }
     pub fn ext(self) -> &'static str {
         match self {
             Self::None => ".img",
             Self::Gzip => ".img.gz",
-            Self::Zstd => ".img.zst",
+            Self::Zstd | Self::SeekableZstd => ".img.zst",
             Self::Xz => ".img.xz",
         }
     }
-    pub fn pipe(self) -> &'static str {
+    /// Remote binary the image is piped through, if any.
+    pub fn tool(self) -> Option<&'static str> {
+        match self {
+            Self::None => None,
+            Self::Gzip => Some("gzip"),
+            Self::Zstd | Self::SeekableZstd => Some("zstd"),
+            Self::Xz => Some("xz"),
+        }
+    }
+    pub fn pipe(self, frame_size: u64) -> String {
         match self {
-            Self::None => "cat",
-            Self::Gzip => "gzip -c",
-            Self::Zstd => "zstd -q -c",
-            Self::Xz => "xz -c",
+            Self::None => "cat".into(),
+            Self::Gzip => "gzip -c".into(),
+            Self::Zstd => "zstd -q -c".into(),
+            Self::SeekableZstd => seekable::remote_pipe(frame_size),
+            Self::Xz => "xz -c".into(),
         }
     }
 }
 
+/// One device and the local image it is copied into.
+#[derive(Debug)]
+pub struct DdTarget {
+    pub device: BlockDevice,
+    pub imager: Imager,
+    pub local_path: PathBuf,
+}
+
 /// Immutable job configuration for the pipeline.
 #[derive(Debug)]
 pub struct DdSnapshotConfig {
     pub ssh: Ssh,
-    pub device: BlockDevice,
+    /// `remote.host`, for the records.
+    pub host: String,
+    /// The whole device, or one entry per partition.
+    pub targets: Vec<DdTarget>,
+    /// `sfdisk --dump` of the disk the targets live on.
+    pub partition_table: Option<String>,
     pub compression: Compression,
     pub block_size: u64,
+    pub frame_size: u64,
     pub resume_mode: ResumeMode,
     pub sudo: bool,
-    pub local_path: PathBuf,
+    pub sparse: bool,
+    /// Split each image into volumes of this many bytes.
+    pub volume_size: Option<u64>,
+    pub consistency: Strategy,
+    /// Bandwidth limit and remote priority.
+    pub limits: Limits,
     pub read_to: Duration,
     pub write_to: Duration,
 }
@@ -75,17 +128,13 @@ impl<'a> DdBuilder<'a> {
     }
 
     pub fn build(self) -> Result<DdSnapshotConfig, AppError> {
-        // 1. SSH connect -------------------------------------------------------
-        let r = &self.cfg.remote;
-        let ssh = Ssh::connect(
-            r.host,
-            r.port,
-            &r.user,
-            &r.password,
-            r.private_key.as_deref(),
-        )?;
+        let ssh = Ssh::connect_remote(&self.cfg.remote)?;
+        self.build_on(ssh)
+    }
 
-        // 2. Read [dd] config safely ------------------------------------------
+    /// Like [`Self::build`], over a session already open (and checked).
+    pub fn build_on(self, ssh: Ssh) -> Result<DdSnapshotConfig, AppError> {
+        // 1. Read [dd] config safely ------------------------------------------
         let dd_cfg = self.cfg.dd.as_ref();
 
         let dev_query = dd_cfg.map(|c| c.device.as_str()).unwrap_or("/dev/vda");
@@ -95,6 +144,17 @@ impl<'a> DdBuilder<'a> {
         let compression =
             Compression::parse(dd_cfg.map(|c| c.compression.as_str()).unwrap_or("none"));
 
+        let frame_size = dd_cfg.map(|c| c.frame_size).unwrap_or(4 << 20);
+        if matches!(compression, Compression::SeekableZstd)
+            && (frame_size == 0
+                || !frame_size.is_multiple_of(block_size)
+                || frame_size > u32::MAX as u64)
+        {
+            return Err(AppError::Validation(format!(
+                "dd.frame_size ({frame_size}) must be a multiple of dd.block_size ({block_size}) below 4 GiB"
+            )));
+        }
+
         let resume_mode = match dd_cfg
             .map(|c| c.resume.as_str())
             .unwrap_or("fresh")
@@ -107,40 +167,237 @@ impl<'a> DdBuilder<'a> {
 
         let sudo = dd_cfg.map(|c| c.sudo).unwrap_or(true);
 
-        // 3. Remote lsblk -----------------------------------------------------
-        let devices = remote_lsblk(&ssh, sudo)?;
+        let sparse = dd_cfg.map(|c| c.sparse).unwrap_or(true);
+
+        let volume_size = self.cfg.options.volume_size;
+        if volume_size == Some(0) {
+            return Err(AppError::Validation(
+                "options.volume_size must be above zero".into(),
+            ));
+        }
+
+        let limits = Limits::from_config(&self.cfg.throttle)?;
+
+        let per_partition = dd_cfg.map(|c| c.per_partition).unwrap_or(false);
+
+        let imager = dd_cfg.map(|c| c.imager.as_str()).unwrap_or("dd");
+
+        let consistency = Strategy::parse(
+            dd_cfg.map(|c| c.consistency.as_str()).unwrap_or("none"),
+            dd_cfg
+                .map(|c| c.snapshot_size.as_str())
+                .unwrap_or("10%ORIGIN"),
+        )?;
+
+        // 2. Remote lsblk -----------------------------------------------------
+        let tree = remote_lsblk(&ssh, sudo)?;
+        let devices = flatten(&tree);
         let dev = select_device(dev_query, &devices)
             .ok_or_else(|| AppError::Validation(format!("Device `{dev_query}` not found")))?;
 
-        // 4. Local filename ---------------------------------------------------
-        let mut path = std::path::PathBuf::from(&self.cfg.options.local_download_dir);
-        std::fs::create_dir_all(&path)?;
-        path.push(Utc::now().format("%Y%m%dT%H%M%SZ").to_string());
-        path.set_extension(compression.ext().trim_start_matches('.'));
+        let disk = if dev.is_disk() {
+            Some(dev)
+        } else {
+            dev.pkname
+                .as_deref()
+                .and_then(|p| devices.iter().copied().find(|d| d.name == p))
+        };
+        let partition_table = match disk {
+            Some(d) => remote_partition_table(&ssh, d, sudo)?,
+            None => None,
+        };
+
+        // 3. Local filenames --------------------------------------------------
+        let dir = std::path::PathBuf::from(&self.cfg.options.local_download_dir);
+        std::fs::create_dir_all(&dir)?;
+        let stamp = Utc::now().format(STAMP).to_string();
+        // A resumed run picks up the newest interrupted image under its name.
+        let image_path = |suffix: Option<&str>| {
+            let resumed = matches!(resume_mode, ResumeMode::Continue)
+                .then(|| partial_stamp(&dir, suffix, compression))
+                .flatten();
+            if let Some(s) = &resumed {
+                log::info!("Found interrupted image from {s}");
+            }
+            dir.join(image_name(
+                resumed.as_deref().unwrap_or(&stamp),
+                suffix,
+                compression,
+            ))
+        };
+
+        let target = |d: &BlockDevice, suffix: Option<&str>| -> Result<DdTarget, AppError> {
+            let imager = Imager::resolve(imager, d.fstype.as_deref())?;
+            if matches!(compression, Compression::SeekableZstd)
+                && imager.format() != ImageFormat::Raw
+            {
+                return Err(AppError::Validation(format!(
+                    "{imager} images of {} cannot be stored as zstd-seekable",
+                    d.dev_path()
+                )));
+            }
+            if matches!(resume_mode, ResumeMode::Continue) && !imager.can_resume() {
+                log::warn!(
+                    "{imager} cannot resume; {} is imaged from the start",
+                    d.dev_path()
+                );
+            }
+            Ok(DdTarget {
+                device: d.clone(),
+                imager,
+                local_path: image_path(suffix),
+            })
+        };
+
+        let targets = if per_partition && dev.partitions().next().is_some() {
+            dev.partitions()
+                .map(|p| target(p, Some(&p.name)))
+                .collect::<Result<Vec<_>, _>>()?
+        } else {
+            if per_partition {
+                log::warn!("{} has no partitions; imaging it whole", dev.dev_path());
+            }
+            vec![target(dev, None)?]
+        };
 
         Ok(DdSnapshotConfig {
             ssh,
-            device: dev.clone(),
+            host: self.cfg.remote.host.to_string(),
+            targets,
+            partition_table,
             compression,
             block_size,
+            frame_size,
             resume_mode,
             sudo,
-            local_path: path,
+            sparse,
+            volume_size,
+            consistency,
+            limits,
             read_to: std::time::Duration::from_secs(120),
             write_to: std::time::Duration::from_secs(120),
         })
     }
 }
 
-fn select_device<'d>(q: &str, list: &'d [BlockDevice]) -> Option<&'d BlockDevice> {
-    if q.starts_with("/dev/") {
-        return list.iter().find(|b| b.dev_path() == q);
+/// `<stamp>[-<suffix>]<ext>`, e.g. `20250101T000000Z-sda1.img.zst`.
+fn image_name(stamp: &str, suffix: Option<&str>, compression: Compression) -> String {
+    match suffix {
+        Some(s) => format!("{stamp}-{s}{}", compression.ext()),
+        None => format!("{stamp}{}", compression.ext()),
     }
-    if let Some(x) = q.strip_prefix("UUID=") {
-        return list.iter().find(|b| b.uuid.as_deref() == Some(x));
+}
+
+/// Stamp of the newest `<stamp>[-suffix]<ext>.partial` in `dir`.
+fn partial_stamp(dir: &Path, suffix: Option<&str>, compression: Compression) -> Option<String> {
+    std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|e| e.ok()?.file_name().into_string().ok())
+        .filter_map(|name| {
+            let base = name.strip_suffix(partial::SUFFIX)?;
+            let stamp = base.get(..16)?;
+            (NaiveDateTime::parse_from_str(stamp, STAMP).is_ok()
+                && image_name(stamp, suffix, compression) == base)
+                .then(|| stamp.to_string())
+        })
+        .max()
+}
+
+fn select_device<'d>(q: &str, list: &[&'d BlockDevice]) -> Option<&'d BlockDevice> {
+    let matches = |b: &BlockDevice| {
+        if q.starts_with("/dev/") {
+            return b.dev_path() == q;
+        }
+        let Some((key, val)) = q.split_once('=') else {
+            return false;
+        };
+        let field = match key {
+            "UUID" => &b.uuid,
+            "SERIAL" => &b.serial,
+            "LABEL" => &b.label,
+            "PARTUUID" => &b.partuuid,
+            "PARTLABEL" => &b.partlabel,
+            _ => return false,
+        };
+        field.as_deref() == Some(val)
+    };
+    list.iter().copied().find(|b| matches(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn golden_pipeline_and_extension_for_every_codec() {
+        // setting, remote pipeline after the imager, image name
+        let cases = [
+            ("none", "cat", "20250102T030405Z-sda1.img"),
+            ("gzip", "gzip -c", "20250102T030405Z-sda1.img.gz"),
+            ("xz", "xz -c", "20250102T030405Z-sda1.img.xz"),
+            ("zstd", "zstd -q -c", "20250102T030405Z-sda1.img.zst"),
+            (
+                "zstd-seekable",
+                "split -b 1048576 --filter='zstd -q -c' -",
+                "20250102T030405Z-sda1.img.zst",
+            ),
+        ];
+        for (setting, pipe, name) in cases {
+            let compression = Compression::parse(setting);
+            assert_eq!(compression.name(), setting);
+            let cmd = Imager::Dd
+                .read_cmd("/dev/sda1", 4 << 20, 0, true)
+                .pipe(compression.pipe(1 << 20));
+            assert_eq!(
+                cmd,
+                format!(
+                    "sudo dd if=/dev/sda1 bs=4194304 skip=0 iflag=fullblock,noatime \
+                     status=progress | {pipe}"
+                ),
+                "{setting}"
+            );
+            assert_eq!(
+                image_name("20250102T030405Z", Some("sda1"), compression),
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn resume_finds_the_newest_matching_partial() {
+        let dir = crate::testutil::TempDir::new("partial-stamp");
+        for name in [
+            "20250101T000000Z.img.zst.partial",
+            "20250102T000000Z.img.zst.partial",
+            "20250103T000000Z-sda1.img.zst.partial",
+            "20250104T000000Z.img.gz.partial",
+            "20250105T000000Z.img.zst",
+            "notastamp000000Z.img.zst.partial",
+        ] {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+        let zst = Compression::SeekableZstd;
+        assert_eq!(
+            partial_stamp(dir.path(), None, zst).as_deref(),
+            Some("20250102T000000Z")
+        );
+        assert_eq!(
+            partial_stamp(dir.path(), Some("sda1"), zst).as_deref(),
+            Some("20250103T000000Z")
+        );
+        assert_eq!(partial_stamp(dir.path(), Some("sda2"), zst), None);
+        assert_eq!(partial_stamp(dir.path(), None, Compression::None), None);
     }
-    if let Some(x) = q.strip_prefix("SERIAL=") {
-        return list.iter().find(|b| b.serial.as_deref() == Some(x));
+
+    #[test]
+    fn image_name_without_suffix_and_with_dots() {
+        assert_eq!(
+            image_name("20250102T030405Z", None, Compression::Gzip),
+            "20250102T030405Z.img.gz"
+        );
+        assert_eq!(
+            image_name("20250102T030405Z", Some("vg0.root"), Compression::None),
+            "20250102T030405Z-vg0.root.img"
+        );
     }
-    None
 }
@@ -0,0 +1,178 @@
+//! Point-in-time source for the imager: an LVM or ZFS snapshot of the device,
+//! or the live device with its filesystem frozen for the duration of the read.
+
+use std::fmt;
+
+use chrono::Utc;
+
+use super::probe::BlockDevice;
+use crate::{
+    error::AppError,
+    shell::{Cmd, quote},
+    ssh::Ssh,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Strategy {
+    /// Read the live device (crash-consistent at best).
+    None,
+    /// `lvcreate -s`; `size` is an `-L` size or an `-l` extent spec such as `10%ORIGIN`.
+    Lvm { size: String },
+    /// `zfs snapshot` + `zfs clone` of a zvol.
+    Zfs,
+    /// `fsfreeze -f` on the mounted filesystem while the device is read.
+    Fsfreeze,
+}
+
+impl Strategy {
+    pub fn parse(txt: &str, snapshot_size: &str) -> Result<Self, AppError> {
+        match txt.to_ascii_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "lvm" => Ok(Self::Lvm {
+                size: snapshot_size.into(),
+            }),
+            "zfs" => Ok(Self::Zfs),
+            "fsfreeze" => Ok(Self::Fsfreeze),
+            "btrfs" => Err(AppError::Validation(
+                "a btrfs snapshot is not a block device; use \"fsfreeze\" for dd mode".into(),
+            )),
+            other => Err(AppError::Validation(format!(
+                "unknown consistency strategy `{other}` (expected none, lvm, zfs or fsfreeze)"
+            ))),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Lvm { .. } => "lvm",
+            Self::Zfs => "zfs",
+            Self::Fsfreeze => "fsfreeze",
+        }
+    }
+}
+
+/// Device path to image from; undoes the snapshot or freeze when dropped,
+/// including when the pipeline fails or is interrupted.
+pub struct ConsistentSource<'a> {
+    ssh: &'a Ssh,
+    path: String,
+    /// Undo commands, most recent setup step first.
+    cleanup: Vec<String>,
+}
+
+impl<'a> ConsistentSource<'a> {
+    pub fn prepare(
+        ssh: &'a Ssh,
+        strategy: &Strategy,
+        dev: &BlockDevice,
+        sudo: bool,
+    ) -> Result<Self, AppError> {
+        let cmd = |program: &str| Cmd::new(program).sudo(sudo);
+        let stamp = Utc::now().format("%Y%m%d%H%M%S");
+        let mut src = Self {
+            ssh,
+            path: dev.dev_path(),
+            cleanup: Vec::new(),
+        };
+
+        match strategy {
+            Strategy::None => {}
+            Strategy::Lvm { size } => {
+                let out = capture(
+                    ssh,
+                    cmd("lvs")
+                        .args(["--noheadings", "-o", "vg_name,lv_name"])
+                        .arg(dev.dev_path()),
+                )?;
+                let mut it = out.split_whitespace();
+                let (Some(vg), Some(lv)) = (it.next(), it.next()) else {
+                    return Err(AppError::Remote(format!(
+                        "{} is not an LVM logical volume",
+                        dev.dev_path()
+                    )));
+                };
+                let snap = format!("{lv}-backup-{stamp}");
+                let size_arg = if size.contains('%') { "-l" } else { "-L" };
+                exec(
+                    ssh,
+                    cmd("lvcreate")
+                        .args(["-q", "-s", "-n", &snap, size_arg, size])
+                        .arg(format!("{vg}/{lv}")),
+                )?;
+                src.undo(
+                    cmd("lvremove")
+                        .args(["-q", "-f"])
+                        .arg(format!("{vg}/{snap}")),
+                );
+                src.path = format!("/dev/{vg}/{snap}");
+            }
+            Strategy::Zfs => {
+                let vol = capture(
+                    ssh,
+                    format!(
+                        "for v in $({}); do \
+                         [ \"$(readlink -f /dev/zvol/$v)\" = {} ] && echo $v; done; true",
+                        cmd("zfs").args(["list", "-H", "-o", "name", "-t", "volume"]),
+                        quote(&dev.dev_path())
+                    ),
+                )?;
+                let vol = vol.lines().next().unwrap_or_default().trim().to_string();
+                if vol.is_empty() {
+                    return Err(AppError::Remote(format!(
+                        "{} is not a ZFS volume",
+                        dev.dev_path()
+                    )));
+                }
+                let snap = format!("{vol}@backup-{stamp}");
+                let clone = format!("{vol}-backup-{stamp}");
+                exec(ssh, cmd("zfs").arg("snapshot").arg(&snap))?;
+                src.undo(cmd("zfs").arg("destroy").arg(&snap));
+                exec(ssh, cmd("zfs").arg("clone").arg(&snap).arg(&clone))?;
+                src.undo(cmd("zfs").arg("destroy").arg(&clone));
+                exec(ssh, cmd("udevadm").arg("settle"))?;
+                src.path = format!("/dev/zvol/{clone}");
+            }
+            Strategy::Fsfreeze => match dev.mountpoint.as_deref() {
+                Some(mnt) => {
+                    exec(ssh, cmd("fsfreeze").arg("-f").arg(mnt))?;
+                    src.undo(cmd("fsfreeze").arg("-u").arg(mnt));
+                }
+                None => log::info!("{} is not mounted; nothing to freeze", dev.dev_path()),
+            },
+        }
+        if src.path != dev.dev_path() {
+            log::info!("Imaging {} from snapshot {}", dev.dev_path(), src.path);
+        }
+        Ok(src)
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    fn undo(&mut self, cmd: Cmd) {
+        self.cleanup.insert(0, cmd.to_string());
+    }
+}
+
+impl Drop for ConsistentSource<'_> {
+    fn drop(&mut self) {
+        for cmd in self.cleanup.drain(..) {
+            log::info!("Cleanup: {cmd}");
+            if let Err(e) = exec(self.ssh, &cmd) {
+                log::error!("cleanup `{cmd}` failed: {e}");
+            }
+        }
+    }
+}
+
+fn exec(ssh: &Ssh, cmd: impl fmt::Display) -> Result<(), AppError> {
+    ssh.exec_capture(&cmd.to_string(), &mut std::io::sink())
+}
+
+fn capture(ssh: &Ssh, cmd: impl fmt::Display) -> Result<String, AppError> {
+    let mut out = Vec::new();
+    ssh.exec_capture(&cmd.to_string(), &mut out)?;
+    Ok(String::from_utf8_lossy(&out).into_owned())
+}
@@ -0,0 +1,132 @@
+//! Remote tool that reads a block device into the pipeline, and its inverse.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{error::AppError, shell::Cmd};
+
+/// Format of the uncompressed stream an imager produces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    /// Byte-for-byte device image (`dd`, `e2image -ra`).
+    Raw,
+    /// partclone image; only `partclone.<fs> -r` can restore it.
+    Partclone,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Imager {
+    Dd,
+    /// `e2image -ra`: raw image, free blocks left as zeros (ext2/3/4).
+    E2image,
+    /// `partclone.<fs>`: used blocks only, in partclone's own format.
+    Partclone(String),
+}
+
+impl Imager {
+    /// Resolves the `[dd] imager` setting against the device's filesystem.
+    pub fn resolve(setting: &str, fstype: Option<&str>) -> Result<Self, AppError> {
+        let fstype = fstype.unwrap_or_default();
+        match setting.to_ascii_lowercase().as_str() {
+            "dd" => Ok(Self::Dd),
+            "auto" => Ok(match fstype {
+                "ext2" | "ext3" | "ext4" => Self::E2image,
+                fs => partclone_name(fs).map_or(Self::Dd, |p| Self::Partclone(p.into())),
+            }),
+            "e2image" => match fstype {
+                "ext2" | "ext3" | "ext4" => Ok(Self::E2image),
+                other => Err(AppError::Validation(format!(
+                    "e2image cannot image a `{other}` filesystem"
+                ))),
+            },
+            "partclone" => partclone_name(fstype)
+                .map(|p| Self::Partclone(p.into()))
+                .ok_or_else(|| {
+                    AppError::Validation(format!("no partclone tool for filesystem `{fstype}`"))
+                }),
+            other => Err(AppError::Validation(format!(
+                "unknown imager `{other}` (expected dd, auto, e2image or partclone)"
+            ))),
+        }
+    }
+
+    /// Parses the name recorded in metadata (`dd`, `e2image`, `partclone.xfs`).
+    pub fn from_name(name: &str) -> Result<Self, AppError> {
+        match name {
+            "dd" => Ok(Self::Dd),
+            "e2image" => Ok(Self::E2image),
+            p if p.starts_with("partclone.") => Ok(Self::Partclone(p.into())),
+            other => Err(AppError::Validation(format!("unknown imager `{other}`"))),
+        }
+    }
+
+    pub fn format(&self) -> ImageFormat {
+        match self {
+            Self::Dd | Self::E2image => ImageFormat::Raw,
+            Self::Partclone(_) => ImageFormat::Partclone,
+        }
+    }
+
+    /// Only `dd` can start part-way into a device.
+    pub fn can_resume(&self) -> bool {
+        matches!(self, Self::Dd)
+    }
+
+    /// Remote command writing the image of `dev` to stdout.
+    pub fn read_cmd(&self, dev: &str, block_size: u64, skip_blocks: u64, sudo: bool) -> Cmd {
+        match self {
+            Self::Dd => Cmd::new("dd")
+                .sudo(sudo)
+                .opt("if", dev)
+                .opt("bs", block_size)
+                .opt("skip", skip_blocks)
+                .arg("iflag=fullblock,noatime")
+                .arg("status=progress"),
+            Self::E2image => Cmd::new("e2image").sudo(sudo).arg("-ra").arg(dev).arg("-"),
+            Self::Partclone(tool) => Cmd::new(tool.as_str())
+                .sudo(sudo)
+                .args(["-c", "-q", "-s", dev, "-o", "-"]),
+        }
+    }
+
+    /// Remote command writing an image read from stdin back onto `dev`.
+    pub fn restore_cmd(&self, dev: &str, block_size: u64, sudo: bool) -> Cmd {
+        match self {
+            Self::Dd | Self::E2image => Cmd::new("dd")
+                .sudo(sudo)
+                .opt("of", dev)
+                .opt("bs", block_size)
+                .arg("conv=fsync"),
+            Self::Partclone(tool) => Cmd::new(tool.as_str())
+                .sudo(sudo)
+                .args(["-r", "-q", "-s", "-", "-o", dev]),
+        }
+    }
+}
+
+impl fmt::Display for Imager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Dd => write!(f, "dd"),
+            Self::E2image => write!(f, "e2image"),
+            Self::Partclone(tool) => write!(f, "{tool}"),
+        }
+    }
+}
+
+/// partclone has one binary per filesystem; XFS goes through here as well,
+/// since `xfs_copy` cannot write to a pipe.
+fn partclone_name(fstype: &str) -> Option<&'static str> {
+    Some(match fstype {
+        "ext2" | "ext3" | "ext4" => "partclone.extfs",
+        "xfs" => "partclone.xfs",
+        "btrfs" => "partclone.btrfs",
+        "ntfs" => "partclone.ntfs",
+        "vfat" | "fat" | "fat16" | "fat32" => "partclone.fat",
+        "exfat" => "partclone.exfat",
+        "f2fs" => "partclone.f2fs",
+        _ => return None,
+    })
+}
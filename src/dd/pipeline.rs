@@ -1,132 +1,435 @@
-//! Runs the remote dd copy, verifies hash, writes metadata JSON.
+//! Runs the remote dd copy, verifies hash, writes the snapshot record.
 
 use std::{
     fs::{File, OpenOptions},
-    io::{Read, Write},
+    io::{self, Read, Seek, SeekFrom, Write},
     path::Path,
 };
 
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use indicatif::{ProgressBar, ProgressStyle};
 use sha2::{Digest, Sha256};
 
-use super::{ResumeMode, meta::DdSnapshotMeta};
+use super::{
+    ResumeMode,
+    consistency::ConsistentSource,
+    seekable::{self, FrameScanner, SeekableReader},
+    sparse::SparseWriter,
+};
 use crate::{
-    dd::builder::DdSnapshotConfig,
+    dd::builder::{Compression, DdSnapshotConfig, DdTarget},
     error::AppError,
-    metadata::generator::{BackupMeta, JsonWriter},
+    metadata::{
+        SnapshotRecord,
+        record::{DdSection, Details},
+    },
+    partial,
+    shell::Cmd,
+    volume::{self, VolumeInfo, VolumeWriter},
 };
 
-
-pub fn run_once(cfg: DdSnapshotConfig) -> Result<DdSnapshotMeta, AppError> {
+pub fn run_once(cfg: &DdSnapshotConfig) -> Result<Vec<SnapshotRecord>, AppError> {
     DdPipeline::new(cfg).run()
 }
 
-pub struct DdPipeline {
-    cfg: DdSnapshotConfig,
+pub struct DdPipeline<'a> {
+    cfg: &'a DdSnapshotConfig,
 }
 
-impl DdPipeline {
-    pub fn new(cfg: DdSnapshotConfig) -> Self {
+impl<'a> DdPipeline<'a> {
+    pub fn new(cfg: &'a DdSnapshotConfig) -> Self {
         Self { cfg }
     }
 
-    pub fn run(self) -> Result<DdSnapshotMeta, AppError> {
+    /// Images every target in turn; one metadata record per image.
+    pub fn run(self) -> Result<Vec<SnapshotRecord>, AppError> {
         let cfg = self.cfg;
+        cfg.targets
+            .iter()
+            .map(|target| {
+                log::info!(
+                    "Imaging {} -> {}",
+                    target.device.dev_path(),
+                    target.local_path.display()
+                );
+                let started_at = Utc::now();
+                image_one(cfg, target, started_at).inspect_err(|e| {
+                    new_record(cfg, target, section(cfg, target), started_at)
+                        .record_aborted(&e.to_string());
+                })
+            })
+            .collect()
+    }
+}
 
-       
-        let size_cmd = format!(
-            "{sudo}blockdev --getsize64 {}",
-            cfg.device.dev_path(),
-            sudo = if cfg.sudo { "sudo " } else { "" }
-        );
-        let mut buf = Vec::<u8>::new();
-        cfg.ssh.exec_capture(&size_cmd, &mut buf)?;
-        let dev_size: u64 = String::from_utf8_lossy(&buf).trim().parse().unwrap_or(0);
-
-      
-        let dd_cmd = format!(
-            r#"{sudo}dd if={} bs={} iflag=fullblock,noatime status=progress | {}"#,
-            cfg.device.dev_path(),
+fn image_one(
+    cfg: &DdSnapshotConfig,
+    target: &DdTarget,
+    started_at: DateTime<Utc>,
+) -> Result<SnapshotRecord, AppError> {
+    let source = ConsistentSource::prepare(&cfg.ssh, &cfg.consistency, &target.device, cfg.sudo)?;
+
+    let size_cmd = Cmd::new("blockdev")
+        .sudo(cfg.sudo)
+        .arg("--getsize64")
+        .arg(source.path());
+    let mut buf = Vec::<u8>::new();
+    cfg.ssh.exec_capture(&size_cmd.to_string(), &mut buf)?;
+    let dev_size: u64 = String::from_utf8_lossy(&buf).trim().parse().unwrap_or(0);
+
+    let seekable = matches!(cfg.compression, Compression::SeekableZstd);
+    if seekable && dev_size == 0 {
+        return Err(AppError::Remote(format!(
+            "cannot size {} for a seekable image",
+            target.device.dev_path()
+        )));
+    }
+
+    let mut hasher = Sha256::new();
+    let mut scanner = FrameScanner::default();
+    let plain = matches!(cfg.compression, Compression::None);
+    let resume_mode = match cfg.resume_mode {
+        ResumeMode::Continue if cfg.volume_size.is_some() => {
+            log::warn!(
+                "split images cannot resume; {} is imaged from the start",
+                target.device.dev_path()
+            );
+            ResumeMode::Fresh
+        }
+        ResumeMode::Continue if !seekable && !plain => {
+            log::warn!(
+                "{} images are one stream and cannot resume; {} is imaged from the start",
+                cfg.compression.name(),
+                target.device.dev_path()
+            );
+            ResumeMode::Fresh
+        }
+        _ if target.imager.can_resume() => cfg.resume_mode,
+        _ => ResumeMode::Fresh,
+    };
+    let partial_path = partial::path(&target.local_path);
+    let (file, offset, skip) = match (resume_mode, seekable) {
+        (ResumeMode::Fresh, _) if cfg.volume_size.is_some() => (None, 0, 0),
+        (ResumeMode::Fresh, _) => (Some(File::create(&partial_path)?), 0, 0),
+        (ResumeMode::Continue, false) => {
+            let (f, done) = resume_raw(&partial_path, cfg.block_size, &mut hasher)?;
+            (Some(f), done, done)
+        }
+        (ResumeMode::Continue, true) => {
+            let (f, done) =
+                resume_seekable(&partial_path, cfg.frame_size, &mut scanner, &mut hasher)?;
+            (Some(f), done, done)
+        }
+    };
+
+    let dd_cmd = target
+        .imager
+        .read_cmd(
+            source.path(),
             cfg.block_size,
-            cfg.compression.pipe(),
-            sudo = if cfg.sudo { "sudo " } else { "" },
-        );
+            skip / cfg.block_size,
+            cfg.sudo,
+        )
+        .pipe(cfg.compression.pipe(cfg.frame_size));
+    let dd_cmd = cfg.limits.niced(&cfg.limits.remote_pipe(dd_cmd));
 
-     
-        let (mut file, offset) = match cfg.resume_mode {
-            ResumeMode::Fresh => (File::create(&cfg.local_path)?, 0),
-            ResumeMode::Continue => {
-                let f = OpenOptions::new()
-                    .create(true)
-                    .append(true)
-                    .open(&cfg.local_path)?;
-                (f.try_clone()?, f.metadata()?.len())
-            }
-        };
+    // Dropped on any error before `finish`, which stops the remote side too.
+    let mut job = cfg.ssh.spawn(&dd_cmd)?;
+    let mut pacer = cfg.limits.pacer();
 
-     
-        let mut ch = cfg.ssh.open_stream(&dd_cmd)?;
+    let pb = ProgressBar::new(dev_size.saturating_sub(offset));
+    pb.set_style(
+        ProgressStyle::with_template(
+            "[{elapsed_precise}] {wide_bar} {bytes}/{total_bytes} ({bytes_per_sec})",
+        )
+        .unwrap(),
+    );
 
-   
-        let mut hasher = Sha256::new();
-        let pb = ProgressBar::new(dev_size.saturating_sub(offset));
-        pb.set_style(
-            ProgressStyle::with_template(
-                "[{elapsed_precise}] {wide_bar} {bytes}/{total_bytes} ({bytes_per_sec})",
-            )
-            .unwrap(),
-        );
+    // Holes do not survive splitting, so volumes are always written dense.
+    let sparse = cfg.sparse && matches!(cfg.compression, Compression::None) && file.is_some();
+    let mut out = match file {
+        Some(file) => Output::Single(SparseWriter::new(file, cfg.block_size as usize, sparse)),
+        None => Output::Split(VolumeWriter::new(
+            &target.local_path,
+            cfg.volume_size.unwrap_or(u64::MAX),
+        )),
+    };
+
+    let mut buf64 = [0u8; 1 << 20];
+    let mut written = if seekable {
+        scanner.complete_len()
+    } else {
+        offset
+    };
+    loop {
+        let n = job.read(&mut buf64)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf64[..n]);
+        if seekable {
+            scanner.feed(&buf64[..n])?;
+        }
+        out.write(&buf64[..n])?;
+        written += n as u64;
+        pb.inc(n as u64);
+        pacer.pace(n)?;
+    }
+    pb.finish();
+    let code = job.finish()?;
+    if code != 0 {
+        return Err(AppError::RemoteExit(code));
+    }
+
+    let mut table = Vec::new();
+    if seekable {
+        if scanner.complete_len() != written {
+            return Err(AppError::Remote("zstd stream ended mid-frame".into()));
+        }
+        table = seekable::encode_seek_table(scanner.frames(), cfg.frame_size, dev_size);
+        hasher.update(&table);
+        written += table.len() as u64;
+    }
+    let (bytes_sparse, volumes) = out.finish(&table)?;
+    if sparse {
+        log::info!("{bytes_sparse} zero bytes left as holes");
+    }
+    if !volumes.is_empty() {
+        log::info!("Image split into {} volumes", volumes.len());
+    }
+
+    let sha_hex = hex::encode(hasher.finalize());
+    // Only a checked, fully written image gets its final name.
+    if volumes.is_empty() {
+        partial::commit(&target.local_path)?;
+    } else {
+        volume::commit(&target.local_path, &volumes)?;
+    }
+
+    let dd = DdSection {
+        device_bytes: dev_size,
+        bytes_sparse,
+        frame_size: seekable.then_some(cfg.frame_size),
+        frames: seekable.then(|| scanner.frames().len()),
+        ..section(cfg, target)
+    };
+    let record = new_record(cfg, target, dd, started_at).completed(written, sha_hex, volumes);
+    record.write()?;
+    Ok(record)
+}
+
+/// What is known about an image before it is taken.
+fn section(cfg: &DdSnapshotConfig, target: &DdTarget) -> DdSection {
+    DdSection {
+        device: target.device.dev_path(),
+        parent_device: target.device.pkname.as_ref().map(|p| format!("/dev/{p}")),
+        fstype: target.device.fstype.clone(),
+        imager: target.imager.to_string(),
+        image_format: target.imager.format(),
+        consistency: cfg.consistency.name().into(),
+        partition_table: cfg.partition_table.clone(),
+        device_bytes: 0,
+        bytes_sparse: 0,
+        frame_size: None,
+        frames: None,
+    }
+}
+
+fn new_record(
+    cfg: &DdSnapshotConfig,
+    target: &DdTarget,
+    dd: DdSection,
+    started_at: DateTime<Utc>,
+) -> SnapshotRecord {
+    SnapshotRecord::new(
+        Details::Dd(dd),
+        &target.local_path,
+        &cfg.host,
+        cfg.compression.name(),
+        started_at,
+    )
+}
+
+/// Where the stream goes: one (possibly sparse) file, or numbered volumes.
+enum Output {
+    Single(SparseWriter),
+    Split(VolumeWriter),
+}
 
-        let mut buf64 = [0u8; 1 << 20];
-        let mut written = offset;
-        loop {
-            let n = ch.read(&mut buf64)?;
-            if n == 0 {
-                break;
+impl Output {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Self::Single(w) => w.write(data),
+            Self::Split(w) => w.write_all(data),
+        }
+    }
+
+    /// Appends `tail` and syncs. Returns the bytes left as holes and the volumes.
+    fn finish(self, tail: &[u8]) -> io::Result<(u64, Vec<VolumeInfo>)> {
+        match self {
+            Self::Single(w) => {
+                let (mut file, bytes_sparse) = w.finish()?;
+                file.write_all(tail)?;
+                file.sync_all()?;
+                Ok((bytes_sparse, Vec::new()))
+            }
+            Self::Split(mut w) => {
+                w.write_all(tail)?;
+                Ok((0, w.finish()?))
             }
-            hasher.update(&buf64[..n]);
-            file.write_all(&buf64[..n])?;
-            written += n as u64;
-            pb.inc(n as u64);
         }
-        pb.finish();
-        ch.wait_close()?;
-        if ch.exit_status()? != 0 {
-            return Err(AppError::RemoteExit(ch.exit_status()?));
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Reopens an interrupted raw image, cuts it back to whole blocks and
+/// re-hashes them. Returns the file and the bytes already done.
+fn resume_raw(path: &Path, block_size: u64, hasher: &mut Sha256) -> Result<(File, u64), AppError> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(path)?;
+    let len = file.metadata()?.len();
+    let keep = len - len % block_size;
+    file.set_len(keep)?;
+    std::io::copy(&mut (&mut file).take(keep), hasher)?;
+    file.seek(SeekFrom::End(0))?;
+    if keep > 0 {
+        log::info!("Resuming {} after {keep} bytes", path.display());
+    }
+    Ok((file, keep))
+}
+
+/// Reopens an interrupted seekable image, drops its trailing partial frame and
+/// re-hashes the kept prefix. Returns the file and the uncompressed bytes already done.
+fn resume_seekable(
+    path: &Path,
+    frame_size: u64,
+    scanner: &mut FrameScanner,
+    hasher: &mut Sha256,
+) -> Result<(File, u64), AppError> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(path)?;
+
+    if SeekableReader::open(file.try_clone()?)?.is_some() {
+        log::warn!(
+            "{} is already a complete image; starting over",
+            path.display()
+        );
+        file.set_len(0)?;
+        return Ok((file, 0));
+    }
+
+    *scanner = seekable::scan_partial(&mut file)?;
+    let keep = scanner.complete_len();
+    file.set_len(keep)?;
+    file.seek(SeekFrom::Start(0))?;
+    std::io::copy(&mut (&mut file).take(keep), hasher)?;
+    file.seek(SeekFrom::End(0))?;
+
+    let done = scanner.frames().len() as u64 * frame_size;
+    if done > 0 {
+        log::info!(
+            "Resuming {} after {} complete frames ({done} bytes)",
+            path.display(),
+            scanner.frames().len()
+        );
+    }
+    Ok((file, done))
+}
+
+#[cfg(test)]
+mod tests {
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn data(len: usize) -> Vec<u8> {
+        let mut x = 0x9E37_79B9u32;
+        (0..len)
+            .map(|i| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                // Compressible, but not trivially so.
+                if i % 3 == 0 { 0 } else { x as u8 }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn raw_image_resumes_after_the_last_whole_block() {
+        let dir = TempDir::new("resume-raw");
+        let path = dir.join("a.img.partial");
+        let block = 4096;
+        let want = data(10 * block + 123);
+        std::fs::write(&path, &want[..2 * block + 1000]).unwrap();
+
+        let mut hasher = Sha256::new();
+        let (mut file, done) = resume_raw(&path, block as u64, &mut hasher).unwrap();
+        assert_eq!(done, 2 * block as u64);
+        // What the remote sends with skip=done/block_size.
+        file.write_all(&want[done as usize..]).unwrap();
+        hasher.update(&want[done as usize..]);
+
+        assert_eq!(std::fs::read(&path).unwrap(), want);
+        assert_eq!(hasher.finalize(), Sha256::digest(&want));
+    }
+
+    #[test]
+    fn seekable_image_resumes_after_the_last_whole_frame() {
+        let dir = TempDir::new("resume-seekable");
+        let path = dir.join("a.img.zst.partial");
+        let frame_size = 64 << 10;
+        let want = data(7 * frame_size + 5000);
+        let frames: Vec<Vec<u8>> = want
+            .chunks(frame_size)
+            .map(|f| zstd::bulk::compress(f, 3).unwrap())
+            .collect();
+        // Interrupted in the middle of the fourth frame.
+        let mut cut = frames[..3].concat();
+        cut.extend_from_slice(&frames[3][..frames[3].len() / 2]);
+        std::fs::write(&path, &cut).unwrap();
+
+        let mut scanner = FrameScanner::default();
+        let mut hasher = Sha256::new();
+        let (mut file, done) =
+            resume_seekable(&path, frame_size as u64, &mut scanner, &mut hasher).unwrap();
+        assert_eq!(done, 3 * frame_size as u64);
+        for f in &frames[3..] {
+            scanner.feed(f).unwrap();
+            hasher.update(f);
+            file.write_all(f).unwrap();
         }
+        let table =
+            seekable::encode_seek_table(scanner.frames(), frame_size as u64, want.len() as u64);
+        hasher.update(&table);
+        file.write_all(&table).unwrap();
+        drop(file);
+
+        let mut fresh = frames.concat();
+        fresh.extend_from_slice(&table);
+        let got = std::fs::read(&path).unwrap();
+        assert!(got == fresh, "resumed image differs from a fresh one");
+        assert_eq!(hasher.finalize(), Sha256::digest(&fresh));
 
-        let sha_hex = hex::encode(hasher.finalize());
-
- 
-        let meta = DdSnapshotMeta {
-            device: cfg.device.dev_path(),
-            host: cfg.ssh.remote_addr_string(),
-            local_path: cfg.local_path.to_string_lossy().into_owned(),
-            bytes_total: dev_size,
-            bytes_written: written,
-            sha256: sha_hex.clone(),
-            compression: format!("{:?}", cfg.compression),
-            finished_at: Utc::now(),
-        };
-
-        JsonWriter::write(
-            &BackupMeta {
-                snapshot_name: Path::new(&meta.local_path)
-                    .file_stem()
-                    .unwrap()
-                    .to_string_lossy()
-                    .into_owned(),
-                remote_path: meta.device.clone(),
-                local_path: meta.local_path.clone(),
-                size_bytes: meta.bytes_total,
-                sha256: sha_hex,
-                timestamp: meta.finished_at,
-                filesystems: vec![],
-            },
-            Path::new("metadata"),
-        )?;
-
-        Ok(meta)
+        let mut reader = SeekableReader::open(File::open(&path).unwrap())
+            .unwrap()
+            .unwrap();
+        let mut back = vec![0u8; want.len()];
+        assert_eq!(reader.read_at(0, &mut back).unwrap(), want.len());
+        assert!(back == want);
     }
 }
@@ -1,14 +1,33 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-use crate::{error::AppError, ssh::Ssh};
+use crate::{error::AppError, shell::Cmd, ssh::Ssh};
 
-#[derive(Debug, Deserialize, Clone)]
+const LSBLK_COLUMNS: &str =
+    "NAME,TYPE,PKNAME,FSTYPE,LABEL,UUID,PARTUUID,PARTLABEL,SERIAL,MODEL,SIZE,MOUNTPOINT";
+/// Device node column, util-linux 2.33 and later.
+const LSBLK_PATH: &str = "PATH";
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct BlockDevice {
     pub name: String,
+    /// Device node as lsblk reports it; missing from older lsblk.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// `disk`, `part`, `lvm`, `rom`, …
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    pub pkname: Option<String>,
+    pub fstype: Option<String>,
+    pub label: Option<String>,
     pub serial: Option<String>,
     pub uuid: Option<String>,
+    pub partuuid: Option<String>,
+    pub partlabel: Option<String>,
+    pub model: Option<String>,
     pub size: String,
     pub mountpoint: Option<String>,
+    #[serde(default)]
+    pub children: Vec<BlockDevice>,
 }
 #[derive(Debug, Deserialize)]
 struct LsblkJson {
@@ -16,19 +35,59 @@ struct LsblkJson {
 }
 
 impl BlockDevice {
+    /// Device node. `NAME` of a device-mapper device (LVM, dm-crypt) is its
+    /// mapper name, whose node is under `/dev/mapper`.
     pub fn dev_path(&self) -> String {
-        format!("/dev/{}", self.name)
+        if let Some(path) = &self.path {
+            return path.clone();
+        }
+        match self.kind.as_deref() {
+            Some("lvm" | "crypt" | "dm" | "mpath") => format!("/dev/mapper/{}", self.name),
+            _ => format!("/dev/{}", self.name),
+        }
+    }
+
+    pub fn is_disk(&self) -> bool {
+        self.kind.as_deref() == Some("disk")
     }
+
+    /// Direct children of type `part`.
+    pub fn partitions(&self) -> impl Iterator<Item = &BlockDevice> {
+        self.children
+            .iter()
+            .filter(|c| c.kind.as_deref() == Some("part"))
+    }
+
+    /// The device itself followed by all of its descendants, depth first.
+    pub fn walk(&self) -> Vec<&BlockDevice> {
+        let mut out = vec![self];
+        for c in &self.children {
+            out.extend(c.walk());
+        }
+        out
+    }
+}
+
+/// Every device of an lsblk tree, depth first.
+pub fn flatten(tree: &[BlockDevice]) -> Vec<&BlockDevice> {
+    tree.iter().flat_map(BlockDevice::walk).collect()
 }
 
-/// Runs `lsblk` on the remote host and returns the parsed list.
+/// Runs `lsblk` on the remote host and returns the device tree.
 pub fn remote_lsblk(ssh: &Ssh, sudo: bool) -> Result<Vec<BlockDevice>, AppError> {
-    let cmd = if sudo {
-        "sudo lsblk -lJ -o NAME,SERIAL,UUID,SIZE,MOUNTPOINT"
-    } else {
-        "lsblk -lJ -o NAME,SERIAL,UUID,SIZE,MOUNTPOINT"
+    let cmd = |columns: &str| {
+        Cmd::new("lsblk")
+            .sudo(sudo)
+            .arg("-J")
+            .arg("-o")
+            .arg(columns)
     };
-    let mut ch = ssh.open_stream(cmd)?;
+    let cmd = format!(
+        "{} 2>/dev/null || {}",
+        cmd(&format!("{LSBLK_COLUMNS},{LSBLK_PATH}")),
+        cmd(LSBLK_COLUMNS)
+    );
+    let mut ch = ssh.open_stream(&cmd)?;
     let mut json = String::new();
     use std::io::Read;
     ch.read_to_string(&mut json)?;
@@ -40,3 +99,71 @@ pub fn remote_lsblk(ssh: &Ssh, sudo: bool) -> Result<Vec<BlockDevice>, AppError>
         serde_json::from_str(&json).map_err(|e| AppError::Remote(format!("lsblk json: {e}")))?;
     Ok(parsed.blockdevices)
 }
+
+/// Captures the partition table of `disk` as `sfdisk --dump` text.
+/// Returns `None` when the disk carries no partition table.
+pub fn remote_partition_table(
+    ssh: &Ssh,
+    disk: &BlockDevice,
+    sudo: bool,
+) -> Result<Option<String>, AppError> {
+    let cmd = Cmd::new("sfdisk")
+        .sudo(sudo)
+        .arg("--dump")
+        .arg(disk.dev_path());
+    let mut out = Vec::new();
+    match ssh.exec_capture(&cmd.to_string(), &mut out) {
+        Ok(()) => Ok(Some(String::from_utf8_lossy(&out).into_owned())),
+        Err(AppError::RemoteExit(code)) => {
+            log::warn!("sfdisk --dump {} exited with {code}", disk.dev_path());
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LSBLK: &str = r#"{"blockdevices": [
+        {"name": "sda", "type": "disk", "size": "20G", "children": [
+            {"name": "sda1", "type": "part", "pkname": "sda", "size": "1G"},
+            {"name": "sda2", "type": "part", "pkname": "sda", "size": "19G", "children": [
+                {"name": "vg0-root", "type": "lvm", "pkname": "sda2", "size": "10G",
+                 "mountpoint": "/"}
+            ]}
+        ]}
+    ]}"#;
+
+    fn devices(json: &str) -> Vec<BlockDevice> {
+        serde_json::from_str::<LsblkJson>(json)
+            .unwrap()
+            .blockdevices
+    }
+
+    #[test]
+    fn logical_volumes_resolve_under_dev_mapper_without_path() {
+        let tree = devices(LSBLK);
+        let paths: Vec<String> = flatten(&tree).iter().map(|d| d.dev_path()).collect();
+        assert_eq!(
+            paths,
+            ["/dev/sda", "/dev/sda1", "/dev/sda2", "/dev/mapper/vg0-root"]
+        );
+    }
+
+    #[test]
+    fn path_column_wins() {
+        let json = LSBLK.replace(
+            r#""name": "vg0-root","#,
+            r#""name": "vg0-root", "path": "/dev/mapper/vg0-root","#,
+        );
+        let tree = devices(&json);
+        let lv = flatten(&tree)
+            .into_iter()
+            .find(|d| d.name == "vg0-root")
+            .unwrap();
+        assert_eq!(lv.path.as_deref(), Some("/dev/mapper/vg0-root"));
+        assert_eq!(lv.dev_path(), "/dev/mapper/vg0-root");
+    }
+}
@@ -0,0 +1,462 @@
+//! Seekable zstd images: independent frames of a fixed uncompressed size,
+//! followed by a seek table in a skippable frame (zstd "seekable format").
+//!
+//! Plain `zstd -d` still decompresses such a file, since it ignores skippable frames.
+
+use std::{
+    fs::File,
+    io::{self, Read},
+    os::unix::fs::FileExt,
+};
+
+const ZSTD_MAGIC: u32 = 0xFD2F_B528;
+const SKIPPABLE_MAGIC_MASK: u32 = 0xFFFF_FFF0;
+const SKIPPABLE_MAGIC: u32 = 0x184D_2A50;
+const SEEK_TABLE_MAGIC: u32 = 0x184D_2A5E;
+const SEEKABLE_FOOTER_MAGIC: u32 = 0x8F92_EAB1;
+const FOOTER_LEN: u64 = 9;
+
+/// Remote command that cuts the raw stream into independently compressed frames.
+pub fn remote_pipe(frame_size: u64) -> String {
+    format!("split -b {frame_size} --filter='zstd -q -c' -")
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Want {
+    Magic,
+    FrameDescriptor,
+    BlockHeader,
+    SkippableSize,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum After {
+    BlockHeader,
+    Checksum,
+    FrameEnd,
+    SkippableEnd,
+}
+
+/// Finds frame boundaries in a zstd stream as it passes by, without decompressing.
+#[derive(Debug)]
+pub struct FrameScanner {
+    want: Want,
+    hdr: Vec<u8>,
+    skip: u64,
+    after: After,
+    checksum: bool,
+    pos: u64,
+    frame_start: u64,
+    frames: Vec<u32>,
+}
+
+impl Default for FrameScanner {
+    fn default() -> Self {
+        Self {
+            want: Want::Magic,
+            hdr: Vec::with_capacity(8),
+            skip: 0,
+            after: After::FrameEnd,
+            checksum: false,
+            pos: 0,
+            frame_start: 0,
+            frames: Vec::new(),
+        }
+    }
+}
+
+impl FrameScanner {
+    /// Compressed size of every complete frame seen so far.
+    pub fn frames(&self) -> &[u32] {
+        &self.frames
+    }
+
+    /// Byte offset right after the last complete frame.
+    pub fn complete_len(&self) -> u64 {
+        self.frame_start
+    }
+
+    /// Forgets a trailing partial frame so scanning can continue from `complete_len`.
+    pub fn rewind_to_complete(&mut self) {
+        self.want = Want::Magic;
+        self.hdr.clear();
+        self.skip = 0;
+        self.pos = self.frame_start;
+    }
+
+    pub fn feed(&mut self, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            if self.skip > 0 {
+                let n = self.skip.min(data.len() as u64) as usize;
+                self.skip -= n as u64;
+                self.pos += n as u64;
+                data = &data[n..];
+                if self.skip == 0 {
+                    self.finish_skip()?;
+                }
+                continue;
+            }
+
+            let need = match self.want {
+                Want::Magic | Want::SkippableSize => 4,
+                Want::FrameDescriptor => 1,
+                Want::BlockHeader => 3,
+            };
+            let n = (need - self.hdr.len()).min(data.len());
+            self.hdr.extend_from_slice(&data[..n]);
+            self.pos += n as u64;
+            data = &data[n..];
+            if self.hdr.len() == need {
+                self.parse_header()?;
+                self.hdr.clear();
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_header(&mut self) -> io::Result<()> {
+        let h = &self.hdr;
+        match self.want {
+            Want::Magic => {
+                let magic = u32::from_le_bytes([h[0], h[1], h[2], h[3]]);
+                if magic == ZSTD_MAGIC {
+                    self.want = Want::FrameDescriptor;
+                } else if magic & SKIPPABLE_MAGIC_MASK == SKIPPABLE_MAGIC {
+                    self.want = Want::SkippableSize;
+                } else {
+                    return Err(io::Error::other(format!(
+                        "not a zstd frame at offset {}",
+                        self.pos - 4
+                    )));
+                }
+            }
+            Want::FrameDescriptor => {
+                let fhd = h[0];
+                let single_segment = fhd & 0x20 != 0;
+                self.checksum = fhd & 0x04 != 0;
+                let window = if single_segment { 0 } else { 1 };
+                let dict_id = [0, 1, 2, 4][(fhd & 0x03) as usize];
+                let fcs = match fhd >> 6 {
+                    0 if single_segment => 1,
+                    0 => 0,
+                    1 => 2,
+                    2 => 4,
+                    _ => 8,
+                };
+                self.skip_then(window + dict_id + fcs, After::BlockHeader)?;
+            }
+            Want::BlockHeader => {
+                let raw = u32::from_le_bytes([h[0], h[1], h[2], 0]);
+                let last = raw & 1 != 0;
+                let size = raw >> 3;
+                let payload = match (raw >> 1) & 0x3 {
+                    1 => 1, // RLE block: one byte repeated `size` times
+                    3 => return Err(io::Error::other("reserved zstd block type")),
+                    _ => size as u64,
+                };
+                let after = match (last, self.checksum) {
+                    (false, _) => After::BlockHeader,
+                    (true, true) => After::Checksum,
+                    (true, false) => After::FrameEnd,
+                };
+                self.skip_then(payload, after)?;
+            }
+            Want::SkippableSize => {
+                let size = u32::from_le_bytes([h[0], h[1], h[2], h[3]]);
+                self.skip_then(size as u64, After::SkippableEnd)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn skip_then(&mut self, n: u64, after: After) -> io::Result<()> {
+        self.after = after;
+        self.skip = n;
+        if n == 0 {
+            self.finish_skip()?;
+        }
+        Ok(())
+    }
+
+    fn finish_skip(&mut self) -> io::Result<()> {
+        match self.after {
+            After::BlockHeader => self.want = Want::BlockHeader,
+            After::Checksum => return self.skip_then(4, After::FrameEnd),
+            After::FrameEnd => {
+                let size = u32::try_from(self.pos - self.frame_start)
+                    .map_err(|_| io::Error::other("zstd frame larger than 4 GiB"))?;
+                self.frames.push(size);
+                self.frame_start = self.pos;
+                self.want = Want::Magic;
+            }
+            After::SkippableEnd => {
+                self.frame_start = self.pos;
+                self.want = Want::Magic;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Encodes the seek table for frames of `frame_size` uncompressed bytes;
+/// the last frame holds whatever is left of `total` bytes.
+pub fn encode_seek_table(frames: &[u32], frame_size: u64, total: u64) -> Vec<u8> {
+    let entries_len = frames.len() * 8;
+    let mut out = Vec::with_capacity(8 + entries_len + FOOTER_LEN as usize);
+    out.extend_from_slice(&SEEK_TABLE_MAGIC.to_le_bytes());
+    out.extend_from_slice(&((entries_len as u64 + FOOTER_LEN) as u32).to_le_bytes());
+
+    let mut remaining = total;
+    for &c in frames {
+        let d = remaining.min(frame_size);
+        remaining -= d;
+        out.extend_from_slice(&c.to_le_bytes());
+        out.extend_from_slice(&(d as u32).to_le_bytes());
+    }
+
+    out.extend_from_slice(&(frames.len() as u32).to_le_bytes());
+    out.push(0); // descriptor: no per-frame checksums
+    out.extend_from_slice(&SEEKABLE_FOOTER_MAGIC.to_le_bytes());
+    out
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FrameEntry {
+    c_offset: u64,
+    c_size: u32,
+    d_offset: u64,
+    d_size: u32,
+}
+
+/// Random-access reader over a seekable zstd file.
+pub struct SeekableReader {
+    file: File,
+    frames: Vec<FrameEntry>,
+    cached: Option<(usize, Vec<u8>)>,
+}
+
+impl SeekableReader {
+    /// Returns `None` if the file carries no seek table.
+    pub fn open(file: File) -> io::Result<Option<Self>> {
+        let len = file.metadata()?.len();
+        if len < FOOTER_LEN + 8 {
+            return Ok(None);
+        }
+        let mut footer = [0u8; FOOTER_LEN as usize];
+        file.read_exact_at(&mut footer, len - FOOTER_LEN)?;
+        if u32::from_le_bytes(footer[5..9].try_into().unwrap()) != SEEKABLE_FOOTER_MAGIC {
+            return Ok(None);
+        }
+        let count = u32::from_le_bytes(footer[0..4].try_into().unwrap()) as u64;
+        let entry_len = if footer[4] & 0x80 != 0 { 12 } else { 8 };
+
+        let table_len = count * entry_len;
+        let table_start = len
+            .checked_sub(FOOTER_LEN + table_len)
+            .ok_or_else(|| io::Error::other("truncated seek table"))?;
+        let mut table = vec![0u8; table_len as usize];
+        file.read_exact_at(&mut table, table_start)?;
+
+        let mut frames = Vec::with_capacity(count as usize);
+        let (mut c_offset, mut d_offset) = (0u64, 0u64);
+        for e in table.chunks_exact(entry_len as usize) {
+            let c_size = u32::from_le_bytes(e[0..4].try_into().unwrap());
+            let d_size = u32::from_le_bytes(e[4..8].try_into().unwrap());
+            frames.push(FrameEntry {
+                c_offset,
+                c_size,
+                d_offset,
+                d_size,
+            });
+            c_offset += c_size as u64;
+            d_offset += d_size as u64;
+        }
+
+        Ok(Some(Self {
+            file,
+            frames,
+            cached: None,
+        }))
+    }
+
+    /// Total decompressed size.
+    pub fn len(&self) -> u64 {
+        self.frames
+            .last()
+            .map(|f| f.d_offset + f.d_size as u64)
+            .unwrap_or(0)
+    }
+
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            let pos = offset + filled as u64;
+            let idx = self
+                .frames
+                .partition_point(|f| f.d_offset + f.d_size as u64 <= pos);
+            if idx == self.frames.len() {
+                break;
+            }
+            let start = (pos - self.frames[idx].d_offset) as usize;
+            let frame = self.frame(idx)?;
+            let n = (frame.len() - start).min(buf.len() - filled);
+            buf[filled..filled + n].copy_from_slice(&frame[start..start + n]);
+            filled += n;
+        }
+        Ok(filled)
+    }
+
+    fn frame(&mut self, idx: usize) -> io::Result<&[u8]> {
+        if self.cached.as_ref().map(|(i, _)| *i) != Some(idx) {
+            let e = self.frames[idx];
+            let mut compressed = vec![0u8; e.c_size as usize];
+            self.file.read_exact_at(&mut compressed, e.c_offset)?;
+            let data = zstd::bulk::decompress(&compressed, e.d_size as usize)?;
+            self.cached = Some((idx, data));
+        }
+        Ok(&self.cached.as_ref().expect("cached frame").1)
+    }
+}
+
+/// Scans an interrupted seekable image; the result knows where its last complete frame ends.
+pub fn scan_partial<R: Read>(mut r: R) -> io::Result<FrameScanner> {
+    let mut scanner = FrameScanner::default();
+    let mut buf = vec![0u8; 1 << 20];
+    loop {
+        let n = r.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        if scanner.feed(&buf[..n]).is_err() {
+            break;
+        }
+    }
+    scanner.rewind_to_complete();
+    Ok(scanner)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::testutil::TempDir;
+
+    const FRAME: usize = 32 << 10;
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len as u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8 & 0x3f)
+            .collect()
+    }
+
+    /// Independent frames as `split --filter='zstd'` makes them; every other
+    /// one with a content checksum, like the zstd CLI writes by default.
+    fn frames(data: &[u8]) -> Vec<Vec<u8>> {
+        data.chunks(FRAME)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let mut enc = zstd::Encoder::new(Vec::new(), 3).unwrap();
+                enc.include_checksum(i % 2 == 0).unwrap();
+                enc.write_all(chunk).unwrap();
+                enc.finish().unwrap()
+            })
+            .collect()
+    }
+
+    fn image(dir: &TempDir, data: &[u8]) -> (std::path::PathBuf, Vec<Vec<u8>>) {
+        let frames = frames(data);
+        let sizes: Vec<u32> = frames.iter().map(|f| f.len() as u32).collect();
+        let mut bytes = frames.concat();
+        bytes.extend(encode_seek_table(&sizes, FRAME as u64, data.len() as u64));
+        let path = dir.join("a.img.zst");
+        std::fs::write(&path, bytes).unwrap();
+        (path, frames)
+    }
+
+    #[test]
+    fn scanner_finds_frames_fed_in_any_split() {
+        let frames = frames(&data(5 * FRAME + 100));
+        let want: Vec<u32> = frames.iter().map(|f| f.len() as u32).collect();
+        let stream = frames.concat();
+        for chunk in [1, 2, 3, 7, 4096, stream.len()] {
+            let mut s = FrameScanner::default();
+            for piece in stream.chunks(chunk) {
+                s.feed(piece).unwrap();
+            }
+            assert_eq!(s.frames(), want, "chunk {chunk}");
+            assert_eq!(s.complete_len(), stream.len() as u64);
+        }
+    }
+
+    #[test]
+    fn scanner_skips_the_seek_table_and_rejects_garbage() {
+        let frames = frames(&data(2 * FRAME));
+        let sizes: Vec<u32> = frames.iter().map(|f| f.len() as u32).collect();
+        let mut stream = frames.concat();
+        stream.extend(encode_seek_table(&sizes, FRAME as u64, 2 * FRAME as u64));
+        let mut s = FrameScanner::default();
+        s.feed(&stream).unwrap();
+        assert_eq!(s.frames(), sizes);
+        assert_eq!(s.complete_len(), stream.len() as u64);
+        assert!(FrameScanner::default().feed(b"not zstd").is_err());
+    }
+
+    #[test]
+    fn partial_scan_stops_at_the_last_complete_frame() {
+        let frames = frames(&data(4 * FRAME));
+        let mut cut = frames[..2].concat();
+        cut.extend_from_slice(&frames[2][..10]);
+        let s = scan_partial(&cut[..]).unwrap();
+        assert_eq!(s.frames().len(), 2);
+        assert_eq!(s.complete_len(), frames[..2].concat().len() as u64);
+    }
+
+    #[test]
+    fn seek_table_round_trips_through_the_reader() {
+        let dir = TempDir::new("seekable");
+        let want = data(6 * FRAME + 777);
+        let (path, _) = image(&dir, &want);
+
+        let mut r = SeekableReader::open(File::open(&path).unwrap())
+            .unwrap()
+            .expect("seek table");
+        assert_eq!(r.len(), want.len() as u64);
+        assert_eq!(r.frames.len(), 7);
+        assert_eq!(r.frames[6].d_size, 777);
+        // Reads across frame borders, backwards, and past the end.
+        for (off, len) in [(0, 10), (FRAME - 5, 10), (3 * FRAME + 1, 2 * FRAME), (5, 3)] {
+            let mut buf = vec![0u8; len];
+            assert_eq!(r.read_at(off as u64, &mut buf).unwrap(), len);
+            assert!(buf == want[off..off + len], "@{off}+{len}");
+        }
+        let mut buf = [0u8; 1000];
+        assert_eq!(r.read_at(want.len() as u64 - 100, &mut buf).unwrap(), 100);
+
+        // Plain zstd still decompresses it, skipping the table.
+        let plain = zstd::decode_all(File::open(&path).unwrap()).unwrap();
+        assert!(plain == want);
+    }
+
+    #[test]
+    fn truncated_trailer_is_not_taken_for_a_seek_table() {
+        let dir = TempDir::new("seekable-cut");
+        let (path, _) = image(&dir, &data(3 * FRAME));
+        let full = std::fs::read(&path).unwrap();
+
+        std::fs::write(&path, &full[..full.len() - 1]).unwrap();
+        assert!(
+            SeekableReader::open(File::open(&path).unwrap())
+                .unwrap()
+                .is_none()
+        );
+
+        // A footer claiming more entries than the file holds.
+        let mut bad = full[..20].to_vec();
+        bad.extend_from_slice(&1000u32.to_le_bytes());
+        bad.push(0);
+        bad.extend_from_slice(&SEEKABLE_FOOTER_MAGIC.to_le_bytes());
+        std::fs::write(&path, &bad).unwrap();
+        assert!(SeekableReader::open(File::open(&path).unwrap()).is_err());
+    }
+}
@@ -0,0 +1,150 @@
+//! Zero-block detection for uncompressed images: all-zero blocks become holes.
+
+use std::{
+    fs::File,
+    io::{self, Seek, SeekFrom, Write},
+};
+
+pub struct SparseWriter {
+    file: File,
+    block_size: usize,
+    enabled: bool,
+    pending: Vec<u8>,
+    skipped: u64,
+}
+
+impl SparseWriter {
+    /// With `enabled == false` every byte is written through unchanged.
+    pub fn new(file: File, block_size: usize, enabled: bool) -> Self {
+        Self {
+            file,
+            block_size,
+            enabled,
+            pending: Vec::with_capacity(if enabled { block_size } else { 0 }),
+            skipped: 0,
+        }
+    }
+
+    pub fn write(&mut self, mut data: &[u8]) -> io::Result<()> {
+        if !self.enabled {
+            return self.file.write_all(data);
+        }
+
+        if !self.pending.is_empty() {
+            let n = (self.block_size - self.pending.len()).min(data.len());
+            self.pending.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.pending.len() < self.block_size {
+                return Ok(());
+            }
+            let block = std::mem::take(&mut self.pending);
+            self.put_block(&block)?;
+            self.pending = block;
+            self.pending.clear();
+        }
+
+        let mut blocks = data.chunks_exact(self.block_size);
+        for block in &mut blocks {
+            self.put_block(block)?;
+        }
+        self.pending.extend_from_slice(blocks.remainder());
+        Ok(())
+    }
+
+    fn put_block(&mut self, block: &[u8]) -> io::Result<()> {
+        if block.iter().all(|&b| b == 0) {
+            self.file.seek(SeekFrom::Current(block.len() as i64))?;
+            self.skipped += block.len() as u64;
+            Ok(())
+        } else {
+            self.file.write_all(block)
+        }
+    }
+
+    /// Flushes the tail, extends the file over a trailing hole and returns the
+    /// file with the number of zero bytes that were never written.
+    pub fn finish(mut self) -> io::Result<(File, u64)> {
+        let tail = std::mem::take(&mut self.pending);
+        if !tail.is_empty() {
+            self.put_block(&tail)?;
+        }
+        let end = self.file.stream_position()?;
+        if self.file.metadata()?.len() < end {
+            self.file.set_len(end)?;
+        }
+        Ok((self.file, self.skipped))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::MetadataExt;
+
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+    use crate::testutil::TempDir;
+
+    const BLOCK: usize = 4096;
+
+    /// Writes `data` in `chunk`-sized pieces; checks the file reads back as
+    /// `data`, with the same hash, and returns the bytes skipped.
+    fn write_sparse(data: &[u8], chunk: usize, enabled: bool) -> (u64, std::fs::Metadata) {
+        let dir = TempDir::new("sparse");
+        let path = dir.join("image.img");
+        let mut w = SparseWriter::new(File::create(&path).unwrap(), BLOCK, enabled);
+        for piece in data.chunks(chunk) {
+            w.write(piece).unwrap();
+        }
+        let (file, skipped) = w.finish().unwrap();
+        drop(file);
+        let back = std::fs::read(&path).unwrap();
+        assert_eq!(back.len(), data.len());
+        assert!(back == data, "image differs from the stream");
+        assert_eq!(
+            hex::encode(Sha256::digest(&back)),
+            hex::encode(Sha256::digest(data))
+        );
+        (skipped, std::fs::metadata(&path).unwrap())
+    }
+
+    #[test]
+    fn trailing_hole_keeps_the_file_length() {
+        let mut data = vec![0u8; BLOCK * 64];
+        data[..10].fill(0xaa);
+        let (skipped, meta) = write_sparse(&data, 1000, true);
+        assert_eq!(skipped, (BLOCK * 63) as u64);
+        assert_eq!(meta.len(), data.len() as u64);
+        // Holes are not allocated where the filesystem supports them.
+        assert!(meta.blocks() * 512 < meta.len(), "{} blocks", meta.blocks());
+    }
+
+    #[test]
+    fn short_zero_tail_is_a_hole_too() {
+        let mut data = vec![0u8; BLOCK + 1000];
+        data[..BLOCK].fill(1);
+        let (skipped, meta) = write_sparse(&data, BLOCK, true);
+        assert_eq!(skipped, 1000);
+        assert_eq!(meta.len(), data.len() as u64);
+    }
+
+    #[test]
+    fn only_whole_zero_blocks_are_skipped() {
+        // Zeros from byte 100 to 9096 span all of block 1 and part of
+        // blocks 0 and 2, which must still be written.
+        let mut data = vec![7u8; BLOCK * 4];
+        data[100..BLOCK * 2 + 1000].fill(0);
+        for chunk in [1, 333, BLOCK - 1, BLOCK, BLOCK + 1, data.len()] {
+            let (skipped, _) = write_sparse(&data, chunk, true);
+            assert_eq!(skipped, BLOCK as u64, "chunk {chunk}");
+        }
+    }
+
+    #[test]
+    fn disabled_writes_every_byte() {
+        let data = vec![0u8; BLOCK * 4];
+        let (skipped, meta) = write_sparse(&data, BLOCK, false);
+        assert_eq!(skipped, 0);
+        assert_eq!(meta.len(), data.len() as u64);
+    }
+}
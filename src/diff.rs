@@ -0,0 +1,130 @@
+//! Compare two snapshots of the same host (`diff` command).
+
+mod archive; // tar listing --> per-file changes
+mod image; // block-by-block comparison of dd images
+
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::{
+    error::AppError,
+    metadata::{SnapshotRecord, sidecar},
+};
+
+pub use archive::TarDiff;
+pub use image::ImageDiff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SnapshotKind {
+    Tar,
+    Image,
+}
+
+impl SnapshotKind {
+    fn detect(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_string_lossy().to_ascii_lowercase();
+        if name.contains(".tar") || name.ends_with(".tgz") || name.ends_with(".txz") {
+            Some(Self::Tar)
+        } else if name.contains(".img") {
+            Some(Self::Image)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum DiffReport {
+    Tar(TarDiff),
+    Image(ImageDiff),
+}
+
+pub fn run(a: &Path, b: &Path, block_size: u64, json: bool, force: bool) -> Result<(), AppError> {
+    let kind = match (SnapshotKind::detect(a), SnapshotKind::detect(b)) {
+        (Some(x), Some(y)) if x == y => x,
+        (Some(_), Some(_)) => {
+            return Err(AppError::Validation(
+                "cannot diff a tar snapshot against a dd image".into(),
+            ));
+        }
+        _ => {
+            return Err(AppError::Validation(format!(
+                "cannot tell snapshot type of {} / {} (expected *.tar* or *.img*)",
+                a.display(),
+                b.display()
+            )));
+        }
+    };
+    if block_size == 0 {
+        return Err(AppError::Validation("block size must be > 0".into()));
+    }
+    if let Err(e) = same_series(a, b) {
+        if !force {
+            return Err(e);
+        }
+        log::warn!("{e}; comparing anyway (--force)");
+    }
+
+    let report = match kind {
+        SnapshotKind::Tar => DiffReport::Tar(archive::diff(a, b)?),
+        SnapshotKind::Image => DiffReport::Image(image::diff(a, b, block_size)?),
+    };
+
+    if json {
+        let out = serde_json::to_string_pretty(&report)
+            .map_err(|e| AppError::Validation(format!("diff json: {e}")))?;
+        println!("{out}");
+    } else {
+        match &report {
+            DiffReport::Tar(d) => print!("{d}"),
+            DiffReport::Image(d) => print!("{d}"),
+        }
+    }
+    Ok(())
+}
+
+/// Both snapshots must come from the same host and series, as their records
+/// say; otherwise every file would show up as changed.
+fn same_series(a: &Path, b: &Path) -> Result<(), AppError> {
+    let load = |p: &Path| SnapshotRecord::load(&sidecar::locate(p)?);
+    let (ra, rb) = (load(a)?, load(b)?);
+    let host = |r: &SnapshotRecord| r.host.clone().unwrap_or_else(|| "unknown host".into());
+    if ra.host != rb.host || ra.series() != rb.series() {
+        return Err(AppError::Validation(format!(
+            "{} ({}, {}) and {} ({}, {}) are not snapshots of the same series; use --force to compare them anyway",
+            a.display(),
+            host(&ra),
+            ra.series(),
+            b.display(),
+            host(&rb),
+            rb.series()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+    use crate::testutil::{TempDir, tar_snapshot};
+
+    #[test]
+    fn snapshots_of_other_hosts_or_jobs_need_force() {
+        let dir = TempDir::new("diff");
+        let at = Utc.with_ymd_and_hms(2026, 10, 18, 2, 0, 0).unwrap();
+        let snapshot = |name, host, fs| tar_snapshot(dir.path(), name, host, &[fs], at);
+        let a = snapshot("a.tar", "10.0.0.1", "/etc");
+        let b = snapshot("b.tar", "10.0.0.1", "/etc");
+        let other_host = snapshot("c.tar", "10.0.0.2", "/etc");
+        let other_job = snapshot("d.tar", "10.0.0.1", "/home");
+        assert!(same_series(&a, &b).is_ok());
+        for other in [other_host, other_job] {
+            let err = same_series(&a, &other).unwrap_err().to_string();
+            assert!(err.contains("--force"), "{err}");
+        }
+    }
+}
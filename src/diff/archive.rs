@@ -0,0 +1,324 @@
+//! File-level comparison of two tar archives, based on `tar --list --verbose`.
+
+use std::{collections::BTreeMap, fmt, io, path::Path, process::Command};
+
+use serde::Serialize;
+
+/// One member of an archive listing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    kind: char,
+    perms: String,
+    owner: String,
+    size: String,
+    mtime: String,
+    link: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AttrChange {
+    pub path: String,
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct TarDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub modified: Vec<String>,
+    pub permissions: Vec<AttrChange>,
+    pub ownership: Vec<AttrChange>,
+}
+
+pub fn diff(a: &Path, b: &Path) -> io::Result<TarDiff> {
+    let old = list(a)?;
+    let new = list(b)?;
+    let mut out = TarDiff::default();
+
+    for (path, o) in &old {
+        let Some(n) = new.get(path) else {
+            out.removed.push(path.clone());
+            continue;
+        };
+        let content_changed = o.kind != n.kind
+            || o.link != n.link
+            || (o.kind != 'd' && (o.size != n.size || o.mtime != n.mtime));
+        if content_changed {
+            out.modified.push(path.clone());
+        }
+        if o.perms != n.perms {
+            out.permissions.push(AttrChange {
+                path: path.clone(),
+                from: o.perms.clone(),
+                to: n.perms.clone(),
+            });
+        }
+        if o.owner != n.owner {
+            out.ownership.push(AttrChange {
+                path: path.clone(),
+                from: o.owner.clone(),
+                to: n.owner.clone(),
+            });
+        }
+    }
+    out.added = new
+        .keys()
+        .filter(|p| !old.contains_key(*p))
+        .cloned()
+        .collect();
+
+    Ok(out)
+}
+
+/// Runs the local `tar` (which detects the compression itself) and parses its listing.
+fn list(path: &Path) -> io::Result<BTreeMap<String, Entry>> {
+    let out = Command::new("tar")
+        .args([
+            "--list",
+            "--verbose",
+            "--numeric-owner",
+            "--full-time",
+            // Names in double quotes with C escapes: ` -> ` inside a name
+            // cannot be taken for the link arrow, and newlines stay on one line.
+            "--quoting-style=c",
+            "-f",
+        ])
+        .arg(path)
+        .output()?;
+    if !out.status.success() {
+        return Err(io::Error::other(format!(
+            "tar --list {} failed: {}",
+            path.display(),
+            String::from_utf8_lossy(&out.stderr).trim()
+        )));
+    }
+
+    let text = String::from_utf8_lossy(&out.stdout);
+    Ok(text.lines().filter_map(parse_line).collect())
+}
+
+/// Parses `-rw-r--r-- 0/0  1234 2024-05-01 10:11:12 "etc/hostname"`, with
+/// ` -> "target"` after symlinks and ` link to "target"` after hard links.
+fn parse_line(line: &str) -> Option<(String, Entry)> {
+    let (fields, rest) = split_fields(line, 5)?;
+    let mode = fields[0];
+    let kind = mode.chars().next()?;
+
+    let (name, rest) = unquote(rest)?;
+    let link = match (kind, rest) {
+        ('l', r) => r.strip_prefix(" -> "),
+        ('h', r) => r.strip_prefix(" link to "),
+        _ => None,
+    }
+    .and_then(unquote)
+    .map(|(target, _)| target);
+    let name = name.trim_start_matches("./").trim_end_matches('/');
+    if name.is_empty() {
+        return None;
+    }
+
+    Some((
+        name.to_string(),
+        Entry {
+            kind,
+            perms: mode[1..].to_string(),
+            owner: fields[1].to_string(),
+            size: fields[2].to_string(),
+            mtime: format!("{} {}", fields[3], fields[4]),
+            link,
+        },
+    ))
+}
+
+/// A leading C-quoted string, unescaped, and what follows it.
+fn unquote(s: &str) -> Option<(String, &str)> {
+    let body = s.strip_prefix('"')?;
+    let mut out = Vec::new();
+    let mut bytes = body.bytes().enumerate();
+    while let Some((i, b)) = bytes.next() {
+        match b {
+            b'"' => return Some((String::from_utf8_lossy(&out).into_owned(), &body[i + 1..])),
+            b'\\' => {
+                let (_, e) = bytes.next()?;
+                match e {
+                    b'0'..=b'7' => {
+                        // Always three octal digits.
+                        let mut v = u32::from(e - b'0');
+                        for _ in 0..2 {
+                            let (_, d) = bytes.next()?;
+                            v = v * 8 + u32::from(d.checked_sub(b'0').filter(|d| *d < 8)?);
+                        }
+                        out.push(u8::try_from(v).ok()?);
+                    }
+                    b'a' => out.push(0x07),
+                    b'b' => out.push(0x08),
+                    b'f' => out.push(0x0c),
+                    b'n' => out.push(b'\n'),
+                    b'r' => out.push(b'\r'),
+                    b't' => out.push(b'\t'),
+                    b'v' => out.push(0x0b),
+                    // `\\`, `\"`, `\?` and the like stand for themselves.
+                    other => out.push(other),
+                }
+            }
+            other => out.push(other),
+        }
+    }
+    None
+}
+
+/// Splits off `n` whitespace-separated fields and returns them with the untouched remainder.
+fn split_fields(line: &str, n: usize) -> Option<(Vec<&str>, &str)> {
+    let mut fields = Vec::with_capacity(n);
+    let mut rest = line;
+    for _ in 0..n {
+        rest = rest.trim_start();
+        let end = rest.find(char::is_whitespace)?;
+        fields.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+    let rest = rest.strip_prefix(' ').unwrap_or(rest);
+    Some((fields, rest))
+}
+
+impl fmt::Display for TarDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for p in &self.added {
+            writeln!(f, "+ {p}")?;
+        }
+        for p in &self.removed {
+            writeln!(f, "- {p}")?;
+        }
+        for p in &self.modified {
+            writeln!(f, "M {p}")?;
+        }
+        for c in &self.permissions {
+            writeln!(f, "P {} {} -> {}", c.path, c.from, c.to)?;
+        }
+        for c in &self.ownership {
+            writeln!(f, "O {} {} -> {}", c.path, c.from, c.to)?;
+        }
+        writeln!(
+            f,
+            "{} added, {} removed, {} modified, {} permission and {} ownership changes",
+            self.added.len(),
+            self.removed.len(),
+            self.modified.len(),
+            self.permissions.len(),
+            self.ownership.len()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn parse(line: &str) -> (String, Entry) {
+        parse_line(line).unwrap_or_else(|| panic!("{line:?} not parsed"))
+    }
+
+    #[test]
+    fn plain_member() {
+        let (name, e) = parse(r#"-rw-r--r-- 0/0  1234 2024-05-01 10:11:12 "./etc/hostname""#);
+        assert_eq!(name, "etc/hostname");
+        assert_eq!(e.kind, '-');
+        assert_eq!(e.perms, "rw-r--r--");
+        assert_eq!((e.owner.as_str(), e.size.as_str()), ("0/0", "1234"));
+        assert_eq!(e.mtime, "2024-05-01 10:11:12");
+        assert_eq!(e.link, None);
+
+        let (name, e) = parse(r#"drwxr-xr-x 1000/1000 0 2024-05-01 10:11:12 "home/alice/""#);
+        assert_eq!((name.as_str(), e.kind), ("home/alice", 'd'));
+    }
+
+    #[test]
+    fn arrows_inside_names_are_not_links() {
+        let (name, e) = parse(r#"-rw-r--r-- 0/0 0 2024-05-01 10:11:12 "d/a -> b""#);
+        assert_eq!((name.as_str(), e.link), ("d/a -> b", None));
+
+        let (name, e) = parse(r#"lrwxrwxrwx 0/0 0 2024-05-01 10:11:12 "d/ln -> k" -> "a -> b""#);
+        assert_eq!(name, "d/ln -> k");
+        assert_eq!(e.link.as_deref(), Some("a -> b"));
+
+        let (name, e) = parse(
+            r#"hrw-r--r-- 0/0 0 2024-05-01 10:11:12 "d/hard link to x" link to "d/\303\274""#,
+        );
+        assert_eq!(name, "d/hard link to x");
+        assert_eq!(e.link.as_deref(), Some("d/ü"));
+    }
+
+    #[test]
+    fn escapes_are_undone() {
+        let cases = [
+            (r#""d/nl\nx""#, "d/nl\nx"),
+            (r#""d/tab\tx""#, "d/tab\tx"),
+            (r#""d/back\\slash""#, "d/back\\slash"),
+            (r#""d/say \"hi\"""#, "d/say \"hi\""),
+            (r#""d/\303\274ber""#, "d/über"),
+            (r#""d/bell\a\001""#, "d/bell\x07\x01"),
+        ];
+        for (quoted, name) in cases {
+            let line = format!("-rw-r--r-- 0/0 0 2024-05-01 10:11:12 {quoted}");
+            assert_eq!(parse(&line).0, name, "{quoted}");
+        }
+    }
+
+    #[test]
+    fn malformed_lines_are_skipped() {
+        for line in [
+            "",
+            "tar: Removing leading `/' from member names",
+            r#"-rw-r--r-- 0/0 0 2024-05-01 10:11:12 "unterminated"#,
+            r#"-rw-r--r-- 0/0 0 2024-05-01 10:11:12 "short\30""#,
+            r#"drwxr-xr-x 0/0 0 2024-05-01 10:11:12 "./""#,
+        ] {
+            assert!(parse_line(line).is_none(), "{line:?}");
+        }
+    }
+
+    #[test]
+    fn diff_of_real_archives_with_awkward_names() {
+        let dir = TempDir::new("diff-archive");
+        let tree = dir.join("d");
+        fs::create_dir(&tree).unwrap();
+        let keep = ["a -> b", "line\nbreak", "über", "back\\slash"];
+        for name in keep {
+            fs::write(tree.join(name), "same").unwrap();
+        }
+        fs::write(tree.join("gone"), "x").unwrap();
+        std::os::unix::fs::symlink("a -> b", tree.join("ln -> k")).unwrap();
+        let tar = |name: &str| {
+            let archive = dir.join(name);
+            let ok = Command::new("tar")
+                .args(["--mtime=2024-05-01 00:00:00", "-cf"])
+                .arg(&archive)
+                .arg("-C")
+                .arg(dir.path())
+                .arg("d")
+                .status()
+                .unwrap()
+                .success();
+            assert!(ok);
+            archive
+        };
+        let a = tar("a.tar");
+
+        fs::remove_file(tree.join("gone")).unwrap();
+        fs::write(tree.join("new -> file"), "y").unwrap();
+        fs::write(tree.join("über"), "changed").unwrap();
+        fs::remove_file(tree.join("ln -> k")).unwrap();
+        std::os::unix::fs::symlink("line\nbreak", tree.join("ln -> k")).unwrap();
+        let b = tar("b.tar");
+
+        let d = diff(&a, &b).unwrap();
+        assert_eq!(d.added, ["d/new -> file"]);
+        assert_eq!(d.removed, ["d/gone"]);
+        assert_eq!(d.modified, ["d/ln -> k", "d/über"]);
+        assert!(d.permissions.is_empty() && d.ownership.is_empty());
+    }
+}
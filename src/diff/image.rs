@@ -0,0 +1,100 @@
+//! Block-level comparison of two dd images.
+
+use std::{fmt, io, path::Path};
+
+use serde::Serialize;
+
+use crate::codec::{open_decompressed, read_full};
+
+/// Half-open range of differing blocks, `[start_block, end_block)`.
+#[derive(Debug, Serialize)]
+pub struct BlockRange {
+    pub start_block: u64,
+    pub end_block: u64,
+    pub offset: u64,
+    pub length: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImageDiff {
+    pub block_size: u64,
+    pub size_a: u64,
+    pub size_b: u64,
+    pub differing_blocks: u64,
+    pub ranges: Vec<BlockRange>,
+}
+
+pub fn diff(a: &Path, b: &Path, block_size: u64) -> io::Result<ImageDiff> {
+    let mut ra = open_decompressed(a)?;
+    let mut rb = open_decompressed(b)?;
+    let bs = block_size as usize;
+    let mut buf_a = vec![0u8; bs];
+    let mut buf_b = vec![0u8; bs];
+
+    let mut out = ImageDiff {
+        block_size,
+        size_a: 0,
+        size_b: 0,
+        differing_blocks: 0,
+        ranges: Vec::new(),
+    };
+    let mut block = 0u64;
+    let mut open_range: Option<u64> = None;
+
+    loop {
+        let na = read_full(&mut *ra, &mut buf_a)?;
+        let nb = read_full(&mut *rb, &mut buf_b)?;
+        if na == 0 && nb == 0 {
+            break;
+        }
+        out.size_a += na as u64;
+        out.size_b += nb as u64;
+
+        if na != nb || buf_a[..na] != buf_b[..nb] {
+            out.differing_blocks += 1;
+            open_range.get_or_insert(block);
+        } else if let Some(start) = open_range.take() {
+            out.push_range(start, block);
+        }
+        block += 1;
+    }
+    if let Some(start) = open_range {
+        out.push_range(start, block);
+    }
+
+    Ok(out)
+}
+
+impl ImageDiff {
+    fn push_range(&mut self, start: u64, end: u64) {
+        self.ranges.push(BlockRange {
+            start_block: start,
+            end_block: end,
+            offset: start * self.block_size,
+            length: (end - start) * self.block_size,
+        });
+    }
+}
+
+impl fmt::Display for ImageDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.size_a != self.size_b {
+            writeln!(f, "size differs: {} -> {} bytes", self.size_a, self.size_b)?;
+        }
+        for r in &self.ranges {
+            writeln!(
+                f,
+                "blocks {}..{} (offset {}, {} bytes)",
+                r.start_block, r.end_block, r.offset, r.length
+            )?;
+        }
+        writeln!(
+            f,
+            "{} of {} blocks differ ({} ranges, block size {})",
+            self.differing_blocks,
+            self.size_a.max(self.size_b).div_ceil(self.block_size),
+            self.ranges.len(),
+            self.block_size
+        )
+    }
+}
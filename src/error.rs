@@ -16,6 +16,46 @@ pub enum AppError {
 
     #[error("validation error: {0}")]
     Validation(String),
+
+    #[error("interrupted")]
+    Interrupted,
+
+    #[error("hook failed: {0}")]
+    Hook(String),
+
+    #[error("storage error: {0}")]
+    Storage(String),
+
+    #[error("locked: {0}")]
+    Locked(String),
+
+    #[error("preflight: {0}")]
+    Preflight(#[from] PreflightError),
+}
+
+/// A check run before the snapshot failed; each says what to fix.
+#[derive(Debug, Error)]
+pub enum PreflightError {
+    #[error(
+        "`{tool}` is not installed on the remote ({needed_for}); install it or change the config"
+    )]
+    MissingTool { tool: String, needed_for: String },
+    #[error(
+        "sudo on the remote asks {user} for a password; allow it NOPASSWD for the backup commands"
+    )]
+    SudoPassword { user: String },
+    #[error("remote directory {0} does not exist; create it or change backup.dir")]
+    MissingDir(String),
+    #[error("{0}; check dd.device and that lsblk and blockdev run on the remote")]
+    Device(String),
+    #[error(
+        "not enough space in {place}: about {need} needed, {avail} available; free some or point it elsewhere"
+    )]
+    NoSpace {
+        place: String,
+        need: String,
+        avail: String,
+    },
 }
 
 #[derive(Debug, Error)]
@@ -0,0 +1,449 @@
+//! User commands run around a backup, on the remote host or locally.
+//!
+//! Every hook sees `DATA_BACKUP_*` variables describing the run (phase, mode,
+//! host, and the snapshot paths and hash once known). What each hook printed
+//! is kept and ends up in the snapshot metadata.
+
+use std::{
+    collections::BTreeMap,
+    io::Read,
+    os::unix::process::CommandExt,
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{Config, HookConfig, Remote},
+    error::AppError,
+    shell::quote,
+    signal::kill_group,
+    ssh::Ssh,
+};
+
+/// Output kept per hook; anything beyond is dropped from the front.
+const MAX_OUTPUT: usize = 64 * 1024;
+/// Exit status of coreutils `timeout` when the limit was hit.
+const TIMEOUT_EXIT: i32 = 124;
+/// Printed after a remote hook returns, so its own exit 124 is not taken
+/// for a timeout.
+const DONE_MARK: &str = "\n__data_backup_hook_done__\n";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Phase {
+    PreConnect,
+    PreSnapshot,
+    PostSnapshot,
+    PostDownload,
+    OnFailure,
+}
+
+impl Phase {
+    pub fn parse(txt: &str) -> Result<Self, AppError> {
+        match txt.to_ascii_lowercase().as_str() {
+            "pre-connect" => Ok(Self::PreConnect),
+            "pre-snapshot" => Ok(Self::PreSnapshot),
+            "post-snapshot" => Ok(Self::PostSnapshot),
+            "post-download" => Ok(Self::PostDownload),
+            "on-failure" => Ok(Self::OnFailure),
+            other => Err(AppError::Validation(format!(
+                "unknown hook phase `{other}` (expected pre-connect, pre-snapshot, \
+                 post-snapshot, post-download or on-failure)"
+            ))),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::PreConnect => "pre-connect",
+            Self::PreSnapshot => "pre-snapshot",
+            Self::PostSnapshot => "post-snapshot",
+            Self::PostDownload => "post-download",
+            Self::OnFailure => "on-failure",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Location {
+    Remote,
+    Local,
+}
+
+/// What a hook did, as recorded in the metadata.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HookRecord {
+    pub phase: Phase,
+    pub run_on: Location,
+    pub command: String,
+    /// `None` when the hook could not be started or was killed.
+    pub exit_code: Option<i32>,
+    pub timed_out: bool,
+    /// stdout and stderr, interleaved.
+    pub output: String,
+    pub started_at: DateTime<Utc>,
+}
+
+impl HookRecord {
+    fn succeeded(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+}
+
+struct Hook {
+    phase: Phase,
+    run_on: Location,
+    command: String,
+    timeout: Duration,
+    abort_on_failure: bool,
+    env: BTreeMap<String, String>,
+}
+
+/// The configured hooks plus the records of those that already ran.
+pub struct Hooks<'a> {
+    remote: &'a Remote,
+    hooks: Vec<Hook>,
+    env: BTreeMap<String, String>,
+    records: Vec<HookRecord>,
+}
+
+impl<'a> Hooks<'a> {
+    pub fn new(cfg: &'a Config) -> Result<Self, AppError> {
+        let hooks = cfg
+            .hooks
+            .iter()
+            .map(Hook::from_config)
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut env = BTreeMap::new();
+        env.insert("DATA_BACKUP_MODE".into(), cfg.mode.clone());
+        env.insert("DATA_BACKUP_HOST".into(), cfg.remote.host.to_string());
+        Ok(Self {
+            remote: &cfg.remote,
+            hooks,
+            env,
+            records: Vec::new(),
+        })
+    }
+
+    /// Sets `DATA_BACKUP_<key>` for every hook run from now on.
+    pub fn set(&mut self, key: &str, value: impl Into<String>) {
+        self.env.insert(format!("DATA_BACKUP_{key}"), value.into());
+    }
+
+    pub fn records(&self) -> &[HookRecord] {
+        &self.records
+    }
+
+    /// Runs the hooks of `phase` in config order. Remote hooks use `ssh`, or a
+    /// fresh connection when there is none yet (pre-connect, on-failure).
+    pub fn run(&mut self, phase: Phase, ssh: Option<&Ssh>) -> Result<(), AppError> {
+        let Self {
+            remote,
+            hooks,
+            env: base_env,
+            records,
+        } = self;
+        let mut own = None;
+        for hook in hooks.iter().filter(|h| h.phase == phase) {
+            let mut env = base_env.clone();
+            env.insert("DATA_BACKUP_PHASE".into(), phase.name().into());
+            env.extend(hook.env.clone());
+
+            log::info!(
+                "Running {} hook ({:?}): {}",
+                phase.name(),
+                hook.run_on,
+                hook.command
+            );
+            let record = match hook.run_on {
+                Location::Local => hook.run_local(&env),
+                Location::Remote => {
+                    let ssh = match ssh {
+                        Some(ssh) => ssh,
+                        None => match &own {
+                            Some(ssh) => ssh,
+                            None => own.insert(Ssh::connect_remote(remote)?),
+                        },
+                    };
+                    hook.run_remote(ssh, &env)
+                }
+            };
+            let ok = record.succeeded();
+            let status = describe(&record);
+            records.push(record);
+
+            if ok {
+                continue;
+            }
+            if hook.abort_on_failure && phase != Phase::OnFailure {
+                return Err(AppError::Hook(format!("`{}` {status}", hook.command)));
+            }
+            log::warn!("{} hook `{}` {status}", phase.name(), hook.command);
+        }
+        Ok(())
+    }
+
+    /// Runs the on-failure hooks for `err`; their own failures are only logged.
+    pub fn run_on_failure(&mut self, err: &AppError) {
+        self.set("ERROR", err.to_string());
+        if let Err(e) = self.run(Phase::OnFailure, None) {
+            log::error!("on-failure hooks: {e}");
+        }
+    }
+}
+
+impl Hook {
+    fn from_config(h: &HookConfig) -> Result<Self, AppError> {
+        let run_on = match h.run_on.to_ascii_lowercase().as_str() {
+            "remote" => Location::Remote,
+            "local" => Location::Local,
+            other => {
+                return Err(AppError::Validation(format!(
+                    "unknown hook location `{other}` (expected remote or local)"
+                )));
+            }
+        };
+        if let Some(key) = h.env.keys().find(|k| !is_name(k)) {
+            return Err(AppError::Validation(format!(
+                "hook env key `{key}` is not a valid variable name"
+            )));
+        }
+        Ok(Self {
+            phase: Phase::parse(&h.phase)?,
+            run_on,
+            command: h.command.clone(),
+            timeout: Duration::from_secs(h.timeout),
+            abort_on_failure: h.abort_on_failure,
+            env: h.env.clone(),
+        })
+    }
+
+    fn record(&self) -> HookRecord {
+        HookRecord {
+            phase: self.phase,
+            run_on: self.run_on,
+            command: self.command.clone(),
+            exit_code: None,
+            timed_out: false,
+            output: String::new(),
+            started_at: Utc::now(),
+        }
+    }
+
+    /// `timeout` on the remote bounds the command; output is merged into stdout.
+    fn run_remote(&self, ssh: &Ssh, env: &BTreeMap<String, String>) -> HookRecord {
+        let mut rec = self.record();
+        let mut out = Vec::new();
+        let res = ssh.exec_capture(&self.remote_script(env), &mut out);
+        finish_remote(&mut rec, res, out);
+        rec
+    }
+
+    /// The hook runs in a subshell, so even its `exit` is followed by [`DONE_MARK`].
+    fn remote_script(&self, env: &BTreeMap<String, String>) -> String {
+        let exports: String = env
+            .iter()
+            .map(|(k, v)| format!("export {k}={}; ", quote(v)))
+            .collect();
+        let mark = quote(DONE_MARK.trim());
+        format!(
+            "{exports}timeout {} sh -c {} 2>&1",
+            self.timeout.as_secs().max(1),
+            quote(&format!(
+                "(exec 2>&1; {}\n); rc=$?; printf '\\n%s\\n' {mark}; exit $rc",
+                self.command
+            ))
+        )
+    }
+
+    fn run_local(&self, env: &BTreeMap<String, String>) -> HookRecord {
+        let mut rec = self.record();
+        // Its own process group, so a timeout also kills what the hook started.
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(format!("exec 2>&1; {}", self.command))
+            .envs(env)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .process_group(0)
+            .spawn();
+        let mut child = match child {
+            Ok(c) => c,
+            Err(e) => {
+                rec.output = e.to_string();
+                return rec;
+            }
+        };
+
+        // Drain on a thread so a chatty hook cannot block on a full pipe.
+        let mut stdout = child.stdout.take().expect("piped stdout");
+        let reader = thread::spawn(move || {
+            let mut out = Vec::new();
+            let _ = stdout.read_to_end(&mut out);
+            out
+        });
+
+        // Done once the shell has exited and nothing it started holds the pipe.
+        let deadline = Instant::now() + self.timeout;
+        let status = loop {
+            match child.try_wait() {
+                Ok(Some(status)) if reader.is_finished() => break Some(status),
+                Ok(status) if Instant::now() >= deadline => {
+                    kill_group(&child, libc::SIGKILL);
+                    let _ = child.wait();
+                    rec.timed_out = status.is_none();
+                    break status;
+                }
+                Ok(_) => thread::sleep(Duration::from_millis(100)),
+                Err(e) => {
+                    log::error!("waiting for hook: {e}");
+                    break None;
+                }
+            }
+        };
+        rec.exit_code = status.and_then(|s| s.code());
+        rec.output = tail(&reader.join().unwrap_or_default());
+        rec
+    }
+}
+
+/// Fills in `rec` from a remote run; a 124 without [`DONE_MARK`] is a timeout.
+fn finish_remote(rec: &mut HookRecord, res: Result<(), AppError>, mut out: Vec<u8>) {
+    let done = out.ends_with(DONE_MARK.as_bytes());
+    if done {
+        out.truncate(out.len() - DONE_MARK.len());
+    }
+    match res {
+        Ok(()) => rec.exit_code = Some(0),
+        Err(AppError::RemoteExit(TIMEOUT_EXIT)) if !done => rec.timed_out = true,
+        Err(AppError::RemoteExit(code)) => rec.exit_code = Some(code),
+        Err(e) => out.extend_from_slice(e.to_string().as_bytes()),
+    }
+    rec.output = tail(&out);
+}
+
+/// A portable shell variable name.
+fn is_name(key: &str) -> bool {
+    let mut chars = key.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn describe(rec: &HookRecord) -> String {
+    match (rec.timed_out, rec.exit_code) {
+        (true, _) => "timed out".into(),
+        (false, Some(code)) => format!("exited with {code}"),
+        (false, None) => format!("did not run: {}", rec.output.trim()),
+    }
+}
+
+/// Last `MAX_OUTPUT` bytes, lossily decoded.
+fn tail(out: &[u8]) -> String {
+    let start = out.len().saturating_sub(MAX_OUTPUT);
+    String::from_utf8_lossy(&out[start..]).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hook(command: &str, timeout: u64) -> Hook {
+        Hook::from_config(&HookConfig {
+            phase: "pre-snapshot".into(),
+            command: command.into(),
+            run_on: "local".into(),
+            timeout,
+            abort_on_failure: true,
+            env: BTreeMap::new(),
+        })
+        .unwrap()
+    }
+
+    /// [`Hook::remote_script`] run through the local `sh`, as sshd would.
+    fn run_script(hook: &Hook) -> HookRecord {
+        let env = BTreeMap::from([("DATA_BACKUP_PHASE".to_string(), "it's".to_string())]);
+        let out = Command::new("sh")
+            .arg("-c")
+            .arg(hook.remote_script(&env))
+            .output()
+            .unwrap();
+        let res = match out.status.code() {
+            Some(0) => Ok(()),
+            Some(code) => Err(AppError::RemoteExit(code)),
+            None => Err(AppError::Remote("killed".into())),
+        };
+        let mut rec = hook.record();
+        finish_remote(&mut rec, res, out.stdout);
+        rec
+    }
+
+    #[test]
+    fn remote_exit_124_is_not_a_timeout() {
+        let rec = run_script(&hook("echo \"$DATA_BACKUP_PHASE\"; exit 124", 10));
+        assert!(!rec.timed_out);
+        assert_eq!(rec.exit_code, Some(124));
+        assert_eq!(rec.output, "it's\n");
+    }
+
+    #[test]
+    fn remote_timeout_and_plain_exits() {
+        let rec = run_script(&hook("echo start; sleep 10", 1));
+        assert!(rec.timed_out);
+        assert_eq!(rec.exit_code, None);
+        assert_eq!(rec.output, "start\n");
+
+        let rec = run_script(&hook("echo out; echo err >&2; exit 3 # done", 10));
+        assert_eq!((rec.timed_out, rec.exit_code), (false, Some(3)));
+        assert_eq!(rec.output, "out\nerr\n");
+
+        let rec = run_script(&hook("printf partial", 10));
+        assert_eq!(rec.exit_code, Some(0));
+        assert_eq!(rec.output, "partial");
+    }
+
+    #[test]
+    fn local_timeout_kills_grandchildren() {
+        let started = Instant::now();
+        let rec = hook("echo start; sleep 30; echo never", 1).run_local(&BTreeMap::new());
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert!(rec.timed_out);
+        assert_eq!(rec.exit_code, None);
+        assert_eq!(rec.output, "start\n");
+    }
+
+    #[test]
+    fn local_background_child_cannot_hold_the_run() {
+        let started = Instant::now();
+        let rec = hook("(sleep 30 &); echo done", 1).run_local(&BTreeMap::new());
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert!(!rec.timed_out);
+        assert_eq!(rec.exit_code, Some(0));
+        assert_eq!(rec.output, "done\n");
+    }
+
+    #[test]
+    fn env_keys_must_be_variable_names() {
+        for good in ["A", "_x", "DB_HOST2"] {
+            assert!(is_name(good), "{good}");
+        }
+        for bad in ["", "2X", "A-B", "A B", "X;rm -rf /", "$(id)", "Ä"] {
+            assert!(!is_name(bad), "{bad}");
+        }
+        let cfg = HookConfig {
+            phase: "pre-snapshot".into(),
+            command: "true".into(),
+            run_on: "remote".into(),
+            timeout: 10,
+            abort_on_failure: true,
+            env: BTreeMap::from([("X;id".to_string(), "1".to_string())]),
+        };
+        assert!(Hook::from_config(&cfg).is_err());
+    }
+}
@@ -0,0 +1,261 @@
+//! Facts about the remote host, saved as `<snapshot>.host.json` so a
+//! replacement machine can be set up like the one a snapshot came from:
+//! OS, kernel, disk layout, fstab, mounts, network and installed packages.
+//!
+//! Collection is best effort. A fact the remote cannot provide is listed
+//! under `missing` and never fails the backup.
+
+use std::{collections::BTreeMap, path::Path};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{error::AppError, metadata::sidecar, partial, preflight::SBIN, shell::quote, ssh::Ssh};
+
+const LSBLK_COLUMNS: &str =
+    "NAME,PATH,TYPE,SIZE,PTTYPE,PTUUID,PARTTYPE,PARTUUID,FSTYPE,LABEL,UUID,MOUNTPOINT,MODEL,SERIAL";
+/// Columns every `lsblk` with `--json` knows, for older util-linux.
+const LSBLK_BASIC: &str = "NAME,TYPE,SIZE,FSTYPE,LABEL,UUID,MOUNTPOINT";
+
+/// Interface configuration of ifupdown, netplan, network-scripts and
+/// systemd-networkd. NetworkManager profiles are left out: they hold secrets.
+const NETWORK_FILES: &str = "/etc/network/interfaces /etc/network/interfaces.d/* \
+    /etc/netplan/*.yaml /etc/sysconfig/network-scripts/ifcfg-* \
+    /etc/systemd/network/*.network /etc/systemd/network/*.netdev";
+const FILE_MARK: &str = "==> ";
+
+/// One `<name>\t<version>` line per package; the first line names the
+/// manager. dpkg also knows removed packages whose config files remain.
+const PACKAGES: &str = r#"if command -v dpkg-query >/dev/null 2>&1; then
+    echo dpkg
+    dpkg-query -W -f '${db:Status-Abbrev}\t${binary:Package}\t${Version}\n' |
+        awk -F '\t' '$1 ~ /^.i/ { print $2 "\t" $3 }'
+elif command -v rpm >/dev/null 2>&1; then
+    echo rpm; rpm -qa --qf '%{NAME}.%{ARCH}\t%{VERSION}-%{RELEASE}\n'
+elif command -v apk >/dev/null 2>&1; then
+    echo apk; apk info -v
+else
+    echo none
+fi"#;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Inventory {
+    pub collected_at: DateTime<Utc>,
+    pub hostname: Option<String>,
+    /// `/etc/os-release` as key/value pairs.
+    pub os_release: BTreeMap<String, String>,
+    /// `uname -srvm`.
+    pub kernel: Option<String>,
+    /// `lsblk --json` tree: partition tables, filesystems, UUIDs, sizes in bytes.
+    pub block_devices: Option<Value>,
+    pub fstab: Option<String>,
+    /// `/proc/self/mounts`.
+    pub mounts: Option<String>,
+    pub network: Network,
+    pub packages: Option<Packages>,
+    /// Facts that could not be collected, and why.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub missing: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Network {
+    /// `ip -json addr`; plain text from an `ip` without JSON output.
+    pub addresses: Option<Value>,
+    /// `ip -json route`, likewise.
+    pub routes: Option<Value>,
+    pub resolv_conf: Option<String>,
+    pub hosts: Option<String>,
+    /// Interface configuration files by path.
+    #[serde(default)]
+    pub config_files: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Packages {
+    /// `dpkg`, `rpm` or `apk`.
+    pub manager: String,
+    pub installed: Vec<Package>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Package {
+    pub name: String,
+    pub version: String,
+}
+
+impl Inventory {
+    pub fn collect(ssh: &Ssh) -> Self {
+        log::info!("Collecting host inventory");
+        let mut c = Collector {
+            ssh,
+            missing: BTreeMap::new(),
+        };
+        let hostname = c
+            .run("hostname", "hostname -f 2>/dev/null || hostname")
+            .map(|s| s.trim().to_string());
+        let os_release = c
+            .run(
+                "os_release",
+                "cat /etc/os-release 2>/dev/null || cat /usr/lib/os-release",
+            )
+            .map(|s| parse_os_release(&s))
+            .unwrap_or_default();
+        let kernel = c.run("kernel", "uname -srvm").map(|s| s.trim().to_string());
+        let block_devices = c
+            .run(
+                "block_devices",
+                &format!(
+                    "lsblk --json --bytes -o {LSBLK_COLUMNS} 2>/dev/null || lsblk --json --bytes -o {LSBLK_BASIC}"
+                ),
+            )
+            .map(|s| json_or_text(&s));
+        let fstab = c.run("fstab", "cat /etc/fstab");
+        let mounts = c.run("mounts", "cat /proc/self/mounts");
+        let network = Network {
+            addresses: c
+                .run("network.addresses", "ip -json addr 2>/dev/null || ip addr")
+                .map(|s| json_or_text(&s)),
+            routes: c
+                .run("network.routes", "ip -json route 2>/dev/null || ip route")
+                .map(|s| json_or_text(&s)),
+            resolv_conf: c.run("network.resolv_conf", "cat /etc/resolv.conf"),
+            hosts: c.run("network.hosts", "cat /etc/hosts"),
+            config_files: c
+                .run(
+                    "network.config_files",
+                    // netplan files are often readable by root only.
+                    &format!(
+                        "for f in {NETWORK_FILES}; do [ -f \"$f\" ] || continue; \
+                         printf '{FILE_MARK}%s\\n' \"$f\"; \
+                         sudo -n cat \"$f\" 2>/dev/null || cat \"$f\"; done; true"
+                    ),
+                )
+                .map(|s| split_files(&s))
+                .unwrap_or_default(),
+        };
+        let packages = c.run("packages", PACKAGES).and_then(|s| parse_packages(&s));
+        if packages.is_none() && !c.missing.contains_key("packages") {
+            c.missing
+                .insert("packages".into(), "no dpkg, rpm or apk found".into());
+        }
+
+        if !c.missing.is_empty() {
+            let names: Vec<&str> = c.missing.keys().map(String::as_str).collect();
+            log::warn!("Host inventory incomplete: no {}", names.join(", "));
+        }
+        Self {
+            collected_at: Utc::now(),
+            hostname,
+            os_release,
+            kernel,
+            block_devices,
+            fstab,
+            mounts,
+            network,
+            packages,
+            missing: c.missing,
+        }
+    }
+
+    /// Writes `<snapshot>.host.json`.
+    pub fn write(&self, snapshot: &Path) -> Result<(), AppError> {
+        let path = sidecar::inventory_path(snapshot);
+        let json = serde_json::to_string_pretty(self).map_err(std::io::Error::other)?;
+        partial::write(&path, json.as_bytes())?;
+        log::info!("Host inventory saved to {}", path.display());
+        Ok(())
+    }
+}
+
+struct Collector<'a> {
+    ssh: &'a Ssh,
+    missing: BTreeMap<String, String>,
+}
+
+impl Collector<'_> {
+    /// Stdout of `script`, or `None` with the reason noted under `fact`.
+    fn run(&mut self, fact: &str, script: &str) -> Option<String> {
+        let script = format!("PATH=\"$PATH:{SBIN}\"; {script}");
+        let mut out = Vec::new();
+        match self
+            .ssh
+            .exec_capture(&format!("sh -c {} 2>/dev/null", quote(&script)), &mut out)
+        {
+            Ok(()) => Some(String::from_utf8_lossy(&out).into_owned()),
+            Err(e) => {
+                log::debug!("inventory {fact}: {e}");
+                self.missing.insert(fact.into(), e.to_string());
+                None
+            }
+        }
+    }
+}
+
+fn parse_os_release(text: &str) -> BTreeMap<String, String> {
+    text.lines()
+        .filter(|l| !l.trim_start().starts_with('#'))
+        .filter_map(|l| l.split_once('='))
+        .map(|(k, v)| {
+            let v = v.trim();
+            let v = v
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .or_else(|| v.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')))
+                .unwrap_or(v);
+            (k.trim().to_string(), v.to_string())
+        })
+        .collect()
+}
+
+fn json_or_text(text: &str) -> Value {
+    serde_json::from_str(text).unwrap_or_else(|_| Value::String(text.to_string()))
+}
+
+/// Splits `==> path` separated output back into files.
+fn split_files(text: &str) -> BTreeMap<String, String> {
+    let mut files = BTreeMap::new();
+    let mut current: Option<(String, String)> = None;
+    for line in text.lines() {
+        if let Some(path) = line.strip_prefix(FILE_MARK) {
+            files.extend(current.take());
+            current = Some((path.to_string(), String::new()));
+        } else if let Some((_, body)) = &mut current {
+            body.push_str(line);
+            body.push('\n');
+        }
+    }
+    files.extend(current);
+    files
+}
+
+fn parse_packages(text: &str) -> Option<Packages> {
+    let mut lines = text.lines();
+    let manager = lines.next()?.trim().to_string();
+    let mut installed: Vec<Package> = lines
+        .filter(|l| !l.trim().is_empty())
+        .filter_map(|l| match manager.as_str() {
+            // `apk info -v` prints `<name>-<version>-r<release>`.
+            "apk" => {
+                let mut parts = l.trim().rsplitn(3, '-');
+                let release = parts.next()?;
+                let version = parts.next()?;
+                let name = parts.next()?;
+                Some(Package {
+                    name: name.into(),
+                    version: format!("{version}-{release}"),
+                })
+            }
+            _ => l.split_once('\t').map(|(name, version)| Package {
+                name: name.into(),
+                version: version.into(),
+            }),
+        })
+        .collect();
+    if !["dpkg", "rpm", "apk"].contains(&manager.as_str()) {
+        return None;
+    }
+    installed.sort_by(|a, b| a.name.cmp(&b.name));
+    Some(Packages { manager, installed })
+}
@@ -0,0 +1,327 @@
+//! One run per backup job at a time: an `flock` in `local_download_dir`, and
+//! optionally a lock file in `backup.dir` on the remote for runs started from
+//! other machines. A job is its host, mode, sources and targets, so other
+//! jobs on the same host are not held up.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    os::fd::AsRawFd,
+    path::PathBuf,
+    thread,
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{config::Config, error::AppError, shell::Cmd, signal, ssh::Ssh};
+
+const POLL: Duration = Duration::from_secs(5);
+/// Exit status of the remote create when the lock file already exists.
+const HELD_EXIT: i32 = 3;
+
+/// Written into the lock files so a blocked run can say who holds them.
+#[derive(Debug, Serialize, Deserialize)]
+struct Holder {
+    pid: u32,
+    host: String,
+    started_at: DateTime<Utc>,
+}
+
+impl Holder {
+    fn me() -> Self {
+        Self {
+            pid: std::process::id(),
+            host: hostname(),
+            started_at: Utc::now(),
+        }
+    }
+
+    /// A holder on this machine is checked by its PID; one elsewhere only
+    /// by age, once `stale_after` is set.
+    fn is_stale(&self, stale_after: Option<Duration>) -> bool {
+        if self.host == hostname() {
+            return !pid_alive(self.pid);
+        }
+        stale_after.is_some_and(|ttl| {
+            (Utc::now() - self.started_at)
+                .to_std()
+                .is_ok_and(|age| age > ttl)
+        })
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "pid {} on {} since {}",
+            self.pid,
+            self.host,
+            self.started_at.format("%Y-%m-%d %H:%M:%S UTC")
+        )
+    }
+}
+
+/// Held for the whole run; dropping it releases both locks.
+pub struct JobLock {
+    _local: File,
+    remote: Option<(Ssh, String)>,
+}
+
+/// Takes the locks for this config's job. With `wait`, blocks until a
+/// running backup finishes (Ctrl-C still aborts); otherwise fails.
+/// `break_lock` removes a remote lock file whoever holds it.
+pub fn acquire(cfg: &Config, wait: bool, break_lock: bool) -> Result<JobLock, AppError> {
+    let name = format!(
+        ".data-backup-{}-{}-{}.lock",
+        cfg.remote.host,
+        cfg.mode,
+        job_key(cfg)
+    );
+
+    let mut path = PathBuf::from(&cfg.options.local_download_dir);
+    std::fs::create_dir_all(&path)?;
+    path.push(&name);
+    let local = lock_local(&path, wait)?;
+
+    let remote = if cfg.lock.remote {
+        let ssh = Ssh::connect_remote(&cfg.remote)?;
+        let remote_path = format!("{}/{name}", cfg.backup.dir.trim_end_matches('/'));
+        let stale_after =
+            (cfg.lock.stale_after > 0).then(|| Duration::from_secs(cfg.lock.stale_after));
+        lock_remote(&ssh, &remote_path, wait, stale_after, break_lock)?;
+        Some((ssh, remote_path))
+    } else {
+        None
+    };
+    Ok(JobLock {
+        _local: local,
+        remote,
+    })
+}
+
+impl Drop for JobLock {
+    fn drop(&mut self) {
+        if let Some((ssh, path)) = &self.remote {
+            let rm = Cmd::new("rm").sudo(true).arg("-f").arg(path.as_str());
+            if let Err(e) = ssh.exec_capture(&rm.to_string(), &mut Vec::new()) {
+                log::warn!("cannot remove remote lock {path}: {e}");
+            }
+        }
+        // The flock goes with the file descriptor.
+    }
+}
+
+fn lock_local(path: &std::path::Path, wait: bool) -> Result<File, AppError> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(path)?;
+    let mut announced = false;
+    loop {
+        // The kernel drops an flock with its process, so it is never stale.
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+            break;
+        }
+        let err = std::io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EWOULDBLOCK) {
+            return Err(err.into());
+        }
+        let mut raw = String::new();
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_string(&mut raw)?;
+        let holder = serde_json::from_str::<Holder>(&raw)
+            .map(|h| h.describe())
+            .unwrap_or_else(|_| "another process".into());
+        blocked(&path.display().to_string(), &holder, wait, &mut announced)?;
+    }
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    let me = serde_json::to_string(&Holder::me()).map_err(std::io::Error::other)?;
+    file.write_all(me.as_bytes())?;
+    file.sync_all()?;
+    Ok(file)
+}
+
+fn lock_remote(
+    ssh: &Ssh,
+    path: &str,
+    wait: bool,
+    stale_after: Option<Duration>,
+    mut break_lock: bool,
+) -> Result<(), AppError> {
+    let me = serde_json::to_string(&Holder::me()).map_err(std::io::Error::other)?;
+    // noclobber makes the create fail if the file exists: an atomic test-and-set.
+    // Any other failure (no such directory, read-only, sudo) is an error.
+    let create = Cmd::new("sh").sudo(true).args([
+        "-c",
+        &format!(
+            "set -C; {{ printf '%s\\n' \"$1\" > \"$2\"; }} 2>&1 && exit 0; \
+             [ -e \"$2\" ] && exit {HELD_EXIT}; exit 1"
+        ),
+        "lock",
+        me.as_str(),
+        path,
+    ]);
+    let cat = Cmd::new("cat").sudo(true).arg(path);
+    let mut announced = false;
+    loop {
+        let mut out = Vec::new();
+        match ssh.exec_capture(&format!("{create} 2>&1"), &mut out) {
+            Ok(()) => return Ok(()),
+            Err(AppError::RemoteExit(HELD_EXIT)) => {}
+            Err(e) => {
+                return Err(AppError::Remote(format!(
+                    "cannot create remote lock {path}: {}",
+                    match String::from_utf8_lossy(&out).trim() {
+                        "" => e.to_string(),
+                        msg => msg.to_string(),
+                    }
+                )));
+            }
+        }
+        let mut raw = Vec::new();
+        let holder = match ssh.exec_capture(&cat.to_string(), &mut raw) {
+            Ok(()) => serde_json::from_slice::<Holder>(&raw).ok(),
+            // Most likely released between the two commands.
+            Err(_) => {
+                thread::sleep(Duration::from_millis(500));
+                signal::check()?;
+                continue;
+            }
+        };
+        let who = holder
+            .as_ref()
+            .map_or_else(|| "unknown holder".into(), Holder::describe);
+        let stale = holder.as_ref().is_some_and(|h| h.is_stale(stale_after));
+        if stale || break_lock {
+            let why = if stale { "stale" } else { "held" };
+            log::warn!("removing {why} remote lock {path} ({who})");
+            let rm = Cmd::new("rm").sudo(true).arg("-f").arg(path);
+            ssh.exec_capture(&rm.to_string(), &mut Vec::new())?;
+            // Only the lock seen now; a run that takes it next is respected.
+            break_lock = false;
+            continue;
+        }
+        blocked(
+            &format!("{}:{path}", ssh.remote_addr_string()),
+            &who,
+            wait,
+            &mut announced,
+        )?;
+    }
+}
+
+/// Fails, or logs once and sleeps before the next attempt.
+fn blocked(lock: &str, holder: &str, wait: bool, announced: &mut bool) -> Result<(), AppError> {
+    if !wait {
+        return Err(AppError::Locked(format!("{lock} is held by {holder}")));
+    }
+    if !*announced {
+        log::info!("Waiting for {lock}, held by {holder}");
+        *announced = true;
+    }
+    thread::sleep(POLL);
+    signal::check()
+}
+
+/// Short digest of what makes up the job: mode, sources and targets.
+fn job_key(cfg: &Config) -> String {
+    let mut h = Sha256::new();
+    let mut add = |part: &str| {
+        h.update(part.as_bytes());
+        h.update([0]);
+    };
+    add(&cfg.remote.host.to_string());
+    add(&cfg.remote.port.to_string());
+    add(&cfg.mode);
+    for fs in &cfg.filesystems {
+        add(&fs.to_string());
+    }
+    add(cfg.dd.as_ref().map_or("", |d| d.device.as_str()));
+    add(&cfg.backup.dir);
+    add(&cfg.backup.filename);
+    add(&cfg.options.local_download_dir);
+    hex::encode(&h.finalize()[..6])
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let rc = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) };
+    if rc != 0 {
+        return "unknown".into();
+    }
+    let end = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..end]).into_owned()
+}
+
+fn pid_alive(pid: u32) -> bool {
+    // Signal 0 only checks; EPERM means it exists under another user.
+    let rc = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    rc == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{TempDir, config};
+
+    fn holder(pid: u32, host: &str, age_secs: i64) -> Holder {
+        Holder {
+            pid,
+            host: host.into(),
+            started_at: Utc::now() - chrono::Duration::seconds(age_secs),
+        }
+    }
+
+    #[test]
+    fn second_run_of_a_job_is_refused_until_the_first_releases() {
+        let dir = TempDir::new("lock");
+        let cfg = config(dir.path(), "");
+        let first = acquire(&cfg, false, false).unwrap();
+        match acquire(&cfg, false, false) {
+            Err(AppError::Locked(msg)) => {
+                assert!(
+                    msg.contains(&format!("pid {}", std::process::id())),
+                    "{msg}"
+                )
+            }
+            Err(e) => panic!("{e}"),
+            Ok(_) => panic!("lock taken twice"),
+        }
+        drop(first);
+        acquire(&cfg, false, false).unwrap();
+    }
+
+    #[test]
+    fn other_jobs_are_not_held_up() {
+        let dir = TempDir::new("lock-jobs");
+        let etc = config(dir.path(), "");
+        let mut home = config(dir.path(), "");
+        home.filesystems = vec![crate::config::Filesystem::Home];
+        let _etc = acquire(&etc, false, false).unwrap();
+        acquire(&home, false, false).unwrap();
+    }
+
+    #[test]
+    fn local_holder_is_stale_once_its_process_is_gone() {
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let dead = child.id();
+        child.wait().unwrap();
+        assert!(holder(dead, &hostname(), 0).is_stale(None));
+        assert!(!holder(std::process::id(), &hostname(), 0).is_stale(None));
+        // Age does not matter while the process lives.
+        let ttl = Some(Duration::from_secs(60));
+        assert!(!holder(std::process::id(), &hostname(), 3600).is_stale(ttl));
+    }
+
+    #[test]
+    fn remote_holder_is_stale_only_past_stale_after() {
+        let ttl = Some(Duration::from_secs(3600));
+        assert!(!holder(1, "elsewhere", 7200).is_stale(None));
+        assert!(!holder(1, "elsewhere", 60).is_stale(ttl));
+        assert!(holder(1, "elsewhere", 7200).is_stale(ttl));
+    }
+}
@@ -1,14 +1,36 @@
 #![allow(dead_code)]
 mod backup;
+mod catalog;
 mod cli;
+mod codec;
 mod config;
+mod daemon;
+mod db;
 mod dd;
+mod diff;
 mod error;
+mod hooks;
+mod inventory;
+mod lock;
 mod metadata;
+mod mount;
+mod partial;
+mod preflight;
+mod replicate;
+mod restore;
+mod shell;
+mod signal;
+mod snapshots;
 mod ssh;
+mod storage;
 mod tar;
+#[cfg(test)]
+mod testutil;
+mod throttle;
+mod verify;
+mod volume;
 use clap::Parser;
-use cli::Args;
+use cli::{Args, Command};
 
 use crate::error::AppError;
 fn main() -> Result<(), AppError> {
@@ -17,12 +39,64 @@ fn main() -> Result<(), AppError> {
         .init();
 
     let args = Args::parse();
-    let cfg = config::load(&args.config)?;
+    signal::install();
 
-    match cfg.mode.as_str() {
-        "dd" => backup::run_dd(&cfg)?,
-        "tar" => backup::run(&cfg)?,
-        other => return Err(AppError::Validation(format!("Invalid mode: {other}"))),
+    match args.command.unwrap_or(Command::Backup) {
+        Command::Backup => {
+            let cfg = config::load(&args.config)?;
+            let wait = args.wait || (cfg.lock.wait && !args.no_wait);
+            // Before any hook runs: a held lock is not a failed backup.
+            let _lock = lock::acquire(&cfg, wait, args.break_lock)?;
+            match cfg.mode.as_str() {
+                "dd" => backup::run_dd(&cfg)?,
+                "tar" => backup::run(&cfg)?,
+                other => return Err(AppError::Validation(format!("Invalid mode: {other}"))),
+            }
+        }
+        Command::Diff {
+            a,
+            b,
+            json,
+            block_size,
+            force,
+        } => diff::run(&a, &b, block_size, json, force)?,
+        Command::Mount {
+            snapshot,
+            mountpoint,
+        } => mount::run(&snapshot, &mountpoint)?,
+        Command::Restore {
+            snapshot,
+            target,
+            yes,
+        } => {
+            let cfg = config::load(&args.config)?;
+            restore::run(&cfg, &snapshot, &target, yes)?
+        }
+        Command::Verify { snapshot } => verify::run(&args.config, &snapshot)?,
+        Command::List => snapshots::list(&config::load(&args.config)?)?,
+        Command::Replicate { target } => {
+            let cfg = config::load(&args.config)?;
+            replicate::run(&cfg, target.as_deref())?
+        }
+        Command::Prune {
+            keep_last,
+            older_than_days,
+            dry_run,
+        } => {
+            let cfg = config::load(&args.config)?;
+            snapshots::prune(&cfg, keep_last, older_than_days, dry_run)?
+        }
+        Command::Doctor => preflight::doctor(&config::load(&args.config)?)?,
+        Command::Daemon {
+            mut configs,
+            max_jobs,
+            status,
+        } => {
+            if configs.is_empty() {
+                configs.push(args.config.into());
+            }
+            daemon::run(&configs, max_jobs, &status)?
+        }
     }
 
     Ok(())
@@ -0,0 +1,395 @@
+//! Sidecar formats from before `schema_version`, read only to be migrated:
+//! `<archive>.json` (tar), `<image>.dd.json` (dd), `<dump>.db.json`
+//! (databases), and the run records dd used to write to `metadata/` with the
+//! device in `remote_path`.
+
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::record::{
+    DatabaseSection, DdSection, Details, Hashes, RunState, SnapshotRecord, TarSection,
+};
+use crate::{
+    codec::Codec, db::engine::DumpFormat, dd::imager::ImageFormat, hooks::HookRecord,
+    tar::TarOptions, volume::VolumeInfo,
+};
+
+#[derive(Deserialize)]
+struct BackupMeta {
+    snapshot_name: String,
+    remote_path: String,
+    local_path: String,
+    size_bytes: u64,
+    sha256: String,
+    timestamp: DateTime<Utc>,
+    #[serde(default)]
+    filesystems: Vec<String>,
+    tar_options: Option<TarOptions>,
+    #[serde(default)]
+    volumes: Vec<VolumeInfo>,
+    #[serde(default)]
+    hooks: Vec<HookRecord>,
+    #[serde(default)]
+    state: RunState,
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct DdSnapshotMeta {
+    device: String,
+    parent_device: Option<String>,
+    fstype: Option<String>,
+    #[serde(default = "default_imager")]
+    imager: String,
+    #[serde(default = "default_image_format")]
+    image_format: ImageFormat,
+    #[serde(default = "default_consistency")]
+    consistency: String,
+    partition_table: Option<String>,
+    host: String,
+    local_path: String,
+    bytes_total: u64,
+    bytes_written: u64,
+    sha256: String,
+    /// `Debug` of the compression setting, e.g. `SeekableZstd`.
+    compression: String,
+    frame_size: Option<u64>,
+    frames: Option<usize>,
+    #[serde(default)]
+    bytes_sparse: u64,
+    #[serde(default)]
+    volumes: Vec<VolumeInfo>,
+    finished_at: DateTime<Utc>,
+    #[serde(default)]
+    hooks: Vec<HookRecord>,
+}
+
+#[derive(Deserialize)]
+struct DbDumpMeta {
+    engine: String,
+    database: String,
+    engine_version: Option<String>,
+    format: DumpFormat,
+    compression: String,
+    host: String,
+    local_path: String,
+    bytes_written: u64,
+    sha256: String,
+    started_at: DateTime<Utc>,
+    finished_at: DateTime<Utc>,
+}
+
+fn default_imager() -> String {
+    "dd".into()
+}
+fn default_image_format() -> ImageFormat {
+    ImageFormat::Raw
+}
+fn default_consistency() -> String {
+    "none".into()
+}
+
+/// A current record from an older one, told apart by its fields.
+pub(super) fn migrate(raw: Value) -> Result<SnapshotRecord, serde_json::Error> {
+    if raw.get("bytes_total").is_some() {
+        return Ok(from_dd(serde_json::from_value(raw)?));
+    }
+    if raw.get("engine").is_some() {
+        return Ok(from_db(serde_json::from_value(raw)?));
+    }
+    Ok(from_backup(serde_json::from_value(raw)?))
+}
+
+fn from_backup(m: BackupMeta) -> SnapshotRecord {
+    let compression = Codec::detect(&m.local_path).name();
+    let dd_run = m.tar_options.is_none() && m.remote_path.starts_with("/dev/");
+    let details = if dd_run {
+        Details::Dd(DdSection {
+            device: m.remote_path,
+            parent_device: None,
+            fstype: None,
+            imager: default_imager(),
+            image_format: ImageFormat::Raw,
+            consistency: default_consistency(),
+            partition_table: None,
+            device_bytes: m.size_bytes,
+            bytes_sparse: 0,
+            frame_size: None,
+            frames: None,
+        })
+    } else {
+        Details::Tar(TarSection {
+            remote_path: m.remote_path,
+            filesystems: m.filesystems,
+            // Archives from before the options were recorded were made with none of them.
+            options: m.tar_options.unwrap_or_default(),
+            snapshot: "none".into(),
+        })
+    };
+    let mut r = migrated(details, &m.local_path, None, compression, None);
+    // dd run records named the image by its stem and held the device size;
+    // the image's size was not recorded.
+    if !dd_run {
+        r.snapshot_name = m.snapshot_name;
+        r.size_bytes = m.size_bytes;
+    }
+    r.hashes.sha256 = m.sha256;
+    r.finished_at = m.timestamp;
+    r.volumes = m.volumes;
+    r.hooks = m.hooks;
+    r.state = m.state;
+    r.error = m.error;
+    r
+}
+
+fn from_dd(m: DdSnapshotMeta) -> SnapshotRecord {
+    let details = Details::Dd(DdSection {
+        device: m.device,
+        parent_device: m.parent_device,
+        fstype: m.fstype,
+        imager: m.imager,
+        image_format: m.image_format,
+        consistency: m.consistency,
+        partition_table: m.partition_table,
+        device_bytes: m.bytes_total,
+        bytes_sparse: m.bytes_sparse,
+        frame_size: m.frame_size,
+        frames: m.frames,
+    });
+    let mut r = migrated(
+        details,
+        &m.local_path,
+        Some(&m.host),
+        &config_name(&m.compression),
+        None,
+    );
+    r.size_bytes = m.bytes_written;
+    r.hashes = Hashes { sha256: m.sha256 };
+    r.volumes = m.volumes;
+    r.finished_at = m.finished_at;
+    r.hooks = m.hooks;
+    r
+}
+
+fn from_db(m: DbDumpMeta) -> SnapshotRecord {
+    let details = Details::Database(DatabaseSection {
+        engine: m.engine,
+        database: m.database,
+        engine_version: m.engine_version,
+        format: m.format,
+    });
+    let mut r = migrated(
+        details,
+        &m.local_path,
+        Some(&m.host),
+        &m.compression,
+        Some(m.started_at),
+    );
+    r.size_bytes = m.bytes_written;
+    r.hashes = Hashes { sha256: m.sha256 };
+    r.finished_at = m.finished_at;
+    r.duration_secs = Some((m.finished_at - m.started_at).num_milliseconds() as f64 / 1000.0);
+    r
+}
+
+/// What the old formats did not record is left empty, not guessed.
+fn migrated(
+    details: Details,
+    local_path: &str,
+    host: Option<&str>,
+    compression: &str,
+    started_at: Option<DateTime<Utc>>,
+) -> SnapshotRecord {
+    let mut r = SnapshotRecord::new(
+        details,
+        Path::new(local_path),
+        "",
+        compression,
+        started_at.unwrap_or_default(),
+    );
+    r.host = host.map(String::from);
+    r.tool_version = None;
+    r.started_at = started_at;
+    r
+}
+
+/// `SeekableZstd` → `zstd-seekable`, as in the config.
+fn config_name(debug: &str) -> String {
+    match debug {
+        "SeekableZstd" => "zstd-seekable".into(),
+        other => other.to_ascii_lowercase(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::metadata::sidecar::SidecarKind;
+
+    fn parse(raw: &str) -> SnapshotRecord {
+        SnapshotRecord::parse(raw, "fixture").unwrap()
+    }
+
+    /// `BackupMeta` as the tar path wrote it before any of the options existed.
+    #[test]
+    fn baseline_tar_record() {
+        let r = parse(
+            r#"{
+  "snapshot_name": "backup-2024-03-01T02:00:00Z.tar.gz",
+  "remote_path": "/tmp/backup-2024-03-01T02:00:00Z.tar.gz",
+  "local_path": "/backups/backup-2024-03-01T02:00:00Z.tar.gz",
+  "size_bytes": 1234,
+  "sha256": "aa11",
+  "timestamp": "2024-03-01T02:05:00Z",
+  "filesystems": ["/home", "/var"]
+}"#,
+        );
+        assert_eq!(r.mode, SidecarKind::Archive);
+        assert_eq!(r.state, RunState::Completed);
+        assert_eq!(r.snapshot_name, "backup-2024-03-01T02:00:00Z.tar.gz");
+        assert_eq!(r.size_bytes, 1234);
+        assert_eq!(r.compression, "gzip");
+        assert_eq!(r.hashes.sha256, "aa11");
+        assert_eq!(
+            r.finished_at,
+            Utc.with_ymd_and_hms(2024, 3, 1, 2, 5, 0).unwrap()
+        );
+        assert_eq!((r.host, r.tool_version, r.started_at), (None, None, None));
+        let tar = r.tar.unwrap();
+        assert_eq!(tar.remote_path, "/tmp/backup-2024-03-01T02:00:00Z.tar.gz");
+        assert_eq!(tar.filesystems, ["/home", "/var"]);
+        assert_eq!(tar.options, TarOptions::default());
+        assert_eq!(tar.snapshot, "none");
+        assert!(r.dd.is_none());
+    }
+
+    /// The run record dd wrote to `metadata/`: image stem as the name, the
+    /// device in `remote_path` and the device size in `size_bytes`.
+    #[test]
+    fn baseline_dd_run_record() {
+        let r = parse(
+            r#"{
+  "snapshot_name": "vda-20240301T020000Z.img",
+  "remote_path": "/dev/vda",
+  "local_path": "/backups/vda-20240301T020000Z.img.zst",
+  "size_bytes": 21474836480,
+  "sha256": "bb22",
+  "timestamp": "2024-03-01T03:00:00Z",
+  "filesystems": []
+}"#,
+        );
+        assert_eq!(r.mode, SidecarKind::Image);
+        assert_eq!(r.snapshot_name, "vda-20240301T020000Z.img.zst");
+        assert_eq!(r.compression, "zstd");
+        assert_eq!(r.size_bytes, 0);
+        assert_eq!(r.hashes.sha256, "bb22");
+        let dd = r.dd.unwrap();
+        assert_eq!(dd.device, "/dev/vda");
+        assert_eq!(dd.device_bytes, 21474836480);
+        assert_eq!(
+            (dd.imager.as_str(), dd.image_format),
+            ("dd", ImageFormat::Raw)
+        );
+        assert_eq!(dd.consistency, "none");
+        assert!(r.tar.is_none());
+    }
+
+    #[test]
+    fn baseline_dd_snapshot_meta() {
+        let r = parse(
+            r#"{
+  "device": "/dev/vdb",
+  "host": "10.0.0.5:22",
+  "local_path": "/backups/vdb-20240301T020000Z.img.gz",
+  "bytes_total": 1073741824,
+  "bytes_written": 52428800,
+  "sha256": "cc33",
+  "compression": "Gzip",
+  "finished_at": "2024-03-01T02:30:00Z"
+}"#,
+        );
+        assert_eq!(r.mode, SidecarKind::Image);
+        assert_eq!(r.host.as_deref(), Some("10.0.0.5:22"));
+        assert_eq!(r.compression, "gzip");
+        assert_eq!(r.size_bytes, 52428800);
+        let dd = r.dd.unwrap();
+        assert_eq!(dd.device, "/dev/vdb");
+        assert_eq!(dd.device_bytes, 1073741824);
+        assert_eq!((dd.bytes_sparse, dd.frame_size), (0, None));
+        assert_eq!(dd.imager, "dd");
+    }
+
+    #[test]
+    fn seekable_dd_snapshot_meta() {
+        let r = parse(
+            r#"{
+  "device": "/dev/vda1",
+  "parent_device": "/dev/vda",
+  "fstype": "ext4",
+  "imager": "e2image",
+  "image_format": "raw",
+  "consistency": "fsfreeze",
+  "host": "10.0.0.5:22",
+  "local_path": "/backups/vda1.img.zst",
+  "bytes_total": 4194304,
+  "bytes_written": 4096,
+  "bytes_sparse": 2097152,
+  "sha256": "dd44",
+  "compression": "SeekableZstd",
+  "frame_size": 1048576,
+  "frames": 4,
+  "finished_at": "2024-03-01T02:30:00Z"
+}"#,
+        );
+        assert_eq!(r.compression, "zstd-seekable");
+        let dd = r.dd.unwrap();
+        assert_eq!(dd.device, "/dev/vda1");
+        assert_eq!(dd.parent_device.as_deref(), Some("/dev/vda"));
+        assert_eq!(dd.fstype.as_deref(), Some("ext4"));
+        assert_eq!(
+            (dd.imager.as_str(), dd.consistency.as_str()),
+            ("e2image", "fsfreeze")
+        );
+        assert_eq!((dd.frame_size, dd.frames), (Some(1048576), Some(4)));
+        assert_eq!(dd.bytes_sparse, 2097152);
+    }
+
+    #[test]
+    fn db_dump_meta() {
+        let r = parse(
+            r#"{
+  "engine": "postgres",
+  "database": "app",
+  "engine_version": "16.2",
+  "format": "pg-custom",
+  "compression": "zstd",
+  "host": "10.0.0.5:22",
+  "local_path": "/backups/postgres-app-20240301T020000Z.dump.zst",
+  "bytes_written": 9000,
+  "sha256": "ee55",
+  "started_at": "2024-03-01T02:00:00Z",
+  "finished_at": "2024-03-01T02:01:30Z"
+}"#,
+        );
+        assert_eq!(r.mode, SidecarKind::Database);
+        assert_eq!(r.compression, "zstd");
+        assert_eq!(r.size_bytes, 9000);
+        assert_eq!(r.duration_secs, Some(90.0));
+        assert_eq!(
+            r.started_at,
+            Some(Utc.with_ymd_and_hms(2024, 3, 1, 2, 0, 0).unwrap())
+        );
+        let db = r.database.unwrap();
+        assert_eq!(
+            (db.engine.as_str(), db.database.as_str()),
+            ("postgres", "app")
+        );
+        assert_eq!(db.engine_version.as_deref(), Some("16.2"));
+        assert_eq!(db.format, DumpFormat::PgCustom);
+    }
+}
@@ -1,2 +1,6 @@
 pub mod generator;
-pub use generator::{BackupMeta, JsonWriter};
+mod legacy;
+pub mod record;
+pub mod sidecar;
+pub use generator::{JsonWriter, METADATA_DIR};
+pub use record::SnapshotRecord;
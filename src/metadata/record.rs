@@ -0,0 +1,273 @@
+//! The snapshot record: one schema for tar archives, dd images and database
+//! dumps, written next to the snapshot as `<snapshot>.json`. Its JSON Schema
+//! is `schema/snapshot-record.v2.json`; bump [`SCHEMA_VERSION`] with it.
+
+use std::{io, path::Path};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{JsonWriter, METADATA_DIR, legacy, sidecar::SidecarKind};
+use crate::{
+    db::engine::DumpFormat, dd::imager::ImageFormat, error::AppError, hooks::HookRecord,
+    tar::TarOptions, volume::VolumeInfo,
+};
+
+pub const SCHEMA_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RunState {
+    #[default]
+    Completed,
+    /// Interrupted or failed; whatever was written locally is still `.partial`.
+    Aborted,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotRecord {
+    pub schema_version: u32,
+    /// File name of the snapshot; of its volumes without the `.NNN` when split.
+    pub snapshot_name: String,
+    /// Says which of `tar`, `dd` and `database` is filled in.
+    pub mode: SidecarKind,
+    #[serde(default)]
+    pub state: RunState,
+    /// Why an aborted run stopped.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Remote host, as configured. Optional fields are missing from records
+    /// migrated from formats that did not have them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// Version of data-backup that wrote the record.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_secs: Option<f64>,
+    pub local_path: String,
+    /// Bytes on disk, over all volumes.
+    pub size_bytes: u64,
+    /// `none`, `gzip`, `xz`, `zstd` or `zstd-seekable`, as in the config.
+    pub compression: String,
+    pub hashes: Hashes,
+    /// Volumes `<local_path>.001`, … when the snapshot was split; `hashes` cover them all.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub volumes: Vec<VolumeInfo>,
+    /// The snapshot of the same series stored before this one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+    /// `options.tags` at the time of the run.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Hooks run around the backup, with their output.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hooks: Vec<HookRecord>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tar: Option<TarSection>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dd: Option<DdSection>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub database: Option<DatabaseSection>,
+}
+
+/// Of the whole snapshot, volumes concatenated.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Hashes {
+    pub sha256: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TarSection {
+    /// Where tar wrote the archive on the remote.
+    pub remote_path: String,
+    pub filesystems: Vec<String>,
+    /// Switches the archive was created with.
+    #[serde(default)]
+    pub options: TarOptions,
+    /// Volume snapshot it was archived from: `none`, `lvm` or `btrfs`.
+    #[serde(default = "no_snapshot")]
+    pub snapshot: String,
+}
+
+fn no_snapshot() -> String {
+    "none".into()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DdSection {
+    pub device: String,
+    /// Disk the imaged partition belongs to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_device: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fstype: Option<String>,
+    /// Remote tool that produced the image (`dd`, `e2image`, `partclone.xfs`, …).
+    pub imager: String,
+    pub image_format: ImageFormat,
+    /// How a point-in-time view was obtained: `none`, `lvm`, `zfs` or `fsfreeze`.
+    pub consistency: String,
+    /// `sfdisk --dump` of the disk, for recreating partitions before a restore.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub partition_table: Option<String>,
+    /// Size of the device read.
+    pub device_bytes: u64,
+    /// Zero bytes seen in the stream and left as holes in a sparse image.
+    #[serde(default)]
+    pub bytes_sparse: u64,
+    /// Uncompressed frame size and frame count of a seekable zstd image.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frame_size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frames: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DatabaseSection {
+    /// `postgres`, `mysql`, `sqlite` or `redis`.
+    pub engine: String,
+    /// Database name, or the database file for SQLite.
+    pub database: String,
+    /// Server version reported at dump time, when it could be queried.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub engine_version: Option<String>,
+    pub format: DumpFormat,
+}
+
+/// The mode-specific part a record is started with.
+pub enum Details {
+    Tar(TarSection),
+    Dd(DdSection),
+    Database(DatabaseSection),
+}
+
+impl SnapshotRecord {
+    /// A run that began at `started_at`; finish it with [`Self::completed`]
+    /// or [`Self::record_aborted`].
+    pub fn new(
+        details: Details,
+        local_path: &Path,
+        host: &str,
+        compression: &str,
+        started_at: DateTime<Utc>,
+    ) -> Self {
+        let mut record = Self {
+            schema_version: SCHEMA_VERSION,
+            snapshot_name: local_path
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            mode: SidecarKind::Archive,
+            state: RunState::Completed,
+            error: None,
+            host: Some(host.into()),
+            tool_version: Some(env!("CARGO_PKG_VERSION").into()),
+            started_at: Some(started_at),
+            finished_at: started_at,
+            duration_secs: None,
+            local_path: local_path.to_string_lossy().into_owned(),
+            size_bytes: 0,
+            compression: compression.into(),
+            hashes: Hashes::default(),
+            volumes: Vec::new(),
+            parent: None,
+            tags: Vec::new(),
+            hooks: Vec::new(),
+            tar: None,
+            dd: None,
+            database: None,
+        };
+        match details {
+            Details::Tar(s) => record.tar = Some(s),
+            Details::Dd(s) => {
+                record.mode = SidecarKind::Image;
+                record.dd = Some(s);
+            }
+            Details::Database(s) => {
+                record.mode = SidecarKind::Database;
+                record.database = Some(s);
+            }
+        }
+        record
+    }
+
+    /// Reads a record of any version; older formats are migrated.
+    pub fn parse(raw: &str, origin: &str) -> Result<Self, AppError> {
+        let bad = |e: serde_json::Error| AppError::Validation(format!("{origin}: {e}"));
+        let value: Value = serde_json::from_str(raw).map_err(bad)?;
+        match value.get("schema_version").and_then(Value::as_u64) {
+            Some(v) if v > u64::from(SCHEMA_VERSION) => Err(AppError::Validation(format!(
+                "{origin}: schema version {v} is newer than this build reads ({SCHEMA_VERSION})"
+            ))),
+            Some(_) => serde_json::from_value(value).map_err(bad),
+            None => legacy::migrate(value).map_err(bad),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, AppError> {
+        let raw = std::fs::read_to_string(path)?;
+        Self::parse(&raw, &path.display().to_string())
+    }
+
+    /// The data is all on disk with this size and hash.
+    pub fn completed(mut self, size_bytes: u64, sha256: String, volumes: Vec<VolumeInfo>) -> Self {
+        self.size_bytes = size_bytes;
+        self.hashes.sha256 = sha256;
+        self.volumes = volumes;
+        self.state = RunState::Completed;
+        self.finish();
+        self
+    }
+
+    /// Writes `<local_path>.json`.
+    pub fn write(&self) -> io::Result<()> {
+        let path = Path::new(&self.local_path);
+        let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+            return Ok(());
+        };
+        JsonWriter::write_as(self, dir, &name.to_string_lossy())
+    }
+
+    /// Marks the run aborted and writes it to [`METADATA_DIR`]; failing to
+    /// is only logged, as the run already failed.
+    pub fn record_aborted(mut self, error: &str) {
+        self.state = RunState::Aborted;
+        self.error = Some(error.into());
+        self.finish();
+        match JsonWriter::write_as(&self, METADATA_DIR, &self.snapshot_name) {
+            Ok(()) => log::warn!(
+                "Run aborted; recorded in {METADATA_DIR}/{}.json",
+                self.snapshot_name
+            ),
+            Err(e) => log::error!("cannot record aborted run: {e}"),
+        }
+    }
+
+    /// Snapshots of the same job form one series, for pruning and parents:
+    /// same host, mode and source (tar filesystems, dd device or database).
+    pub fn series(&self) -> String {
+        let source = match (&self.tar, &self.dd, &self.database) {
+            (Some(tar), _, _) => {
+                let mut fs = tar.filesystems.clone();
+                fs.sort();
+                fs.join(",")
+            }
+            (_, Some(dd), _) => dd.device.clone(),
+            (_, _, Some(db)) => format!("{}/{}", db.engine, db.database),
+            _ => String::new(),
+        };
+        let host = self.host.as_deref().unwrap_or("-");
+        format!("{} {host} {source}", self.mode.name())
+    }
+
+    fn finish(&mut self) {
+        self.finished_at = Utc::now();
+        self.duration_secs = self
+            .started_at
+            .map(|s| (self.finished_at - s).num_milliseconds() as f64 / 1000.0);
+    }
+}
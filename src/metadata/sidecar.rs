@@ -0,0 +1,80 @@
+//! Finds the metadata record written next to a local snapshot.
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::AppError;
+
+/// Host facts saved next to a snapshot; not a metadata record itself.
+pub const INVENTORY_SUFFIX: &str = ".host.json";
+
+/// Written next to every snapshot, whatever its mode.
+pub const SUFFIX: &str = ".json";
+/// Where earlier versions put dd and database records; still read.
+const LEGACY_SUFFIXES: [&str; 2] = [".dd.json", ".db.json"];
+
+/// Which pipeline wrote the snapshot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SidecarKind {
+    /// dd image.
+    #[serde(rename = "dd")]
+    Image,
+    /// Database dump.
+    #[serde(rename = "db")]
+    Database,
+    /// Tar archive.
+    #[serde(rename = "tar")]
+    Archive,
+}
+
+impl SidecarKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Image => "dd",
+            Self::Database => "db",
+            Self::Archive => "tar",
+        }
+    }
+}
+
+/// The record of `snapshot`: `<snapshot>.json`, or a legacy dd / database one.
+pub fn locate(snapshot: &Path) -> Result<PathBuf, AppError> {
+    let candidates: Vec<PathBuf> = [SUFFIX]
+        .iter()
+        .chain(&LEGACY_SUFFIXES)
+        .map(|s| with_suffix(snapshot, s))
+        .collect();
+    if let Some(path) = candidates.iter().find(|p| p.exists()) {
+        return Ok(path.clone());
+    }
+    Err(AppError::Validation(format!(
+        "no metadata found for {} (expected {})",
+        snapshot.display(),
+        candidates[0].display()
+    )))
+}
+
+/// `x.img.zst.json` or `x.img.zst.dd.json` → `x.img.zst`; `None` for anything
+/// that is not a record file name.
+pub fn split_name(name: &str) -> Option<&str> {
+    if name.ends_with(INVENTORY_SUFFIX) {
+        return None;
+    }
+    LEGACY_SUFFIXES
+        .iter()
+        .chain([&SUFFIX])
+        .find_map(|s| name.strip_suffix(s))
+        .filter(|s| !s.is_empty())
+}
+
+/// `<snapshot>.host.json`, see [`crate::inventory`].
+pub fn inventory_path(snapshot: &Path) -> PathBuf {
+    with_suffix(snapshot, INVENTORY_SUFFIX)
+}
+
+fn with_suffix(snapshot: &Path, suffix: &str) -> PathBuf {
+    let mut name = snapshot.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
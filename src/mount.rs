@@ -0,0 +1,74 @@
+//! Read-only FUSE view of a local snapshot (`mount` command).
+
+mod fs; // fuser::Filesystem over an index + byte source
+mod index; // inode tree built from a tar listing or a dd image
+mod source; // random-access reads into (possibly compressed) snapshot data
+
+use std::path::Path;
+
+use fuser::MountOption;
+
+use crate::{codec::Codec, error::AppError};
+
+use self::{fs::SnapshotFs, index::Index, source::ByteSource};
+
+pub fn run(snapshot: &Path, mountpoint: &Path) -> Result<(), AppError> {
+    let name = snapshot
+        .file_name()
+        .map(|n| n.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    let codec = Codec::detect(snapshot);
+
+    let (index, source) = if name.contains(".tar") || name.ends_with(".tgz") {
+        log::info!("Indexing {}", snapshot.display());
+        let mut index = Index::from_tar(codec.open(snapshot)?)?;
+        let mut source = ByteSource::open(snapshot, codec)?;
+        if !source.is_random_access() {
+            log::warn!(
+                "{} is a {} stream without a seek table: reading files out of archive order decompresses it again; seekable zstd or an uncompressed tar mounts faster",
+                snapshot.display(),
+                codec.name()
+            );
+        }
+        index.resolve_sparse(&mut source);
+        (index, source)
+    } else if name.contains(".img") {
+        let source = ByteSource::open(snapshot, codec)?;
+        if !source.is_random_access() {
+            return Err(AppError::Validation(format!(
+                "{} is a {} stream without a seek table; only uncompressed or seekable zstd dd images can be mounted",
+                snapshot.display(),
+                codec.name()
+            )));
+        }
+        // Expose `x.img.zst` as `x.img`, ready for `losetup`.
+        let file_name = snapshot
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .map(|n| match n.find(".img") {
+                Some(i) => n[..i + 4].to_string(),
+                None => n,
+            })
+            .unwrap_or_else(|| "disk.img".into());
+        (Index::single_file(&file_name, source.len()?), source)
+    } else {
+        return Err(AppError::Validation(format!(
+            "cannot tell snapshot type of {} (expected *.tar* or *.img*)",
+            snapshot.display()
+        )));
+    };
+
+    log::info!(
+        "Mounting {} read-only at {} ({} inodes); unmount with `fusermount -u` or `umount`",
+        snapshot.display(),
+        mountpoint.display(),
+        index.len()
+    );
+    let options = [
+        MountOption::RO,
+        MountOption::FSName("data-backup".into()),
+        MountOption::Subtype("snapshot".into()),
+    ];
+    fuser::mount2(SnapshotFs::new(index, source), mountpoint, &options)?;
+    Ok(())
+}
@@ -0,0 +1,171 @@
+use std::{
+    ffi::OsStr,
+    os::unix::ffi::OsStrExt,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use fuser::{
+    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, Request,
+};
+
+use super::{
+    index::{Index, NodeKind},
+    source::ByteSource,
+};
+
+const TTL: Duration = Duration::from_secs(3600);
+const ENOENT: i32 = 2;
+const EIO: i32 = 5;
+const EINVAL: i32 = 22;
+const ENOTDIR: i32 = 20;
+
+pub struct SnapshotFs {
+    index: Index,
+    source: ByteSource,
+}
+
+impl SnapshotFs {
+    pub fn new(index: Index, source: ByteSource) -> Self {
+        Self { index, source }
+    }
+
+    fn attr(&self, ino: u64) -> Option<FileAttr> {
+        let node = self.index.node(ino)?;
+        let (kind, size, nlink) = match &node.kind {
+            NodeKind::Dir(children) => (FileType::Directory, 0, 2 + children.len() as u32),
+            NodeKind::File { size, .. }
+            | NodeKind::Sparse { size, .. }
+            | NodeKind::Unreadable { size, .. } => (FileType::RegularFile, *size, 1),
+            NodeKind::Symlink(target) => (FileType::Symlink, target.as_os_str().len() as u64, 1),
+        };
+        let mtime = UNIX_EPOCH + Duration::from_secs(node.mtime);
+        Some(FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime: mtime,
+            mtime,
+            ctime: mtime,
+            crtime: mtime,
+            kind,
+            // Read-only view: never advertise write bits.
+            perm: (node.mode & 0o7555) as u16,
+            nlink,
+            uid: node.uid,
+            gid: node.gid,
+            rdev: 0,
+            blksize: 4096,
+            flags: 0,
+        })
+    }
+}
+
+impl Filesystem for SnapshotFs {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        match self
+            .index
+            .lookup(parent, name)
+            .and_then(|ino| self.attr(ino))
+        {
+            Some(attr) => reply.entry(&TTL, &attr, 0),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        match self.attr(ino) {
+            Some(attr) => reply.attr(&TTL, &attr),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        match self.index.node(ino).map(|n| &n.kind) {
+            Some(NodeKind::Symlink(target)) => reply.data(target.as_os_str().as_bytes()),
+            Some(_) => reply.error(EINVAL),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let offset = offset.max(0) as u64;
+        let Some(node) = self.index.node(ino) else {
+            return reply.error(ENOENT);
+        };
+        let len = match &node.kind {
+            NodeKind::File { size, .. } | NodeKind::Sparse { size, .. } => *size,
+            NodeKind::Unreadable { why, .. } => {
+                log::error!("read inode {ino}: {why}");
+                return reply.error(EIO);
+            }
+            _ => return reply.error(EINVAL),
+        };
+        if offset >= len {
+            return reply.data(&[]);
+        }
+        let mut buf = vec![0u8; (size as u64).min(len - offset) as usize];
+        let res = match &node.kind {
+            NodeKind::Sparse { map, .. } => self
+                .source
+                .read_sparse(map, offset, &mut buf)
+                .map(|()| buf.len()),
+            NodeKind::File { offset: start, .. } => self.source.read_at(start + offset, &mut buf),
+            _ => unreachable!("checked above"),
+        };
+        match res {
+            Ok(n) => reply.data(&buf[..n]),
+            Err(e) => {
+                log::error!("read inode {ino} @ {offset}: {e}");
+                reply.error(EIO)
+            }
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let Some(node) = self.index.node(ino) else {
+            return reply.error(ENOENT);
+        };
+        let NodeKind::Dir(children) = &node.kind else {
+            return reply.error(ENOTDIR);
+        };
+
+        let dots = [
+            (ino, FileType::Directory, OsStr::new(".")),
+            (node.parent, FileType::Directory, OsStr::new("..")),
+        ];
+        let entries = dots
+            .into_iter()
+            .chain(children.iter().map(|(name, &child)| {
+                let kind = match self.index.node(child).map(|n| &n.kind) {
+                    Some(NodeKind::Dir(_)) => FileType::Directory,
+                    Some(NodeKind::Symlink(_)) => FileType::Symlink,
+                    _ => FileType::RegularFile,
+                };
+                (child, kind, name.as_os_str())
+            }));
+
+        for (i, (child, kind, name)) in entries.enumerate().skip(offset.max(0) as usize) {
+            if reply.add(child, (i + 1) as i64, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
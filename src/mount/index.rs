@@ -0,0 +1,584 @@
+use std::{
+    collections::BTreeMap,
+    ffi::{OsStr, OsString},
+    io::{self, Read},
+    path::{Component, Path, PathBuf},
+};
+
+use ::tar::{Archive, EntryType, GnuExtSparseHeader, GnuSparseHeader};
+
+use super::source::ByteSource;
+
+pub const ROOT_INO: u64 = 1;
+
+#[derive(Debug, Clone)]
+pub enum NodeKind {
+    Dir(BTreeMap<OsString, u64>),
+    /// Data lives at `offset..offset + size` of the decompressed stream.
+    File {
+        offset: u64,
+        size: u64,
+    },
+    Symlink(PathBuf),
+    /// GNU sparse member: `size` bytes, zero outside the data segments.
+    Sparse {
+        size: u64,
+        map: Vec<Segment>,
+    },
+    /// Listed, but its data cannot be located; reads fail with `EIO`.
+    Unreadable {
+        size: u64,
+        why: String,
+    },
+}
+
+/// `len` bytes of a sparse member at `offset`, stored at `at` in the
+/// decompressed stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub offset: u64,
+    pub len: u64,
+    pub at: u64,
+}
+
+/// Sparse map still to be read from the archive data, see [`Index::resolve_sparse`].
+#[derive(Debug, Clone)]
+enum Pending {
+    /// Old GNU format: extension headers at `at`, then the data. `map` holds
+    /// the segments from the main header, `at` relative to the data start.
+    GnuExtended { at: u64, map: Vec<Segment> },
+    /// PAX format 1.0: a decimal map at `at`, padded to a block, then the data.
+    PaxMap { at: u64 },
+}
+
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub parent: u64,
+    pub kind: NodeKind,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: u64,
+}
+
+/// Inode table; inode `n` is `nodes[n - 1]`.
+#[derive(Debug)]
+pub struct Index {
+    nodes: Vec<Node>,
+    pending: Vec<(u64, Pending)>,
+}
+
+impl Index {
+    fn empty() -> Self {
+        Self {
+            nodes: vec![Node::dir(ROOT_INO)],
+            pending: Vec::new(),
+        }
+    }
+
+    /// Exposes a dd image as `/<name>`.
+    pub fn single_file(name: &str, size: u64) -> Self {
+        let mut idx = Self::empty();
+        let ino = idx.push(Node {
+            parent: ROOT_INO,
+            kind: NodeKind::File { offset: 0, size },
+            mode: 0o444,
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+        });
+        idx.children_mut(ROOT_INO).insert(name.into(), ino);
+        idx
+    }
+
+    /// Walks a decompressed tar stream once and records where each member's data starts.
+    pub fn from_tar<R: Read>(reader: R) -> io::Result<Self> {
+        let mut idx = Self::empty();
+        let mut archive = Archive::new(reader);
+
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let sparse = PaxSparse::read(&mut entry)?;
+            let path = match &sparse.name {
+                Some(name) => PathBuf::from(name),
+                None => entry.path()?.into_owned(),
+            };
+            let hdr = entry.header();
+            let attrs = Node {
+                parent: ROOT_INO,
+                kind: NodeKind::Dir(BTreeMap::new()),
+                mode: hdr.mode().unwrap_or(0o644) & 0o7777,
+                uid: hdr.uid().unwrap_or(0) as u32,
+                gid: hdr.gid().unwrap_or(0) as u32,
+                mtime: hdr.mtime().unwrap_or(0),
+            };
+
+            let mut pending = None;
+            let kind = match hdr.entry_type() {
+                EntryType::Directory => NodeKind::Dir(BTreeMap::new()),
+                EntryType::GNUSparse => {
+                    let at = entry.raw_file_position();
+                    let gnu = hdr
+                        .as_gnu()
+                        .ok_or_else(|| io::Error::other("sparse member without a GNU header"))?;
+                    let map = segments(&gnu.sparse, 0)?;
+                    if gnu.is_extended() {
+                        pending = Some(Pending::GnuExtended { at, map });
+                        NodeKind::Sparse {
+                            size: entry.size(),
+                            map: Vec::new(),
+                        }
+                    } else {
+                        NodeKind::Sparse {
+                            size: entry.size(),
+                            map: map.into_iter().map(|s| s.shifted(at)).collect(),
+                        }
+                    }
+                }
+                EntryType::Regular | EntryType::Continuous if sparse.is_sparse() => {
+                    let at = entry.raw_file_position();
+                    match sparse.kind(at) {
+                        Ok((kind, later)) => {
+                            pending = later;
+                            kind
+                        }
+                        Err(why) => {
+                            log::warn!("{}: {why}; reads will fail", path.display());
+                            NodeKind::Unreadable {
+                                size: sparse.size.unwrap_or(entry.size()),
+                                why,
+                            }
+                        }
+                    }
+                }
+                EntryType::Regular | EntryType::Continuous => NodeKind::File {
+                    offset: entry.raw_file_position(),
+                    size: entry.size(),
+                },
+                EntryType::Symlink => {
+                    NodeKind::Symlink(entry.link_name()?.unwrap_or_default().into_owned())
+                }
+                EntryType::Link => {
+                    let target = entry.link_name()?.unwrap_or_default();
+                    match idx.resolve(&target) {
+                        Some(ino) => {
+                            pending = idx
+                                .pending
+                                .iter()
+                                .find(|(i, _)| *i == ino)
+                                .map(|(_, p)| p.clone());
+                            idx.node(ino).expect("resolved inode").kind.clone()
+                        }
+                        None => continue,
+                    }
+                }
+                // Device nodes, fifos, … have no content worth browsing.
+                _ => continue,
+            };
+            let ino = idx.insert(&path, Node { kind, ..attrs });
+            if let (Some(ino), Some(p)) = (ino, pending) {
+                idx.pending.push((ino, p));
+            }
+        }
+        Ok(idx)
+    }
+
+    /// Reads the sparse maps stored in the archive data itself. Members come
+    /// in archive order, so a compressed stream is only read forward.
+    pub fn resolve_sparse(&mut self, source: &mut ByteSource) {
+        for (ino, pending) in std::mem::take(&mut self.pending) {
+            let node = &mut self.nodes[ino as usize - 1];
+            let NodeKind::Sparse { size, map } = &mut node.kind else {
+                continue;
+            };
+            match pending.read_map(source) {
+                Ok(m) => *map = m,
+                Err(e) => {
+                    log::warn!("inode {ino}: cannot read sparse map: {e}; reads will fail");
+                    node.kind = NodeKind::Unreadable {
+                        size: *size,
+                        why: format!("sparse map: {e}"),
+                    };
+                }
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn node(&self, ino: u64) -> Option<&Node> {
+        self.nodes.get(ino.checked_sub(1)? as usize)
+    }
+
+    pub fn lookup(&self, parent: u64, name: &OsStr) -> Option<u64> {
+        match &self.node(parent)?.kind {
+            NodeKind::Dir(children) => children.get(name).copied(),
+            _ => None,
+        }
+    }
+
+    fn push(&mut self, node: Node) -> u64 {
+        self.nodes.push(node);
+        self.nodes.len() as u64
+    }
+
+    fn children_mut(&mut self, ino: u64) -> &mut BTreeMap<OsString, u64> {
+        match &mut self.nodes[ino as usize - 1].kind {
+            NodeKind::Dir(children) => children,
+            _ => unreachable!("inode {ino} is not a directory"),
+        }
+    }
+
+    fn resolve(&self, path: &Path) -> Option<u64> {
+        let mut ino = ROOT_INO;
+        for name in normal_components(path) {
+            ino = self.lookup(ino, name)?;
+        }
+        Some(ino)
+    }
+
+    /// Inserts `node` at `path`, creating missing parent directories.
+    /// Returns its inode, or `None` for the root.
+    fn insert(&mut self, path: &Path, mut node: Node) -> Option<u64> {
+        let names: Vec<&OsStr> = normal_components(path).collect();
+        let Some((last, parents)) = names.split_last() else {
+            // `./` itself: keep the root but take its attributes.
+            if matches!(node.kind, NodeKind::Dir(_)) {
+                let root = &mut self.nodes[0];
+                (root.mode, root.uid, root.gid, root.mtime) =
+                    (node.mode, node.uid, node.gid, node.mtime);
+            }
+            return None;
+        };
+
+        let mut dir = ROOT_INO;
+        for name in parents {
+            dir = match self.lookup(dir, name) {
+                Some(ino) if matches!(self.nodes[ino as usize - 1].kind, NodeKind::Dir(_)) => ino,
+                _ => {
+                    let ino = self.push(Node::dir(dir));
+                    self.children_mut(dir).insert((*name).to_owned(), ino);
+                    ino
+                }
+            };
+        }
+
+        node.parent = dir;
+        match self.lookup(dir, last) {
+            // A directory seen before its own header: keep its children.
+            Some(ino) if matches!(node.kind, NodeKind::Dir(_)) => {
+                let existing = &mut self.nodes[ino as usize - 1];
+                if let NodeKind::Dir(children) = &mut existing.kind {
+                    node.kind = NodeKind::Dir(std::mem::take(children));
+                }
+                *existing = node;
+                Some(ino)
+            }
+            Some(ino) => {
+                self.nodes[ino as usize - 1] = node;
+                Some(ino)
+            }
+            None => {
+                let ino = self.push(node);
+                self.children_mut(dir).insert((*last).to_owned(), ino);
+                Some(ino)
+            }
+        }
+    }
+}
+
+impl Node {
+    fn dir(parent: u64) -> Self {
+        Self {
+            parent,
+            kind: NodeKind::Dir(BTreeMap::new()),
+            mode: 0o755,
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+        }
+    }
+}
+
+impl Segment {
+    fn shifted(self, by: u64) -> Self {
+        Self {
+            at: self.at + by,
+            ..self
+        }
+    }
+}
+
+/// Segments packed one after another from `at`, skipping empty slots.
+fn segments(blocks: &[GnuSparseHeader], at: u64) -> io::Result<Vec<Segment>> {
+    let mut at = at;
+    let mut out = Vec::new();
+    for b in blocks.iter().filter(|b| !b.is_empty()) {
+        let (offset, len) = (b.offset()?, b.length()?);
+        out.push(Segment { offset, len, at });
+        at += len;
+    }
+    Ok(out)
+}
+
+fn pack(pairs: &[(u64, u64)], at: u64) -> Vec<Segment> {
+    let mut at = at;
+    pairs
+        .iter()
+        .map(|&(offset, len)| {
+            let s = Segment { offset, len, at };
+            at += len;
+            s
+        })
+        .collect()
+}
+
+impl Pending {
+    fn read_map(self, source: &mut ByteSource) -> io::Result<Vec<Segment>> {
+        match self {
+            Self::GnuExtended { mut at, mut map } => {
+                let mut ext = GnuExtSparseHeader::new();
+                loop {
+                    if source.read_at(at, ext.as_mut_bytes())? != BLOCK {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                    at += BLOCK as u64;
+                    let base = map.last().map_or(0, |s| s.at + s.len);
+                    map.extend(segments(ext.sparse(), base)?);
+                    if !ext.is_extended() {
+                        break;
+                    }
+                }
+                Ok(map.into_iter().map(|s| s.shifted(at)).collect())
+            }
+            Self::PaxMap { at } => {
+                // `count\n` then `offset\nlength\n` pairs, padded to a block.
+                let mut text = Vec::new();
+                let mut block = [0u8; BLOCK];
+                let numbers = loop {
+                    if source.read_at(at + text.len() as u64, &mut block)? != BLOCK {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                    text.extend_from_slice(&block);
+                    let numbers = decimal_lines(&text)?;
+                    if let Some(&count) = numbers.first()
+                        && numbers.len() as u64 > count.saturating_mul(2)
+                    {
+                        break numbers;
+                    }
+                };
+                let count = numbers[0] as usize;
+                let pairs: Vec<_> = numbers[1..=2 * count]
+                    .chunks(2)
+                    .map(|p| (p[0], p[1]))
+                    .collect();
+                Ok(pack(&pairs, at + text.len() as u64))
+            }
+        }
+    }
+}
+
+/// Complete newline-terminated decimal lines at the start of `text`.
+fn decimal_lines(text: &[u8]) -> io::Result<Vec<u64>> {
+    let complete = text.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+    std::str::from_utf8(&text[..complete])
+        .ok()
+        .and_then(|t| t.lines().map(|l| l.parse().ok()).collect())
+        .ok_or_else(|| io::Error::other("malformed PAX sparse map"))
+}
+
+const BLOCK: usize = 512;
+
+/// `GNU.sparse.*` PAX records (sparse formats 0.0, 0.1 and 1.0).
+#[derive(Debug, Default)]
+struct PaxSparse {
+    name: Option<String>,
+    size: Option<u64>,
+    major: Option<u64>,
+    /// From `GNU.sparse.map` (0.1) or `offset`/`numbytes` pairs (0.0).
+    pairs: Vec<(u64, u64)>,
+    /// A `GNU.sparse.offset` waiting for its `numbytes`.
+    offset: Option<u64>,
+    bad: Option<String>,
+}
+
+impl PaxSparse {
+    fn read<R: Read>(entry: &mut ::tar::Entry<'_, R>) -> io::Result<Self> {
+        let mut out = Self::default();
+        let Some(exts) = entry.pax_extensions()? else {
+            return Ok(out);
+        };
+        for ext in exts {
+            let ext = ext?;
+            let Ok(key) = ext.key() else { continue };
+            let Some(key) = key.strip_prefix("GNU.sparse.") else {
+                continue;
+            };
+            let value = ext.value().unwrap_or_default();
+            let num = || value.parse::<u64>().ok();
+            match key {
+                "name" => out.name = Some(value.to_string()),
+                "size" | "realsize" => out.size = num(),
+                "major" => out.major = num(),
+                "offset" => out.offset = num(),
+                "numbytes" => match (out.offset.take(), num()) {
+                    (Some(o), Some(n)) => out.pairs.push((o, n)),
+                    _ => out.bad = Some("unpaired GNU.sparse.numbytes".into()),
+                },
+                "map" => {
+                    let nums: Option<Vec<u64>> = value
+                        .split(',')
+                        .filter(|v| !v.is_empty())
+                        .map(|v| v.parse().ok())
+                        .collect();
+                    match nums {
+                        Some(n) if n.len() % 2 == 0 => {
+                            out.pairs.extend(n.chunks(2).map(|p| (p[0], p[1])))
+                        }
+                        _ => out.bad = Some(format!("malformed GNU.sparse.map `{value}`")),
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(out)
+    }
+
+    fn is_sparse(&self) -> bool {
+        self.size.is_some() || self.major.is_some() || !self.pairs.is_empty()
+    }
+
+    /// The node for a member whose data starts at `at`, and the map still to
+    /// read for format 1.0.
+    fn kind(&self, at: u64) -> Result<(NodeKind, Option<Pending>), String> {
+        if let Some(why) = &self.bad {
+            return Err(why.clone());
+        }
+        let size = self
+            .size
+            .ok_or_else(|| "sparse member without GNU.sparse.size".to_string())?;
+        match self.major {
+            Some(1) => Ok((
+                NodeKind::Sparse {
+                    size,
+                    map: Vec::new(),
+                },
+                Some(Pending::PaxMap { at }),
+            )),
+            None | Some(0) => Ok((
+                NodeKind::Sparse {
+                    size,
+                    map: pack(&self.pairs, at),
+                },
+                None,
+            )),
+            Some(v) => Err(format!("unsupported GNU sparse format {v}")),
+        }
+    }
+}
+
+fn normal_components(path: &Path) -> impl Iterator<Item = &OsStr> {
+    path.components().filter_map(|c| match c {
+        Component::Normal(n) => Some(n),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{self, File},
+        os::unix::fs::FileExt,
+        process::Command,
+    };
+
+    use super::*;
+    use crate::codec::Codec;
+
+    /// A 3 MiB file: data at the start, 30 small runs, then a trailing hole.
+    /// More runs than fit in the old GNU header, so extension headers are used.
+    fn sparse_file(dir: &Path) -> Vec<u8> {
+        let f = File::create(dir.join("disk.raw")).unwrap();
+        f.set_len(3 << 20).unwrap();
+        f.write_all_at(&[b'A'; 5000], 0).unwrap();
+        for i in 1..=30u64 {
+            f.write_all_at(&[i as u8; 700], i * (64 << 10) + 100)
+                .unwrap();
+        }
+        fs::read(dir.join("disk.raw")).unwrap()
+    }
+
+    fn tar(dir: &Path, name: &str, args: &[&str]) -> PathBuf {
+        let out = dir.join(name);
+        let ok = Command::new("tar")
+            .args(["--sparse", "-C"])
+            .arg(dir)
+            .args(args)
+            .arg("-cf")
+            .arg(&out)
+            .arg("disk.raw")
+            .status()
+            .unwrap()
+            .success();
+        assert!(ok, "tar {args:?}");
+        out
+    }
+
+    fn read_back(archive: &Path) -> Vec<u8> {
+        let codec = Codec::detect(archive);
+        let mut idx = Index::from_tar(codec.open(archive).unwrap()).unwrap();
+        let mut source = ByteSource::open(archive, codec).unwrap();
+        idx.resolve_sparse(&mut source);
+        let ino = idx.lookup(ROOT_INO, OsStr::new("disk.raw")).unwrap();
+        let NodeKind::Sparse { size, map } = &idx.node(ino).unwrap().kind else {
+            panic!("{}: not indexed as sparse", archive.display());
+        };
+        // Past the old GNU header's 4 slots and one 21-slot extension.
+        assert!(
+            map.len() > 25,
+            "{}: {} segments",
+            archive.display(),
+            map.len()
+        );
+        // Odd-sized chunks so reads start inside holes and segments.
+        let mut out = vec![0u8; *size as usize];
+        for (i, chunk) in out.chunks_mut(77_777).enumerate() {
+            source.read_sparse(map, i as u64 * 77_777, chunk).unwrap();
+        }
+        out
+    }
+
+    #[test]
+    fn gnu_sparse_members_read_back_in_every_format() {
+        let dir = std::env::temp_dir().join(format!("data-backup-sparse-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let want = sparse_file(&dir);
+        let cases: [(&str, &[&str]); 5] = [
+            ("gnu.tar", &["--format=gnu"]),
+            ("pax00.tar", &["--format=posix", "--sparse-version=0.0"]),
+            ("pax01.tar", &["--format=posix", "--sparse-version=0.1"]),
+            ("pax10.tar", &["--format=posix", "--sparse-version=1.0"]),
+            (
+                "pax10.tar.gz",
+                &["--format=posix", "--sparse-version=1.0", "-z"],
+            ),
+        ];
+        for (name, args) in cases {
+            let got = read_back(&tar(&dir, name, args));
+            assert!(got == want, "{name}: contents differ");
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pax_sparse_without_size_is_listed_but_unreadable() {
+        let sparse = PaxSparse {
+            pairs: vec![(0, 10)],
+            ..Default::default()
+        };
+        assert!(sparse.is_sparse());
+        assert!(sparse.kind(512).is_err());
+    }
+}
@@ -0,0 +1,196 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+};
+
+use super::index::Segment;
+use crate::{
+    codec::{Codec, read_full},
+    dd::seekable::SeekableReader,
+};
+
+/// Decoders kept open on a plain compressed stream.
+const CURSORS: usize = 8;
+
+/// Snapshot bytes addressed by offset into the *decompressed* stream.
+pub enum ByteSource {
+    /// Uncompressed file: served with `pread`.
+    Plain(File),
+    /// zstd file with a seek table: one frame decompressed per read.
+    Seekable(SeekableReader),
+    /// Compressed stream: decoders can only read forward. A few are kept at
+    /// different offsets so interleaved reads of several files stay cheap; a
+    /// read behind all of them decompresses again from the top.
+    Stream {
+        path: PathBuf,
+        codec: Codec,
+        cursors: Vec<Cursor>,
+        /// Bumped on every read, for evicting the least recently used cursor.
+        clock: u64,
+    },
+}
+
+pub struct Cursor {
+    reader: Box<dyn Read>,
+    pos: u64,
+    used: u64,
+}
+
+impl ByteSource {
+    pub fn open(path: &Path, codec: Codec) -> io::Result<Self> {
+        if codec == Codec::Zstd
+            && let Some(r) = SeekableReader::open(File::open(path)?)?
+        {
+            return Ok(Self::Seekable(r));
+        }
+        Ok(match codec {
+            Codec::None => Self::Plain(File::open(path)?),
+            _ => Self::Stream {
+                path: path.to_path_buf(),
+                codec,
+                cursors: Vec::new(),
+                clock: 0,
+            },
+        })
+    }
+
+    /// Whether reads at arbitrary offsets are cheap.
+    pub fn is_random_access(&self) -> bool {
+        !matches!(self, Self::Stream { .. })
+    }
+
+    /// Size of the decompressed data; unknown for plain compressed streams.
+    pub fn len(&self) -> io::Result<u64> {
+        match self {
+            Self::Plain(f) => Ok(f.metadata()?.len()),
+            Self::Seekable(r) => Ok(r.len()),
+            Self::Stream { .. } => Err(io::Error::other("stream length is unknown")),
+        }
+    }
+
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(f) => {
+                let mut filled = 0;
+                while filled < buf.len() {
+                    let n = f.read_at(&mut buf[filled..], offset + filled as u64)?;
+                    if n == 0 {
+                        break;
+                    }
+                    filled += n;
+                }
+                Ok(filled)
+            }
+            Self::Seekable(r) => r.read_at(offset, buf),
+            Self::Stream {
+                path,
+                codec,
+                cursors,
+                clock,
+            } => {
+                *clock += 1;
+                let nearest = cursors
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, c)| c.pos <= offset)
+                    .max_by_key(|(_, c)| c.pos)
+                    .map(|(i, _)| i);
+                let i = match nearest {
+                    Some(i) => i,
+                    None => {
+                        if offset > 0 {
+                            log::debug!(
+                                "{}: restarting decompression to reach offset {offset}",
+                                path.display()
+                            );
+                        }
+                        let fresh = Cursor {
+                            reader: codec.open(&*path)?,
+                            pos: 0,
+                            used: 0,
+                        };
+                        if cursors.len() < CURSORS {
+                            cursors.push(fresh);
+                            cursors.len() - 1
+                        } else {
+                            let lru = (0..cursors.len())
+                                .min_by_key(|&i| cursors[i].used)
+                                .expect("cursors is full");
+                            cursors[lru] = fresh;
+                            lru
+                        }
+                    }
+                };
+                let c = &mut cursors[i];
+                c.used = *clock;
+                let skip = offset - c.pos;
+                c.pos += io::copy(&mut (&mut c.reader).take(skip), &mut io::sink())?;
+                if c.pos < offset {
+                    return Ok(0);
+                }
+                let n = read_full(&mut c.reader, buf)?;
+                c.pos += n as u64;
+                Ok(n)
+            }
+        }
+    }
+
+    /// Fills `buf` from byte `offset` of a sparse member: data segments from
+    /// here, zeros in the holes between them.
+    pub fn read_sparse(&mut self, map: &[Segment], offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        buf.fill(0);
+        let end = offset + buf.len() as u64;
+        let first = map.partition_point(|s| s.offset + s.len <= offset);
+        for seg in map[first..].iter().take_while(|s| s.offset < end) {
+            let from = seg.offset.max(offset);
+            let to = (seg.offset + seg.len).min(end);
+            if from >= to {
+                continue;
+            }
+            let dst = &mut buf[(from - offset) as usize..(to - offset) as usize];
+            if self.read_at(seg.at + (from - seg.offset), dst)? < dst.len() {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use super::*;
+
+    #[test]
+    fn stream_reads_out_of_order_match_the_data() {
+        let path = std::env::temp_dir().join(format!("data-backup-stream-{}", std::process::id()));
+        let data: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        assert!(
+            Command::new("gzip")
+                .arg("-f")
+                .arg(&path)
+                .status()
+                .unwrap()
+                .success()
+        );
+        let gz = path.with_extension("gz");
+
+        let mut source = ByteSource::open(&gz, Codec::Gzip).unwrap();
+        assert!(!source.is_random_access());
+        let mut buf = [0u8; 4096];
+        for offset in [900_000, 10, 500_000, 20_000, 900_100, 0, 999_000] {
+            let n = source.read_at(offset, &mut buf).unwrap();
+            let end = (offset as usize + buf.len()).min(data.len());
+            assert_eq!(&buf[..n], &data[offset as usize..end], "@{offset}");
+        }
+        let ByteSource::Stream { cursors, .. } = &source else {
+            unreachable!()
+        };
+        assert!(cursors.len() <= CURSORS);
+        std::fs::remove_file(gz).unwrap();
+    }
+}
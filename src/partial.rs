@@ -0,0 +1,133 @@
+//! Crash-safe writes: data goes to `<name>.partial`, is synced, and is
+//! renamed into place only once it is known to be good. Anything still
+//! named `.partial` is an incomplete run.
+
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
+
+pub const SUFFIX: &str = ".partial";
+
+/// `<path>.partial`.
+pub fn path(final_path: &Path) -> PathBuf {
+    let mut name = final_path.as_os_str().to_owned();
+    name.push(SUFFIX);
+    PathBuf::from(name)
+}
+
+pub fn is_partial(name: &str) -> bool {
+    name.ends_with(SUFFIX)
+}
+
+/// Renames the (already synced) `<path>.partial` to `path` and syncs the
+/// directory so the rename itself survives a crash.
+pub fn commit(final_path: &Path) -> io::Result<()> {
+    fs::rename(path(final_path), final_path)?;
+    sync_dir(final_path)
+}
+
+/// Writes `data` to `path` atomically: readers see the old file or the new
+/// one, never a truncated mix.
+pub fn write(final_path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = path(final_path);
+    fs::write(&tmp, data)?;
+    File::open(&tmp)?.sync_all()?;
+    commit(final_path)
+}
+
+fn sync_dir(file: &Path) -> io::Result<()> {
+    let dir = match file.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use super::*;
+    use crate::{
+        storage::{LocalStorage, Storage},
+        testutil::TempDir,
+        volume::{VolumeWriter, volume_path},
+    };
+
+    fn names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Yields `n` bytes, then fails as a dropped connection would.
+    struct Cut(usize);
+
+    impl Read for Cut {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0 == 0 {
+                return Err(io::Error::new(io::ErrorKind::ConnectionReset, "cut"));
+            }
+            let n = self.0.min(buf.len());
+            buf[..n].fill(1);
+            self.0 -= n;
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn target_appears_only_on_commit() {
+        let dir = TempDir::new("partial-commit");
+        let target = dir.join("x.img");
+        let mut file = File::create(path(&target)).unwrap();
+        file.write_all(b"data").unwrap();
+        file.sync_all().unwrap();
+        assert!(!target.exists());
+        assert_eq!(names(dir.path()), ["x.img.partial"]);
+        commit(&target).unwrap();
+        assert_eq!(names(dir.path()), ["x.img"]);
+        assert_eq!(fs::read(&target).unwrap(), b"data");
+    }
+
+    #[test]
+    fn write_replaces_the_whole_file() {
+        let dir = TempDir::new("partial-write");
+        let target = dir.join("x.json");
+        fs::write(&target, "old and longer").unwrap();
+        write(&target, b"new").unwrap();
+        assert_eq!(fs::read(&target).unwrap(), b"new");
+        assert_eq!(names(dir.path()), ["x.json"]);
+    }
+
+    #[test]
+    fn interrupted_store_leaves_only_the_partial() {
+        let dir = TempDir::new("partial-store");
+        let store = LocalStorage::new(dir.path());
+        assert!(store.put("x.tar", &mut Cut(10_000), 20_000).is_err());
+        assert_eq!(names(dir.path()), ["x.tar.partial"]);
+        assert!(store.list().unwrap().iter().all(|o| is_partial(&o.key)));
+    }
+
+    #[test]
+    fn dropped_volume_writer_leaves_only_partials() {
+        let dir = TempDir::new("partial-volumes");
+        let base = dir.join("x.img");
+        let mut w = VolumeWriter::new(&base, 100);
+        w.write_all(&[1; 250]).unwrap();
+        drop(w);
+        assert_eq!(
+            names(dir.path()),
+            [
+                "x.img.001.partial",
+                "x.img.002.partial",
+                "x.img.003.partial"
+            ]
+        );
+        assert!(!volume_path(&base, 1).exists());
+    }
+}
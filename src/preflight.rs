@@ -0,0 +1,422 @@
+//! Checks run before any snapshot is taken, and by the `doctor` command:
+//! the remote tools the config needs, password-less sudo, and room for the
+//! snapshot on both ends. Each failure is a [`PreflightError`] saying what
+//! to fix.
+
+use std::{ffi::CString, os::unix::ffi::OsStrExt, path::Path};
+
+use indicatif::HumanBytes;
+
+use crate::{
+    config::Config,
+    dd::{Compression as DdCompression, DdBuilder, DdSnapshotConfig, Strategy, imager::Imager},
+    error::{AppError, PreflightError},
+    shell::{Cmd, quote},
+    ssh::Ssh,
+    tar::{Compression, Compressor, snapshot::SnapshotKind},
+    throttle::Limits,
+};
+
+/// Directories searched besides the login `PATH`, where admin tools live.
+pub(crate) const SBIN: &str = "/usr/local/sbin:/usr/sbin:/sbin";
+
+pub enum Outcome {
+    Pass(String),
+    /// Might still work; worth a look.
+    Warn(String),
+    Fail(PreflightError),
+}
+
+pub struct Finding {
+    pub check: String,
+    pub outcome: Outcome,
+}
+
+impl Finding {
+    fn new(check: impl Into<String>, outcome: Outcome) -> Self {
+        Self {
+            check: check.into(),
+            outcome,
+        }
+    }
+}
+
+/// Remote binary, and what it is needed for.
+struct Tool {
+    name: String,
+    needed_for: String,
+    /// Missing only degrades the run.
+    optional: bool,
+}
+
+impl Tool {
+    fn required(name: impl Into<String>, needed_for: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            needed_for: needed_for.into(),
+            optional: false,
+        }
+    }
+
+    fn optional(name: impl Into<String>, needed_for: impl Into<String>) -> Self {
+        Self {
+            optional: true,
+            ..Self::required(name, needed_for)
+        }
+    }
+}
+
+/// Logs every finding and fails with the first failed check.
+pub fn enforce(findings: Vec<Finding>) -> Result<(), AppError> {
+    let mut first = None;
+    for f in findings {
+        match f.outcome {
+            Outcome::Pass(detail) => log::info!("preflight {}: {detail}", f.check),
+            Outcome::Warn(msg) => log::warn!("preflight {}: {msg}", f.check),
+            Outcome::Fail(e) => {
+                log::error!("preflight {}: {e}", f.check);
+                first.get_or_insert(e);
+            }
+        }
+    }
+    match first {
+        Some(e) => Err(e.into()),
+        None => Ok(()),
+    }
+}
+
+/// `doctor`: connects as a backup would, runs every check and prints the
+/// results. Fails when any check failed.
+pub fn doctor(cfg: &Config) -> Result<(), AppError> {
+    let findings = match cfg.mode.as_str() {
+        "tar" => {
+            log::info!("Connecting to {}", cfg.remote.host);
+            let ssh = Ssh::connect_remote(&cfg.remote)?;
+            tar_checks(cfg, &ssh)?
+        }
+        "dd" => {
+            log::info!("Connecting to {}", cfg.remote.host);
+            let ssh = Ssh::connect_remote(&cfg.remote)?;
+            let mut findings = dd_checks(cfg, &ssh)?;
+            // Probing the device needs what was just checked.
+            if !findings
+                .iter()
+                .any(|f| matches!(f.outcome, Outcome::Fail(_)))
+            {
+                match DdBuilder::new(cfg).build_on(ssh) {
+                    Ok(dd) => findings.extend(dd_target_checks(cfg, &dd)?),
+                    Err(e) => findings.push(Finding::new(
+                        "device",
+                        Outcome::Fail(PreflightError::Device(e.to_string())),
+                    )),
+                }
+            }
+            findings
+        }
+        other => return Err(AppError::Validation(format!("Invalid mode: {other}"))),
+    };
+    let mut first = None;
+    for f in findings {
+        let (mark, text) = match f.outcome {
+            Outcome::Pass(detail) => ("ok  ", detail),
+            Outcome::Warn(msg) => ("warn", msg),
+            Outcome::Fail(e) => {
+                let text = e.to_string();
+                first.get_or_insert(e);
+                ("FAIL", text)
+            }
+        };
+        println!("[{mark}] {:<14} {text}", f.check);
+    }
+    match first {
+        Some(e) => Err(e.into()),
+        None => Ok(()),
+    }
+}
+
+/// Tools, sudo, and space in `backup.dir` (and locally when downloading)
+/// for the estimated size of the filesystems.
+pub fn tar_checks(cfg: &Config, ssh: &Ssh) -> Result<Vec<Finding>, AppError> {
+    let compressor = Compressor::new(
+        Compression::parse(&cfg.backup.compression)?,
+        cfg.backup.compression_level,
+        cfg.backup.compression_threads,
+    )?;
+    let mut tools = vec![Tool::required("tar", "creating the archive")];
+    if let Some(tool) = compressor.tool() {
+        tools.push(Tool::required(tool, "backup.compression"));
+    }
+    match SnapshotKind::parse(&cfg.backup.snapshot, &cfg.backup.snapshot_size)? {
+        SnapshotKind::None => {}
+        SnapshotKind::Lvm { .. } => {
+            tools.push(Tool::required("findmnt", "backup.snapshot"));
+            tools.push(Tool::required("lvcreate", "backup.snapshot = \"lvm\""));
+        }
+        SnapshotKind::Btrfs => {
+            tools.push(Tool::required("findmnt", "backup.snapshot"));
+            tools.push(Tool::required("btrfs", "backup.snapshot = \"btrfs\""));
+        }
+    }
+    tools.extend(throttle_tools(cfg)?);
+
+    let mut out = vec![check_sudo(ssh, &cfg.remote.user)];
+    out.extend(check_tools(ssh, &tools)?);
+
+    let dir = &cfg.backup.dir;
+    let paths: Vec<String> = cfg.filesystems.iter().map(ToString::to_string).collect();
+    let estimate = estimate_tar(ssh, &paths);
+    let compressed = compressor.codec != Compression::None;
+    match remote_avail(ssh, dir) {
+        Err(e) => out.push(Finding::new("remote space", Outcome::Fail(e))),
+        Ok(avail) => out.push(space(
+            "remote space",
+            &format!("remote {dir}"),
+            estimate,
+            avail,
+            compressed,
+        )),
+    }
+    if cfg.options.download_to_local {
+        let local = &cfg.options.local_download_dir;
+        out.push(space(
+            "local space",
+            &format!("local {local}"),
+            estimate,
+            local_avail(Path::new(local))?,
+            compressed,
+        ));
+    }
+    Ok(out)
+}
+
+/// Sudo and the tools the `[dd]` settings need, before the device is probed
+/// (which already runs `lsblk` and `sfdisk`).
+pub fn dd_checks(cfg: &Config, ssh: &Ssh) -> Result<Vec<Finding>, AppError> {
+    let dd = cfg.dd.as_ref();
+    let sudo = dd.is_none_or(|d| d.sudo);
+    let compression = DdCompression::parse(dd.map_or("none", |d| d.compression.as_str()));
+    let consistency = Strategy::parse(
+        dd.map_or("none", |d| d.consistency.as_str()),
+        dd.map_or("10%ORIGIN", |d| d.snapshot_size.as_str()),
+    )?;
+
+    let mut tools = vec![
+        Tool::required("lsblk", "finding the device"),
+        Tool::required("blockdev", "sizing the device"),
+        Tool::optional("sfdisk", "saving the partition table"),
+    ];
+    if let Some(tool) = compression.tool() {
+        tools.push(Tool::required(tool, "dd.compression"));
+    }
+    if matches!(compression, DdCompression::SeekableZstd) {
+        tools.push(Tool::required(
+            "split",
+            "dd.compression = \"zstd-seekable\"",
+        ));
+    }
+    match consistency {
+        Strategy::None => {}
+        Strategy::Lvm { .. } => tools.push(Tool::required("lvcreate", "dd.consistency")),
+        Strategy::Zfs => tools.push(Tool::required("zfs", "dd.consistency")),
+        Strategy::Fsfreeze => tools.push(Tool::required("fsfreeze", "dd.consistency")),
+    }
+    tools.extend(throttle_tools(cfg)?);
+
+    let mut out = Vec::new();
+    if sudo {
+        out.push(check_sudo(ssh, &cfg.remote.user));
+    }
+    out.extend(check_tools(ssh, &tools)?);
+    Ok(out)
+}
+
+/// The imagers picked for each device, and local space for the images.
+pub fn dd_target_checks(cfg: &Config, dd: &DdSnapshotConfig) -> Result<Vec<Finding>, AppError> {
+    let tools: Vec<Tool> = dd
+        .targets
+        .iter()
+        .map(|t| Tool::required(t.imager.to_string(), "dd.imager"))
+        .collect();
+    let mut out = check_tools(&dd.ssh, &tools)?;
+
+    // Raw images take the whole device; anything else (compressed, used
+    // blocks only) is smaller by an unknown amount.
+    let mut need = Some(0u64);
+    let mut exact = true;
+    for t in &dd.targets {
+        let dev = t.device.dev_path();
+        let size = Cmd::new("blockdev")
+            .sudo(dd.sudo)
+            .arg("--getsize64")
+            .arg(dev.as_str());
+        let mut buf = Vec::new();
+        let bytes = match dd.ssh.exec_capture(&size.to_string(), &mut buf) {
+            Ok(()) => String::from_utf8_lossy(&buf).trim().parse::<u64>().ok(),
+            Err(e) => {
+                out.push(Finding::new(
+                    "device size",
+                    Outcome::Fail(PreflightError::Device(format!(
+                        "blockdev --getsize64 {dev} failed: {e}"
+                    ))),
+                ));
+                None
+            }
+        };
+        need = need.zip(bytes).map(|(n, b)| n + b);
+        exact &= matches!(t.imager, Imager::Dd);
+    }
+    exact &= matches!(dd.compression, DdCompression::None) && !dd.sparse;
+    let local = &cfg.options.local_download_dir;
+    out.push(space(
+        "local space",
+        &format!("local {local}"),
+        need,
+        local_avail(Path::new(local))?,
+        !exact,
+    ));
+    Ok(out)
+}
+
+fn throttle_tools(cfg: &Config) -> Result<Vec<Tool>, AppError> {
+    let t = &cfg.throttle;
+    let mut tools = Vec::new();
+    if t.remote && Limits::from_config(t)?.rate_now() > 0 {
+        tools.push(Tool::required("pv", "throttle.remote"));
+    }
+    if t.nice.is_some() {
+        tools.push(Tool::optional("renice", "throttle.nice"));
+    }
+    if t.ionice.is_some() {
+        tools.push(Tool::optional("ionice", "throttle.ionice"));
+    }
+    Ok(tools)
+}
+
+fn check_sudo(ssh: &Ssh, user: &str) -> Finding {
+    match ssh.exec_capture("sudo -n true", &mut std::io::sink()) {
+        Ok(()) => Finding::new("sudo", Outcome::Pass("works without a password".into())),
+        Err(_) => Finding::new(
+            "sudo",
+            Outcome::Fail(PreflightError::SudoPassword { user: user.into() }),
+        ),
+    }
+}
+
+/// One round trip for all tools: prints the names not found.
+fn check_tools(ssh: &Ssh, tools: &[Tool]) -> Result<Vec<Finding>, AppError> {
+    let names: Vec<String> = tools.iter().map(|t| quote(&t.name)).collect();
+    let script = format!(
+        "PATH=\"$PATH:{SBIN}\"; for t in {}; do command -v \"$t\" >/dev/null 2>&1 || echo \"$t\"; done",
+        names.join(" ")
+    );
+    let mut out = Vec::new();
+    ssh.exec_capture(&format!("sh -c {}", quote(&script)), &mut out)?;
+    let missing = String::from_utf8_lossy(&out);
+    let missing: Vec<&str> = missing.lines().map(str::trim).collect();
+
+    let mut seen = Vec::new();
+    let mut findings = Vec::new();
+    for tool in tools {
+        if seen.contains(&tool.name) {
+            continue;
+        }
+        seen.push(tool.name.clone());
+        let check = format!("tool {}", tool.name);
+        let outcome = if !missing.contains(&tool.name.as_str()) {
+            Outcome::Pass(format!("found ({})", tool.needed_for))
+        } else if tool.optional {
+            Outcome::Warn(format!(
+                "not installed; {} will have no effect",
+                tool.needed_for
+            ))
+        } else {
+            Outcome::Fail(PreflightError::MissingTool {
+                tool: tool.name.clone(),
+                needed_for: tool.needed_for.clone(),
+            })
+        };
+        findings.push(Finding::new(check, outcome));
+    }
+    Ok(findings)
+}
+
+/// Bytes used under `paths`, one filesystem each; `None` if `du` failed.
+fn estimate_tar(ssh: &Ssh, paths: &[String]) -> Option<u64> {
+    let du = Cmd::new("du")
+        .sudo(true)
+        .args(["-sxb", "--"])
+        .args(paths.iter().cloned());
+    let mut out = Vec::new();
+    if let Err(e) = ssh.exec_capture(&du.to_string(), &mut out) {
+        log::warn!("cannot estimate the archive size: {e}");
+        return None;
+    }
+    Some(
+        String::from_utf8_lossy(&out)
+            .lines()
+            .filter_map(|l| l.split_whitespace().next()?.parse::<u64>().ok())
+            .sum(),
+    )
+}
+
+fn remote_avail(ssh: &Ssh, dir: &str) -> Result<u64, PreflightError> {
+    let script = format!("test -d {d} && df -P -B1 {d}", d = quote(dir));
+    let mut out = Vec::new();
+    if ssh.exec_capture(&script, &mut out).is_err() {
+        return Err(PreflightError::MissingDir(dir.into()));
+    }
+    // Filesystem 1-blocks Used Available Capacity Mounted-on
+    String::from_utf8_lossy(&out)
+        .lines()
+        .nth(1)
+        .and_then(|l| l.split_whitespace().nth(3)?.parse().ok())
+        .ok_or_else(|| PreflightError::MissingDir(dir.into()))
+}
+
+/// Free bytes for an unprivileged user on the filesystem holding `dir`, or
+/// its nearest existing parent.
+fn local_avail(dir: &Path) -> Result<u64, AppError> {
+    let mut dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    while !dir.exists() {
+        dir = dir.parent().unwrap_or(Path::new("."));
+    }
+    let c = CString::new(dir.as_os_str().as_bytes())
+        .map_err(|_| AppError::Validation(format!("bad path {}", dir.display())))?;
+    let mut st: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c.as_ptr(), &mut st) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(st.f_bavail as u64 * st.f_frsize as u64)
+}
+
+/// With `upper_bound`, `need` over-estimates (compression), so a shortfall
+/// is only a warning.
+fn space(check: &str, place: &str, need: Option<u64>, avail: u64, upper_bound: bool) -> Finding {
+    let Some(need) = need else {
+        return Finding::new(
+            check,
+            Outcome::Warn(format!("size unknown; {} free", HumanBytes(avail))),
+        );
+    };
+    let detail = format!(
+        "{} free, about {} needed",
+        HumanBytes(avail),
+        HumanBytes(need)
+    );
+    let outcome = if avail >= need {
+        Outcome::Pass(detail)
+    } else if upper_bound {
+        Outcome::Warn(format!("{detail} before compression; may not fit"))
+    } else {
+        Outcome::Fail(PreflightError::NoSpace {
+            place: place.into(),
+            need: HumanBytes(need).to_string(),
+            avail: HumanBytes(avail).to_string(),
+        })
+    };
+    Finding::new(check, outcome)
+}
@@ -0,0 +1,215 @@
+//! Copies snapshots from the primary storage to the `[[replicas]]` targets
+//! and checks each copy's hash (`replicate` command, and after a backup).
+
+use std::{collections::HashSet, io::Read, path::Path};
+
+use chrono::Utc;
+
+use crate::{
+    catalog::{Catalog, ReplicaState, ReplicaStatus},
+    config::{Config, ReplicaConfig, StorageConfig},
+    error::AppError,
+    storage::{self, Storage, Stored},
+    verify,
+};
+
+/// Brings every replica (or only `only`) up to date with the primary storage.
+pub fn run(cfg: &Config, only: Option<&str>) -> Result<(), AppError> {
+    if let Some(name) = only
+        && !cfg.replicas.iter().any(|r| r.name == name)
+    {
+        return Err(AppError::Validation(format!("no replica named `{name}`")));
+    }
+    let primary = storage::open(cfg)?;
+    let all = storage::snapshots(primary.as_ref())?;
+    log::info!("{} snapshots in {}", all.len(), primary.describe());
+    let failed = replicate(cfg, primary.as_ref(), &all, only)?;
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(AppError::Storage(format!(
+            "replication failed: {}",
+            failed.join(", ")
+        )))
+    }
+}
+
+/// Copies `snapshots` to the replicas that do not hold a verified copy yet
+/// and records the outcome in the catalog. Returns the copies that failed;
+/// errors are left for the catalog itself.
+pub fn replicate(
+    cfg: &Config,
+    primary: &dyn Storage,
+    snapshots: &[Stored],
+    only: Option<&str>,
+) -> Result<Vec<String>, AppError> {
+    let catalog_path = Path::new(&cfg.options.catalog);
+    let mut catalog = Catalog::load(catalog_path)?;
+    for s in snapshots {
+        catalog.entry(s, &primary.describe());
+    }
+
+    let mut failed = Vec::new();
+    for replica in cfg
+        .replicas
+        .iter()
+        .filter(|r| only.is_none_or(|n| n == r.name))
+    {
+        let opened = open(cfg, replica).and_then(|t| {
+            let present: HashSet<String> = t.list()?.into_iter().map(|o| o.key).collect();
+            Ok((t, present))
+        });
+        let (target, present) = match opened {
+            Ok(t) => t,
+            Err(e) => {
+                log::error!("replica {}: {e}", replica.name);
+                failed.push(replica.name.clone());
+                // A copy verified before stays so; the others are marked failed.
+                for s in snapshots {
+                    let entry = catalog.entry(s, &primary.describe());
+                    if entry
+                        .replicas
+                        .get(&replica.name)
+                        .is_some_and(|r| r.state == ReplicaState::Verified)
+                    {
+                        continue;
+                    }
+                    entry.replicas.insert(
+                        replica.name.clone(),
+                        ReplicaStatus {
+                            state: ReplicaState::Failed,
+                            target: describe(&replica.target),
+                            checked_at: Utc::now(),
+                            error: Some(e.to_string()),
+                        },
+                    );
+                }
+                catalog.save(catalog_path)?;
+                continue;
+            }
+        };
+
+        for s in snapshots {
+            let entry = catalog.entry(s, &primary.describe());
+            let known_good = entry
+                .replicas
+                .get(&replica.name)
+                .is_some_and(|r| r.state == ReplicaState::Verified);
+            let complete =
+                present.contains(&s.meta_key) && s.data_keys().iter().all(|k| present.contains(k));
+            if complete && known_good {
+                continue;
+            }
+
+            let res = sync_one(primary, target.as_ref(), s, complete);
+            let status = ReplicaStatus {
+                state: if res.is_ok() {
+                    ReplicaState::Verified
+                } else {
+                    ReplicaState::Failed
+                },
+                target: target.describe(),
+                checked_at: Utc::now(),
+                error: res.as_ref().err().map(ToString::to_string),
+            };
+            match &res {
+                Ok(()) => log::info!("{} replicated to {}", s.name, replica.name),
+                Err(e) => {
+                    log::error!("{} -> {}: {e}", s.name, replica.name);
+                    failed.push(format!("{} -> {}", s.name, replica.name));
+                }
+            }
+            catalog
+                .entry(s, &primary.describe())
+                .replicas
+                .insert(replica.name.clone(), status);
+            // Saved as we go, so an interrupted run keeps what it did.
+            catalog.save(catalog_path)?;
+        }
+    }
+    catalog.save(catalog_path)?;
+    Ok(failed)
+}
+
+/// What a target would call itself, for one that could not be opened.
+fn describe(t: &StorageConfig) -> String {
+    format!("{} {}", t.backend, t.path)
+}
+
+fn open(cfg: &Config, replica: &ReplicaConfig) -> Result<Box<dyn Storage>, AppError> {
+    let t = &replica.target;
+    if t.backend.eq_ignore_ascii_case("local") && t.path.is_empty() {
+        return Err(AppError::Validation(format!(
+            "local replica `{}` needs a `path`",
+            replica.name
+        )));
+    }
+    storage::open_target(t, &cfg.options.local_download_dir)
+}
+
+/// A copy that is already there is only checked; a missing or damaged one
+/// is (re)written, data first and metadata last, then checked.
+fn sync_one(
+    primary: &dyn Storage,
+    target: &dyn Storage,
+    s: &Stored,
+    present: bool,
+) -> Result<(), AppError> {
+    if present && verify::check_stored(target, s).is_ok() {
+        return Ok(());
+    }
+    let sizes: Vec<(String, u64)> = if s.meta.volumes.is_empty() {
+        vec![(s.name.clone(), s.size)]
+    } else {
+        s.meta
+            .volumes
+            .iter()
+            .map(|v| (v.file.clone(), v.bytes))
+            .collect()
+    };
+    for (key, len) in &sizes {
+        log::info!("Copying {key} ({len} bytes) to {}", target.describe());
+        target.put(key, &mut primary.get(key)?, *len)?;
+    }
+    if let Some(key) = &s.inventory_key {
+        let mut inv = Vec::new();
+        primary.get(key)?.read_to_end(&mut inv)?;
+        target.put(key, &mut inv.as_slice(), inv.len() as u64)?;
+    }
+    let mut meta = Vec::new();
+    primary.get(&s.meta_key)?.read_to_end(&mut meta)?;
+    target.put(&s.meta_key, &mut meta.as_slice(), meta.len() as u64)?;
+    verify::check_stored(target, s)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+    use crate::testutil::{TempDir, config, tar_snapshot};
+
+    #[test]
+    fn unreachable_replica_is_recorded_not_raised() {
+        let dir = TempDir::new("replicate");
+        let cfg = config(
+            dir.path(),
+            r#"
+            [[replicas]]
+            name = "offsite"
+            backend = "local"
+            "#,
+        );
+        let at = Utc.with_ymd_and_hms(2026, 10, 18, 2, 0, 0).unwrap();
+        tar_snapshot(&dir.join("store"), "a.tar", "10.0.0.1", &["/etc"], at);
+
+        let store = storage::open(&cfg).unwrap();
+        let all = storage::snapshots(store.as_ref()).unwrap();
+        let failed = replicate(&cfg, store.as_ref(), &all, None).unwrap();
+        assert_eq!(failed, ["offsite"]);
+        let catalog = Catalog::load(&dir.join("catalog.json")).unwrap();
+        let status = &catalog.snapshots["a.tar"].replicas["offsite"];
+        assert_eq!(status.state, ReplicaState::Failed);
+        assert!(status.error.as_deref().unwrap().contains("needs a `path`"));
+    }
+}
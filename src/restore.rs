@@ -0,0 +1,85 @@
+//! Write a local snapshot back onto the remote host (`restore` command).
+
+mod archive; // tar snapshots --> `tar -x` under a remote directory
+mod database; // database dumps --> the engine's client
+mod image; // dd images --> block device, through the imager that made them
+
+use std::{fmt, io::Read, path::Path};
+
+use crate::{
+    codec::Codec,
+    config::Config,
+    error::AppError,
+    metadata::{
+        SnapshotRecord,
+        sidecar::{self, SidecarKind},
+    },
+    storage,
+    volume::{self, VolumeInfo},
+};
+
+pub fn run(cfg: &Config, snapshot: &Path, target: &str, yes: bool) -> Result<(), AppError> {
+    // The first volume of a split snapshot stands for the whole of it.
+    let mut snapshot = volume::base_of(snapshot);
+    if sidecar::locate(&snapshot).is_err() {
+        // Not here: stage it from the configured backend.
+        let store = storage::open(cfg)?;
+        let dir = Path::new(&cfg.options.local_download_dir);
+        snapshot = storage::fetch(store.as_ref(), &storage::file_name(&snapshot), dir)?;
+    }
+    let meta_path = sidecar::locate(&snapshot)?;
+    let meta = SnapshotRecord::load(&meta_path)?;
+    match (meta.mode, &meta.tar, &meta.dd, &meta.database) {
+        (SidecarKind::Image, _, Some(dd), _) => {
+            image::restore(cfg, &snapshot, &meta, dd, target, yes)
+        }
+        (SidecarKind::Database, _, _, Some(db)) => {
+            database::restore(cfg, &snapshot, &meta, db, target, yes)
+        }
+        (SidecarKind::Archive, Some(tar), _, _) => {
+            archive::restore(cfg, &snapshot, &meta, tar, target, yes)
+        }
+        (mode, ..) => Err(AppError::Validation(format!(
+            "{}: mode {} without its section",
+            meta_path.display(),
+            mode.name()
+        ))),
+    }
+}
+
+/// Checks `snapshot` (reassembled from `volumes` if it was split) against
+/// its recorded hash, then opens it for sending. Returns the reader and length.
+fn open_verified(
+    snapshot: &Path,
+    volumes: &[VolumeInfo],
+    sha256: &str,
+) -> Result<(Box<dyn Read>, u64), AppError> {
+    let (reader, _) = volume::open(snapshot, volumes)?;
+    let sha = volume::sha256_reader(reader)?;
+    if sha != sha256 {
+        return Err(AppError::Validation(format!(
+            "{} does not match its recorded sha256 ({sha} != {sha256})",
+            snapshot.display()
+        )));
+    }
+    Ok(volume::open(snapshot, volumes)?)
+}
+
+/// `cmd` fed with the decompressed contents of `snapshot` on stdin.
+fn decompress_into(snapshot: &Path, cmd: impl fmt::Display) -> String {
+    match Codec::detect(snapshot).decompress_cmd() {
+        Some(dec) => format!("{dec} | {cmd}"),
+        None => cmd.to_string(),
+    }
+}
+
+/// Destructive restores need an explicit `--yes`.
+fn confirm(yes: bool, what: &str) -> Result<(), AppError> {
+    if yes {
+        Ok(())
+    } else {
+        Err(AppError::Validation(format!(
+            "refusing to overwrite {what} without --yes"
+        )))
+    }
+}
@@ -0,0 +1,35 @@
+use std::path::Path;
+
+use crate::{
+    config::Config,
+    error::AppError,
+    metadata::{SnapshotRecord, record::TarSection},
+    shell::Cmd,
+    ssh::Ssh,
+};
+
+/// Extracts a tar snapshot into the remote directory `target`, with the
+/// ACL / xattr / SELinux / ownership switches it was created with.
+pub fn restore(
+    cfg: &Config,
+    archive: &Path,
+    record: &SnapshotRecord,
+    meta: &TarSection,
+    target: &str,
+    yes: bool,
+) -> Result<(), AppError> {
+    let (mut input, len) = super::open_verified(archive, &record.volumes, &record.hashes.sha256)?;
+    super::confirm(yes, &format!("files under {target} on {}", cfg.remote.host))?;
+
+    let tar = Cmd::new("tar")
+        .sudo(true)
+        .args(["-x", "-p", "-f", "-", "-C", target])
+        .args(meta.options.extract_args());
+    let cmd = super::decompress_into(archive, tar);
+
+    let ssh = Ssh::connect_remote(&cfg.remote)?;
+    log::info!("Restoring {} into {target}: {cmd}", archive.display());
+    let sent = ssh.exec_stdin(&cmd, &mut input, len)?;
+    log::info!("Restore complete, {sent} bytes sent");
+    Ok(())
+}
@@ -0,0 +1,79 @@
+use std::{
+    io::{Cursor, Read},
+    path::Path,
+};
+
+use crate::{
+    codec::Codec,
+    config::Config,
+    db::engine::{Database, DumpFormat, Engine},
+    error::AppError,
+    metadata::{SnapshotRecord, record::DatabaseSection},
+    shell::quote,
+    ssh::Ssh,
+};
+
+/// Feeds a dump back through the engine's client. `target` is a database
+/// name, or the database / RDB file path for SQLite and Redis.
+pub fn restore(
+    cfg: &Config,
+    dump: &Path,
+    record: &SnapshotRecord,
+    meta: &DatabaseSection,
+    target: &str,
+    yes: bool,
+) -> Result<(), AppError> {
+    let (mut input, len) = super::open_verified(dump, &record.volumes, &record.hashes.sha256)?;
+
+    let engine = Engine::parse(&meta.engine)?;
+    // Connection settings come from the matching `[[databases]]` entry, if any.
+    let db = match cfg
+        .databases
+        .iter()
+        .find(|c| Engine::parse(&c.engine).ok() == Some(engine) && c.name == meta.database)
+    {
+        Some(c) => Database::from_config(c)?,
+        None => Database::bare(engine, &meta.database, meta.format),
+    };
+    super::confirm(
+        yes,
+        &format!("{} `{target}` on {}", engine.name(), cfg.remote.host),
+    )?;
+
+    let ssh = Ssh::connect_remote(&cfg.remote)?;
+    if engine == Engine::Sqlite {
+        // A `.dump` script replayed into an existing database would clash with its tables.
+        let mut out = Vec::new();
+        if ssh
+            .exec_capture(&format!("test -e {}", quote(target)), &mut out)
+            .is_ok()
+        {
+            return Err(AppError::Validation(format!(
+                "{target} already exists; SQLite dumps restore into a new file"
+            )));
+        }
+    }
+
+    // Decompressed after the client's shell has read the password line.
+    let cmd = match Codec::detect(dump).decompress_cmd() {
+        Some(dec) => db.restore_cmd(target).input(dec),
+        None => db.restore_cmd(target),
+    };
+    log::info!(
+        "Restoring {} ({} {:?}) into {target}: {cmd}",
+        dump.display(),
+        engine.name(),
+        meta.format
+    );
+    let secret = db.stdin_prefix();
+    let mut stdin = Cursor::new(&secret).chain(&mut input);
+    let sent = ssh.exec_stdin(&cmd.to_string(), &mut stdin, len + secret.len() as u64)?;
+    log::info!(
+        "Restore complete, {} bytes sent",
+        sent - secret.len() as u64
+    );
+    if meta.format == DumpFormat::Rdb {
+        log::info!("Start redis with `dbfilename`/`dir` pointing at {target} to load it");
+    }
+    Ok(())
+}
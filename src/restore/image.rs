@@ -0,0 +1,39 @@
+use std::path::Path;
+
+use crate::{
+    config::Config,
+    dd::imager::Imager,
+    error::AppError,
+    metadata::{SnapshotRecord, record::DdSection},
+    ssh::Ssh,
+};
+
+pub fn restore(
+    cfg: &Config,
+    image: &Path,
+    record: &SnapshotRecord,
+    meta: &DdSection,
+    device: &str,
+    yes: bool,
+) -> Result<(), AppError> {
+    let (mut input, len) = super::open_verified(image, &record.volumes, &record.hashes.sha256)?;
+
+    let imager = Imager::from_name(&meta.imager)?;
+    super::confirm(yes, &format!("{device} on {}", cfg.remote.host))?;
+
+    let dd = cfg.dd.as_ref();
+    let sudo = dd.map(|c| c.sudo).unwrap_or(true);
+    let block_size = dd.map(|c| c.block_size).unwrap_or(64 * 1024);
+
+    let cmd = super::decompress_into(image, imager.restore_cmd(device, block_size, sudo));
+
+    let ssh = Ssh::connect_remote(&cfg.remote)?;
+    log::info!(
+        "Restoring {} ({imager}, {:?}) onto {device}: {cmd}",
+        image.display(),
+        meta.image_format
+    );
+    let sent = ssh.exec_stdin(&cmd, &mut input, len)?;
+    log::info!("Restore complete, {sent} bytes sent");
+    Ok(())
+}
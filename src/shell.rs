@@ -0,0 +1,332 @@
+//! Remote command lines built from argv and quoted for POSIX `sh`.
+//!
+//! Every word is quoted on its own, so paths with spaces, quotes or `$` reach
+//! the remote program unchanged instead of being split or expanded.
+
+use std::{ffi::OsStr, fmt, os::unix::ffi::OsStrExt};
+
+/// One remote program invocation.
+#[derive(Debug, Clone, Default)]
+pub struct Cmd {
+    sudo: bool,
+    sudo_user: Option<String>,
+    env: Vec<(String, String)>,
+    /// Variable read from the first line of stdin.
+    secret: Option<String>,
+    /// Filter stdin passes through first.
+    input: Option<String>,
+    /// Already quoted.
+    argv: Vec<String>,
+}
+
+impl Cmd {
+    pub fn new(program: impl Into<String>) -> Self {
+        Self {
+            argv: vec![quote(&program.into())],
+            ..Self::default()
+        }
+    }
+
+    /// Prefix with `sudo` when `on`.
+    pub fn sudo(mut self, on: bool) -> Self {
+        self.sudo = on;
+        self
+    }
+
+    /// Run as `user` through `sudo -u`.
+    pub fn sudo_user(mut self, user: Option<&str>) -> Self {
+        self.sudo_user = user.map(String::from);
+        self
+    }
+
+    /// Set a variable for the program only (`env NAME=value`).
+    pub fn env(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.env.push((name.into(), value.into()));
+        self
+    }
+
+    /// Read variable `name` from the first line of stdin and export it, so
+    /// its value never shows on a command line; the caller sends it first.
+    pub fn secret_env(mut self, name: impl Into<String>) -> Self {
+        self.secret = Some(name.into());
+        self
+    }
+
+    /// Pass stdin through `filter`, e.g. a decompressor, before the program.
+    /// It runs after the secret is read, so it sees only the rest of stdin.
+    pub fn input(mut self, filter: impl fmt::Display) -> Self {
+        self.input = Some(filter.to_string());
+        self
+    }
+
+    pub fn arg(mut self, arg: impl Into<String>) -> Self {
+        self.argv.push(quote(&arg.into()));
+        self
+    }
+
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.argv.extend(args.into_iter().map(|a| quote(&a.into())));
+        self
+    }
+
+    /// A path, passed on byte for byte even when it is not UTF-8.
+    pub fn path(mut self, path: impl AsRef<OsStr>) -> Self {
+        self.argv.push(quote_os(path.as_ref()));
+        self
+    }
+
+    pub fn paths<I, P>(mut self, paths: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<OsStr>,
+    {
+        self.argv
+            .extend(paths.into_iter().map(|p| quote_os(p.as_ref())));
+        self
+    }
+
+    /// `name=value` as one word, e.g. `if=/dev/sda` or `--exclude=*.log`.
+    pub fn opt(self, name: &str, value: impl fmt::Display) -> Self {
+        self.arg(format!("{name}={value}"))
+    }
+
+    /// `self | next`, as a shell string.
+    pub fn pipe(&self, next: impl fmt::Display) -> String {
+        format!("{self} | {next}")
+    }
+}
+
+impl fmt::Display for Cmd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut words = Vec::new();
+        if self.sudo || self.sudo_user.is_some() {
+            words.push("sudo".to_string());
+        }
+        if let Some(user) = &self.sudo_user {
+            words.push("-u".into());
+            words.push(quote(user));
+        }
+        let mut program = Vec::new();
+        if !self.env.is_empty() {
+            program.push("env".to_string());
+            for (k, v) in &self.env {
+                program.push(format!("{k}={}", quote(v)));
+            }
+        }
+        program.extend(self.argv.iter().cloned());
+        let program = program.join(" ");
+        let input = self
+            .input
+            .as_ref()
+            .map(|i| format!("{i} | "))
+            .unwrap_or_default();
+        match &self.secret {
+            // `read` takes stdin a byte at a time, leaving the rest for the program.
+            Some(var) => {
+                let script = format!("IFS= read -r {var} && export {var} && {input}exec {program}");
+                words.extend(["sh".into(), "-c".into(), quote(&script)]);
+                write!(f, "{}", words.join(" "))
+            }
+            None => {
+                words.push(program);
+                write!(f, "{input}{}", words.join(" "))
+            }
+        }
+    }
+}
+
+/// Quotes `s` as a single `sh` word; plain words are left readable.
+pub fn quote(s: &str) -> String {
+    let plain = !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"_-+=:,./@%".contains(&b));
+    if plain {
+        s.to_string()
+    } else {
+        format!("'{}'", s.replace('\'', r"'\''"))
+    }
+}
+
+/// Like [`quote`], for bytes that may not be UTF-8. Invalid bytes cannot be
+/// sent in the command string, so each becomes `"$(printf '\NNN')"`.
+pub fn quote_os(s: &OsStr) -> String {
+    if let Some(s) = s.to_str() {
+        return quote(s);
+    }
+    let mut word = String::new();
+    for chunk in s.as_bytes().utf8_chunks() {
+        if !chunk.valid().is_empty() {
+            word.push_str(&quote(chunk.valid()));
+        }
+        for b in chunk.invalid() {
+            word.push_str(&format!("\"$(printf '\\{b:03o}')\""));
+        }
+    }
+    word
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::OsString,
+        io::Write,
+        os::unix::ffi::OsStringExt,
+        process::{Command, Stdio},
+    };
+
+    use super::*;
+
+    /// Runs `cmd` through `sh` and returns the words `printf '%s\0'` received.
+    fn words_seen_by_sh(cmd: Cmd) -> Vec<Vec<u8>> {
+        let line = cmd.to_string();
+        let out = Command::new("sh").arg("-c").arg(&line).output().unwrap();
+        assert!(out.status.success(), "{line}: {out:?}");
+        let mut words: Vec<Vec<u8>> = out.stdout.split(|&b| b == 0).map(<[u8]>::to_vec).collect();
+        assert_eq!(words.pop(), Some(Vec::new()));
+        words
+    }
+
+    #[test]
+    fn quote_leaves_plain_words_readable() {
+        assert_eq!(quote("/var/lib/postgresql"), "/var/lib/postgresql");
+        assert_eq!(quote("--exclude=./proc"), "--exclude=./proc");
+        assert_eq!(quote("user@host:2222"), "user@host:2222");
+    }
+
+    #[test]
+    fn quote_hostile_names() {
+        let cases = [
+            ("", "''"),
+            ("my files", "'my files'"),
+            ("it's", r"'it'\''s'"),
+            ("'", r"''\'''"),
+            ("$(rm -rf ~)", "'$(rm -rf ~)'"),
+            ("`id`", "'`id`'"),
+            ("${HOME}", "'${HOME}'"),
+            ("a\nb", "'a\nb'"),
+            ("*.log", "'*.log'"),
+            ("[ab]?", "'[ab]?'"),
+            ("x;y|z&", "'x;y|z&'"),
+            ("~root", "'~root'"),
+            ("back\\slash", "'back\\slash'"),
+            ("tab\there", "'tab\there'"),
+            ("naïve", "'naïve'"),
+        ];
+        for (raw, quoted) in cases {
+            assert_eq!(quote(raw), quoted, "{raw:?}");
+        }
+    }
+
+    #[test]
+    fn quote_does_not_hide_a_leading_dash() {
+        // Quoting keeps the word whole but the program still sees an option;
+        // callers end options with `--` before paths.
+        assert_eq!(quote("-rf"), "-rf");
+        assert_eq!(
+            quote("--checkpoint-action=exec=sh"),
+            "--checkpoint-action=exec=sh"
+        );
+    }
+
+    #[test]
+    fn quote_os_escapes_bytes_that_are_not_utf8() {
+        let name = OsString::from_vec(b"caf\xe9 it's".to_vec());
+        assert_eq!(quote_os(&name), r#"caf"$(printf '\351')"' it'\''s'"#);
+        assert_eq!(quote_os(OsStr::new("plain")), "plain");
+    }
+
+    #[test]
+    fn cmd_renders_sudo_env_and_args() {
+        let cmd = Cmd::new("pg_dump")
+            .sudo_user(Some("postgres"))
+            .env("PGAPPNAME", "data backup")
+            .arg("-d")
+            .arg("my db")
+            .opt("--file", "/tmp/a b.sql");
+        assert_eq!(
+            cmd.to_string(),
+            "sudo -u postgres env PGAPPNAME='data backup' pg_dump -d 'my db' '--file=/tmp/a b.sql'"
+        );
+        assert_eq!(Cmd::new("ls").sudo(true).arg("/").to_string(), "sudo ls /");
+        assert_eq!(Cmd::new("cat").arg("a").pipe("wc -c"), "cat a | wc -c");
+    }
+
+    #[test]
+    fn secret_env_is_read_from_stdin_not_the_command_line() {
+        let cmd = Cmd::new("pg_dump")
+            .sudo_user(Some("postgres"))
+            .secret_env("PGPASSWORD")
+            .arg("my db");
+        assert_eq!(
+            cmd.to_string(),
+            r#"sudo -u postgres sh -c 'IFS= read -r PGPASSWORD && export PGPASSWORD && exec pg_dump '\''my db'\'''"#
+        );
+        assert_eq!(
+            Cmd::new("psql").input("zstd -q -dc").to_string(),
+            "zstd -q -dc | psql"
+        );
+    }
+
+    #[test]
+    fn secret_line_is_split_off_before_the_input_filter() {
+        let cmd = Cmd::new("sh")
+            .secret_env("PW")
+            .input("tr a-z A-Z")
+            .args(["-c", r#"printf '%s|' "$PW"; cat"#]);
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(cmd.to_string())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut stdin = child.stdin.take().unwrap();
+        stdin
+            .write_all(b"it's $(a) secret\ndump data\nmore\n")
+            .unwrap();
+        drop(stdin);
+        let out = child.wait_with_output().unwrap();
+        assert!(out.status.success());
+        assert_eq!(out.stdout, b"it's $(a) secret|DUMP DATA\nMORE\n");
+    }
+
+    #[test]
+    fn hostile_words_reach_the_program_unchanged() {
+        let names = [
+            "my files",
+            "it's",
+            "\"double\"",
+            "$(touch /tmp/pwned)",
+            "`touch /tmp/pwned`",
+            "$HOME",
+            "line\nbreak",
+            "trailing newline\n",
+            "*",
+            "-rf",
+            "a;b && c || d",
+            "",
+            " ",
+        ];
+        let cmd = Cmd::new("printf").arg(r"%s\0").args(names);
+        let seen = words_seen_by_sh(cmd);
+        let expected: Vec<Vec<u8>> = names.iter().map(|n| n.as_bytes().to_vec()).collect();
+        assert_eq!(seen, expected);
+    }
+
+    #[test]
+    fn non_utf8_paths_reach_the_program_unchanged() {
+        let paths = [
+            OsString::from_vec(b"caf\xe9".to_vec()),
+            OsString::from_vec(b"\xff\xfe it's $(id)".to_vec()),
+            OsString::from_vec(b"ok \xc3\xa9 then \xc3".to_vec()),
+        ];
+        let cmd = Cmd::new("printf").arg(r"%s\0").paths(&paths);
+        let seen = words_seen_by_sh(cmd);
+        let expected: Vec<Vec<u8>> = paths.iter().map(|p| p.as_bytes().to_vec()).collect();
+        assert_eq!(seen, expected);
+    }
+}
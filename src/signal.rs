@@ -0,0 +1,66 @@
+//! SIGINT/SIGTERM handling: the first signal raises a flag that long-running
+//! loops poll, so cleanup guards get to run; a second one exits immediately.
+//! The daemon also takes SIGHUP as a reload request. Children started in
+//! their own process group are stopped with [`kill_group`].
+
+use std::{
+    process::Child,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::error::AppError;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(_sig: libc::c_int) {
+    if INTERRUPTED.swap(true, Ordering::SeqCst) {
+        unsafe { libc::_exit(130) };
+    }
+}
+
+pub fn install() {
+    let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+extern "C" fn on_hangup(_sig: libc::c_int) {
+    RELOAD.store(true, Ordering::SeqCst);
+}
+
+/// On top of [`install`].
+pub fn install_daemon() {
+    let handler = on_hangup as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGHUP, handler);
+    }
+}
+
+/// True once per SIGHUP received since the last call.
+pub fn take_reload() -> bool {
+    RELOAD.swap(false, Ordering::SeqCst)
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Sends `sig` to the process group of a child spawned with
+/// `process_group(0)`, so whatever it started goes too.
+pub fn kill_group(child: &Child, sig: libc::c_int) {
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), sig);
+    }
+}
+
+/// `Err(AppError::Interrupted)` once Ctrl-C was pressed or SIGTERM arrived.
+pub fn check() -> Result<(), AppError> {
+    if interrupted() {
+        Err(AppError::Interrupted)
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,165 @@
+//! `list` and `prune` over the configured storage.
+
+use std::{collections::BTreeMap, path::Path};
+
+use chrono::{Duration, Utc};
+
+use crate::{
+    catalog::Catalog,
+    config::Config,
+    error::AppError,
+    storage::{self, LocalStorage, Storage, Stored},
+};
+
+pub fn list(cfg: &Config) -> Result<(), AppError> {
+    let store = storage::open(cfg)?;
+    let all = storage::snapshots(store.as_ref())?;
+    log::info!("{} snapshots in {}", all.len(), store.describe());
+    for s in &all {
+        let volumes = match s.meta.volumes.len() {
+            0 => String::new(),
+            n => format!(" ({n} volumes)"),
+        };
+        println!(
+            "{}  {:<4} {:>14}  {}{volumes}",
+            s.timestamp().format("%Y-%m-%d %H:%M:%S"),
+            s.kind.name(),
+            s.size,
+            s.name
+        );
+    }
+
+    // Interrupted runs, in the backend and in the staging directory.
+    let staging = LocalStorage::new(&cfg.options.local_download_dir);
+    let mut stores: Vec<&dyn Storage> = vec![store.as_ref()];
+    if store
+        .local_dir()
+        .is_none_or(|d| !storage::same_dir(d, Path::new(&cfg.options.local_download_dir)))
+    {
+        stores.push(&staging);
+    }
+    for st in stores {
+        for o in storage::partials(st)? {
+            println!(
+                "{:<19}  {:<4} {:>14}  {} in {}",
+                "incomplete",
+                "-",
+                o.size,
+                o.key,
+                st.describe()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Deletes snapshots beyond the newest `keep_last` of each series (same
+/// host and tar job, dd device or database), and/or older than
+/// `older_than_days`.
+/// The newest snapshot of a series is always kept.
+pub fn prune(
+    cfg: &Config,
+    keep_last: Option<usize>,
+    older_than_days: Option<u64>,
+    dry_run: bool,
+) -> Result<(), AppError> {
+    if keep_last.is_none() && older_than_days.is_none() {
+        return Err(AppError::Validation(
+            "prune needs --keep-last and/or --older-than-days".into(),
+        ));
+    }
+    let store = storage::open(cfg)?;
+    let cutoff = older_than_days.map(|d| Utc::now() - Duration::days(d as i64));
+
+    let mut series: BTreeMap<String, Vec<Stored>> = BTreeMap::new();
+    for s in storage::snapshots(store.as_ref())? {
+        series.entry(s.series()).or_default().push(s);
+    }
+
+    let catalog_path = Path::new(&cfg.options.catalog);
+    let mut catalog = Catalog::load(catalog_path)?;
+    let mut removed = 0;
+    for (name, mut snaps) in series {
+        // Newest first.
+        snaps.reverse();
+        let keep = keep_last.unwrap_or(0).max(1);
+        for s in snaps.iter().skip(keep) {
+            if cutoff.is_some_and(|c| s.timestamp() >= c) {
+                continue;
+            }
+            log::info!(
+                "{}{name}: {} from {}",
+                if dry_run { "[dry run] " } else { "" },
+                s.name,
+                s.timestamp()
+            );
+            if !dry_run {
+                // Metadata first: a half-deleted snapshot is no longer listed.
+                store.delete(&s.meta_key)?;
+                for key in s.data_keys().into_iter().chain(s.inventory_key.clone()) {
+                    store.delete(&key)?;
+                }
+                catalog.snapshots.remove(&s.name);
+            }
+            removed += 1;
+        }
+    }
+    if !dry_run {
+        catalog.save(catalog_path)?;
+    }
+    log::info!(
+        "{removed} snapshots {} from {}",
+        if dry_run {
+            "would be removed"
+        } else {
+            "removed"
+        },
+        store.describe()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::testutil::{self, TempDir};
+
+    #[test]
+    fn prune_keeps_the_newest_of_each_host_and_job() {
+        let dir = TempDir::new("prune");
+        let cfg = testutil::config(dir.path(), "");
+        let store = dir.join("store");
+        let jobs = [
+            ("web", "10.0.0.1", &["/etc"][..]),
+            ("www", "10.0.0.1", &["/var/www", "/home"][..]),
+            ("db", "10.0.0.2", &["/etc"][..]),
+        ];
+        for night in 1..=4 {
+            for (job, host, fs) in jobs {
+                let at = Utc.with_ymd_and_hms(2026, 10, night, 2, 0, 0).unwrap();
+                testutil::tar_snapshot(&store, &format!("{job}-{night}.tar"), host, fs, at);
+            }
+        }
+
+        prune(&cfg, Some(2), None, false).unwrap();
+        let mut left: Vec<String> = storage::snapshots(storage::open(&cfg).unwrap().as_ref())
+            .unwrap()
+            .into_iter()
+            .map(|s| s.name)
+            .collect();
+        left.sort();
+        assert_eq!(
+            left,
+            [
+                "db-3.tar",
+                "db-4.tar",
+                "web-3.tar",
+                "web-4.tar",
+                "www-3.tar",
+                "www-4.tar"
+            ]
+        );
+    }
+}
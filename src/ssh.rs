@@ -1,13 +1,19 @@
-use crate::error::AppError;
+use crate::{config::Remote, error::AppError, shell::quote, signal, throttle::Pacer};
 
-use ssh2::Session;
+use ssh2::{Channel, Session};
 use std::{
-    io::{Read, Write},
+    io::{self, Read, Write},
     net::{IpAddr, SocketAddr, TcpStream},
     path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
     time::Duration,
 };
 
+/// How long a read on a silent [`RemoteJob`] blocks before Ctrl-C is checked.
+const POLL_MS: u32 = 500;
+
+static JOBS: AtomicUsize = AtomicUsize::new(0);
+
 pub struct Ssh {
     session: Session,
     peer: SocketAddr,
@@ -49,51 +55,104 @@ impl Ssh {
         })
     }
 
+    /// Connects with the `[remote]` section of the config.
+    pub fn connect_remote(r: &Remote) -> Result<Self, AppError> {
+        Self::connect(
+            r.host,
+            r.port,
+            &r.user,
+            &r.password,
+            r.private_key.as_deref(),
+        )
+    }
+
     pub fn exec_verbose(&self, cmd: &str) -> Result<(), AppError> {
-        let mut channel = self.session.channel_session()?;
-        channel.exec(cmd)?;
-        {
-            use std::io::Read;
-            let mut buf = [0u8; 4096];
-            while let Ok(n) = channel.read(&mut buf) {
-                if n == 0 {
-                    break;
-                }
-                log::info!("{}", String::from_utf8_lossy(&buf[..n]));
+        let mut job = self.spawn(cmd)?;
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = job.read(&mut buf)?;
+            if n == 0 {
+                break;
             }
+            log::info!("{}", String::from_utf8_lossy(&buf[..n]));
         }
-        channel.wait_close()?;
-        match channel.exit_status()? {
+        match job.finish()? {
             0 => Ok(()),
             code => Err(AppError::RemoteExit(code)),
         }
     }
 
-    pub fn download<P: AsRef<Path>>(
+    /// Starts a long-running command whose whole process group is killed
+    /// if the job is dropped before [`RemoteJob::finish`].
+    pub fn spawn(&self, cmd: &str) -> Result<RemoteJob<'_>, AppError> {
+        let pgid_file = format!(
+            "/tmp/.data-backup-{}-{}.pgid",
+            std::process::id(),
+            JOBS.fetch_add(1, Ordering::SeqCst)
+        );
+        // sshd makes the command's shell a process group leader, so `$$` names
+        // the group holding the pipeline and anything it started under sudo.
+        let wrapped = format!(
+            "echo $$ > {f}; {cmd}\nrc=$?; rm -f {f}; exit $rc",
+            f = quote(&pgid_file)
+        );
+        let mut channel = self.session.channel_session()?;
+        channel.exec(&wrapped)?;
+        Ok(RemoteJob {
+            ssh: self,
+            channel,
+            pgid_file,
+            done: false,
+        })
+    }
+
+    /// Streams a remote file into `local`, no faster than `pacer` allows.
+    pub fn download_into<W: Write>(
         &self,
         remote_path: &str,
-        local_path: P,
+        local: &mut W,
+        pacer: &mut Pacer,
     ) -> Result<(), AppError> {
         let (mut remote, stat) = self.session.scp_recv(Path::new(remote_path))?;
         let pb = indicatif::ProgressBar::new(stat.size()).with_message("Downloading snapshot");
-        let mut local = std::fs::File::create(local_path)?;
         let mut buf = [0u8; 8192];
         loop {
+            signal::check()?;
             let n = remote.read(&mut buf)?;
             if n == 0 {
                 break;
             }
             local.write_all(&buf[..n])?;
             pb.inc(n as u64);
+            pacer.pace(n)?;
         }
         remote.send_eof()?;
         remote.wait_eof()?;
         pb.finish_with_message("Download complete");
         Ok(())
     }
+    /// SFTP subsystem on this connection.
+    pub fn sftp(&self) -> Result<ssh2::Sftp, AppError> {
+        Ok(self.session.sftp()?)
+    }
+
     pub fn exec_capture<W: std::io::Write>(&self, cmd: &str, sink: &mut W) -> Result<(), AppError> {
+        self.exec_capture_input(cmd, &[], sink)
+    }
+
+    /// Like [`Self::exec_capture`], with `input` sent on stdin first.
+    pub fn exec_capture_input<W: std::io::Write>(
+        &self,
+        cmd: &str,
+        input: &[u8],
+        sink: &mut W,
+    ) -> Result<(), AppError> {
         let mut ch = self.session.channel_session()?;
         ch.exec(cmd)?;
+        if !input.is_empty() {
+            ch.write_all(input)?;
+            ch.send_eof()?;
+        }
         std::io::copy(&mut ch, sink)?;
         ch.wait_close()?;
         if ch.exit_status()? == 0 {
@@ -103,6 +162,32 @@ impl Ssh {
         }
     }
 
+    /// Runs `cmd` with `input` piped to its stdin; returns the bytes sent.
+    pub fn exec_stdin<R: Read>(&self, cmd: &str, input: &mut R, len: u64) -> Result<u64, AppError> {
+        let mut ch = self.session.channel_session()?;
+        ch.exec(cmd)?;
+        let pb = indicatif::ProgressBar::new(len).with_message("Uploading");
+        let mut buf = [0u8; 1 << 16];
+        let mut sent = 0u64;
+        loop {
+            let n = input.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            ch.write_all(&buf[..n])?;
+            sent += n as u64;
+            pb.inc(n as u64);
+        }
+        ch.send_eof()?;
+        pb.finish();
+        std::io::copy(&mut ch.stderr(), &mut std::io::sink())?;
+        ch.wait_close()?;
+        match ch.exit_status()? {
+            0 => Ok(sent),
+            code => Err(AppError::RemoteExit(code)),
+        }
+    }
+
     /// Open channel, keep it streaming.
     pub fn open_stream(&self, cmd: &str) -> Result<ssh2::Channel, AppError> {
         let mut ch = self.session.channel_session()?;
@@ -115,3 +200,72 @@ impl Ssh {
         self.peer.to_string()
     }
 }
+
+/// A command started by [`Ssh::spawn`].
+pub struct RemoteJob<'a> {
+    ssh: &'a Ssh,
+    channel: Channel,
+    pgid_file: String,
+    done: bool,
+}
+
+impl RemoteJob<'_> {
+    /// Reads its stdout; gives up with `AppError::Interrupted` on Ctrl-C
+    /// even while the command prints nothing.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, AppError> {
+        loop {
+            signal::check()?;
+            self.ssh.session.set_timeout(POLL_MS);
+            let res = self.channel.read(buf);
+            self.ssh.session.set_timeout(0);
+            match res {
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                res => return Ok(res?),
+            }
+        }
+    }
+
+    /// Writes `data` to its stdin and closes it.
+    pub fn send_input(&mut self, data: &[u8]) -> Result<(), AppError> {
+        self.channel.write_all(data)?;
+        self.channel.send_eof()?;
+        Ok(())
+    }
+
+    pub fn stderr(&mut self) -> ssh2::Stream {
+        self.channel.stderr()
+    }
+
+    /// Waits for the command to exit and returns its status.
+    pub fn finish(mut self) -> Result<i32, AppError> {
+        self.channel.wait_close()?;
+        let code = self.channel.exit_status()?;
+        self.done = true;
+        Ok(code)
+    }
+}
+
+impl Drop for RemoteJob<'_> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        log::warn!("Stopping remote command");
+        let script = format!(
+            "P=$(cat {f} 2>/dev/null) && [ -n \"$P\" ] || exit 0; \
+             kill -TERM -$P 2>/dev/null; i=0; \
+             while kill -0 -$P 2>/dev/null && [ $i -lt 50 ]; do sleep 0.1; i=$((i+1)); done; \
+             kill -KILL -$P 2>/dev/null; rm -f {f}",
+            f = quote(&self.pgid_file)
+        );
+        // Members may run as root; without sudo, kill what we own.
+        let cmd = format!(
+            "sudo -n sh -c {s} 2>/dev/null || sh -c {s}",
+            s = quote(&script)
+        );
+        if let Err(e) = self.ssh.exec_capture(&cmd, &mut io::sink()) {
+            log::error!("cannot stop remote command (see {}): {e}", self.pgid_file);
+        }
+        let _ = self.channel.close();
+    }
+}
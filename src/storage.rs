@@ -0,0 +1,246 @@
+//! Where finished snapshots are kept: `local_download_dir`, another
+//! directory, a second SFTP server or an S3-compatible bucket.
+//!
+//! Pipelines still stage into `local_download_dir` (dd resume, sparse
+//! images and volume splitting need a seekable file); [`publish`] then
+//! streams the snapshot and its metadata to the backend.
+
+mod local; // plain directory
+mod s3; // S3-compatible object store, multipart upload
+mod sftp; // directory on a second SSH server
+mod sigv4; // AWS Signature Version 4 for the s3 backend
+
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+    config::{Config, StorageConfig},
+    error::AppError,
+    metadata::{
+        SnapshotRecord,
+        sidecar::{self, INVENTORY_SUFFIX, SidecarKind},
+    },
+    partial,
+};
+
+pub use local::LocalStorage;
+
+/// One stored object.
+#[derive(Debug, Clone)]
+pub struct Object {
+    pub key: String,
+    pub size: u64,
+}
+
+pub trait Storage {
+    /// Backend and location, for logs.
+    fn describe(&self) -> String;
+    /// Stores everything `input` yields (`len` bytes) as `key`, replacing any
+    /// existing object.
+    fn put(&self, key: &str, input: &mut dyn Read, len: u64) -> Result<(), AppError>;
+    fn get(&self, key: &str) -> Result<Box<dyn Read + '_>, AppError>;
+    fn list(&self) -> Result<Vec<Object>, AppError>;
+    fn delete(&self, key: &str) -> Result<(), AppError>;
+    /// The directory the objects live in, when they are plain local files.
+    fn local_dir(&self) -> Option<&Path> {
+        None
+    }
+}
+
+/// The backend from `[storage]`, or `local_download_dir` itself.
+pub fn open(cfg: &Config) -> Result<Box<dyn Storage>, AppError> {
+    match &cfg.storage {
+        Some(sc) => open_target(sc, &cfg.options.local_download_dir),
+        None => Ok(Box::new(LocalStorage::new(&cfg.options.local_download_dir))),
+    }
+}
+
+/// One backend; a local one without `path` is `default_dir`.
+pub fn open_target(sc: &StorageConfig, default_dir: &str) -> Result<Box<dyn Storage>, AppError> {
+    match sc.backend.to_ascii_lowercase().as_str() {
+        "local" if sc.path.is_empty() => Ok(Box::new(LocalStorage::new(default_dir))),
+        "local" => Ok(Box::new(LocalStorage::new(&sc.path))),
+        "sftp" => {
+            let remote = sc.sftp.as_ref().ok_or_else(|| {
+                AppError::Validation("storage backend `sftp` needs an `sftp` table".into())
+            })?;
+            Ok(Box::new(sftp::SftpStorage::connect(remote, &sc.path)?))
+        }
+        "s3" => {
+            let s3 = sc.s3.as_ref().ok_or_else(|| {
+                AppError::Validation("storage backend `s3` needs an `s3` table".into())
+            })?;
+            Ok(Box::new(s3::S3Storage::new(s3, &sc.path)?))
+        }
+        other => Err(AppError::Validation(format!(
+            "unknown storage backend `{other}` (expected local, sftp or s3)"
+        ))),
+    }
+}
+
+/// Moves a staged snapshot (data or volumes, then its metadata) to `store`.
+/// The metadata goes last, so a listed snapshot is always complete.
+pub fn publish(cfg: &Config, store: &dyn Storage, snapshot: &Path) -> Result<(), AppError> {
+    let dir = snapshot.parent().unwrap_or(Path::new(""));
+    if store.local_dir().is_some_and(|d| same_dir(d, dir)) {
+        return Ok(());
+    }
+    let meta_path = sidecar::locate(snapshot)?;
+    let meta = SnapshotRecord::load(&meta_path)?;
+
+    let mut files: Vec<PathBuf> = if meta.volumes.is_empty() {
+        vec![snapshot.to_path_buf()]
+    } else {
+        meta.volumes.iter().map(|v| dir.join(&v.file)).collect()
+    };
+    let inventory = sidecar::inventory_path(snapshot);
+    if inventory.exists() {
+        files.push(inventory);
+    }
+    // Metadata last: the snapshot is listed only once everything is there.
+    files.push(meta_path);
+
+    for path in &files {
+        let key = file_name(path);
+        let mut file = std::fs::File::open(path)?;
+        let len = file.metadata()?.len();
+        log::info!("Uploading {key} ({len} bytes) to {}", store.describe());
+        store.put(&key, &mut file, len)?;
+    }
+
+    let keep = cfg.storage.as_ref().is_some_and(|s| s.keep_local);
+    if !keep {
+        for path in &files {
+            std::fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Leftovers of interrupted writes in `store`.
+pub fn partials(store: &dyn Storage) -> Result<Vec<Object>, AppError> {
+    Ok(store
+        .list()?
+        .into_iter()
+        .filter(|o| partial::is_partial(&o.key))
+        .collect())
+}
+
+/// A snapshot known from its metadata object.
+#[derive(Debug)]
+pub struct Stored {
+    /// Object key of the snapshot, or of its volumes without the `.NNN`.
+    pub name: String,
+    pub kind: SidecarKind,
+    pub meta_key: String,
+    pub meta: SnapshotRecord,
+    /// Data bytes, over all volumes.
+    pub size: u64,
+    /// `<name>.host.json`, when the host inventory was saved.
+    pub inventory_key: Option<String>,
+}
+
+impl Stored {
+    /// Keys of the data objects: the snapshot itself or its volumes.
+    pub fn data_keys(&self) -> Vec<String> {
+        if self.meta.volumes.is_empty() {
+            vec![self.name.clone()]
+        } else {
+            self.meta.volumes.iter().map(|v| v.file.clone()).collect()
+        }
+    }
+
+    /// Snapshots of the same job form one series for pruning.
+    pub fn series(&self) -> String {
+        self.meta.series()
+    }
+
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        self.meta.finished_at
+    }
+}
+
+/// Every snapshot in `store`, oldest first.
+pub fn snapshots(store: &dyn Storage) -> Result<Vec<Stored>, AppError> {
+    let objects = store.list()?;
+    let size_of = |key: &str| objects.iter().find(|o| o.key == key).map(|o| o.size);
+
+    let mut out = Vec::new();
+    for obj in &objects {
+        let Some(name) = sidecar::split_name(&obj.key) else {
+            continue;
+        };
+        let mut raw = String::new();
+        store.get(&obj.key)?.read_to_string(&mut raw)?;
+        let meta = match SnapshotRecord::parse(&raw, &obj.key) {
+            Ok(m) => m,
+            Err(e) => {
+                log::warn!("skipping {e}");
+                continue;
+            }
+        };
+        let mut stored = Stored {
+            name: name.to_string(),
+            kind: meta.mode,
+            meta_key: obj.key.clone(),
+            meta,
+            size: 0,
+            inventory_key: Some(format!("{name}{INVENTORY_SUFFIX}"))
+                .filter(|k| size_of(k).is_some()),
+        };
+        stored.size = stored.data_keys().iter().filter_map(|k| size_of(k)).sum();
+        out.push(stored);
+    }
+    out.sort_by_key(Stored::timestamp);
+    Ok(out)
+}
+
+/// The snapshot stored as `name`, or whose first volume is `name`.
+pub fn find(store: &dyn Storage, name: &str) -> Result<Stored, AppError> {
+    snapshots(store)?
+        .into_iter()
+        .find(|s| s.name == name || s.data_keys().first().is_some_and(|k| k == name))
+        .ok_or_else(|| AppError::Validation(format!("{name} not found in {}", store.describe())))
+}
+
+/// Copies a stored snapshot and its metadata into `dir`; returns the local
+/// path of the snapshot.
+pub fn fetch(store: &dyn Storage, name: &str, dir: &Path) -> Result<PathBuf, AppError> {
+    let stored = find(store, name)?;
+    std::fs::create_dir_all(dir)?;
+    let keys = stored
+        .data_keys()
+        .into_iter()
+        .chain(stored.inventory_key.clone());
+    for key in keys.chain([stored.meta_key.clone()]) {
+        log::info!("Downloading {key} from {}", store.describe());
+        let path = dir.join(&key);
+        let mut out = std::fs::File::create(partial::path(&path))?;
+        std::io::copy(&mut store.get(&key)?, &mut out)?;
+        out.sync_all()?;
+        partial::commit(&path)?;
+    }
+    Ok(dir.join(&stored.name))
+}
+
+pub fn same_dir(a: &Path, b: &Path) -> bool {
+    let canon = |p: &Path| {
+        let p = if p.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            p
+        };
+        p.canonicalize().ok()
+    };
+    matches!((canon(a), canon(b)), (Some(x), Some(y)) if x == y)
+}
+
+pub fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
@@ -0,0 +1,66 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use super::{Object, Storage};
+use crate::{error::AppError, partial};
+
+/// Objects are files in one directory.
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+}
+
+impl Storage for LocalStorage {
+    fn describe(&self) -> String {
+        self.root.display().to_string()
+    }
+
+    fn put(&self, key: &str, input: &mut dyn Read, _len: u64) -> Result<(), AppError> {
+        fs::create_dir_all(&self.root)?;
+        let path = self.root.join(key);
+        let mut out = File::create(partial::path(&path))?;
+        io::copy(input, &mut out)?;
+        out.sync_all()?;
+        Ok(partial::commit(&path)?)
+    }
+
+    fn get(&self, key: &str) -> Result<Box<dyn Read + '_>, AppError> {
+        Ok(Box::new(File::open(self.root.join(key))?))
+    }
+
+    fn list(&self) -> Result<Vec<Object>, AppError> {
+        let entries = match fs::read_dir(&self.root) {
+            Ok(e) => e,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut out = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            if meta.is_file() {
+                out.push(Object {
+                    key: entry.file_name().to_string_lossy().into_owned(),
+                    size: meta.len(),
+                });
+            }
+        }
+        Ok(out)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), AppError> {
+        Ok(fs::remove_file(self.root.join(key))?)
+    }
+
+    fn local_dir(&self) -> Option<&Path> {
+        Some(&self.root)
+    }
+}
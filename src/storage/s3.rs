@@ -0,0 +1,369 @@
+use std::{io::Read, time::Duration};
+
+use chrono::Utc;
+
+use super::{
+    Object, Storage,
+    sigv4::{self, Signer},
+};
+use crate::{config::S3Config, error::AppError, signal};
+
+/// S3 never takes parts below 5 MiB, except the last.
+const MIN_PART: u64 = 5 << 20;
+/// Parts in one multipart upload, and the largest object it can make.
+const MAX_PARTS: u64 = 10_000;
+const MAX_OBJECT: u64 = 5 << 40;
+/// Socket timeouts, as for the dd pipeline, so a dead endpoint fails the
+/// run instead of hanging it.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const IO_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Objects under `prefix` in one bucket of an S3-compatible store.
+pub struct S3Storage {
+    agent: ureq::Agent,
+    scheme: String,
+    /// `host[:port]`, exactly as signed and sent.
+    host: String,
+    /// `/bucket` for path-style addressing, empty otherwise.
+    bucket_path: String,
+    bucket: String,
+    /// `dir/`, or empty for the bucket root.
+    prefix: String,
+    access_key: String,
+    secret_key: String,
+    region: String,
+    part_size: u64,
+}
+
+impl S3Storage {
+    pub fn new(cfg: &S3Config, path: &str) -> Result<Self, AppError> {
+        if cfg.part_size < MIN_PART {
+            return Err(AppError::Validation(format!(
+                "storage.s3.part_size ({}) must be at least {MIN_PART}",
+                cfg.part_size
+            )));
+        }
+        let (scheme, rest) = cfg.endpoint.split_once("://").ok_or_else(|| {
+            AppError::Validation(format!(
+                "storage.s3.endpoint `{}` needs a scheme (http:// or https://)",
+                cfg.endpoint
+            ))
+        })?;
+        let endpoint_host = rest.split('/').next().unwrap_or_default();
+        let (host, bucket_path) = if cfg.path_style {
+            (endpoint_host.to_string(), format!("/{}", cfg.bucket))
+        } else {
+            (format!("{}.{endpoint_host}", cfg.bucket), String::new())
+        };
+        let prefix = match path.trim_matches('/') {
+            "" => String::new(),
+            p => format!("{p}/"),
+        };
+        Ok(Self {
+            agent: ureq::AgentBuilder::new()
+                .timeout_connect(CONNECT_TIMEOUT)
+                .timeout_read(IO_TIMEOUT)
+                .timeout_write(IO_TIMEOUT)
+                .build(),
+            scheme: scheme.to_ascii_lowercase(),
+            host,
+            bucket_path,
+            bucket: cfg.bucket.clone(),
+            prefix,
+            access_key: cfg.access_key.clone(),
+            secret_key: cfg.secret_key.clone(),
+            region: cfg.region.clone(),
+            part_size: cfg.part_size,
+        })
+    }
+
+    /// Signs and sends one request. `key` is `None` for bucket-level calls.
+    fn send(
+        &self,
+        method: &str,
+        key: Option<&str>,
+        params: &[(&str, &str)],
+        body: &[u8],
+    ) -> Result<ureq::Response, AppError> {
+        let object = key
+            .map(|k| format!("{}{k}", self.prefix))
+            .unwrap_or_default();
+        let path = format!("{}/{}", self.bucket_path, sigv4::uri_encode(&object, true));
+        let query = sigv4::canonical_query(params);
+        let payload = sigv4::sha256_hex(body);
+        let signer = Signer {
+            access_key: &self.access_key,
+            secret_key: &self.secret_key,
+            region: &self.region,
+        };
+        let headers = signer.sign(
+            &sigv4::Request {
+                method,
+                host: &self.host,
+                path: &path,
+                query: &query,
+                payload_sha256: &payload,
+            },
+            Utc::now(),
+        );
+
+        let mut url = format!("{}://{}{path}", self.scheme, self.host);
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(&query);
+        }
+        let mut req = self.agent.request(method, &url).set("host", &self.host);
+        for (name, value) in &headers {
+            req = req.set(name, value);
+        }
+        let res = if body.is_empty() && method != "PUT" && method != "POST" {
+            req.call()
+        } else {
+            req.send_bytes(body)
+        };
+        res.map_err(|e| match e {
+            ureq::Error::Status(code, resp) => {
+                let body = resp.into_string().unwrap_or_default();
+                let msg = tag(&body, "Message").unwrap_or(body.trim());
+                AppError::Storage(format!("{method} {path}: HTTP {code}: {msg}"))
+            }
+            ureq::Error::Transport(t) => AppError::Storage(format!("{method} {path}: {t}")),
+        })
+    }
+
+    /// The configured part size, raised so `len` fits in [`MAX_PARTS`] parts.
+    fn part_size_for(&self, len: u64) -> u64 {
+        const MIB: u64 = 1 << 20;
+        self.part_size
+            .max(len.div_ceil(MAX_PARTS).div_ceil(MIB) * MIB)
+    }
+
+    fn put_multipart(&self, key: &str, input: &mut dyn Read, len: u64) -> Result<(), AppError> {
+        let part_size = self.part_size_for(len);
+        if part_size != self.part_size {
+            log::info!("{key}: {len} bytes, uploading in parts of {part_size} bytes");
+        }
+        let resp = self.send("POST", Some(key), &[("uploads", "")], &[])?;
+        let body = resp.into_string()?;
+        let upload_id = tag(&body, "UploadId")
+            .map(unescape)
+            .ok_or_else(|| AppError::Storage(format!("no UploadId in reply for {key}")))?;
+
+        let res = self.upload_parts(key, &upload_id, input, part_size);
+        if res.is_err() {
+            // Otherwise the parts are billed until a lifecycle rule removes them.
+            let _ = self.send("DELETE", Some(key), &[("uploadId", &upload_id)], &[]);
+        }
+        res
+    }
+
+    fn upload_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+        input: &mut dyn Read,
+        part_size: u64,
+    ) -> Result<(), AppError> {
+        let mut etags = Vec::new();
+        let mut buf = Vec::with_capacity(part_size as usize);
+        loop {
+            signal::check()?;
+            buf.clear();
+            input.take(part_size).read_to_end(&mut buf)?;
+            if buf.is_empty() && !etags.is_empty() {
+                break;
+            }
+            if etags.len() as u64 == MAX_PARTS {
+                return Err(AppError::Storage(format!(
+                    "{key} is larger than announced; S3 takes at most {MAX_PARTS} parts"
+                )));
+            }
+            let number = (etags.len() + 1).to_string();
+            let resp = self.send(
+                "PUT",
+                Some(key),
+                &[("partNumber", &number), ("uploadId", upload_id)],
+                &buf,
+            )?;
+            let etag = resp
+                .header("ETag")
+                .ok_or_else(|| AppError::Storage(format!("no ETag for part {number} of {key}")))?
+                .to_string();
+            log::debug!("{key}: part {number} uploaded ({} bytes)", buf.len());
+            etags.push(etag);
+            if (buf.len() as u64) < part_size {
+                break;
+            }
+        }
+
+        let mut xml = String::from("<CompleteMultipartUpload>");
+        for (i, etag) in etags.iter().enumerate() {
+            xml.push_str(&format!(
+                "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                i + 1,
+                escape(etag)
+            ));
+        }
+        xml.push_str("</CompleteMultipartUpload>");
+        let resp = self.send(
+            "POST",
+            Some(key),
+            &[("uploadId", upload_id)],
+            xml.as_bytes(),
+        )?;
+        // Completion can fail after the 200 status line has been sent.
+        let body = resp.into_string()?;
+        if body.contains("<Error>") {
+            return Err(AppError::Storage(format!(
+                "completing {key}: {}",
+                tag(&body, "Message").unwrap_or(body.trim())
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Storage for S3Storage {
+    fn describe(&self) -> String {
+        format!("s3://{}/{}", self.bucket, self.prefix)
+    }
+
+    fn put(&self, key: &str, input: &mut dyn Read, len: u64) -> Result<(), AppError> {
+        if len > MAX_OBJECT {
+            return Err(AppError::Storage(format!(
+                "{key} is {len} bytes; S3 objects are at most {MAX_OBJECT} bytes (5 TiB)"
+            )));
+        }
+        if len > self.part_size {
+            return self.put_multipart(key, input, len);
+        }
+        let mut body = Vec::with_capacity(len as usize);
+        input.read_to_end(&mut body)?;
+        self.send("PUT", Some(key), &[], &body)?;
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Box<dyn Read + '_>, AppError> {
+        Ok(Box::new(
+            self.send("GET", Some(key), &[], &[])?.into_reader(),
+        ))
+    }
+
+    fn list(&self) -> Result<Vec<Object>, AppError> {
+        let mut out = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let mut params = vec![
+                ("list-type", "2"),
+                ("prefix", self.prefix.as_str()),
+                ("delimiter", "/"),
+            ];
+            if let Some(t) = &token {
+                params.push(("continuation-token", t));
+            }
+            let body = self.send("GET", None, &params, &[])?.into_string()?;
+            for entry in blocks(&body, "Contents") {
+                let (Some(key), Some(size)) = (tag(entry, "Key"), tag(entry, "Size")) else {
+                    continue;
+                };
+                let key = unescape(key);
+                if let Some(name) = key.strip_prefix(&self.prefix) {
+                    out.push(Object {
+                        key: name.to_string(),
+                        size: size.parse().unwrap_or(0),
+                    });
+                }
+            }
+            token = match tag(&body, "IsTruncated") {
+                Some("true") => tag(&body, "NextContinuationToken").map(unescape),
+                _ => None,
+            };
+            if token.is_none() {
+                return Ok(out);
+            }
+        }
+    }
+
+    fn delete(&self, key: &str) -> Result<(), AppError> {
+        self.send("DELETE", Some(key), &[], &[])?;
+        Ok(())
+    }
+}
+
+/// Text of the first `<name>` element.
+fn tag<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    blocks(xml, name).into_iter().next()
+}
+
+/// Text of every `<name>` element, in order.
+fn blocks<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    let (open, close) = (format!("<{name}>"), format!("</{name}>"));
+    let mut out = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        let after = &rest[start + open.len()..];
+        let Some(end) = after.find(&close) else {
+            break;
+        };
+        out.push(&after[..end]);
+        rest = &after[end + close.len()..];
+    }
+    out
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage(part_size: u64) -> S3Storage {
+        let cfg = S3Config {
+            endpoint: "http://127.0.0.1:1".into(),
+            bucket: "backups".into(),
+            region: "us-east-1".into(),
+            access_key: "key".into(),
+            secret_key: "secret".into(),
+            path_style: true,
+            part_size,
+        };
+        S3Storage::new(&cfg, "").unwrap()
+    }
+
+    #[test]
+    fn part_size_grows_to_fit_max_parts() {
+        const MIB: u64 = 1 << 20;
+        const GIB: u64 = 1 << 30;
+        let s = storage(16 * MIB);
+        assert_eq!(s.part_size_for(100 * GIB), 16 * MIB);
+        assert_eq!(s.part_size_for(500 * GIB), 52 * MIB);
+        assert_eq!(s.part_size_for(MAX_OBJECT), 525 * MIB);
+        for len in [160 * GIB, 161 * GIB, 2 << 40, MAX_OBJECT] {
+            let part = s.part_size_for(len);
+            assert_eq!(part % MIB, 0);
+            assert!(len.div_ceil(part) <= MAX_PARTS, "{len}");
+        }
+        assert_eq!(storage(64 * MIB).part_size_for(500 * GIB), 64 * MIB);
+    }
+
+    #[test]
+    fn objects_over_5_tib_are_rejected_before_upload() {
+        let err = storage(16 << 20)
+            .put("big.img", &mut std::io::empty(), MAX_OBJECT + 1)
+            .unwrap_err();
+        assert!(err.to_string().contains("5 TiB"), "{err}");
+    }
+}
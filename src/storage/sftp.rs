@@ -0,0 +1,89 @@
+use std::{
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use ssh2::Sftp;
+
+use super::{Object, Storage};
+use crate::{config::Remote, error::AppError, partial, ssh::Ssh};
+
+/// Objects are files in one directory on a second SSH server.
+pub struct SftpStorage {
+    host: String,
+    root: PathBuf,
+    sftp: Sftp,
+    // Keeps the session the SFTP channel runs on alive.
+    _ssh: Ssh,
+}
+
+impl SftpStorage {
+    pub fn connect(remote: &Remote, path: &str) -> Result<Self, AppError> {
+        let ssh = Ssh::connect_remote(remote)?;
+        let sftp = ssh.sftp()?;
+        let root = PathBuf::from(if path.is_empty() { "." } else { path });
+        mkdir_p(&sftp, &root)?;
+        Ok(Self {
+            host: remote.host.to_string(),
+            root,
+            sftp,
+            _ssh: ssh,
+        })
+    }
+}
+
+impl Storage for SftpStorage {
+    fn describe(&self) -> String {
+        format!("sftp://{}/{}", self.host, self.root.display())
+    }
+
+    fn put(&self, key: &str, input: &mut dyn Read, _len: u64) -> Result<(), AppError> {
+        let path = self.root.join(key);
+        let tmp = partial::path(&path);
+        let mut out = self.sftp.create(&tmp)?;
+        io::copy(input, &mut out)?;
+        out.fsync()?;
+        drop(out);
+        // SFTP v3 servers refuse to rename over an existing file.
+        if self.sftp.rename(&tmp, &path, None).is_err() {
+            let _ = self.sftp.unlink(&path);
+            self.sftp.rename(&tmp, &path, None)?;
+        }
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Box<dyn Read + '_>, AppError> {
+        Ok(Box::new(self.sftp.open(self.root.join(key))?))
+    }
+
+    fn list(&self) -> Result<Vec<Object>, AppError> {
+        Ok(self
+            .sftp
+            .readdir(&self.root)?
+            .into_iter()
+            .filter(|(_, stat)| stat.is_file())
+            .filter_map(|(path, stat)| {
+                Some(Object {
+                    key: path.file_name()?.to_string_lossy().into_owned(),
+                    size: stat.size.unwrap_or(0),
+                })
+            })
+            .collect())
+    }
+
+    fn delete(&self, key: &str) -> Result<(), AppError> {
+        Ok(self.sftp.unlink(&self.root.join(key))?)
+    }
+}
+
+/// Creates `dir` and its parents; existing ones are fine.
+fn mkdir_p(sftp: &Sftp, dir: &Path) -> Result<(), AppError> {
+    let mut cur = PathBuf::new();
+    for part in dir.components() {
+        cur.push(part);
+        if sftp.stat(&cur).is_err() {
+            sftp.mkdir(&cur, 0o755)?;
+        }
+    }
+    Ok(())
+}
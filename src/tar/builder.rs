@@ -1,7 +1,8 @@
 use super::{
     command::build_tar_command,
-    compression::Compression,
+    compression::{Compression, Compressor},
     exclude::ExcludeList,
+    options::TarOptions,
     paths::PathList,
     verify::{VerifyMode, list_archive, sha256_file},
 };
@@ -24,7 +25,9 @@ pub struct TarBuilder {
     out_file: String,
     paths: PathList,
     excludes: ExcludeList,
-    compression: Compression,
+    compression: Compressor,
+    options: TarOptions,
+    directory: Option<String>,
     verify: Option<VerifyMode>,
 }
 
@@ -34,7 +37,9 @@ impl TarBuilder {
             out_file: out_file.into(),
             paths: PathList::default(),
             excludes: ExcludeList::default(),
-            compression: Compression::Gzip,
+            compression: Compressor::default(),
+            options: TarOptions::default(),
+            directory: None,
             verify: None,
         }
     }
@@ -60,16 +65,57 @@ impl TarBuilder {
         self
     }
 
+    pub fn excludes<I, P>(mut self, iter: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<String>,
+    {
+        for p in iter {
+            self.excludes.push_unique(p);
+        }
+        self
+    }
+
+    /// Skip every directory containing a file named `tag` (e.g. `.nobackup`).
+    pub fn exclude_if_present(mut self, tag: impl Into<String>) -> Self {
+        self.excludes.push_tag(tag);
+        self
+    }
+
+    /// Skip cache directories marked with a `CACHEDIR.TAG`.
+    pub fn exclude_caches(mut self, on: bool) -> Self {
+        self.excludes.set_caches(on);
+        self
+    }
+
     pub fn exclude_default_runtime(mut self) -> Self {
         self.excludes.add_default_runtime();
         self
     }
 
     pub fn compression(mut self, c: Compression) -> Self {
+        self.compression.codec = c;
+        self
+    }
+
+    /// Level and thread count (`0` = one per core); see [`Compressor`].
+    pub fn compressor(mut self, c: Compressor) -> Self {
         self.compression = c;
         self
     }
 
+    /// ACL, xattr, SELinux, ownership and sparse-file switches.
+    pub fn options(mut self, o: TarOptions) -> Self {
+        self.options = o;
+        self
+    }
+
+    /// Archive from `dir` (`tar -C`): absolute paths and excludes are taken relative to it.
+    pub fn directory(mut self, dir: impl Into<String>) -> Self {
+        self.directory = Some(dir.into());
+        self
+    }
+
     pub fn verify(mut self, mode: VerifyMode) -> Self {
         self.verify = Some(mode);
         self
@@ -83,7 +129,9 @@ impl TarBuilder {
             &self.out_file,
             &self.paths,
             &self.excludes,
-            self.compression,
+            &self.compression,
+            &self.options,
+            self.directory.as_deref(),
         ))
     }
 
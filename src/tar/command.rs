@@ -1,33 +1,126 @@
-use super::{compression::Compression, exclude::ExcludeList, paths::PathList};
+use super::{compression::Compressor, exclude::ExcludeList, options::TarOptions, paths::PathList};
+use crate::shell::Cmd;
 
 pub fn build_tar_command(
     out_file: &str,
     paths: &PathList,
     excludes: &ExcludeList,
-    comp: Compression,
+    comp: &Compressor,
+    options: &TarOptions,
+    directory: Option<&str>,
 ) -> String {
-    let mut cmd = String::from("sudo tar");
+    let mut cmd = Cmd::new("tar")
+        .sudo(true)
+        .arg("-cpv")
+        .arg("-f")
+        .arg(out_file)
+        .args(comp.tar_args())
+        .args(options.create_args())
+        .arg("--ignore-failed-read");
 
-    match comp {
-        Compression::Gzip | Compression::Xz => {
-            cmd.push_str(&format!(" -c{}pvf", comp.flag()));
-            cmd.push_str(&format!(" '{}'", out_file));
-        }
-        Compression::Zstd => {
-            cmd.push_str(" -cpvf");
-            cmd.push_str(&format!(" '{}' {}", comp.flag(), out_file));
+    // `--` keeps a path like `-rf` (from `/-rf` under `-C`) from being read as an option.
+    cmd = match directory {
+        None => cmd.args(excludes.args()).arg("--").paths(paths.as_slice()),
+        Some(dir) => cmd
+            .arg("-C")
+            .arg(dir)
+            .args(excludes.relative_args())
+            .arg("--")
+            .paths(paths.relative().as_slice()),
+    };
+
+    cmd.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsString, os::unix::ffi::OsStringExt, path::PathBuf};
+
+    use super::*;
+    use crate::tar::Compression;
+
+    fn paths<I: IntoIterator<Item = PathBuf>>(iter: I) -> PathList {
+        let mut list = PathList::default();
+        for p in iter {
+            list.push_unique(p);
         }
+        list
     }
 
-    cmd.push_str(" --ignore-failed-read");
-    let exclude_str = excludes.to_string();
-    if !exclude_str.is_empty() {
-        cmd.push(' ');
-        cmd.push_str(&exclude_str);
+    fn command(paths: &PathList, directory: Option<&str>) -> String {
+        let comp = Compressor {
+            codec: Compression::None,
+            level: None,
+            threads: None,
+        };
+        build_tar_command(
+            "/backup/out.tar",
+            paths,
+            &ExcludeList::default(),
+            &comp,
+            &TarOptions::default(),
+            directory,
+        )
     }
 
-    cmd.push(' ');
-    cmd.push_str(&paths.to_string());
+    #[test]
+    fn golden_command_for_every_codec_and_mode() {
+        let list = paths([PathBuf::from("/etc"), PathBuf::from("/var/lib")]);
+        // codec, level, threads, compressor switches
+        let codecs: &[(Compression, Option<u32>, Option<u32>, &str)] = &[
+            (Compression::None, None, None, ""),
+            (Compression::Gzip, None, None, " -z"),
+            (Compression::Gzip, Some(9), Some(0), " -I 'pigz -9'"),
+            (Compression::Xz, None, None, " -J"),
+            (Compression::Xz, Some(6), Some(0), " -I 'xz -T0 -6'"),
+            (Compression::Zstd, None, None, " --zstd"),
+            (Compression::Zstd, Some(19), Some(0), " -I 'zstd -T0 -19'"),
+        ];
+        for &(codec, level, threads, switches) in codecs {
+            let comp = Compressor::new(codec, level, threads).unwrap();
+            let out = format!("/backup/out{}", codec.ext());
+            let build = |directory| {
+                build_tar_command(
+                    &out,
+                    &list,
+                    &ExcludeList::default(),
+                    &comp,
+                    &TarOptions::default(),
+                    directory,
+                )
+            };
+            let head = format!("sudo tar -cpv -f {out}{switches} --ignore-failed-read");
+            assert_eq!(build(None), format!("{head} -- /etc /var/lib"));
+            assert_eq!(
+                build(Some("/mnt/snap")),
+                format!("{head} -C /mnt/snap -- etc var/lib")
+            );
+        }
+    }
 
-    cmd
+    #[test]
+    fn hostile_paths_are_quoted_after_end_of_options() {
+        let list = paths([
+            PathBuf::from("/srv/it's $(rm -rf ~)"),
+            PathBuf::from("/srv/`id`\nnext"),
+            PathBuf::from("/srv/*.log"),
+            PathBuf::from(OsString::from_vec(b"/srv/caf\xe9".to_vec())),
+        ]);
+        assert_eq!(
+            command(&list, None),
+            "sudo tar -cpv -f /backup/out.tar --ignore-failed-read -- \
+             '/srv/it'\\''s $(rm -rf ~)' '/srv/`id`\nnext' '/srv/*.log' \
+             /srv/caf\"$(printf '\\351')\""
+        );
+    }
+
+    #[test]
+    fn leading_dash_under_directory_is_not_an_option() {
+        let list = paths([PathBuf::from("/-rf"), PathBuf::from("/--to-command=sh")]);
+        let cmd = command(&list, Some("/mnt/snap"));
+        assert!(
+            cmd.ends_with("-C /mnt/snap -- -rf --to-command=sh"),
+            "{cmd}"
+        );
+    }
 }
@@ -1,24 +1,63 @@
 use std::fmt;
+
+use crate::error::AppError;
+
 #[allow(dead_code)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Compression {
+    None,
     Gzip,
     Xz,
     Zstd,
 }
 
 impl Compression {
-    pub fn flag(self) -> &'static str {
+    pub fn parse(txt: &str) -> Result<Self, AppError> {
+        match txt.to_ascii_lowercase().as_str() {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            "xz" => Ok(Compression::Xz),
+            "zstd" => Ok(Compression::Zstd),
+            other => Err(AppError::Validation(format!(
+                "unknown tar compression `{other}` (expected none, gzip, xz or zstd)"
+            ))),
+        }
+    }
+
+    /// tar's built-in switch for the default level.
+    pub fn flag(self) -> Option<&'static str> {
         match self {
-            Compression::Gzip => "z", // -z
-            Compression::Xz => "J",   // -J
-            Compression::Zstd => "--zstd",
+            Compression::None => None,
+            Compression::Gzip => Some("-z"),
+            Compression::Xz => Some("-J"),
+            Compression::Zstd => Some("--zstd"),
+        }
+    }
+
+    /// Archive file extension.
+    pub fn ext(self) -> &'static str {
+        match self {
+            Compression::None => ".tar",
+            Compression::Gzip => ".tar.gz",
+            Compression::Xz => ".tar.xz",
+            Compression::Zstd => ".tar.zst",
+        }
+    }
+
+    fn levels(self) -> (u32, u32) {
+        match self {
+            Compression::None => (0, 0),
+            Compression::Gzip => (1, 9),
+            Compression::Xz => (0, 9),
+            Compression::Zstd => (1, 22),
         }
     }
 }
+
 impl fmt::Display for Compression {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let s = match self {
+            Compression::None => "none",
             Compression::Gzip => "gzip",
             Compression::Xz => "xz",
             Compression::Zstd => "zstd",
@@ -26,3 +65,168 @@ impl fmt::Display for Compression {
         write!(f, "{s}")
     }
 }
+
+/// Codec plus optional level and thread count (`0` = one per core).
+#[derive(Debug, Clone, Copy)]
+pub struct Compressor {
+    pub codec: Compression,
+    pub level: Option<u32>,
+    pub threads: Option<u32>,
+}
+
+impl Compressor {
+    pub fn new(
+        codec: Compression,
+        level: Option<u32>,
+        threads: Option<u32>,
+    ) -> Result<Self, AppError> {
+        if let Some(l) = level {
+            let (min, max) = codec.levels();
+            if codec == Compression::None || l < min || l > max {
+                return Err(AppError::Validation(format!(
+                    "compression level {l} is out of range for {codec}"
+                )));
+            }
+        }
+        Ok(Self {
+            codec,
+            level,
+            threads,
+        })
+    }
+
+    /// `tar` arguments selecting the compressor: the built-in switch, or
+    /// `-I <program>` when a level or thread count is set.
+    pub fn tar_args(&self) -> Vec<String> {
+        match self.program() {
+            Some(prog) => vec!["-I".into(), prog],
+            None => self.codec.flag().map(String::from).into_iter().collect(),
+        }
+    }
+
+    /// Remote binary tar runs for compression, if any.
+    pub fn tool(&self) -> Option<String> {
+        let program = self.program();
+        let program = program.as_deref().or(match self.codec {
+            Compression::None => None,
+            Compression::Gzip => Some("gzip"),
+            Compression::Xz => Some("xz"),
+            Compression::Zstd => Some("zstd"),
+        })?;
+        program.split_whitespace().next().map(String::from)
+    }
+
+    fn program(&self) -> Option<String> {
+        if self.level.is_none() && self.threads.is_none() {
+            return None;
+        }
+        let mut words = match (self.codec, self.threads) {
+            (Compression::None, _) => return None,
+            // gzip is single-threaded; pigz writes the same format.
+            (Compression::Gzip, None) => vec!["gzip".to_string()],
+            (Compression::Gzip, Some(0)) => vec!["pigz".to_string()],
+            (Compression::Gzip, Some(n)) => vec!["pigz".into(), format!("-p{n}")],
+            (Compression::Xz, None) => vec!["xz".into()],
+            (Compression::Xz, Some(n)) => vec!["xz".into(), format!("-T{n}")],
+            (Compression::Zstd, None) => vec!["zstd".into()],
+            (Compression::Zstd, Some(n)) => vec!["zstd".into(), format!("-T{n}")],
+        };
+        if let Some(l) = self.level {
+            if self.codec == Compression::Zstd && l > 19 {
+                words.push("--ultra".into());
+            }
+            words.push(format!("-{l}"));
+        }
+        Some(words.join(" "))
+    }
+}
+
+impl Default for Compressor {
+    fn default() -> Self {
+        Self {
+            codec: Compression::Gzip,
+            level: None,
+            threads: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [Compression; 4] = [
+        Compression::None,
+        Compression::Gzip,
+        Compression::Xz,
+        Compression::Zstd,
+    ];
+
+    #[test]
+    fn parse_round_trips_every_codec() {
+        for codec in ALL {
+            assert_eq!(Compression::parse(&codec.to_string()).unwrap(), codec);
+            assert_eq!(
+                Compression::parse(&codec.to_string().to_uppercase()).unwrap(),
+                codec
+            );
+        }
+        assert!(Compression::parse("bzip2").is_err());
+    }
+
+    /// Codec, level, threads, tar arguments, remote tool, extension.
+    type Case = (
+        Compression,
+        Option<u32>,
+        Option<u32>,
+        &'static [&'static str],
+        Option<&'static str>,
+        &'static str,
+    );
+
+    #[test]
+    fn golden_tar_args_tool_and_extension() {
+        #[rustfmt::skip]
+        let cases: &[Case] = &[
+            (Compression::None, None, None, &[], None, ".tar"),
+            (Compression::None, None, Some(0), &[], None, ".tar"),
+            (Compression::Gzip, None, None, &["-z"], Some("gzip"), ".tar.gz"),
+            (Compression::Gzip, Some(9), None, &["-I", "gzip -9"], Some("gzip"), ".tar.gz"),
+            (Compression::Gzip, None, Some(0), &["-I", "pigz"], Some("pigz"), ".tar.gz"),
+            (Compression::Gzip, Some(6), Some(4), &["-I", "pigz -p4 -6"], Some("pigz"), ".tar.gz"),
+            (Compression::Xz, None, None, &["-J"], Some("xz"), ".tar.xz"),
+            (Compression::Xz, Some(0), None, &["-I", "xz -0"], Some("xz"), ".tar.xz"),
+            (Compression::Xz, None, Some(0), &["-I", "xz -T0"], Some("xz"), ".tar.xz"),
+            (Compression::Xz, Some(6), Some(2), &["-I", "xz -T2 -6"], Some("xz"), ".tar.xz"),
+            (Compression::Zstd, None, None, &["--zstd"], Some("zstd"), ".tar.zst"),
+            (Compression::Zstd, Some(19), None, &["-I", "zstd -19"], Some("zstd"), ".tar.zst"),
+            (Compression::Zstd, None, Some(0), &["-I", "zstd -T0"], Some("zstd"), ".tar.zst"),
+            (Compression::Zstd, Some(22), Some(8), &["-I", "zstd -T8 --ultra -22"], Some("zstd"), ".tar.zst"),
+        ];
+        for &(codec, level, threads, args, tool, ext) in cases {
+            let c = Compressor::new(codec, level, threads).unwrap();
+            let case = format!("{codec} level={level:?} threads={threads:?}");
+            assert_eq!(c.tar_args(), args, "{case}");
+            assert_eq!(c.tool().as_deref(), tool, "{case}");
+            assert_eq!(codec.ext(), ext, "{case}");
+        }
+    }
+
+    #[test]
+    fn levels_out_of_range_are_rejected() {
+        let bad = [
+            (Compression::None, 1),
+            (Compression::Gzip, 0),
+            (Compression::Gzip, 10),
+            (Compression::Xz, 10),
+            (Compression::Zstd, 0),
+            (Compression::Zstd, 23),
+        ];
+        for (codec, level) in bad {
+            assert!(
+                Compressor::new(codec, Some(level), None).is_err(),
+                "{codec} {level}"
+            );
+        }
+    }
+}
@@ -1,16 +1,36 @@
 use std::fmt;
 
+use crate::shell::quote;
+
 #[derive(Debug, Default, Clone)]
-pub struct ExcludeList(Vec<String>);
+pub struct ExcludeList {
+    /// `--exclude` patterns: `/`-prefixed ones are paths, the rest match anywhere.
+    rules: Vec<String>,
+    /// Marker files whose directory is skipped entirely (`--exclude-tag-all`).
+    tags: Vec<String>,
+    /// Skip directories holding a `CACHEDIR.TAG`.
+    caches: bool,
+}
 
 impl ExcludeList {
     pub fn push_unique(&mut self, rule: impl Into<String>) {
         let r = rule.into();
-        if !self.0.contains(&r) {
-            self.0.push(r);
+        if !self.rules.contains(&r) {
+            self.rules.push(r);
+        }
+    }
+
+    pub fn push_tag(&mut self, tag: impl Into<String>) {
+        let t = tag.into();
+        if !self.tags.contains(&t) {
+            self.tags.push(t);
         }
     }
 
+    pub fn set_caches(&mut self, on: bool) {
+        self.caches = on;
+    }
+
     pub fn add_default_runtime(&mut self) {
         for r in [
             "/proc",
@@ -28,13 +48,50 @@ impl ExcludeList {
         }
     }
 
+    /// `tar` arguments for these rules.
+    pub fn args(&self) -> Vec<String> {
+        let mut args = self.tag_args();
+        args.extend(self.rules.iter().map(|e| format!("--exclude={e}")));
+        args
+    }
+
+    /// Arguments for archiving under `tar -C`, where member names are relative:
+    /// path rules lose their leading `/` and are anchored so `/proc` still
+    /// means the top-level `proc` only; the others keep matching anywhere.
+    pub fn relative_args(&self) -> Vec<String> {
+        let mut args = self.tag_args();
+        let (paths, names): (Vec<&String>, Vec<&String>) =
+            self.rules.iter().partition(|r| r.starts_with('/'));
+        if !paths.is_empty() {
+            args.push("--anchored".into());
+            // `/` itself is archived as `.`, so its members read `./proc`.
+            for r in paths {
+                let rel = r.trim_start_matches('/');
+                args.push(format!("--exclude={rel}"));
+                args.push(format!("--exclude=./{rel}"));
+            }
+            args.push("--no-anchored".into());
+        }
+        args.extend(names.iter().map(|r| format!("--exclude={r}")));
+        args
+    }
+
     pub fn join_for_shell(&self) -> String {
-        self.0
+        self.args()
             .iter()
-            .map(|e| format!("--exclude={}", e))
+            .map(|a| quote(a))
             .collect::<Vec<_>>()
             .join(" ")
     }
+
+    fn tag_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if self.caches {
+            args.push("--exclude-caches".into());
+        }
+        args.extend(self.tags.iter().map(|t| format!("--exclude-tag-all={t}")));
+        args
+    }
 }
 
 impl fmt::Display for ExcludeList {
@@ -42,3 +99,112 @@ impl fmt::Display for ExcludeList {
         write!(f, "{}", self.join_for_shell())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, process::Command};
+
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn list(rules: &[&str]) -> ExcludeList {
+        let mut l = ExcludeList::default();
+        for r in rules {
+            l.push_unique(*r);
+        }
+        l
+    }
+
+    #[test]
+    fn absolute_args_keep_the_rules_as_written() {
+        let mut l = list(&["/proc", "*.log", "/proc"]);
+        l.push_tag(".nobackup");
+        l.set_caches(true);
+        assert_eq!(
+            l.args(),
+            [
+                "--exclude-caches",
+                "--exclude-tag-all=.nobackup",
+                "--exclude=/proc",
+                "--exclude=*.log",
+            ]
+        );
+        assert_eq!(
+            list(&["/srv/it's here", "*.o"]).join_for_shell(),
+            r#"'--exclude=/srv/it'\''s here' '--exclude=*.o'"#
+        );
+    }
+
+    #[test]
+    fn relative_args_anchor_path_rules_only() {
+        assert_eq!(
+            list(&["/proc", "*.log", "/var/cache"]).relative_args(),
+            [
+                "--anchored",
+                "--exclude=proc",
+                "--exclude=./proc",
+                "--exclude=var/cache",
+                "--exclude=./var/cache",
+                "--no-anchored",
+                "--exclude=*.log",
+            ]
+        );
+        assert_eq!(list(&["*.log"]).relative_args(), ["--exclude=*.log"]);
+    }
+
+    /// The point of the anchoring: GNU tar under `-C` drops the top-level
+    /// `proc` but not `srv/proc`, and `*.log` anywhere.
+    #[test]
+    fn relative_args_select_the_right_members() {
+        let dir = TempDir::new("exclude-tar");
+        let root = dir.join("root");
+        for d in ["proc", "srv/proc", "srv/logs", "home/tagged"] {
+            fs::create_dir_all(root.join(d)).unwrap();
+        }
+        for f in [
+            "proc/stat",
+            "srv/proc/keep",
+            "srv/logs/a.log",
+            "srv/logs/keep",
+            "home/tagged/.nobackup",
+            "home/tagged/secret",
+        ] {
+            fs::write(root.join(f), "x").unwrap();
+        }
+        let mut rules = list(&["/proc", "*.log"]);
+        rules.push_tag(".nobackup");
+
+        let members = |paths: &[&str]| {
+            let archive = dir.join("out.tar");
+            let ok = Command::new("tar")
+                .arg("-cf")
+                .arg(&archive)
+                .arg("-C")
+                .arg(&root)
+                .args(rules.relative_args())
+                .args(paths)
+                .status()
+                .unwrap()
+                .success();
+            assert!(ok);
+            let out = Command::new("tar")
+                .arg("-tf")
+                .arg(&archive)
+                .output()
+                .unwrap();
+            let mut names: Vec<String> = String::from_utf8_lossy(&out.stdout)
+                .lines()
+                .filter(|l| !l.ends_with('/'))
+                .map(String::from)
+                .collect();
+            names.sort();
+            names
+        };
+        // `/` is archived as `.`; other filesystems by their relative path.
+        assert_eq!(members(&["."]), ["./srv/logs/keep", "./srv/proc/keep"]);
+        assert_eq!(
+            members(&["proc", "srv", "home"]),
+            ["srv/logs/keep", "srv/proc/keep"]
+        );
+    }
+}
@@ -2,8 +2,11 @@ pub mod builder;
 pub mod command;
 pub mod compression;
 pub mod exclude;
+pub mod options;
 pub mod paths;
+pub mod snapshot;
 pub mod verify;
 
 pub use builder::TarBuilder;
-pub use compression::Compression;
+pub use compression::{Compression, Compressor};
+pub use options::TarOptions;
@@ -0,0 +1,61 @@
+use serde::{Deserialize, Serialize};
+
+/// Metadata and file-layout switches; recorded with the archive so a
+/// restore extracts with the same ones.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TarOptions {
+    /// POSIX ACLs (`--acls`).
+    pub acls: bool,
+    /// Extended attributes of every namespace (`--xattrs`).
+    pub xattrs: bool,
+    /// SELinux contexts (`--selinux`).
+    pub selinux: bool,
+    /// Store uid/gid only, not user and group names.
+    pub numeric_owner: bool,
+    /// Stay on the filesystem of each listed path.
+    pub one_file_system: bool,
+    /// Store holes of sparse files instead of zeros.
+    pub sparse: bool,
+}
+
+impl TarOptions {
+    pub fn create_args(&self) -> Vec<String> {
+        let mut args = self.attr_args();
+        if self.numeric_owner {
+            args.push("--numeric-owner".into());
+        }
+        if self.one_file_system {
+            args.push("--one-file-system".into());
+        }
+        if self.sparse {
+            args.push("--sparse".into());
+        }
+        args
+    }
+
+    /// Extraction needs the same attribute switches; sparse members are
+    /// recreated as sparse files automatically.
+    pub fn extract_args(&self) -> Vec<String> {
+        let mut args = self.attr_args();
+        if self.numeric_owner {
+            args.push("--numeric-owner".into());
+        }
+        args
+    }
+
+    fn attr_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if self.acls {
+            args.push("--acls".into());
+        }
+        if self.xattrs {
+            // Without the include only `user.*` is restored.
+            args.push("--xattrs".into());
+            args.push("--xattrs-include=*".into());
+        }
+        if self.selinux {
+            args.push("--selinux".into());
+        }
+        args
+    }
+}
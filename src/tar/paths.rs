@@ -1,5 +1,7 @@
 use std::{fmt, path::PathBuf};
 
+use crate::shell::quote_os;
+
 #[derive(Debug, Default, Clone)]
 pub struct PathList(Vec<PathBuf>);
 
@@ -10,7 +12,6 @@ impl PathList {
             self.0.push(p);
         }
     }
-    #[allow(dead_code)]
     pub fn as_slice(&self) -> &[PathBuf] {
         &self.0
     }
@@ -19,10 +20,24 @@ impl PathList {
         self.0.is_empty()
     }
 
+    /// Same paths without the leading `/` (`/` itself becomes `.`), for `tar -C`.
+    pub fn relative(&self) -> Self {
+        let mut out = Self::default();
+        for p in &self.0 {
+            let rel = p.strip_prefix("/").unwrap_or(p);
+            if rel.as_os_str().is_empty() {
+                out.push_unique(".");
+            } else {
+                out.push_unique(rel);
+            }
+        }
+        out
+    }
+
     pub fn join_for_shell(&self) -> String {
         self.0
             .iter()
-            .map(|p| p.to_string_lossy())
+            .map(|p| quote_os(p.as_os_str()))
             .collect::<Vec<_>>()
             .join(" ")
     }
@@ -43,3 +58,30 @@ impl fmt::Display for PathList {
         write!(f, "{}", self.join_for_shell())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_paths_for_tar_directory() {
+        let list = PathList::from(vec![
+            "/".to_string(),
+            "/home".into(),
+            "/var/www/".into(),
+            "/home".into(),
+            "srv".into(),
+        ]);
+        assert_eq!(list.relative().to_string(), ". home var/www srv");
+    }
+
+    #[test]
+    fn shell_form_quotes_each_path() {
+        let list = PathList::from(vec!["/srv/my files".to_string(), "/opt/it's".into()]);
+        assert_eq!(list.to_string(), r#"'/srv/my files' '/opt/it'\''s'"#);
+        assert_eq!(
+            list.relative().to_string(),
+            r#"'srv/my files' 'opt/it'\''s'"#
+        );
+    }
+}
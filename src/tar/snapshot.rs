@@ -0,0 +1,406 @@
+//! Point-in-time tar source: every volume behind the configured paths is
+//! snapshotted and mounted read-only under a temp dir on the remote, at the
+//! same relative location, so archiving `-C <root> home/user` yields the
+//! original member names.
+
+use std::fmt;
+
+use chrono::Utc;
+
+use crate::{error::AppError, shell::Cmd, ssh::Ssh};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotKind {
+    None,
+    /// `lvcreate -s`; `size` is an `-L` size or an `-l` extent spec.
+    Lvm {
+        size: String,
+    },
+    /// Read-only `btrfs subvolume snapshot`, bind-mounted into the root.
+    Btrfs,
+}
+
+impl SnapshotKind {
+    pub fn parse(txt: &str, size: &str) -> Result<Self, AppError> {
+        match txt.to_ascii_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "lvm" => Ok(Self::Lvm { size: size.into() }),
+            "btrfs" => Ok(Self::Btrfs),
+            other => Err(AppError::Validation(format!(
+                "unknown tar snapshot kind `{other}` (expected none, lvm or btrfs)"
+            ))),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Lvm { .. } => "lvm",
+            Self::Btrfs => "btrfs",
+        }
+    }
+}
+
+/// Filesystems with nothing worth archiving, or nothing a volume snapshot
+/// could capture; not worth a warning when nested under a listed path.
+const PSEUDO_FS: &[&str] = &[
+    "autofs",
+    "binfmt_misc",
+    "bpf",
+    "cgroup",
+    "cgroup2",
+    "configfs",
+    "debugfs",
+    "devpts",
+    "devtmpfs",
+    "efivarfs",
+    "fusectl",
+    "hugetlbfs",
+    "mqueue",
+    "nsfs",
+    "overlay",
+    "proc",
+    "pstore",
+    "rpc_pipefs",
+    "securityfs",
+    "squashfs",
+    "sysfs",
+    "tmpfs",
+    "tracefs",
+];
+
+#[derive(Debug, PartialEq, Eq)]
+struct MountInfo {
+    source: String,
+    target: String,
+    fstype: String,
+}
+
+/// Temp root holding the mounted snapshots; torn down when dropped.
+pub struct SnapshotRoot<'a> {
+    ssh: &'a Ssh,
+    root: String,
+    /// Undo commands, most recent setup step first.
+    cleanup: Vec<String>,
+}
+
+impl<'a> SnapshotRoot<'a> {
+    /// Fails when a device mounted below one of `paths` is not listed
+    /// itself, as it would be archived empty; with `one_file_system` tar
+    /// stops at such mounts anyway.
+    pub fn prepare(
+        ssh: &'a Ssh,
+        kind: &SnapshotKind,
+        paths: &[String],
+        one_file_system: bool,
+    ) -> Result<Self, AppError> {
+        let mut mounts: Vec<MountInfo> = Vec::new();
+        for p in paths {
+            let m = find_mount(ssh, p)?;
+            if !mounts.iter().any(|x| x.target == m.target) {
+                mounts.push(m);
+            }
+        }
+        if !one_file_system {
+            let all = list_mounts(ssh)?;
+            let (devices, other): (Vec<_>, Vec<_>) = uncovered(&all, paths, &mounts)
+                .into_iter()
+                .partition(|m| m.source.starts_with("/dev/"));
+            for m in other {
+                log::warn!(
+                    "{} ({}) is mounted below a backed-up path and is not in the snapshot",
+                    m.target,
+                    m.fstype
+                );
+            }
+            if !devices.is_empty() {
+                let list: Vec<String> = devices
+                    .iter()
+                    .map(|m| format!("{} ({})", m.target, m.source))
+                    .collect();
+                return Err(AppError::Validation(format!(
+                    "{} mounted below the backed-up paths would be archived empty from the \
+                     snapshot; add them to `filesystems` or set backup.one_file_system = true",
+                    list.join(", ")
+                )));
+            }
+        }
+        // Parents first, so nested volumes are mounted on top of them.
+        mounts.sort_by_key(|m| m.target.matches('/').count() - usize::from(m.target == "/"));
+
+        let stamp = Utc::now().format("%Y%m%d%H%M%S");
+        let root = capture(ssh, "mktemp -d /tmp/data-backup.XXXXXX")?
+            .trim()
+            .to_string();
+        let mut snap = Self {
+            ssh,
+            root,
+            cleanup: Vec::new(),
+        };
+        // Only empty directories: never descends into a mount that failed to detach.
+        let find = sudo("find").arg(&snap.root);
+        snap.undo(find.args(["-xdev", "-depth", "-type", "d", "-empty", "-delete"]));
+
+        for m in &mounts {
+            let dest = format!("{}{}", snap.root, m.target.trim_end_matches('/'));
+            match kind {
+                SnapshotKind::None => unreachable!("no snapshot root without a snapshot kind"),
+                SnapshotKind::Lvm { size } => {
+                    let out = capture(
+                        ssh,
+                        sudo("lvs")
+                            .args(["--noheadings", "-o", "vg_name,lv_name"])
+                            .arg(&m.source),
+                    )?;
+                    let mut it = out.split_whitespace();
+                    let (Some(vg), Some(lv)) = (it.next(), it.next()) else {
+                        return Err(AppError::Remote(format!(
+                            "{} ({}) is not on an LVM logical volume",
+                            m.target, m.source
+                        )));
+                    };
+                    let name = format!("{lv}-backup-{stamp}");
+                    let size_arg = if size.contains('%') { "-l" } else { "-L" };
+                    exec(
+                        ssh,
+                        sudo("lvcreate")
+                            .args(["-q", "-s", "-n", &name, size_arg, size])
+                            .arg(format!("{vg}/{lv}")),
+                    )?;
+                    snap.undo(
+                        sudo("lvremove")
+                            .args(["-q", "-f"])
+                            .arg(format!("{vg}/{name}")),
+                    );
+                    // XFS refuses a second mount of the same UUID.
+                    let opts = if m.fstype == "xfs" { "ro,nouuid" } else { "ro" };
+                    exec(ssh, sudo("mkdir").arg("-p").arg(&dest))?;
+                    exec(
+                        ssh,
+                        sudo("mount")
+                            .args(["-o", opts])
+                            .arg(format!("/dev/{vg}/{name}"))
+                            .arg(&dest),
+                    )?;
+                    snap.undo(sudo("umount").arg("-l").arg(&dest));
+                }
+                SnapshotKind::Btrfs => {
+                    if m.fstype != "btrfs" {
+                        return Err(AppError::Remote(format!(
+                            "{} is {}, not btrfs",
+                            m.target, m.fstype
+                        )));
+                    }
+                    let dir = format!("{}/.data-backup-{stamp}", m.target.trim_end_matches('/'));
+                    exec(
+                        ssh,
+                        sudo("btrfs").args(["subvolume", "snapshot", "-r", &m.target, &dir]),
+                    )?;
+                    snap.undo(sudo("btrfs").args(["subvolume", "delete", &dir]));
+                    exec(ssh, sudo("mkdir").arg("-p").arg(&dest))?;
+                    exec(ssh, sudo("mount").args(["--bind", "-o", "ro", &dir, &dest]))?;
+                    snap.undo(sudo("umount").arg("-l").arg(&dest));
+                }
+            }
+            log::info!("Snapshot of {} mounted at {dest}", m.target);
+        }
+        Ok(snap)
+    }
+
+    pub fn root(&self) -> &str {
+        &self.root
+    }
+
+    fn undo(&mut self, cmd: Cmd) {
+        self.cleanup.insert(0, cmd.to_string());
+    }
+}
+
+impl Drop for SnapshotRoot<'_> {
+    fn drop(&mut self) {
+        for cmd in self.cleanup.drain(..) {
+            log::info!("Cleanup: {cmd}");
+            if let Err(e) = exec(self.ssh, &cmd) {
+                log::error!("cleanup `{cmd}` failed: {e}");
+            }
+        }
+    }
+}
+
+fn findmnt() -> Cmd {
+    Cmd::new("findmnt").args(["-n", "-r", "-o", "SOURCE,TARGET,FSTYPE"])
+}
+
+/// The mount a path lives on, from `findmnt --target`.
+fn find_mount(ssh: &Ssh, path: &str) -> Result<MountInfo, AppError> {
+    let out = capture(ssh, findmnt().arg("--target").arg(path))?;
+    out.lines()
+        .find_map(parse_mount)
+        .ok_or_else(|| AppError::Remote(format!("findmnt: no mount for {path}")))
+}
+
+fn list_mounts(ssh: &Ssh) -> Result<Vec<MountInfo>, AppError> {
+    Ok(capture(ssh, findmnt())?
+        .lines()
+        .filter_map(parse_mount)
+        .collect())
+}
+
+/// One line of `findmnt -r -o SOURCE,TARGET,FSTYPE`.
+fn parse_mount(line: &str) -> Option<MountInfo> {
+    let mut it = line.split_whitespace().map(unescape);
+    let (source, target, fstype) = (it.next()?, it.next()?, it.next()?);
+    Some(MountInfo {
+        // btrfs reports `/dev/sda2[/@home]`
+        source: source.split('[').next().unwrap_or_default().to_string(),
+        target,
+        fstype,
+    })
+}
+
+/// Mounts below one of `paths` that are not among those snapshotted: in the
+/// snapshot root they are empty directories.
+fn uncovered<'m>(
+    all: &'m [MountInfo],
+    paths: &[String],
+    snapshotted: &[MountInfo],
+) -> Vec<&'m MountInfo> {
+    all.iter()
+        .filter(|m| {
+            !PSEUDO_FS.contains(&m.fstype.as_str())
+                && !snapshotted.iter().any(|s| s.target == m.target)
+                && paths.iter().any(|p| is_below(&m.target, p))
+        })
+        .collect()
+}
+
+fn is_below(target: &str, path: &str) -> bool {
+    let path = path.trim_end_matches('/');
+    target
+        .strip_prefix(path)
+        .is_some_and(|rest| rest.len() > 1 && rest.starts_with('/'))
+}
+
+/// Undoes findmnt's `\x20` escaping in raw output.
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find("\\x") {
+        out.push_str(&rest[..i]);
+        match u8::from_str_radix(rest.get(i + 2..i + 4).unwrap_or_default(), 16) {
+            Ok(b) => {
+                out.push(b as char);
+                rest = &rest[i + 4..];
+            }
+            Err(_) => {
+                out.push_str("\\x");
+                rest = &rest[i + 2..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn sudo(program: &str) -> Cmd {
+    Cmd::new(program).sudo(true)
+}
+
+fn exec(ssh: &Ssh, cmd: impl fmt::Display) -> Result<(), AppError> {
+    ssh.exec_capture(&cmd.to_string(), &mut std::io::sink())
+}
+
+fn capture(ssh: &Ssh, cmd: impl fmt::Display) -> Result<String, AppError> {
+    let mut out = Vec::new();
+    ssh.exec_capture(&cmd.to_string(), &mut out)?;
+    Ok(String::from_utf8_lossy(&out).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FINDMNT: &str = "\
+/dev/mapper/vg0-root / ext4
+proc /proc proc
+/dev/sda1 /boot vfat
+/dev/mapper/vg0-home /home xfs
+/dev/sdb1[/@data] /home/shared\\x20data btrfs
+tmpfs /home/alice/.cache tmpfs
+nas:/export /home/nfs nfs4
+/dev/mapper/vg0-var /var ext4
+/dev/sdc1 /var/lib/docker ext4
+/dev/sdd1 /srv ext4
+";
+
+    fn mounts() -> Vec<MountInfo> {
+        FINDMNT.lines().filter_map(parse_mount).collect()
+    }
+
+    fn mount(source: &str, target: &str, fstype: &str) -> MountInfo {
+        MountInfo {
+            source: source.into(),
+            target: target.into(),
+            fstype: fstype.into(),
+        }
+    }
+
+    fn targets(m: Vec<&MountInfo>) -> Vec<&str> {
+        m.into_iter().map(|m| m.target.as_str()).collect()
+    }
+
+    #[test]
+    fn findmnt_lines_are_unescaped_and_btrfs_subvolumes_dropped() {
+        let all = mounts();
+        assert_eq!(all.len(), 10);
+        assert_eq!(all[4], mount("/dev/sdb1", "/home/shared data", "btrfs"));
+        assert_eq!(parse_mount("/dev/sda1 /boot"), None);
+    }
+
+    #[test]
+    fn unlisted_nested_mounts_are_found() {
+        let all = mounts();
+        let paths = vec!["/home".to_string(), "/var/".to_string()];
+        let snapshotted = vec![
+            mount("/dev/mapper/vg0-home", "/home", "xfs"),
+            mount("/dev/mapper/vg0-var", "/var", "ext4"),
+        ];
+        assert_eq!(
+            targets(uncovered(&all, &paths, &snapshotted)),
+            ["/home/shared data", "/home/nfs", "/var/lib/docker"]
+        );
+    }
+
+    #[test]
+    fn listed_nested_mounts_are_covered() {
+        let all = mounts();
+        let paths = vec!["/var".to_string(), "/var/lib/docker".to_string()];
+        let snapshotted = vec![
+            mount("/dev/mapper/vg0-var", "/var", "ext4"),
+            mount("/dev/sdc1", "/var/lib/docker", "ext4"),
+        ];
+        assert!(uncovered(&all, &paths, &snapshotted).is_empty());
+    }
+
+    #[test]
+    fn everything_below_root_counts() {
+        let all = mounts();
+        let paths = vec!["/".to_string()];
+        let snapshotted = vec![mount("/dev/mapper/vg0-root", "/", "ext4")];
+        assert_eq!(
+            targets(uncovered(&all, &paths, &snapshotted)),
+            [
+                "/boot",
+                "/home",
+                "/home/shared data",
+                "/home/nfs",
+                "/var",
+                "/var/lib/docker",
+                "/srv"
+            ]
+        );
+        // Siblings sharing a prefix are not below each other.
+        assert!(!is_below("/srv2", "/srv"));
+        assert!(!is_below("/srv", "/srv"));
+    }
+}
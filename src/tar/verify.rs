@@ -38,7 +38,7 @@ pub fn sha256_file<P: AsRef<std::path::Path>>(path: P) -> io::Result<String> {
 pub fn list_archive(path: &str) -> io::Result<()> {
     let status = Command::new("tar").arg("-tf").arg(path).status()?;
     if !status.success() {
-        return Err(io::Error::new(io::ErrorKind::Other, "tar -t failed"));
+        return Err(io::Error::other("tar -t failed"));
     }
     Ok(())
 }
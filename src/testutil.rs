@@ -0,0 +1,108 @@
+//! Shared unit test helpers: scratch directories, configs and snapshot records.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+    config::Config,
+    metadata::{
+        SnapshotRecord,
+        record::{Details, TarSection},
+    },
+    tar::TarOptions,
+};
+
+/// A fresh directory under the system temp dir, removed on drop.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(tag: &str) -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "data-backup-{tag}-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A tar config storing into `<dir>/store` with its catalog in `dir`;
+/// `extra` is appended as more TOML.
+pub fn config(dir: &Path, extra: &str) -> Config {
+    let store = dir.join("store");
+    fs::create_dir_all(&store).unwrap();
+    toml::from_str(&format!(
+        r#"
+        mode = "tar"
+        filesystems = ["/etc"]
+        [remote]
+        host = "127.0.0.1"
+        user = "root"
+        password = ""
+        [backup]
+        dir = "/backup"
+        filename = "snapshot-{{{{timestamp}}}}.tar"
+        [options]
+        local_download_dir = "{dir}"
+        catalog = "{catalog}"
+        [storage]
+        backend = "local"
+        path = "{store}"
+        {extra}
+        "#,
+        dir = dir.display(),
+        catalog = dir.join("catalog.json").display(),
+        store = store.display(),
+    ))
+    .unwrap()
+}
+
+/// Writes a completed tar snapshot `dir/name` of `filesystems` on `host`,
+/// finished at `at`, with its record next to it.
+pub fn tar_snapshot(
+    dir: &Path,
+    name: &str,
+    host: &str,
+    filesystems: &[&str],
+    at: DateTime<Utc>,
+) -> PathBuf {
+    let path = dir.join(name);
+    let data = format!("{host} {name}");
+    fs::write(&path, &data).unwrap();
+    let details = Details::Tar(TarSection {
+        remote_path: format!("/backup/{name}"),
+        filesystems: filesystems.iter().map(|f| f.to_string()).collect(),
+        options: TarOptions::default(),
+        snapshot: "none".into(),
+    });
+    let mut record = SnapshotRecord::new(details, &path, host, "none", at).completed(
+        data.len() as u64,
+        String::new(),
+        Vec::new(),
+    );
+    record.finished_at = at;
+    record.write().unwrap();
+    path
+}
@@ -0,0 +1,322 @@
+//! `[throttle]`: how fast snapshots may come over the network, by time of
+//! day, and at which CPU/I/O priority the remote `dd`/`tar` run.
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrono::{Local, NaiveTime};
+
+use crate::{config::ThrottleConfig, error::AppError, signal};
+
+/// How often a running transfer looks at the clock for a window change.
+const RECHECK: Duration = Duration::from_secs(1);
+/// Longest single sleep, so Ctrl-C stays responsive at low rates.
+const NAP: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone)]
+struct Window {
+    from: NaiveTime,
+    to: NaiveTime,
+    rate: u64,
+}
+
+impl Window {
+    /// `from` > `to` wraps past midnight (e.g. 22:00-06:00).
+    fn contains(&self, t: NaiveTime) -> bool {
+        if self.from <= self.to {
+            self.from <= t && t < self.to
+        } else {
+            t >= self.from || t < self.to
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    rate: u64,
+    windows: Vec<Window>,
+    remote: bool,
+    nice: Option<i32>,
+    /// `ionice` class and level.
+    ionice: Option<(u8, Option<u8>)>,
+}
+
+impl Limits {
+    pub fn from_config(cfg: &ThrottleConfig) -> Result<Self, AppError> {
+        let windows = cfg
+            .windows
+            .iter()
+            .map(|w| {
+                Ok(Window {
+                    from: parse_time(&w.from)?,
+                    to: parse_time(&w.to)?,
+                    rate: w.bandwidth_limit,
+                })
+            })
+            .collect::<Result<_, AppError>>()?;
+        let ionice = cfg.ionice.as_deref().map(parse_ionice).transpose()?;
+        if let Some(n) = cfg.nice
+            && !(0..=19).contains(&n)
+        {
+            return Err(AppError::Validation(format!(
+                "throttle.nice must be 0-19 (got {n})"
+            )));
+        }
+        Ok(Self {
+            rate: cfg.bandwidth_limit,
+            windows,
+            remote: cfg.remote,
+            nice: cfg.nice,
+            ionice,
+        })
+    }
+
+    /// Bytes per second allowed right now; 0 = unlimited.
+    pub fn rate_now(&self) -> u64 {
+        self.rate_at(Local::now().time())
+    }
+
+    /// The first window containing `t` wins; outside all of them,
+    /// `bandwidth_limit`.
+    fn rate_at(&self, t: NaiveTime) -> u64 {
+        self.windows
+            .iter()
+            .find(|w| w.contains(t))
+            .map_or(self.rate, |w| w.rate)
+    }
+
+    /// `cmd` with its output capped by `pv -L` on the remote, when that is
+    /// enabled and a limit applies at start.
+    pub fn remote_pipe(&self, cmd: String) -> String {
+        match self.rate_now() {
+            rate if self.remote && rate > 0 => format!("{cmd} | pv -q -L {rate}"),
+            _ => cmd,
+        }
+    }
+
+    /// `cmd` preceded by lowering the priority of the shell running it, which
+    /// every process it starts (sudo included) inherits. Best effort: a
+    /// missing `ionice` does not stop the backup.
+    pub fn niced(&self, cmd: &str) -> String {
+        let mut out = String::new();
+        if let Some(n) = self.nice {
+            out.push_str(&format!("renice -n {n} -p $$ >/dev/null 2>&1; "));
+        }
+        if let Some((class, level)) = self.ionice {
+            let level = level.map(|l| format!(" -n {l}")).unwrap_or_default();
+            out.push_str(&format!("ionice -c {class}{level} -p $$ >/dev/null 2>&1; "));
+        }
+        out.push_str(cmd);
+        out
+    }
+
+    pub fn pacer(&self) -> Pacer {
+        Pacer {
+            limits: self.clone(),
+            rate: self.rate_now(),
+            since: Instant::now(),
+            bytes: 0,
+            checked: Instant::now(),
+        }
+    }
+}
+
+/// Keeps one transfer under the current limit.
+pub struct Pacer {
+    limits: Limits,
+    rate: u64,
+    since: Instant,
+    bytes: u64,
+    checked: Instant,
+}
+
+impl Pacer {
+    /// Accounts for `n` bytes just received, sleeping as long as it takes
+    /// to stay under the limit.
+    pub fn pace(&mut self, n: usize) -> Result<(), AppError> {
+        if self.checked.elapsed() >= RECHECK {
+            self.checked = Instant::now();
+            self.set_rate(self.limits.rate_now());
+        }
+        if self.rate == 0 {
+            return Ok(());
+        }
+        self.bytes += n as u64;
+        let due = Duration::from_secs_f64(self.bytes as f64 / self.rate as f64);
+        let elapsed = self.since.elapsed();
+        if elapsed > due + RECHECK {
+            // The source was slower than the limit: do not let it burst to catch up.
+            self.restart();
+            return Ok(());
+        }
+        let mut wait = due.saturating_sub(elapsed);
+        while !wait.is_zero() {
+            signal::check()?;
+            let nap = wait.min(NAP);
+            thread::sleep(nap);
+            wait -= nap;
+        }
+        Ok(())
+    }
+
+    /// A new limit is measured from now, not from the start of the transfer.
+    fn set_rate(&mut self, rate: u64) {
+        if rate != self.rate {
+            log::info!("Bandwidth limit now {}", describe(rate));
+            self.rate = rate;
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.since = Instant::now();
+        self.bytes = 0;
+    }
+}
+
+pub fn describe(rate: u64) -> String {
+    match rate {
+        0 => "unlimited".into(),
+        r => format!("{r} bytes/s"),
+    }
+}
+
+fn parse_time(txt: &str) -> Result<NaiveTime, AppError> {
+    NaiveTime::parse_from_str(txt, "%H:%M")
+        .map_err(|_| AppError::Validation(format!("throttle window time `{txt}` is not HH:MM")))
+}
+
+/// "idle", "best-effort" or "best-effort:<0-7>".
+fn parse_ionice(txt: &str) -> Result<(u8, Option<u8>), AppError> {
+    let (class, level) = match txt.split_once(':') {
+        Some((c, l)) => (c, Some(l)),
+        None => (txt, None),
+    };
+    let bad = || {
+        AppError::Validation(format!(
+            "throttle.ionice `{txt}` (expected idle, best-effort or best-effort:0-7)"
+        ))
+    };
+    match (class.to_ascii_lowercase().as_str(), level) {
+        ("idle", None) => Ok((3, None)),
+        ("best-effort", None) => Ok((2, None)),
+        ("best-effort", Some(l)) => match l.parse::<u8>() {
+            Ok(l) if l <= 7 => Ok((2, Some(l))),
+            _ => Err(bad()),
+        },
+        _ => Err(bad()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn t(hm: &str) -> NaiveTime {
+        parse_time(hm).unwrap()
+    }
+
+    fn limits(toml: &str) -> Result<Limits, AppError> {
+        Limits::from_config(&toml::from_str::<ThrottleConfig>(toml).unwrap())
+    }
+
+    #[test]
+    fn window_bounds_and_midnight_wrap() {
+        let day = Window {
+            from: t("09:00"),
+            to: t("17:00"),
+            rate: 1,
+        };
+        assert!(day.contains(t("09:00")) && day.contains(t("16:59")));
+        assert!(!day.contains(t("17:00")) && !day.contains(t("08:59")));
+
+        let night = Window {
+            from: t("22:00"),
+            to: t("06:00"),
+            rate: 0,
+        };
+        for inside in ["22:00", "23:59", "00:00", "05:59"] {
+            assert!(night.contains(t(inside)), "{inside}");
+        }
+        for outside in ["06:00", "12:00", "21:59"] {
+            assert!(!night.contains(t(outside)), "{outside}");
+        }
+    }
+
+    #[test]
+    fn first_matching_window_sets_the_rate() {
+        let l = limits(
+            r#"
+            bandwidth_limit = 1000
+            [[windows]]
+            from = "22:00"
+            to = "06:00"
+            bandwidth_limit = 0
+            [[windows]]
+            from = "00:00"
+            to = "12:00"
+            bandwidth_limit = 500
+            "#,
+        )
+        .unwrap();
+        assert_eq!(l.rate_at(t("23:00")), 0);
+        assert_eq!(l.rate_at(t("03:00")), 0);
+        assert_eq!(l.rate_at(t("07:00")), 500);
+        assert_eq!(l.rate_at(t("15:00")), 1000);
+    }
+
+    #[test]
+    fn bad_settings_are_rejected() {
+        assert!(limits("nice = 20").is_err());
+        assert!(limits("nice = -1").is_err());
+        assert!(
+            limits("[[windows]]\nfrom = \"25:00\"\nto = \"06:00\"\nbandwidth_limit = 0").is_err()
+        );
+    }
+
+    #[test]
+    fn ionice_classes() {
+        assert_eq!(parse_ionice("idle").unwrap(), (3, None));
+        assert_eq!(parse_ionice("best-effort").unwrap(), (2, None));
+        assert_eq!(parse_ionice("Best-Effort:7").unwrap(), (2, Some(7)));
+        for bad in ["best-effort:8", "best-effort:", "idle:3", "realtime", ""] {
+            assert!(parse_ionice(bad).is_err(), "{bad:?}");
+        }
+        let l = limits("nice = 10\nionice = \"best-effort:7\"").unwrap();
+        assert_eq!(
+            l.niced("dd"),
+            "renice -n 10 -p $$ >/dev/null 2>&1; ionice -c 2 -n 7 -p $$ >/dev/null 2>&1; dd"
+        );
+    }
+
+    #[test]
+    fn pacer_follows_rate_changes() {
+        let mut p = Limits::default().pacer();
+        p.set_rate(1_000_000);
+        let start = Instant::now();
+        p.pace(200_000).unwrap();
+        assert!(
+            start.elapsed() >= Duration::from_millis(180),
+            "{:?}",
+            start.elapsed()
+        );
+
+        // Lifting the limit lets the rest through at once.
+        p.set_rate(0);
+        let start = Instant::now();
+        p.pace(100_000_000).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        // A new limit starts counting afresh instead of charging for the
+        // unlimited stretch.
+        p.set_rate(10_000_000);
+        assert_eq!(p.bytes, 0);
+        let start = Instant::now();
+        p.pace(100_000).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(100));
+        p.set_rate(10_000_000);
+        assert_eq!(p.bytes, 100_000);
+    }
+}
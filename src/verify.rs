@@ -0,0 +1,106 @@
+//! Check a snapshot against its metadata (`verify` command), locally or in
+//! the configured storage.
+
+use std::{io::Read, path::Path};
+
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config,
+    error::AppError,
+    metadata::sidecar,
+    signal,
+    storage::{self, LocalStorage, Storage, Stored},
+    volume,
+};
+
+pub fn run(config_path: &str, snapshot: &Path) -> Result<(), AppError> {
+    let snapshot = volume::base_of(snapshot);
+    let name = storage::file_name(&snapshot);
+    if sidecar::locate(&snapshot).is_ok() {
+        let dir = snapshot.parent().map(Path::to_path_buf).unwrap_or_default();
+        return check(&LocalStorage::new(dir), &name);
+    }
+    // Not here: look in the configured backend.
+    let cfg = config::load(config_path)?;
+    let store = storage::open(&cfg)?;
+    check(store.as_ref(), &name)
+}
+
+fn check(store: &dyn Storage, name: &str) -> Result<(), AppError> {
+    check_stored(store, &storage::find(store, name)?)
+}
+
+/// One pass over the data: per-volume hashes (so a damaged volume is named)
+/// and the whole-stream hash together.
+pub fn check_stored(store: &dyn Storage, stored: &Stored) -> Result<(), AppError> {
+    log::info!(
+        "Verifying {} ({}) in {}",
+        stored.name,
+        stored.kind.name(),
+        store.describe()
+    );
+
+    let mut whole = Sha256::new();
+    let mut total = 0u64;
+    let mut bad = Vec::new();
+    if stored.meta.volumes.is_empty() {
+        total = hash_into(store.get(&stored.name)?, &mut [&mut whole])?;
+    } else {
+        for v in &stored.meta.volumes {
+            let mut part = Sha256::new();
+            let (bytes, sha) = match store.get(&v.file) {
+                Ok(r) => {
+                    let n = hash_into(r, &mut [&mut whole, &mut part])?;
+                    (n, hex::encode(part.finalize()))
+                }
+                Err(e) => {
+                    log::error!("{}: {e}", v.file);
+                    (0, String::new())
+                }
+            };
+            total += bytes;
+            if bytes == v.bytes && sha == v.sha256 {
+                log::info!("{}: ok", v.file);
+            } else {
+                log::error!("{}: missing or does not match its recorded sha256", v.file);
+                bad.push(v.file.clone());
+            }
+        }
+    }
+    if !bad.is_empty() {
+        return Err(AppError::Validation(format!(
+            "{} of {} volumes are damaged: {}",
+            bad.len(),
+            stored.meta.volumes.len(),
+            bad.join(", ")
+        )));
+    }
+
+    let sha = hex::encode(whole.finalize());
+    if sha != stored.meta.hashes.sha256 {
+        return Err(AppError::Validation(format!(
+            "{} does not match its recorded sha256 ({sha} != {})",
+            stored.name, stored.meta.hashes.sha256
+        )));
+    }
+    log::info!("{}: ok, {total} bytes, sha256 {sha}", stored.name);
+    Ok(())
+}
+
+/// Feeds everything `r` yields to each hasher; returns the byte count.
+fn hash_into(mut r: impl Read, hashers: &mut [&mut Sha256]) -> Result<u64, AppError> {
+    let mut buf = vec![0u8; 1 << 20];
+    let mut total = 0u64;
+    loop {
+        signal::check()?;
+        let n = r.read(&mut buf)?;
+        if n == 0 {
+            return Ok(total);
+        }
+        for h in hashers.iter_mut() {
+            h.update(&buf[..n]);
+        }
+        total += n as u64;
+    }
+}
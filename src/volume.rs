@@ -0,0 +1,307 @@
+//! Fixed-size volumes: a local snapshot stored as `<name>.001`, `<name>.002`, …
+//! instead of one file, each volume hashed on its own.
+
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::partial;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VolumeInfo {
+    /// File name, in the directory of the snapshot.
+    pub file: String,
+    pub bytes: u64,
+    pub sha256: String,
+}
+
+/// `<base>.<n>`, numbered from 1.
+pub fn volume_path(base: &Path, n: usize) -> PathBuf {
+    let mut name = base.as_os_str().to_owned();
+    name.push(format!(".{n:03}"));
+    PathBuf::from(name)
+}
+
+/// `x.img.001` → `x.img` when only the volumes exist; anything else unchanged.
+pub fn base_of(path: &Path) -> PathBuf {
+    if !path.exists() {
+        return path.to_path_buf();
+    }
+    let s = path.to_string_lossy();
+    match s.rsplit_once('.') {
+        Some((base, n)) if n.len() >= 3 && n.bytes().all(|b| b.is_ascii_digit()) => {
+            let base = PathBuf::from(base);
+            if base.exists() {
+                path.to_path_buf()
+            } else {
+                base
+            }
+        }
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Writes a stream as consecutive volumes of at most `volume_size` bytes,
+/// each as `<volume>.partial` until [`commit`].
+pub struct VolumeWriter {
+    base: PathBuf,
+    volume_size: u64,
+    current: Option<(File, Sha256, u64)>,
+    done: Vec<VolumeInfo>,
+}
+
+impl VolumeWriter {
+    pub fn new(base: impl Into<PathBuf>, volume_size: u64) -> Self {
+        Self {
+            base: base.into(),
+            volume_size: volume_size.max(1),
+            current: None,
+            done: Vec::new(),
+        }
+    }
+
+    /// Syncs the last volume. Returns the volumes in order, still `.partial`.
+    pub fn finish(mut self) -> io::Result<Vec<VolumeInfo>> {
+        self.close_current()?;
+        Ok(self.done)
+    }
+
+    fn close_current(&mut self) -> io::Result<()> {
+        if let Some((file, hasher, bytes)) = self.current.take() {
+            file.sync_all()?;
+            let path = volume_path(&self.base, self.done.len() + 1);
+            self.done.push(VolumeInfo {
+                file: path
+                    .file_name()
+                    .map(|n| n.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+                bytes,
+                sha256: hex::encode(hasher.finalize()),
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Write for VolumeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if matches!(&self.current, Some((_, _, bytes)) if *bytes >= self.volume_size) {
+            self.close_current()?;
+        }
+        if self.current.is_none() {
+            let path = partial::path(&volume_path(&self.base, self.done.len() + 1));
+            self.current = Some((File::create(path)?, Sha256::new(), 0));
+        }
+        let (file, hasher, bytes) = self.current.as_mut().expect("open volume");
+        let room = (self.volume_size - *bytes).min(buf.len() as u64) as usize;
+        let n = file.write(&buf[..room])?;
+        hasher.update(&buf[..n]);
+        *bytes += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.current {
+            Some((file, _, _)) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Gives the volumes of a finished [`VolumeWriter`] their final names and
+/// removes higher-numbered leftovers of an earlier, longer run.
+pub fn commit(base: &Path, volumes: &[VolumeInfo]) -> io::Result<()> {
+    for n in 1..=volumes.len() {
+        partial::commit(&volume_path(base, n))?;
+    }
+    let mut n = volumes.len() + 1;
+    while volume_path(base, n).exists() {
+        std::fs::remove_file(volume_path(base, n))?;
+        n += 1;
+    }
+    Ok(())
+}
+
+/// Reads the volumes of a snapshot back as one stream.
+pub struct VolumeReader {
+    paths: std::vec::IntoIter<PathBuf>,
+    current: Option<File>,
+}
+
+impl VolumeReader {
+    pub fn open(base: &Path, volumes: &[VolumeInfo]) -> Self {
+        let dir = base.parent().unwrap_or(Path::new(""));
+        let paths: Vec<PathBuf> = volumes.iter().map(|v| dir.join(&v.file)).collect();
+        Self {
+            paths: paths.into_iter(),
+            current: None,
+        }
+    }
+}
+
+impl Read for VolumeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.current.is_none() {
+                match self.paths.next() {
+                    Some(p) => self.current = Some(File::open(p)?),
+                    None => return Ok(0),
+                }
+            }
+            let n = self.current.as_mut().expect("open volume").read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            self.current = None;
+        }
+    }
+}
+
+/// Passes writes through to `inner`, hashing them on the way.
+pub struct HashWriter<W> {
+    inner: W,
+    hasher: Sha256,
+    bytes: u64,
+}
+
+impl<W: Write> HashWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            bytes: 0,
+        }
+    }
+
+    /// The inner writer, the sha256 of what went through and its length.
+    pub fn finish(self) -> (W, String, u64) {
+        (self.inner, hex::encode(self.hasher.finalize()), self.bytes)
+    }
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.bytes += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Opens a snapshot as one stream, reassembling volumes when there are any;
+/// returns the reader and the total length.
+pub fn open(base: &Path, volumes: &[VolumeInfo]) -> io::Result<(Box<dyn Read>, u64)> {
+    if volumes.is_empty() {
+        let file = File::open(base)?;
+        let len = file.metadata()?.len();
+        Ok((Box::new(file), len))
+    } else {
+        let len = volumes.iter().map(|v| v.bytes).sum();
+        Ok((Box::new(VolumeReader::open(base, volumes)), len))
+    }
+}
+
+/// sha256 of everything `r` yields.
+pub fn sha256_reader<R: Read>(mut r: R) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut r, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    const SIZE: u64 = 1000;
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    /// Streams `data` through a hashing volume writer in `chunk`-sized
+    /// writes and commits it; returns the volumes and the whole-stream hash.
+    fn split(base: &Path, data: &[u8], chunk: usize) -> (Vec<VolumeInfo>, String) {
+        let mut w = HashWriter::new(VolumeWriter::new(base, SIZE));
+        for piece in data.chunks(chunk) {
+            w.write_all(piece).unwrap();
+        }
+        let (volumes, sha256, bytes) = w.finish();
+        let volumes = volumes.finish().unwrap();
+        assert_eq!(bytes, data.len() as u64);
+        commit(base, &volumes).unwrap();
+        (volumes, sha256)
+    }
+
+    #[test]
+    fn stream_ending_on_a_boundary_makes_no_empty_volume() {
+        let dir = TempDir::new("volume-boundary");
+        let base = dir.join("x.img");
+        let input = data(3 * SIZE as usize);
+        let (volumes, _) = split(&base, &input, 300);
+        let names: Vec<&str> = volumes.iter().map(|v| v.file.as_str()).collect();
+        assert_eq!(names, ["x.img.001", "x.img.002", "x.img.003"]);
+        for (v, expected) in volumes.iter().zip(input.chunks(SIZE as usize)) {
+            assert_eq!(v.bytes, SIZE);
+            let on_disk = std::fs::read(dir.join(&v.file)).unwrap();
+            assert!(on_disk == expected, "{} differs", v.file);
+            assert_eq!(v.sha256, sha256_reader(expected).unwrap());
+        }
+        assert!(!volume_path(&base, 4).exists());
+        assert!(!partial::path(&volume_path(&base, 3)).exists());
+    }
+
+    #[test]
+    fn volumes_reassemble_to_the_recorded_hash() {
+        let dir = TempDir::new("volume-reassemble");
+        let base = dir.join("x.tar");
+        let input = data(2 * SIZE as usize + 1);
+        for chunk in [1, 999, 1000, 1001, input.len()] {
+            let (volumes, sha256) = split(&base, &input, chunk);
+            assert_eq!(volumes.len(), 3, "chunk {chunk}");
+            assert_eq!(volumes[2].bytes, 1);
+            assert_eq!(sha256, sha256_reader(input.as_slice()).unwrap());
+
+            let mut back = Vec::new();
+            VolumeReader::open(&base, &volumes)
+                .read_to_end(&mut back)
+                .unwrap();
+            assert!(back == input, "reassembled stream differs, chunk {chunk}");
+            let (reader, len) = open(&base, &volumes).unwrap();
+            assert_eq!(len, input.len() as u64);
+            assert_eq!(sha256_reader(reader).unwrap(), sha256);
+        }
+        // Only the volumes exist, so the first one names the snapshot.
+        assert_eq!(base_of(&volume_path(&base, 1)), base);
+    }
+
+    #[test]
+    fn volumes_stay_partial_until_commit_and_leftovers_go() {
+        let dir = TempDir::new("volume-commit");
+        let base = dir.join("x.img");
+        // An earlier, longer run of the same name.
+        split(&base, &data(4 * SIZE as usize), 500);
+        assert!(volume_path(&base, 4).exists());
+
+        let mut w = VolumeWriter::new(&base, SIZE);
+        w.write_all(&data(SIZE as usize + 10)).unwrap();
+        let volumes = w.finish().unwrap();
+        assert!(partial::path(&volume_path(&base, 2)).exists());
+        commit(&base, &volumes).unwrap();
+        assert!(volume_path(&base, 2).exists());
+        assert!(!volume_path(&base, 3).exists());
+        assert!(!volume_path(&base, 4).exists());
+    }
+}